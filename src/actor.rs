@@ -1,17 +1,43 @@
+use actix::dev::{channel, MessageResponse, ResponseChannel};
 use actix::prelude::*;
 use actix::ActorContext;
 use rlua::Error as LuaError;
-use rlua::{FromLua, Function, Lua, MultiValue, ToLua, Value};
+use rlua::{FromLua, Function, Lua, MultiValue, Table, Value};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future};
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::mem;
 use std::str;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-use message::LuaMessage;
-
-use builder::{InitializeVM, LuaActorBuilder};
+#[cfg(feature = "broker")]
+use broker::BrokerSubscription;
+use address::{Forward, LuaAddresses, Register, Unregister};
+use bus::{Broadcast, JoinGroup, LuaBus, Publish, Subscribe};
+use cancel::CancelToken;
+use contract;
+use error::{ActixLuaError, ErrorPolicy};
+use gc;
+use message::{unpack_values, Hop, KeyCache, LuaMessage, Optional, Tell, Values};
+use metrics::{InvocationCost, LuaActorMetrics};
+use remote::{is_remote_address, LuaNode, MessageEncoder, SendEncoded, DEFAULT_BUFFER_CAPACITY};
+#[cfg(feature = "json")]
+use schema::Schema;
+use tenant::{TenantVm, Tenants};
+use version::MessageVersion;
+use vm::{self, catch_panic};
+
+use builder::{
+    AsyncInitializeVM, CostFn, HandleFn, InitializeVM, LuaActorBuilder, NewVM, PanicFn,
+    DEFAULT_MAX_HOPS,
+};
 
 /// Top level struct which holds a lua state for itself.
 ///
@@ -23,6 +49,40 @@ use builder::{InitializeVM, LuaActorBuilder};
 /// ### `ctx.msg`
 /// The message sent to Lua actor.
 ///
+/// In the `started` hook, the argument given with [`LuaActorBuilder::with_start_arg`], or `nil`.
+///
+/// In the `stopped` hook, why the VM is stopped:
+/// * `"stop"`: the actor was stopped with `ctx.terminate()`, or every address was dropped
+/// * `"drain"`: the actor was sent [`Drain`]
+/// * `"restart"`: the VM is replaced by a fresh one, with `ErrorPolicy::Restart` or by a
+///   supervisor
+/// * `"error"`: a hook failed with `ErrorPolicy::Stop`
+/// * `"evict"`: the VM of a tenant is dropped to make room for another one
+/// * `"shutdown"`: the system shut down while the actor was running. The hook can't send
+///   messages anymore.
+///
+/// The `stopping` hook gets the reason as well, see [`LuaActorBuilder::on_stopping_with_lua`].
+///
+/// In the `handle_batch` hook, an array of the queued messages with their count in `ctx.msg.n`.
+/// See [`LuaActorBuilder::on_handle_batch`].
+///
+/// ### `ctx.meta`
+/// The metadata of the message, a table which is empty unless the sender set it with
+/// `ctx.send(recipient, msg, meta)` or [`SendWithMeta`]. It is also passed to the hooks as their
+/// second argument, `local msg, meta = ...`. In the `handle_batch` hook, an array of the
+/// metadata of every message.
+///
+/// ### `ctx.self`
+/// The address of the actor, a string other Lua actors can use as the recipient of
+/// `ctx.send` and `ctx.do_send`. Pass it in a message to be replied to later, e.g.
+/// `ctx.do_send("worker", { job = job, reply_to = ctx.self })`. Only valid in the same
+/// `actix::System`.
+///
+/// ### `ctx.id` and `ctx.name`
+/// A unique id of the actor, and the name it was given with `LuaActorBuilder::with_name`, or
+/// `nil`. The logs of the actor and its `InvocationCost`s name it by its name, or by its id if
+/// it has none.
+///
 /// ### `ctx.notify(msg)`
 /// Send message `msg` to self.
 ///
@@ -32,23 +92,172 @@ use builder::{InitializeVM, LuaActorBuilder};
 /// ### `local recipient = ctx.new_actor(script_path, [actor_name])`
 /// Create a new actor with given lua script. returns a recipient which can be used in `ctx.send` and `ctx.do_send`.
 ///
-/// ### `local result = ctx.send(recipient, msg)`
+/// ### `local result = ctx.send(recipient, msg, [meta])`
 /// Send message `msg` to `recipient asynchronously and wait for response.
 ///
 /// Equivalent to `actix::Recipient.send`.
 ///
-/// ### `ctx.do_send(recipient, msg)`
+/// `recipient` can also be the address of an actor on another node, `"node@host:port/actor_name"`.
+/// Raises an error if the message can't be delivered, or if the handler of `recipient` raised one.
+/// See [`LuaNode`].
+///
+/// Takes the metadata of the message as an optional third argument, a table. Only other
+/// `LuaActor`s receive it.
+///
+/// ### `ctx.do_send(recipient, msg, [meta])`
 /// Send message `msg` to `recipient`.
 ///
-/// Equivalent to `actix::Recipient.do_send`.
+/// Equivalent to `actix::Recipient.do_send`. Takes metadata like `ctx.send`. Raises
+/// `ActixLuaError::ActorStopped` if the recipient, one the script knows by name, has stopped.
+///
+/// ### `local token = ctx.reply_later()`
+/// Answer the message being handled later, with `ctx.reply(token, value)`. The return value of
+/// the `handle` hook is ignored then. Must be called before the hook returns or yields.
+///
+/// ### `ctx.reply(token, value)`
+/// Answer the message deferred with `token`, from any hook. Returns `false` if the message was
+/// already answered or its sender is gone.
+///
+/// ### `ctx.emit(value)`
+/// Send `value` to the stream of a message sent with [`SendStream`], before the hook returns.
+/// Returns `false` if the stream was dropped. Raises an error for other messages.
+///
+/// ### `local id = ctx.stash(value)`
+/// Keep `value` in the VM across messages, and return an integer id for it. Any Lua value can
+/// be stashed, and the id is cheap to pass around in messages instead of the value.
+///
+/// ### `local value = ctx.take(id)`
+/// Remove the value stashed as `id` and return it, `nil` if it was taken already. Stashed values
+/// are lost when the VM is replaced, e.g. by `ErrorPolicy::Restart`.
+///
+/// ### `local handled = ctx.handled_since_idle()`
+/// The messages the actor handled since its mailbox last ran empty. It is not the length of the
+/// mailbox, which actix doesn't expose, and only says how long the actor has been busy: it grows
+/// while messages arrive faster than they are handled, so a handler can shed load past a
+/// threshold, e.g. `if ctx.handled_since_idle() > 100 then return nil end`.
+///
+/// ### `local queued = ctx.mailbox_len()`
+/// The messages sent through the [`LuaAddr`] of the actor which it hasn't started to handle
+/// yet, as of the start of the hook. Messages sent through its `Addr` aren't counted.
+///
+/// ### `local cancelled = ctx.cancelled()`
+/// Whether the sender cancelled the message, which was sent as a [`Cancellable`]. A long running
+/// handler can check it to give up early. The `handle` hook of a cancelled message is aborted
+/// with `ActixLuaError::Cancelled` anyway, a thousand instructions later at most, or when a
+/// thread suspended in `ctx.send` or `ctx.spawn_blocking` is resumed.
+///
+/// ### `ctx.contract([topic], contract)`
+/// Check the messages with `ctx.topic` equal to `topic` against `contract` before the `handle`
+/// hook runs, usually from the `started` hook. Without a `topic`, the contract applies to the
+/// messages whose topic has no contract.
+/// A contract is a type, one of `any`, `boolean`, `integer`, `number`, `string`, `table` and
+/// `array`, with a `?` if `nil` is allowed too, or a table of the contracts of the fields of a
+/// table: `ctx.contract { order_id = "integer", items = "array" }`. Messages which don't match
+/// are answered with `ActixLuaError::InvalidMessage`, and published ones are dropped.
+///
+/// ### `local result = ctx.spawn_blocking(f, [arg])`
+/// Run the function `f` with `arg` in a VM of its own on a new thread, and wait for its result
+/// while the actor handles other messages. Use it for a heavy computation which would hold up
+/// every actor of the arbiter. `f` is copied with `string.dump`: it only sees the standard
+/// libraries, and the locals it captures are `nil`. Raises the error `f` raised.
 ///
 /// ### `ctx.terminate()`
 /// Terminate actor execution.
 ///
+/// ### `local actors = ctx.actors()`
+/// The actors spawned by [`LuaActorSpawner`], an array of tables with their `name`, the hashes
+/// of their `scripts` by hook, their `uptime` in seconds and their `requests` and
+/// `notifications` counts. Empty unless this actor was spawned by it as well.
+///
+/// ### `ctx.subscribe(pattern)`
+/// Subscribe to topics matching `pattern` on the [`LuaBus`]. Published messages are delivered to the `handle` hook.
+///
+/// ### `ctx.publish(topic, msg)`
+/// Publish message `msg` to every subscriber of `topic` on the [`LuaBus`].
+///
+/// ### `ctx.topic`
+/// The topic of the message if it was delivered by the [`LuaBus`], or the name of its type for
+/// a Rust message handled with [`impl_lua_handler!`], `nil` otherwise.
+///
+/// ### `ctx.join(group)`
+/// Join the broadcast group `group`. See [`LuaGroup`].
+///
+/// ### `ctx.broadcast(group, msg)`
+/// Send message `msg` to every member of the broadcast group `group`.
+///
+/// ### `ctx.broker_issue(name, msg)`
+/// Issue `msg` on the `actix-broker` as the Rust message type registered as `name` with
+/// [`LuaActorBuilder::issue_broker`]. Requires the `broker` feature.
+///
+/// [`Cancellable`]: struct.Cancellable.html
+/// [`Drain`]: struct.Drain.html
 /// [`LuaActorBuilder`]: struct.LuaActorBuilder.html
+/// [`LuaActorBuilder::issue_broker`]: struct.LuaActorBuilder.html#method.issue_broker
+/// [`LuaActorBuilder::on_handle_batch`]: struct.LuaActorBuilder.html#method.on_handle_batch
+/// [`LuaActorBuilder::on_stopping_with_lua`]: struct.LuaActorBuilder.html#method.on_stopping_with_lua
+/// [`LuaActorBuilder::with_start_arg`]: struct.LuaActorBuilder.html#method.with_start_arg
+/// [`LuaBus`]: struct.LuaBus.html
+/// [`LuaGroup`]: struct.LuaGroup.html
+/// [`impl_lua_handler!`]: macro.impl_lua_handler.html
+/// [`LuaActorSpawner`]: struct.LuaActorSpawner.html
+/// [`LuaAddr`]: struct.LuaAddr.html
+/// [`LuaNode`]: struct.LuaNode.html
+/// [`SendStream`]: trait.SendStream.html
+/// [`SendWithMeta`]: trait.SendWithMeta.html
 pub struct LuaActor {
-    vm: Lua,
+    pub(crate) vm: Lua,
+    pub(crate) keys: KeyCache,
     pub recipients: HashMap<String, Recipient<LuaMessage>>,
+    pub(crate) lua_recipients: HashMap<String, Recipient<Hop>>,
+    pub(crate) replies: Replies,
+    pub(crate) handle_fn: Option<Box<HandleFn>>,
+    pub(crate) initialize_vm_async: Vec<Box<AsyncInitializeVM>>,
+    pub(crate) error_policy: ErrorPolicy,
+    pub(crate) rebuild_vm: Option<Box<NewVM>>,
+    pub(crate) panic_fn: Option<Box<PanicFn>>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) max_table_depth: Option<usize>,
+    pub(crate) max_hops: usize,
+    pub(crate) batch_size: Option<usize>,
+    pub(crate) accept_tell: bool,
+    // whether the actor handles `Eval`
+    pub(crate) eval: bool,
+    pub(crate) metrics: LuaActorMetrics,
+    pub(crate) cost_fn: Option<Box<CostFn>>,
+    pub(crate) gc_metrics_interval: Option<Duration>,
+    pub(crate) slow_handler_threshold: Option<Duration>,
+    // encodes the messages sent to remote actors
+    pub(crate) encoder: MessageEncoder,
+    // the heap size last reported to `metrics`
+    heap_bytes: u64,
+    pub(crate) tenants: Option<Tenants>,
+    batch: Vec<(Hop, oneshot::Sender<LuaMessage>)>,
+    // `ctx.id` and `ctx.name`
+    pub(crate) id: String,
+    pub(crate) name: Option<String>,
+    // the argument of the `started` hook
+    pub(crate) start_arg: LuaMessage,
+    // started by a `Supervisor`, which restarts the actor when it stops
+    pub(crate) supervised: bool,
+    // between `Actor::started` and `Actor::stopped`, for the `shutdown` of the system
+    running: bool,
+    // the messages handled since the mailbox last ran empty, `None` once it did
+    handled_since_idle: Option<usize>,
+    // the messages sent through a `LuaAddr` and not handled yet
+    pub(crate) queued: Arc<AtomicUsize>,
+    // why the actor was stopped, passed to the `stopped` hook
+    stop_reason: Option<&'static str>,
+    // the token of the `Cancellable` being handled, taken by the `handle` hook
+    pub(crate) cancel: Option<CancelToken>,
+    // `ctx.self`
+    self_address: String,
+    // the versions of the messages of the named handlers
+    pub(crate) versions: HashMap<String, MessageVersion>,
+    // the schemas of the named handlers
+    #[cfg(feature = "json")]
+    pub(crate) schemas: Arc<HashMap<String, Schema>>,
+    #[cfg(feature = "broker")]
+    pub(crate) broker_subscriptions: Vec<Box<BrokerSubscription>>,
 }
 
 impl LuaActor {
@@ -57,44 +266,370 @@ impl LuaActor {
         handle: Option<String>,
         stopped: Option<String>,
         vm_callback: Option<Box<InitializeVM>>,
-    ) -> Result<LuaActor, LuaError> {
-        let vm = Lua::new();
+    ) -> Result<LuaActor, ActixLuaError> {
+        let vm = vm::new_vm()?;
 
         if let Some(vm_callback) = vm_callback {
             vm_callback(&vm)?;
         }
+        vm::load_prelude(&vm)?;
+
+        vm::load_hooks(
+            &vm,
+            &[
+                ("started", started.as_deref()),
+                ("handle", handle.as_deref()),
+                ("stopped", stopped.as_deref()),
+            ],
+        )?;
+        Ok(LuaActor::from_vm(vm))
+    }
 
-        let prelude = include_str!("lua/prelude.lua");
-        vm.eval::<()>(prelude, Some("Prelude"))?;
-        {
-            let load: Function = vm.globals().get("__load")?;
-            if let Some(script) = started {
-                let res = load.call::<(String, String), ()>((script, "started".to_string()));
+    fn take_instructions(&self) -> u64 {
+        self.vm
+            .named_registry_value::<Function>("take_instructions")
+            .and_then(|take| take.call::<_, i64>(()))
+            .map_or(0, |n| n as u64)
+    }
 
-                if let Err(e) = res {
-                    return Result::Err(e);
-                }
+    pub(crate) fn from_vm(vm: Lua) -> LuaActor {
+        let id = Uuid::new_v4().to_string();
+        LuaActor {
+            self_address: format!("LuaActor-{}", id),
+            vm,
+            keys: KeyCache::default(),
+            recipients: HashMap::new(),
+            lua_recipients: HashMap::new(),
+            replies: Replies::default(),
+            handle_fn: None,
+            initialize_vm_async: vec![],
+            error_policy: ErrorPolicy::default(),
+            rebuild_vm: None,
+            panic_fn: None,
+            max_message_size: None,
+            max_table_depth: None,
+            max_hops: DEFAULT_MAX_HOPS,
+            batch_size: None,
+            accept_tell: true,
+            eval: false,
+            metrics: LuaActorMetrics::default(),
+            cost_fn: None,
+            gc_metrics_interval: None,
+            slow_handler_threshold: None,
+            encoder: MessageEncoder::new(DEFAULT_BUFFER_CAPACITY),
+            heap_bytes: 0,
+            tenants: None,
+            batch: vec![],
+            id,
+            name: None,
+            start_arg: LuaMessage::Nil,
+            supervised: false,
+            running: false,
+            handled_since_idle: None,
+            queued: Arc::default(),
+            stop_reason: None,
+            cancel: None,
+            versions: HashMap::new(),
+            #[cfg(feature = "json")]
+            schemas: Arc::new(HashMap::new()),
+            #[cfg(feature = "broker")]
+            broker_subscriptions: vec![],
+        }
+    }
+
+    fn run_stopped(&mut self, ctx: &mut Context<Self>, reason: &str) {
+        if let Err(e) = self.call(
+            ctx,
+            "__run",
+            vec![LuaMessage::from("stopped"), LuaMessage::from(reason)],
+        ) {
+            error!("lua actor `{}` hook `stopped` failed: {}", self.label(), e);
+        }
+    }
+
+    fn run_started(&mut self, ctx: &mut Context<Self>) {
+        // every VM of the actor starts here
+        let set_self = self.vm.globals().get::<_, Table>("ctx").and_then(|t| {
+            t.set("self", self.self_address.as_str())?;
+            t.set("id", self.id.as_str())?;
+            t.set("name", self.name.as_deref())
+        });
+        let args = vec![LuaMessage::from("started"), self.start_arg.clone()];
+        let res = set_self
+            .map_err(ActixLuaError::from)
+            .and_then(|()| self.call(ctx, "__run", args));
+        if let Err(e) = res {
+            self.hook_failed(ctx, "started", &e);
+        }
+    }
+
+    // call a function of the prelude, a panic in the conversions or callbacks is returned as
+    // `ActixLuaError::Panic`
+    fn call(
+        &mut self,
+        ctx: &mut Context<Self>,
+        func_name: &str,
+        args: Vec<LuaMessage>,
+    ) -> Result<LuaMessage, ActixLuaError> {
+        if self.cost_fn.is_none() && self.slow_handler_threshold.is_none() {
+            return self.call_vm(ctx, func_name, args);
+        }
+        let hook = match (func_name, args.first()) {
+            ("__run", Some(LuaMessage::String(hook))) => hook.clone(),
+            ("__run_batch", _) => "handle_batch".to_string(),
+            _ => "resume".to_string(),
+        };
+        // the name of a named handler, or the topic of a published message
+        let tag = match (func_name, args.get(2)) {
+            ("__run", Some(LuaMessage::String(tag))) => Some(tag.clone()),
+            _ => None,
+        };
+        let start = Instant::now();
+        let res = self.call_vm(ctx, func_name, args);
+        let elapsed = start.elapsed();
+        match self.slow_handler_threshold {
+            Some(threshold) if elapsed > threshold && handles_messages(&hook) => {
+                self.metrics.add_slow_invocation();
+                let tag = tag.map_or(String::new(), |tag| format!(" handling `{}`", tag));
+                warn!("lua actor `{}` hook `{}` took {:?}{}", self.label(), hook, elapsed, tag);
             }
-            if let Some(script) = handle {
-                let res = load.call::<(String, String), ()>((script, "handle".to_string()));
+            _ => {}
+        }
+        if let Some(ref cost_fn) = self.cost_fn {
+            cost_fn(&InvocationCost {
+                actor: self.label().to_string(),
+                hook,
+                instructions: self.take_instructions(),
+                elapsed,
+            });
+        }
+        res
+    }
 
-                if let Err(e) = res {
-                    return Result::Err(e);
-                }
+    fn call_vm(
+        &mut self,
+        ctx: &mut Context<Self>,
+        func_name: &str,
+        args: Vec<LuaMessage>,
+    ) -> Result<LuaMessage, ActixLuaError> {
+        let (vm, keys) = (&self.vm, &mut self.keys);
+        let (recs, lua_recs) = (&mut self.recipients, &mut self.lua_recipients);
+        let (replies, encoder) = (&mut self.replies, &mut self.encoder);
+        replies.deferred = None;
+        let handled = self.handled_since_idle.unwrap_or(0);
+        let queued = self.queued.load(Ordering::Relaxed);
+        catch_panic(|| {
+            vm.globals().raw_set("__handled_since_idle", handled)?;
+            vm.globals().raw_set("__mailbox_len", queued)?;
+            let args = args
+                .into_iter()
+                .map(|msg| keys.convert(msg, vm))
+                .collect::<Result<_, _>>()?;
+            let args = MultiValue::from_vec(args);
+            Ok(invoke(ctx, vm, recs, lua_recs, replies, encoder, func_name, args)?)
+        })
+    }
+
+    fn sample_gc(&mut self) {
+        match gc::sample(&self.vm) {
+            Ok((heap_bytes, cycles)) => {
+                self.metrics.update_heap(self.heap_bytes, heap_bytes);
+                self.metrics.add_gc_cycles(cycles);
+                self.heap_bytes = heap_bytes;
+            }
+            Err(e) => warn!(
+                "lua actor `{}` failed to sample its garbage collector: {}",
+                self.label(),
+                e
+            ),
+        }
+    }
+
+    fn check_size(&self, msg: &LuaMessage) -> Result<(), ActixLuaError> {
+        match self.max_message_size {
+            Some(max) if msg.estimated_size() > max => Err(ActixLuaError::MessageTooLarge {
+                size: msg.estimated_size(),
+                max,
+            }),
+            _ => match self.max_table_depth {
+                Some(max) if msg.depth() > max => Err(ActixLuaError::TableTooDeep { max }),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    // the name of the actor, or its id if it has none
+    pub(crate) fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+
+    // log an error raised by `hook` and apply the error policy
+    fn hook_failed(&mut self, ctx: &mut Context<Self>, hook: &str, err: &ActixLuaError) {
+        // the sender gave up on the message, the script didn't fail
+        if let ActixLuaError::Cancelled = err {
+            return;
+        }
+        error!("lua actor `{}` hook `{}` failed: {}", self.label(), hook, err);
+        if let ActixLuaError::Panic { .. } = err {
+            if let Some(ref panic_fn) = self.panic_fn {
+                panic_fn(err, ctx);
             }
-            if let Some(script) = stopped {
-                let res = load.call::<(String, String), ()>((script, "stopped".to_string()));
+        }
+        match self.error_policy {
+            ErrorPolicy::Ignore => {}
+            // a fresh VM would fail in `started` again
+            ErrorPolicy::Restart if hook != "started" => self.restart(ctx),
+            ErrorPolicy::Restart | ErrorPolicy::Stop => self.stop(ctx, "error"),
+        }
+    }
+
+    fn stop(&mut self, ctx: &mut Context<Self>, reason: &'static str) {
+        self.stop_reason = Some(reason);
+        ctx.stop();
+    }
+
+    // the reason of the `stopping` and `stopped` hooks
+    fn stop_reason(&self) -> &'static str {
+        match self.stop_reason {
+            // the supervisor starts the actor over, whatever stopped it
+            _ if self.supervised => "restart",
+            Some(reason) => reason,
+            None => "stop",
+        }
+    }
+
+    // run the `stopped` hook in the VM in use and in the VMs of the other tenants
+    fn stop_vms(&mut self, ctx: &mut Context<Self>, reason: &str) {
+        self.run_stopped(ctx, reason);
+        let idle: Vec<_> = self.tenants.as_mut().map_or(vec![], |t| t.drain().collect());
+        for (_, vm) in idle {
+            self.swap_vm(vm);
+            self.run_stopped(ctx, reason);
+        }
+    }
+
+    // switch to the VM of the tenant of `msg`
+    pub(crate) fn select_tenant(
+        &mut self,
+        msg: &LuaMessage,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), ActixLuaError> {
+        let tenant = match self.tenants {
+            Some(ref tenants) => (tenants.tenant_fn)(msg),
+            None => return Ok(()),
+        };
+        self.switch_tenant(tenant, ctx)
+    }
 
-                if let Err(e) = res {
-                    return Result::Err(e);
+    // swap in the VM of `tenant`, creating it if it's new or was evicted
+    fn switch_tenant(
+        &mut self,
+        tenant: String,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), ActixLuaError> {
+        let mut tenants = match self.tenants.take() {
+            Some(tenants) => tenants,
+            None => return Ok(()),
+        };
+        let res = match tenants.current.take() {
+            // the first tenant gets the VM the actor was built with
+            None => Ok(None),
+            Some(ref current) if *current == tenant => Ok(None),
+            Some(current) => match self.replace_tenant(&mut tenants, &current, &tenant, ctx) {
+                Ok(evicted) => Ok(evicted),
+                Err(e) => {
+                    tenants.current = Some(current);
+                    Err(e)
                 }
+            },
+        };
+        if res.is_ok() {
+            tenants.current = Some(tenant);
+        }
+        self.tenants = Some(tenants);
+
+        if let Some(evicted) = res? {
+            let active = self.swap_vm(evicted);
+            self.run_stopped(ctx, "evict");
+            self.swap_vm(active);
+        }
+        Ok(())
+    }
+
+    // stash the VM of `current` and swap in the one of `tenant`, returning the evicted VM
+    fn replace_tenant(
+        &mut self,
+        tenants: &mut Tenants,
+        current: &str,
+        tenant: &str,
+        ctx: &mut Context<Self>,
+    ) -> Result<Option<TenantVm>, ActixLuaError> {
+        // the batch belongs to the VM in use
+        self.flush_batch(ctx);
+        let seq: i64 = self.vm.globals().get("__thread_id_seq")?;
+        tenants.next_thread_id = tenants.next_thread_id.max(seq);
+
+        let (vm, created) = match tenants.take(tenant) {
+            Some(vm) => (vm, false),
+            None => {
+                let vm = match self.rebuild_vm {
+                    Some(ref rebuild_vm) => catch_panic(rebuild_vm)?,
+                    None => return Err(ActixLuaError::ActorStopped),
+                };
+                vm.globals().set("__thread_id_seq", tenants.next_thread_id)?;
+                let vm = TenantVm {
+                    vm,
+                    keys: KeyCache::default(),
+                    replies: Replies::default(),
+                };
+                (vm, true)
             }
+        };
+        let previous = self.swap_vm(vm);
+        let evicted = tenants.put(current.to_string(), previous).map(|(_, vm)| vm);
+        if created {
+            self.run_started(ctx);
         }
+        Ok(evicted)
+    }
 
-        Result::Ok(LuaActor {
-            vm,
-            recipients: HashMap::new(),
-        })
+    fn swap_vm(&mut self, vm: TenantVm) -> TenantVm {
+        TenantVm {
+            vm: mem::replace(&mut self.vm, vm.vm),
+            keys: mem::replace(&mut self.keys, vm.keys),
+            replies: mem::replace(&mut self.replies, vm.replies),
+        }
+    }
+
+    fn restart(&mut self, ctx: &mut Context<Self>) {
+        if self.rebuild_vm.is_none() {
+            return;
+        }
+        self.run_stopped(ctx, "restart");
+        match self.fresh_vm() {
+            Ok(true) => self.run_started(ctx),
+            Ok(false) => {}
+            Err(e) => {
+                error!("lua actor `{}` restart failed: {}", self.label(), e);
+                self.stop(ctx, "error");
+            }
+        }
+    }
+
+    // replace the VM with a fresh one, returns `false` if the actor can't build one
+    fn fresh_vm(&mut self) -> Result<bool, ActixLuaError> {
+        let vm = match self.rebuild_vm {
+            Some(ref rebuild_vm) => catch_panic(rebuild_vm)?,
+            None => return Ok(false),
+        };
+        // keep thread ids unique, so late replies to threads of the old VM are dropped
+        let seq: i64 = self.vm.globals().get("__thread_id_seq")?;
+        vm.globals().set("__thread_id_seq", seq)?;
+        self.vm = vm;
+        self.keys = KeyCache::default();
+        // the deferred replies can't be answered by the new VM
+        self.replies = Replies::default();
+        Ok(true)
     }
 
     /// Add a recipient to the actor's recipient list.
@@ -106,27 +641,330 @@ impl LuaActor {
     ) -> Option<Recipient<LuaMessage>> {
         self.recipients.insert(name.to_string(), rec)
     }
+
+    /// Add another `LuaActor` to the actor's recipient list.
+    ///
+    /// Unlike `add_recipients`, messages sent to it count the hops between actors, see [`Hop`].
+    ///
+    /// [`Hop`]: struct.Hop.html
+    pub fn add_lua_recipient(
+        &mut self,
+        name: &str,
+        addr: &Addr<LuaActor>,
+    ) -> Option<Recipient<LuaMessage>> {
+        self.lua_recipients
+            .insert(name.to_string(), addr.clone().recipient());
+        self.recipients
+            .insert(name.to_string(), addr.clone().recipient())
+    }
+
+    /// Handle `msg` with the `handle` hook, with `ctx.topic` set to `name`.
+    ///
+    /// `msg` is first migrated to the version the builder was given with
+    /// `with_message_version`, and checked against the schema of `name` with the `json` feature
+    /// if the builder was given one with `with_schema`.
+    ///
+    /// This is what the handlers generated by [`impl_lua_handler!`] run. The message is handled
+    /// right away, even if the actor has a `handle_batch` hook.
+    ///
+    /// [`impl_lua_handler!`]: macro.impl_lua_handler.html
+    pub fn handle_named(
+        &mut self,
+        name: &str,
+        msg: LuaMessage,
+        ctx: &mut Context<Self>,
+    ) -> LuaReply {
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        let msg = match self.versions.get(name) {
+            Some(version) => match version.upgrade(name, msg) {
+                Ok(msg) => msg,
+                Err(e) => return LuaReply::Now(LuaMessage::Error(e)),
+            },
+            None => msg,
+        };
+        #[cfg(feature = "json")]
+        {
+            if let Some(Err(e)) = self.schemas.get(name).map(|s| s.check(name, &msg)) {
+                return LuaReply::Now(LuaMessage::Error(e));
+            }
+        }
+        if let Err(e) = self.select_tenant(&msg, ctx) {
+            return LuaReply::Now(LuaMessage::Error(e));
+        }
+        self.handle_message(Hop::from(msg), LuaMessage::from(name), ctx)
+    }
+
+    // answer `msg` without the VM if it is too large or too deep, or `handle_fn` handles it
+    fn pre_handle(&mut self, msg: &LuaMessage, ctx: &mut Context<Self>) -> Option<LuaMessage> {
+        if let Err(e) = self.check_size(msg) {
+            return Some(LuaMessage::Error(e));
+        }
+        if let Some(ref handle_fn) = self.handle_fn {
+            match catch_panic(|| Ok(handle_fn(msg, ctx))) {
+                Ok(res) => return res,
+                Err(e) => {
+                    self.hook_failed(ctx, "handle", &e);
+                    return Some(LuaMessage::Error(e));
+                }
+            }
+        }
+        None
+    }
+
+    // answer `hop` right away, or queue it for the `handle_batch` hook
+    fn receive(&mut self, hop: Hop, ctx: &mut Context<Self>) -> LuaReply {
+        if let Err(e) = self.select_tenant(&hop.msg, ctx) {
+            return LuaReply::Now(LuaMessage::Error(e));
+        }
+        let batch_size = match self.batch_size {
+            Some(batch_size) => batch_size,
+            None => return self.handle_message(hop, LuaMessage::Nil, ctx),
+        };
+        if let Some(res) = self.pre_handle(&hop.msg, ctx) {
+            return LuaReply::Now(res);
+        }
+
+        // the rest of the batch is flushed after the messages queued in the mailbox so far
+        if self.batch.is_empty() {
+            ctx.notify(FlushBatch);
+        }
+        let (tx, rx) = oneshot::channel();
+        self.batch.push((hop, tx));
+        if self.batch.len() >= batch_size {
+            self.flush_batch(ctx);
+        }
+        LuaReply::Later(rx)
+    }
+
+    // count a message taken from the mailbox, for `ctx.handled_since_idle()`
+    pub(crate) fn count_handled(&mut self, ctx: &mut Context<Self>) {
+        self.handled_since_idle = match self.handled_since_idle {
+            Some(handled) => Some(handled + 1),
+            // like `FlushBatch`, handled once the messages queued in the mailbox ran out
+            None => {
+                ctx.notify(MailboxDrained);
+                Some(0)
+            }
+        };
+    }
+
+    fn flush_batch(&mut self, ctx: &mut Context<Self>) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = mem::take(&mut self.batch);
+        let hops = batch.iter().map(|(hop, _)| hop.hops).max().unwrap_or(0);
+        let mut args = vec![LuaMessage::from(hops), LuaMessage::from(batch.len())];
+        let mut metas = Vec::with_capacity(batch.len());
+        let mut replies = Vec::with_capacity(batch.len());
+        for (mut hop, tx) in batch {
+            metas.push(hop.meta_message());
+            args.push(hop.msg);
+            replies.push(tx);
+        }
+        args.append(&mut metas);
+
+        // a table is split into the replies of the messages, anything else answers all of them
+        match self.call(ctx, "__run_batch", args) {
+            Ok(LuaMessage::Table(mut t)) => {
+                for (i, tx) in replies.into_iter().enumerate() {
+                    let res = t.remove(&(i + 1).to_string()).unwrap_or(LuaMessage::Nil);
+                    let _ = tx.send(res);
+                }
+            }
+            Ok(res) => {
+                for tx in replies {
+                    let _ = tx.send(res.clone());
+                }
+            }
+            Err(e) => {
+                for tx in replies {
+                    let _ = tx.send(LuaMessage::Error(e.clone()));
+                }
+                self.hook_failed(ctx, "handle_batch", &e);
+            }
+        }
+    }
+
+    pub(crate) fn handle_message(
+        &mut self,
+        hop: Hop,
+        topic: LuaMessage,
+        ctx: &mut Context<Self>,
+    ) -> LuaReply {
+        if let Some(res) = self.pre_handle(&hop.msg, ctx) {
+            return LuaReply::Now(res);
+        }
+        match self.run_handle(hop, topic, false, None, ctx) {
+            Ok(LuaReply::Now(res)) => LuaReply::Now(res),
+            Ok(later) => later,
+            Err(e) => LuaReply::Now(LuaMessage::Error(e)),
+        }
+    }
+
+    // the values the `handle` hook returns, see `Values`
+    fn handle_values(&mut self, hop: Hop, ctx: &mut Context<Self>) -> LuaReply<Vec<LuaMessage>> {
+        if let Some(res) = self.pre_handle(&hop.msg, ctx) {
+            return LuaReply::Now(vec![res]);
+        }
+        match self.run_handle(hop, LuaMessage::Nil, true, None, ctx) {
+            Ok(LuaReply::Now(res)) => LuaReply::Now(unpack_values(res)),
+            Ok(later) => later.map(|res| vec![res]),
+            Err(e) => LuaReply::Now(vec![LuaMessage::Error(e)]),
+        }
+    }
+
+    // run the `handle` hook, whose values are packed in a table if `values` is set, and which
+    // emits values to `stream` if it is given
+    fn run_handle(
+        &mut self,
+        mut hop: Hop,
+        topic: LuaMessage,
+        values: bool,
+        stream: Option<i64>,
+        ctx: &mut Context<Self>,
+    ) -> Result<LuaReply, ActixLuaError> {
+        contract::check(&self.vm, &topic, &hop.msg)?;
+        let meta = hop.meta_message();
+        let cancel = self.cancel.take();
+        let res = self.call(
+            ctx,
+            "__run",
+            vec![
+                LuaMessage::from("handle"),
+                hop.msg,
+                topic,
+                LuaMessage::from(hop.hops),
+                meta,
+                stream.map_or(LuaMessage::Nil, LuaMessage::from),
+                LuaMessage::from(values),
+                cancel.map_or(LuaMessage::Nil, LuaMessage::opaque),
+            ],
+        );
+        if let Some(rx) = self.replies.deferred.take() {
+            return Ok(LuaReply::Later(rx));
+        }
+        match res {
+            Ok(res) => Ok(LuaReply::Now(res)),
+            Err(e) => {
+                self.hook_failed(ctx, "handle", &e);
+                Err(e)
+            }
+        }
+    }
+}
+
+// Replies deferred with `ctx.reply_later`, by token, and the streams of the messages sent with
+// `SendStream`, by id.
+#[derive(Default)]
+pub(crate) struct Replies {
+    pending: HashMap<i64, oneshot::Sender<LuaMessage>>,
+    next_token: i64,
+    // the reply of the message being handled, if it was deferred
+    deferred: Option<oneshot::Receiver<LuaMessage>>,
+    streams: HashMap<i64, mpsc::UnboundedSender<StreamItem>>,
+    next_stream: i64,
+}
+
+impl Replies {
+    fn defer(&mut self) -> i64 {
+        // forget the replies whose senders are gone
+        self.pending.retain(|_, tx| !tx.is_canceled());
+        let (tx, rx) = oneshot::channel();
+        let token = self.next_token;
+        self.next_token += 1;
+        self.pending.insert(token, tx);
+        self.deferred = Some(rx);
+        token
+    }
+
+    fn reply(&mut self, token: i64, msg: LuaMessage) -> bool {
+        match self.pending.remove(&token) {
+            Some(tx) => tx.send(msg).is_ok(),
+            None => false,
+        }
+    }
+
+    fn open_stream(&mut self, tx: mpsc::UnboundedSender<StreamItem>) -> i64 {
+        let id = self.next_stream;
+        self.next_stream += 1;
+        self.streams.insert(id, tx);
+        id
+    }
+
+    // returns `false` if the receiver of the stream is gone
+    fn emit(&mut self, stream: i64, msg: LuaMessage) -> bool {
+        match self.streams.get(&stream) {
+            Some(tx) if tx.unbounded_send(Ok(Some(msg))).is_ok() => true,
+            _ => {
+                self.streams.remove(&stream);
+                false
+            }
+        }
+    }
+
+    // end a stream with the result of its hook, a stream which already ended is left alone
+    fn end_stream(&mut self, stream: i64, res: Result<LuaMessage, ActixLuaError>) {
+        if let Some(tx) = self.streams.remove(&stream) {
+            match res {
+                Ok(LuaMessage::Nil) => {}
+                Ok(msg) => {
+                    let _ = tx.unbounded_send(Ok(Some(msg)));
+                }
+                Err(e) => {
+                    let _ = tx.unbounded_send(Err(e));
+                    return;
+                }
+            }
+            let _ = tx.unbounded_send(Ok(None));
+        }
+    }
+}
+
+// a value emitted by a hook, `None` once it returned
+pub(crate) type StreamItem = Result<Option<LuaMessage>, ActixLuaError>;
+
+// the lifecycle hooks don't handle messages
+fn handles_messages(hook: &str) -> bool {
+    !matches!(hook, "started" | "stopping" | "stopped")
+}
+
+// recipient, message, thread id, hops and metadata
+type SendArgs = (String, LuaMessage, i64, usize, LuaMessage);
+
+fn meta_table(meta: LuaMessage) -> Result<HashMap<String, LuaMessage>, LuaError> {
+    match meta {
+        LuaMessage::Nil => Ok(HashMap::new()),
+        LuaMessage::Table(t) => Ok(t),
+        _ => Err(LuaError::RuntimeError(
+            "the metadata of a message must be a table".to_string(),
+        )),
+    }
 }
 
 // Remove all `self` usage with a independent function `invoke`.
-fn invoke(
-    self_addr: &Recipient<SendAttempt>,
+#[allow(clippy::too_many_arguments)]
+fn invoke<'lua>(
     ctx: &mut Context<LuaActor>,
-    vm: &mut Lua,
+    vm: &'lua Lua,
     recs: &mut HashMap<String, Recipient<LuaMessage>>,
+    lua_recs: &mut HashMap<String, Recipient<Hop>>,
+    replies: &mut Replies,
+    encoder: &mut MessageEncoder,
     func_name: &str,
-    args: Vec<LuaMessage>,
+    args: MultiValue<'lua>,
 ) -> Result<LuaMessage, LuaError> {
+    let self_addr: Recipient<SendAttempt> = ctx.address().recipient();
     // `ctx` is used in multiple closure in the lua scope.
     // to create multiple borrow in closures, we use RefCell to move the borrow-checking to runtime.
     // Voliating the check will result in panic. Which shouldn't happend(I think) since lua is single-threaded.
     let ctx = RefCell::new(ctx);
     let recs = RefCell::new(recs);
+    let lua_recs = RefCell::new(lua_recs);
+    let replies = RefCell::new(replies);
+    let encoder = RefCell::new(encoder);
 
-    let iter = args.into_iter()
-        .map(|msg| msg.to_lua(&vm).unwrap())
-        .collect();
-    let args = MultiValue::from_vec(iter);
     // We can't create a function with references to `self` and is 'static since `self` already owns Lua.
     // A function within Lua owning `self` creates self-borrowing cycle.
     //
@@ -168,27 +1006,55 @@ fn invoke(
                     .build()?
                     .start();
 
+                let mut lua_recs = lua_recs.borrow_mut();
+                lua_recs.insert(recipient_name.clone(), addr.clone().recipient());
                 let mut recs = recs.borrow_mut();
                 recs.insert(recipient_name.clone(), addr.recipient());
                 Ok(recipient_name.clone())
             })?;
         globals.set("__new_actor", new_actor)?;
 
-        let do_send =
-            scope.create_function_mut(|_, (recipient_name, msg): (String, LuaMessage)| {
+        let do_send = scope.create_function_mut(
+            |_, (recipient_name, msg, hops, meta): (String, LuaMessage, usize, LuaMessage)| {
+                let meta = meta_table(meta)?;
+                // a recipient which stopped is an error for the script
+                if let Some(r) = lua_recs.borrow().get(&recipient_name) {
+                    r.do_send(Hop { msg, hops, meta })
+                        .map_err(|_| ActixLuaError::ActorStopped)?;
+                    return Ok(());
+                }
+
                 let recs = recs.borrow_mut();
                 let rec = recs.get(&recipient_name);
 
-                // TODO: error handling?
+                // local names win over remote addresses, like in `send`
                 if let Some(r) = rec {
-                    r.do_send(msg).unwrap();
+                    r.do_send(msg).map_err(|_| ActixLuaError::ActorStopped)?;
+                } else if is_remote_address(&recipient_name) {
+                    let msg = encoder.borrow_mut().encode(&msg).map_err(|e| {
+                        ActixLuaError::ConversionError {
+                            message: e.to_string(),
+                        }
+                    })?;
+                    LuaNode::from_registry().do_send(SendEncoded {
+                        address: recipient_name,
+                        msg,
+                        reply: false,
+                    });
+                } else {
+                    LuaAddresses::from_registry().do_send(Forward {
+                        address: recipient_name,
+                        hop: Hop { msg, hops, meta },
+                    });
                 }
                 Ok(())
-            })?;
+            },
+        )?;
         globals.set("do_send", do_send)?;
 
         let send = scope.create_function_mut(
-            |_, (recipient_name, msg, cb_thread_id): (String, LuaMessage, i64)| {
+            |_, (recipient_name, msg, cb_thread_id, hops, meta): SendArgs| {
+                let meta = meta_table(meta)?;
                 // we can't create a lua function which owns `self`
                 // but `self` is needed for resolving `send` future.
                 //
@@ -199,14 +1065,54 @@ fn invoke(
                         recipient_name,
                         msg,
                         cb_thread_id,
+                        hops,
+                        meta,
                     })
-                    .unwrap();
+                    .map_err(|_| ActixLuaError::ActorStopped)?;
 
                 Ok(())
             },
         )?;
         globals.set("send", send)?;
 
+        let reply_later = scope.create_function_mut(|_, ()| Ok(replies.borrow_mut().defer()))?;
+        globals.set("reply_later", reply_later)?;
+
+        let reply = scope.create_function_mut(|_, (token, msg): (i64, LuaMessage)| {
+            Ok(replies.borrow_mut().reply(token, msg))
+        })?;
+        globals.set("reply", reply)?;
+
+        let emit = scope.create_function_mut(|_, (stream, msg): (i64, LuaMessage)| {
+            Ok(replies.borrow_mut().emit(stream, msg))
+        })?;
+        globals.set("emit", emit)?;
+
+        let end_stream = scope.create_function_mut(
+            |_, (stream, msg, traceback): (i64, LuaMessage, Option<String>)| {
+                let res = match traceback {
+                    Some(traceback) => Err(ActixLuaError::RuntimeError { traceback }),
+                    None => Ok(msg),
+                };
+                replies.borrow_mut().end_stream(stream, res);
+                Ok(())
+            },
+        )?;
+        globals.set("end_stream", end_stream)?;
+
+        let spawn_blocking = scope.create_function_mut(
+            |_, (function, arg, cb_thread_id): (::rlua::String, LuaMessage, i64)| {
+                let ctx = ctx.borrow();
+                ctx.address().do_send(SpawnBlocking {
+                    function: function.as_bytes().to_vec(),
+                    arg,
+                    cb_thread_id,
+                });
+                Ok(())
+            },
+        )?;
+        globals.set("spawn_blocking", spawn_blocking)?;
+
         let terminate = scope.create_function_mut(|_, _: LuaMessage| {
             let mut ctx = ctx.borrow_mut();
             ctx.terminate();
@@ -214,9 +1120,46 @@ fn invoke(
         })?;
         globals.set("terminate", terminate)?;
 
+        let subscribe = scope.create_function_mut(|_, pattern: String| {
+            let ctx = ctx.borrow();
+            LuaBus::from_registry().do_send(Subscribe {
+                pattern,
+                recipient: ctx.address().recipient(),
+            });
+            Ok(())
+        })?;
+        globals.set("subscribe", subscribe)?;
+
+        let publish = scope.create_function_mut(|_, (topic, msg): (String, LuaMessage)| {
+            LuaBus::from_registry().do_send(Publish { topic, msg });
+            Ok(())
+        })?;
+        globals.set("publish", publish)?;
+
+        let join = scope.create_function_mut(|_, group: String| {
+            let ctx = ctx.borrow();
+            LuaBus::from_registry().do_send(JoinGroup {
+                group,
+                recipient: ctx.address().recipient(),
+            });
+            Ok(())
+        })?;
+        globals.set("join", join)?;
+
+        let broadcast = scope.create_function_mut(|_, (group, msg): (String, LuaMessage)| {
+            LuaBus::from_registry().do_send(Broadcast { group, msg });
+            Ok(())
+        })?;
+        globals.set("broadcast", broadcast)?;
+
         let lua_handle: Result<Function, LuaError> = globals.get(func_name);
         if let Ok(f) = lua_handle {
-            Ok(LuaMessage::from_lua(f.call::<MultiValue, Value>(args).unwrap(), &vm).unwrap())
+            // the prelude returns the traceback of a failed script as a second value
+            let (res, err) = f.call::<MultiValue, (Value, Option<String>)>(args)?;
+            if let Some(traceback) = err {
+                return Err(LuaError::RuntimeError(traceback));
+            }
+            LuaMessage::from_lua(res, vm)
         } else {
             // return nil if handle is not defined
             Ok(LuaMessage::Nil)
@@ -228,66 +1171,477 @@ impl Actor for LuaActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        if let Err(e) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("started")],
-        ) {
-            panic!("lua actor started failed {:?}", e);
+        self.running = true;
+        LuaAddresses::from_registry().do_send(Register {
+            address: self.self_address.clone(),
+            recipient: ctx.address().recipient(),
+        });
+        #[cfg(feature = "broker")]
+        for subscribe in &self.broker_subscriptions {
+            subscribe(&ctx.address());
+        }
+        if let Some(interval) = self.gc_metrics_interval {
+            ctx.run_interval(interval, |act, _| act.sample_gc());
+        }
+
+        if self.initialize_vm_async.is_empty() {
+            self.run_started(ctx);
+            return;
         }
+
+        // hold off messages and the `started` hook until the VM is initialized
+        let inits = future::join_all(mem::take(&mut self.initialize_vm_async));
+        ctx.wait(inits.into_actor(self).then(|res, act, ctx| {
+            let res = res.map_err(ActixLuaError::from).and_then(|inits| {
+                let vm = &act.vm;
+                catch_panic(|| Ok(inits.into_iter().try_for_each(|init| init(vm))?))
+            });
+            match res {
+                Ok(()) => act.run_started(ctx),
+                Err(e) => act.hook_failed(ctx, "started", &e),
+            }
+            actix::fut::ok(())
+        }));
     }
 
     fn stopped(&mut self, ctx: &mut Context<Self>) {
-        if let Err(e) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("stopped")],
-        ) {
-            panic!("lua actor stopped failed {:?}", e);
+        LuaAddresses::from_registry().do_send(Unregister(self.self_address.clone()));
+        self.flush_batch(ctx);
+        // the VM is gone with the actor
+        self.metrics.update_heap(self.heap_bytes, 0);
+        self.heap_bytes = 0;
+        self.running = false;
+        let reason = self.stop_reason();
+        self.stop_reason = None;
+        self.stop_vms(ctx, reason);
+    }
+
+    fn stopping(&mut self, ctx: &mut Context<Self>) -> Running {
+        let hooked = self
+            .vm
+            .globals()
+            .get::<_, Table>("__scripts")
+            .and_then(|scripts| scripts.contains_key("stopping"));
+        if !hooked.unwrap_or(false) {
+            return Running::Stop;
+        }
+        let reason = LuaMessage::from(self.stop_reason());
+        match self.call(ctx, "__run", vec![LuaMessage::from("stopping"), reason]) {
+            Ok(LuaMessage::String(ref ret)) if ret == "continue" => {
+                self.stop_reason = None;
+                Running::Continue
+            }
+            Ok(_) => Running::Stop,
+            Err(e) => {
+                error!("lua actor `{}` hook `stopping` failed: {}", self.label(), e);
+                Running::Stop
+            }
         }
     }
 }
 
-struct SendAttempt {
-    recipient_name: String,
-    msg: LuaMessage,
-    cb_thread_id: i64,
+// the system shut down without stopping the actor
+impl Drop for LuaActor {
+    fn drop(&mut self) {
+        if !self.running || thread::panicking() {
+            return;
+        }
+        self.running = false;
+        // the hook can't reach the actor anymore
+        let (_, rx) = channel::channel(0);
+        self.stop_vms(&mut Context::with_receiver(rx), "shutdown");
+    }
 }
 
-impl Message for SendAttempt {
-    type Result = LuaMessage;
+/// Stop a `LuaActor` once it handled the messages sent to it before, with the `drain` reason
+/// for its `stopped` hook.
+///
+/// ```rust,ignore
+/// addr.do_send(Drain);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drain;
+
+impl Message for Drain {
+    type Result = ();
 }
 
-struct SendAttemptResult {
-    msg: LuaMessage,
-    cb_thread_id: i64,
+impl Handler<Drain> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Drain, ctx: &mut Context<Self>) {
+        self.stop(ctx, "drain");
+    }
 }
 
-impl Message for SendAttemptResult {
-    type Result = LuaMessage;
+/// Send `msg` to the named handler `name` of a `LuaActor`, like a message of a type given to
+/// `impl_lua_handler!`: the `handle` hook sees it with `ctx.topic` set to `name`. For senders
+/// which pick the handler at runtime, e.g. `JsonRpcServer`.
+///
+/// ```rust,ignore
+/// let reply = addr.send(Named {
+///     name: "charge".to_string(),
+///     msg: LuaMessage::from(42),
+/// });
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Named {
+    pub name: String,
+    pub msg: LuaMessage,
 }
 
-impl Handler<LuaMessage> for LuaActor {
+impl Message for Named {
+    type Result = LuaMessage;
+}
+
+impl Handler<Named> for LuaActor {
+    type Result = LuaReply;
+
+    fn handle(&mut self, named: Named, ctx: &mut Context<Self>) -> Self::Result {
+        self.handle_named(&named.name, named.msg, ctx)
+    }
+}
+
+// what a method of a server like `JsonRpcServer` calls: an actor, or a named handler of one
+#[cfg(any(feature = "jsonrpc", feature = "grpc"))]
+#[derive(Clone)]
+pub(crate) enum Target {
+    Actor(Recipient<LuaMessage>),
+    Handler(Recipient<Named>, String),
+}
+
+#[cfg(any(feature = "jsonrpc", feature = "grpc"))]
+impl Target {
+    pub(crate) fn send(
+        &self,
+        msg: LuaMessage,
+    ) -> Box<dyn Future<Item = LuaMessage, Error = MailboxError>> {
+        match self {
+            Target::Actor(recipient) => Box::new(recipient.send(msg)),
+            Target::Handler(recipient, name) => Box::new(recipient.send(Named {
+                name: name.clone(),
+                msg,
+            })),
+        }
+    }
+}
+
+// a supervised actor starts over with a fresh VM, and runs `started` again
+impl Supervised for LuaActor {
+    fn restarting(&mut self, _: &mut Context<Self>) {
+        // the VMs of the other tenants were dropped when the actor stopped
+        if let Some(ref mut tenants) = self.tenants {
+            tenants.current = None;
+        }
+        if let Err(e) = self.fresh_vm() {
+            error!("lua actor `{}` restart failed: {}", self.label(), e);
+        }
+    }
+}
+
+struct SendAttempt {
+    recipient_name: String,
+    msg: LuaMessage,
+    cb_thread_id: i64,
+    hops: usize,
+    meta: HashMap<String, LuaMessage>,
+}
+
+impl Message for SendAttempt {
+    type Result = LuaMessage;
+}
+
+struct SendAttemptResult {
+    result: Result<LuaMessage, String>,
+    cb_thread_id: i64,
+    // the tenant of the VM of the thread
+    tenant: Option<String>,
+}
+
+impl Message for SendAttemptResult {
     type Result = LuaMessage;
+}
+
+// a message sent with `SendStream::send_stream`, cancelled when its stream is dropped
+pub(crate) struct StreamRequest {
+    pub(crate) msg: LuaMessage,
+    pub(crate) tx: mpsc::UnboundedSender<StreamItem>,
+    pub(crate) token: CancelToken,
+}
+
+impl Message for StreamRequest {
+    type Result = ();
+}
+
+impl Handler<StreamRequest> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, req: StreamRequest, ctx: &mut Context<Self>) {
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        if req.token.is_cancelled() {
+            let _ = req.tx.unbounded_send(Err(ActixLuaError::Cancelled));
+            return;
+        }
+        if let Err(e) = self.select_tenant(&req.msg, ctx) {
+            let _ = req.tx.unbounded_send(Err(e));
+            return;
+        }
+        // the streams belong to the VM of the tenant
+        let stream = self.replies.open_stream(req.tx);
+        if let Some(res) = self.pre_handle(&req.msg, ctx) {
+            let res = match res {
+                LuaMessage::Error(e) => Err(e),
+                res => Ok(res),
+            };
+            self.replies.end_stream(stream, res);
+            return;
+        }
+        // the stream is ended by the prelude once the hook returns
+        self.cancel = Some(req.token);
+        let res = self.run_handle(Hop::from(req.msg), LuaMessage::Nil, false, Some(stream), ctx);
+        self.cancel = None;
+        if let Err(e) = res {
+            self.replies.end_stream(stream, Err(e));
+        }
+    }
+}
+
+// run a function dumped by `ctx.spawn_blocking` on a thread of its own, and resume the thread
+// which called it with the result
+struct SpawnBlocking {
+    function: Vec<u8>,
+    arg: LuaMessage,
+    cb_thread_id: i64,
+}
+
+impl Message for SpawnBlocking {
+    type Result = ();
+}
+
+impl Handler<SpawnBlocking> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, spawn: SpawnBlocking, ctx: &mut Context<Self>) {
+        let self_addr = ctx.address();
+        let tenant = self.tenants.as_ref().and_then(|t| t.current.clone());
+        let cb_thread_id = spawn.cb_thread_id;
+        thread::spawn(move || {
+            let result = catch_panic(|| run_blocking(spawn.function, spawn.arg))
+                .map_err(|e| e.to_string());
+            self_addr.do_send(SendAttemptResult {
+                result,
+                cb_thread_id,
+                tenant,
+            });
+        });
+    }
+}
+
+// run a dumped function in a new VM, which only has the standard libraries
+fn run_blocking(function: Vec<u8>, arg: LuaMessage) -> Result<LuaMessage, ActixLuaError> {
+    let vm = Lua::new();
+    let load: Function = vm.globals().get("load")?;
+    let (function, err): (Option<Function>, Option<String>) =
+        load.call((LuaMessage::from(function), "spawn_blocking", "b"))?;
+    match function {
+        Some(function) => Ok(function.call(arg)?),
+        None => Err(ActixLuaError::RuntimeError {
+            traceback: err.unwrap_or_default(),
+        }),
+    }
+}
+
+/// The reply of a `LuaActor` to a message.
+///
+/// Sent once the message was handled, which is later than the message was received if it
+/// waits for a batch or the script deferred the reply with `ctx.reply_later`. The reply is a
+/// `Vec` for `Values` and an `Option` for `Optional`.
+pub enum LuaReply<T = LuaMessage> {
+    /// The message was handled right away.
+    Now(T),
+    /// The message is queued for the `handle_batch` hook, or its reply was deferred.
+    Later(oneshot::Receiver<T>),
+}
+
+impl<T: 'static> LuaReply<T> {
+    /// Convert the reply with `f`, once it is sent if it is deferred.
+    pub fn map<U, F>(self, f: F) -> LuaReply<U>
+    where
+        U: 'static,
+        F: FnOnce(T) -> U + 'static,
+    {
+        match self {
+            LuaReply::Now(res) => LuaReply::Now(f(res)),
+            LuaReply::Later(rx) => {
+                let (tx, later) = oneshot::channel();
+                // dropping `tx` cancels `later` as well
+                Arbiter::spawn(rx.map(move |res| {
+                    let _ = tx.send(f(res));
+                }).map_err(|_| ()));
+                LuaReply::Later(later)
+            }
+        }
+    }
+}
+
+impl<T: 'static, M: Message<Result = T>> MessageResponse<LuaActor, M> for LuaReply<T> {
+    fn handle<R: ResponseChannel<M>>(self, _: &mut Context<LuaActor>, tx: Option<R>) {
+        match (self, tx) {
+            (LuaReply::Now(msg), Some(tx)) => tx.send(msg),
+            // dropping `tx` fails the request if the actor stopped before replying
+            (LuaReply::Later(rx), Some(tx)) => {
+                Arbiter::spawn(rx.map(|msg| tx.send(msg)).map_err(|_| ()))
+            }
+            (_, None) => {}
+        }
+    }
+}
+
+// Run the `handle_batch` hook with the messages queued so far.
+struct FlushBatch;
+
+impl Message for FlushBatch {
+    type Result = ();
+}
+
+impl Handler<FlushBatch> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: FlushBatch, ctx: &mut Context<Self>) {
+        self.flush_batch(ctx);
+    }
+}
+
+// Reset the count of `ctx.handled_since_idle()`.
+struct MailboxDrained;
+
+impl Message for MailboxDrained {
+    type Result = ();
+}
+
+impl Handler<MailboxDrained> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: MailboxDrained, _: &mut Context<Self>) {
+        self.handled_since_idle = None;
+    }
+}
+
+impl Handler<LuaMessage> for LuaActor {
+    type Result = LuaReply;
 
     fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        self.receive(Hop::from(msg), ctx)
+    }
+}
+
+impl Handler<Values> for LuaActor {
+    type Result = LuaReply<Vec<LuaMessage>>;
+
+    fn handle(&mut self, Values(msg): Values, ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        if let Err(e) = self.select_tenant(&msg, ctx) {
+            return LuaReply::Now(vec![LuaMessage::Error(e)]);
+        }
+        self.handle_values(Hop::from(msg), ctx)
+    }
+}
+
+impl Handler<Optional> for LuaActor {
+    type Result = LuaReply<Option<LuaMessage>>;
+
+    fn handle(&mut self, Optional(msg): Optional, ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        self.receive(Hop::from(msg), ctx).map(|res| match res {
+            LuaMessage::Nil => None,
+            res => Some(res),
+        })
+    }
+}
+
+impl Handler<Tell> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, Tell(msg): Tell, ctx: &mut Context<Self>) {
+        if !self.accept_tell {
+            self.metrics.add_rejected_notification();
+            warn!(
+                "lua actor `{}` dropped a message sent without waiting for a reply",
+                self.label()
+            );
+            return;
+        }
+        self.metrics.add_notification();
+        self.count_handled(ctx);
+        self.receive(Hop::from(msg), ctx);
+    }
+}
+
+impl Handler<Hop> for LuaActor {
+    type Result = LuaReply;
+
+    fn handle(&mut self, hop: Hop, ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        if hop.hops > self.max_hops {
+            let e = ActixLuaError::TooManyHops {
+                max: self.max_hops,
+            };
+            warn!("lua actor `{}` rejected message: {}", self.label(), e);
+            return LuaReply::Now(LuaMessage::Error(e));
+        }
+        self.receive(hop, ctx)
+    }
+}
+
+impl Handler<Publish> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, publish: Publish, ctx: &mut Context<Self>) {
+        self.count_handled(ctx);
+        if let Err(e) = self.check_size(&publish.msg) {
+            warn!(
+                "lua actor `{}` dropped message published to `{}`: {}",
+                self.label(),
+                publish.topic,
+                e
+            );
+            return;
+        }
+        if let Err(e) = self.select_tenant(&publish.msg, ctx) {
+            warn!(
+                "lua actor `{}` dropped message published to `{}`: {}",
+                self.label(),
+                publish.topic,
+                e
+            );
+            return;
+        }
+        let topic = LuaMessage::from(publish.topic.as_str());
+        if let Err(e) = contract::check(&self.vm, &topic, &publish.msg) {
+            warn!(
+                "lua actor `{}` dropped message published to `{}`: {}",
+                self.label(),
+                publish.topic,
+                e
+            );
+            return;
+        }
+        if let Err(e) = self.call(
             ctx,
-            &mut self.vm,
-            &mut self.recipients,
             "__run",
-            vec![LuaMessage::from("handle"), msg],
+            vec![
+                LuaMessage::from("handle"),
+                publish.msg,
+                LuaMessage::from(publish.topic),
+            ],
         ) {
-            res
-        } else {
-            LuaMessage::Nil
+            self.hook_failed(ctx, "handle", &e);
         }
     }
 }
@@ -296,17 +1650,28 @@ impl Handler<SendAttemptResult> for LuaActor {
     type Result = LuaMessage;
 
     fn handle(&mut self, result: SendAttemptResult, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
+        if let Some(tenant) = result.tenant {
+            if let Err(e) = self.switch_tenant(tenant, ctx) {
+                return LuaMessage::Error(e);
+            }
+        }
+        match self.call(
             ctx,
-            &mut self.vm,
-            &mut self.recipients,
             "__resume",
-            vec![LuaMessage::from(result.cb_thread_id), result.msg],
+            match result.result {
+                Ok(msg) => vec![LuaMessage::from(result.cb_thread_id), msg],
+                Err(e) => vec![
+                    LuaMessage::from(result.cb_thread_id),
+                    LuaMessage::Nil,
+                    LuaMessage::from(e),
+                ],
+            },
         ) {
-            res
-        } else {
-            LuaMessage::Nil
+            Ok(res) => res,
+            Err(e) => {
+                self.hook_failed(ctx, "handle", &e);
+                LuaMessage::Error(e)
+            }
         }
     }
 }
@@ -315,20 +1680,61 @@ impl Handler<SendAttempt> for LuaActor {
     type Result = LuaMessage;
 
     fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
-        let rec = &self.recipients[&attempt.recipient_name];
+        let (name, cb_thread_id) = (attempt.recipient_name, attempt.cb_thread_id);
+        let reply = |res: Result<LuaMessage, MailboxError>| match res {
+            Ok(LuaMessage::Error(e)) => Err(e.to_string()),
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(e.to_string()),
+        };
+        let fut: Box<dyn Future<Item = LuaMessage, Error = String>> =
+            if let Some(rec) = self.lua_recipients.get(&name) {
+                Box::new(rec.send(Hop {
+                    msg: attempt.msg,
+                    hops: attempt.hops,
+                    meta: attempt.meta,
+                }).then(reply))
+            } else if let Some(rec) = self.recipients.get(&name) {
+                Box::new(rec.send(attempt.msg).then(reply))
+            } else if is_remote_address(&name) {
+                match self.encoder.encode(&attempt.msg) {
+                    Ok(msg) => Box::new(
+                        LuaNode::from_registry()
+                            .send(SendEncoded {
+                                address: name,
+                                msg,
+                                reply: true,
+                            })
+                            .map_err(|e| e.to_string())
+                            .and_then(|res| res.map_err(|e| e.to_string())),
+                    ),
+                    Err(e) => Box::new(future::err(e.to_string())),
+                }
+            } else {
+                // `ctx.self` of another actor, or unknown
+                Box::new(
+                    LuaAddresses::from_registry()
+                        .send(Forward {
+                            address: name,
+                            hop: Hop {
+                                msg: attempt.msg,
+                                hops: attempt.hops,
+                                meta: attempt.meta,
+                            },
+                        })
+                        .map_err(|e| e.to_string())
+                        .and_then(|res| res),
+                )
+            };
+
         let self_addr = ctx.address().clone();
-        rec.send(attempt.msg.clone())
-            .into_actor(self)
-            .then(move |res, _, _| {
-                match res {
-                    Ok(msg) => self_addr.do_send(SendAttemptResult {
-                        msg,
-                        cb_thread_id: attempt.cb_thread_id,
-                    }),
-                    _ => {
-                        panic!("send attempt failed {:?}", res);
-                    }
-                };
+        let tenant = self.tenants.as_ref().and_then(|t| t.current.clone());
+        fut.into_actor(self)
+            .then(move |result, _, _| {
+                self_addr.do_send(SendAttemptResult {
+                    result,
+                    cb_thread_id,
+                    tenant,
+                });
                 actix::fut::ok(())
             })
             .wait(ctx);
@@ -342,10 +1748,12 @@ mod tests {
     use super::*;
     use futures_timer::Delay;
     use std::collections::HashMap;
+    use std::sync::Arc;
     use std::time::Duration;
     use tokio::prelude::Future;
 
     use builder::LuaActorBuilder;
+    use bus::LuaGroup;
 
     fn lua_actor_with_handle(script: &str) -> LuaActor {
         LuaActorBuilder::new()
@@ -370,6 +1778,37 @@ mod tests {
     }
 
     #[test]
+    fn lua_actor_runtime_error() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        local function lookup(t)
+            return t.x.y
+        end
+        local y = lookup({})
+        return y
+        "#,
+        ).start();
+
+        let l = lua_addr.send(LuaMessage::Nil);
+        Arbiter::spawn(l.map(|res| {
+            match res {
+                LuaMessage::Error(ActixLuaError::RuntimeError { traceback }) => {
+                    assert!(traceback.starts_with(r#"[string "handle"]:3: attempt to index"#));
+                    assert!(traceback.contains("stack traceback:"));
+                    assert!(traceback.contains(r#"[string "handle"]:5: in main chunk"#));
+                }
+                res => panic!("unexpected reply {:?}", res),
+            }
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    #[allow(clippy::redundant_pattern_matching)]
     fn lua_actor_syntax_error() {
         let res = LuaActorBuilder::new()
             .on_handle_with_lua(r"return 1+")
@@ -499,6 +1938,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn lua_actor_rpc_new_actor() {
         let system = System::new("test");
 
@@ -623,6 +2063,75 @@ mod tests {
         system.run();
     }
 
+    #[test]
+    fn lua_actor_do_send_local_name_like_remote() {
+        let system = System::new("test");
+
+        let sink = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "get" then return ctx.state.got end
+            ctx.state.got = ctx.msg
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"ctx.do_send("sink@127.0.0.1:1/logs", ctx.msg)"#)
+            .build()
+            .unwrap();
+        actor.add_recipients("sink@127.0.0.1:1/logs", sink.clone().recipient());
+        let addr = actor.start();
+
+        let l = addr
+            .send(LuaMessage::from("hello"))
+            .and_then(move |_| sink.send(LuaMessage::from("get")));
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from("hello"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_do_send_stopped() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.child = ctx.new_actor("src/lua/test/terminate.lua")"#)
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "stop" then
+                return ctx.do_send(ctx.state.child, "stop")
+            end
+            local ok, e = pcall(ctx.do_send, ctx.state.child, "hello")
+            return tostring(ok) .. " " .. tostring(e)
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("stop"))
+            .and_then(|_| Delay::new(Duration::from_millis(200)).map_err(|_| MailboxError::Closed))
+            .and_then(move |_| addr.send(LuaMessage::from("send")));
+        Arbiter::spawn(l.map(|res| {
+            // the child stopped, the script gets an error instead of a panic
+            match res {
+                LuaMessage::String(s) => {
+                    assert!(s.starts_with("false actor stopped"), "{}", s)
+                }
+                res => panic!("unexpected reply {:?}", res),
+            }
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
     #[test]
     fn lua_actor_terminate() {
         // TODO: validate on_stopped is called
@@ -701,4 +2210,1081 @@ mod tests {
 
         system.run();
     }
+
+    #[test]
+    fn lua_actor_with_fn() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_fn(|msg, _| match msg {
+                LuaMessage::Integer(x) => Some(LuaMessage::from(x * 2)),
+                _ => None,
+            })
+            .on_handle_with_lua(r#"return "lua: " .. ctx.msg"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from(21));
+        let l2 = addr.send(LuaMessage::from("hello"));
+        Arbiter::spawn(l.join(l2).map(|(res, res2)| {
+            assert_eq!(res, LuaMessage::from(42));
+            assert_eq!(res2, LuaMessage::from("lua: hello"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_multiple_vm() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return greet(ctx.msg)"#)
+            .with_vm(|vm| vm.globals().set("greeting", "Hello"))
+            .with_vm(|vm| {
+                vm.exec::<()>(
+                    r#"function greet(name) return greeting .. ", " .. name .. "!" end"#,
+                    None,
+                )
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from("World"));
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from("Hello, World!"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_vm_async() {
+        let system = System::new("test");
+
+        let config = Delay::new(Duration::from_millis(200))
+            .map(|()| "remote".to_string())
+            .map_err(|e| LuaError::RuntimeError(e.to_string()));
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.mode = mode"#)
+            .on_handle_with_lua(r#"return ctx.state.mode"#)
+            .with_vm_async(config.map(|mode| move |vm: &Lua| vm.globals().set("mode", mode)))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from("remote"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_error_policy() {
+        let system = System::new("test");
+
+        let build = |policy| {
+            LuaActorBuilder::new()
+                .on_started_with_lua(r#"ctx.state.count = 10"#)
+                .on_handle_with_lua(
+                    r#"
+                if ctx.msg == "fail" then error("boom") end
+                ctx.state.count = ctx.state.count + 1
+                return ctx.state.count
+                "#,
+                )
+                .with_error_policy(policy)
+                .build()
+                .unwrap()
+                .start()
+        };
+        let restarted = build(ErrorPolicy::Restart);
+        let stopped = build(ErrorPolicy::Stop);
+
+        let l = restarted
+            .send(LuaMessage::Nil)
+            .join(restarted.send(LuaMessage::from("fail")))
+            .join(restarted.send(LuaMessage::Nil))
+            .map(|((first, failed), second)| {
+                assert_eq!(first, LuaMessage::from(11));
+                match failed {
+                    LuaMessage::Error(ActixLuaError::RuntimeError { traceback }) => {
+                        assert!(traceback.contains("boom"))
+                    }
+                    res => panic!("unexpected reply {:?}", res),
+                }
+                // the state was lost and `started` ran again
+                assert_eq!(second, LuaMessage::from(11));
+            })
+            .and_then(move |()| stopped.send(LuaMessage::from("fail")).map(|_| stopped))
+            .and_then(|stopped| {
+                stopped.send(LuaMessage::Nil).then(|res| {
+                    assert!(res.is_err());
+                    System::current().stop();
+                    Ok(())
+                })
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_stop_reasons() {
+        use std::sync::Mutex;
+
+        let system = System::new("test");
+
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let build = |name: &str, policy| {
+            let reasons = reasons.clone();
+            LuaActorBuilder::new()
+                .on_handle_with_lua(r#"if ctx.msg == "fail" then error("boom") end"#)
+                .on_stopped_with_lua("record(ctx.name .. ':' .. ctx.msg)")
+                .with_name(name)
+                .with_error_policy(policy)
+                .with_vm(move |vm| {
+                    let reasons = reasons.clone();
+                    let record = vm.create_function(move |_, reason: String| {
+                        reasons.lock().unwrap().push(reason);
+                        Ok(())
+                    })?;
+                    vm.globals().set("record", record)
+                })
+                .build()
+                .unwrap()
+                .start()
+        };
+        let restarted = build("restarted", ErrorPolicy::Restart);
+        let failed = build("failed", ErrorPolicy::Stop);
+        let drained = build("drained", ErrorPolicy::Stop);
+        let _running = build("running", ErrorPolicy::Stop);
+
+        drained.do_send(Drain);
+        let l = restarted
+            .send(LuaMessage::from("fail"))
+            .join(failed.send(LuaMessage::from("fail")))
+            .join(drained.send(LuaMessage::Nil).then(Ok::<_, MailboxError>))
+            .map(|_| System::current().stop());
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+
+        let mut reasons = reasons.lock().unwrap().clone();
+        reasons.sort();
+        assert_eq!(
+            reasons,
+            [
+                "drained:drain",
+                "failed:error",
+                "restarted:restart",
+                "restarted:shutdown",
+                "running:shutdown",
+            ]
+        );
+    }
+
+    #[test]
+    fn lua_actor_stopping() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.vetoes = {}")
+            .on_handle_with_lua("return table.concat(ctx.state.vetoes, ',')")
+            .on_stopping_with_lua(
+                r#"
+            table.insert(ctx.state.vetoes, ctx.msg)
+            if #ctx.state.vetoes == 1 then return "continue" end
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(Drain);
+        let l = addr.send(LuaMessage::Nil).and_then(move |vetoes| {
+            assert_eq!(vetoes, LuaMessage::from("drain"));
+            addr.do_send(Drain);
+            addr.send(LuaMessage::Nil).then(|res| {
+                assert!(res.is_err());
+                System::current().stop();
+                Ok(())
+            })
+        });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handled_since_idle() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua("return ctx.handled_since_idle()")
+            .build()
+            .unwrap()
+            .start();
+
+        let sends: Vec<_> = (0..4).map(|_| addr.send(LuaMessage::Nil)).collect();
+        let burst = future::join_all(sends);
+        let l = burst.and_then(move |counts| {
+            assert_eq!(counts, (0..4).map(LuaMessage::from).collect::<Vec<_>>());
+            addr.send(LuaMessage::Nil)
+        });
+        Arbiter::spawn(l.map(|count| {
+            // the mailbox ran empty in between
+            assert_eq!(count, LuaMessage::from(0));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_on_panic() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let system = System::new("test");
+
+        let panics = Arc::new(AtomicUsize::new(0));
+        let counted = panics.clone();
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "explode" then explode() end
+            return "ok"
+            "#,
+            )
+            .with_vm(|vm| {
+                let explode = vm.create_function(|_, ()| -> Result<(), LuaError> {
+                    panic!("boom")
+                })?;
+                vm.globals().set("explode", explode)
+            })
+            .on_panic(move |_, _| {
+                counted.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("explode"))
+            .join(addr.send(LuaMessage::ThreadYield("0".to_string())))
+            .join(addr.send(LuaMessage::Nil))
+            .map(move |((callback, conversion), res)| {
+                assert_eq!(
+                    callback,
+                    LuaMessage::Error(ActixLuaError::Panic {
+                        message: "boom".to_string()
+                    })
+                );
+                // a message scripts can't get is an error, not a panic
+                match conversion {
+                    LuaMessage::Error(ActixLuaError::ConversionError { .. }) => {}
+                    res => panic!("unexpected reply {:?}", res),
+                }
+                assert_eq!(res, LuaMessage::from("ok"));
+                assert_eq!(panics.load(Ordering::SeqCst), 1);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_max_message_size() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return #ctx.msg"#)
+            .with_max_message_size(100)
+            .build()
+            .unwrap()
+            .start();
+
+        let big = LuaMessage::from("x".repeat(1000));
+        let size = big.estimated_size();
+        let l = addr
+            .send(LuaMessage::from("small"))
+            .join(addr.send(big))
+            .map(move |(small, big)| {
+                assert_eq!(small, LuaMessage::from(5));
+                assert_eq!(
+                    big,
+                    LuaMessage::Error(ActixLuaError::MessageTooLarge { size, max: 100 })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_max_table_depth() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "deep" then
+                local t = {}
+                for _ = 1, 100000 do
+                    t = { t }
+                end
+                return t
+            end
+            return { a = { b = ctx.msg } }
+            "#,
+            )
+            .with_max_table_depth(3)
+            .build()
+            .unwrap()
+            .start();
+
+        let nested = |msg| {
+            let mut t = HashMap::new();
+            t.insert("t".to_string(), msg);
+            LuaMessage::from(t)
+        };
+        let too_deep = nested(nested(nested(nested(LuaMessage::Nil))));
+        let l = addr
+            .send(LuaMessage::from("flat"))
+            .join3(addr.send(LuaMessage::from("deep")), addr.send(too_deep))
+            .map(|(flat, deep, too_deep)| {
+                assert_eq!(flat.depth(), 2);
+                let err = LuaMessage::Error(ActixLuaError::TableTooDeep { max: 3 });
+                assert_eq!(deep, err);
+                assert_eq!(too_deep, err);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_max_hops() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let system = System::new("test");
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counted = handled.clone();
+        let ping_pong = move |peer: &str| {
+            let handled = counted.clone();
+            LuaActorBuilder::new()
+                .on_handle_with_fn(move |_, _| {
+                    handled.fetch_add(1, Ordering::SeqCst);
+                    None
+                })
+                .on_handle_with_lua(format!(r#"ctx.do_send("{}", ctx.msg)"#, peer))
+                .with_max_hops(5)
+                .build()
+                .unwrap()
+        };
+        let ping = LuaActor::create(move |ctx| {
+            let mut pong = ping_pong("ping");
+            pong.add_lua_recipient("ping", &ctx.address());
+            let pong = pong.start();
+            let mut ping = ping_pong("pong");
+            ping.add_lua_recipient("pong", &pong);
+            ping
+        });
+        ping.do_send(LuaMessage::from("ball"));
+
+        let l = Delay::new(Duration::from_millis(200)).map(move |()| {
+            // handled with 0 to 5 hops, rejected with 6
+            assert_eq!(handled.load(Ordering::SeqCst), 6);
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("timer error {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_meta() {
+        let system = System::new("test");
+
+        let tracer = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.meta.trace_id .. ":" .. ctx.msg"#)
+            .build()
+            .unwrap()
+            .start();
+        let mut actor = lua_actor_with_handle(
+            r#"
+        local token = ctx.reply_later()
+        local reply = ctx.send("tracer", ctx.msg, { trace_id = ctx.meta.trace_id })
+        -- the metadata of the message is kept while waiting for the reply
+        ctx.reply(token, reply .. ":" .. ctx.meta.trace_id)
+        "#,
+        );
+        actor.add_lua_recipient("tracer", &tracer);
+        let addr = actor.start();
+
+        let mut meta = HashMap::new();
+        meta.insert("trace_id".to_string(), LuaMessage::from("abc"));
+        let l = addr.send(Hop {
+            msg: LuaMessage::from("ping"),
+            hops: 0,
+            meta,
+        });
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from("abc:ping:abc"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_stash() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == "put" then
+            local big = { n = 0 }
+            for i = 1, 1000 do
+                big[i] = i
+                big.n = big.n + i
+            end
+            return ctx.stash(big)
+        end
+        local big = ctx.take(ctx.msg)
+        return big and big.n or "gone"
+        "#,
+        )
+        .start();
+
+        let l = addr
+            .send(LuaMessage::from("put"))
+            .and_then(move |id| addr.send(id.clone()).join(addr.send(id)));
+        Arbiter::spawn(l.map(|(taken, gone)| {
+            assert_eq!(taken, LuaMessage::from(500_500));
+            assert_eq!(gone, LuaMessage::from("gone"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_values() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == "pair" then
+            return 1, nil, "three"
+        elseif ctx.msg == "later" then
+            local token = ctx.reply_later()
+            ctx.reply(token, "late")
+            return
+        elseif ctx.msg == "fail" then
+            error("boom")
+        elseif ctx.msg == "table" then
+            return { 1, 2 }
+        end
+        "#,
+        )
+        .start();
+
+        let values = future::join_all(vec![
+            addr.send(Values("pair".into())),
+            addr.send(Values("later".into())),
+            addr.send(Values("fail".into())),
+            addr.send(Values("none".into())),
+        ]);
+        let optional = addr
+            .send(Optional("none".into()))
+            .join(addr.send(Optional("table".into())));
+        Arbiter::spawn(values.join(optional).map(|(values, (none, table))| {
+            assert_eq!(values[0], [1.into(), LuaMessage::Nil, "three".into()]);
+            assert_eq!(values[1], ["late".into()]);
+            match values[2][..] {
+                [LuaMessage::Error(ActixLuaError::RuntimeError { ref traceback })] => {
+                    assert!(traceback.contains("boom"))
+                }
+                ref res => panic!("unexpected result {:?}", res),
+            }
+            assert!(values[3].is_empty());
+            assert_eq!(none, None);
+            let mut t = HashMap::new();
+            t.insert("1".to_string(), LuaMessage::from(1));
+            t.insert("2".to_string(), LuaMessage::from(2));
+            assert_eq!(table, Some(LuaMessage::from(t)));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_spawn_blocking() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == "ping" then
+            return "pong"
+        end
+        local token = ctx.reply_later()
+        local sum = ctx.spawn_blocking(function (n)
+            local sum = 0
+            for i = 1, n do
+                sum = sum + i
+            end
+            return sum
+        end, ctx.msg)
+        ctx.reply(token, sum)
+        "#,
+        )
+        .start();
+
+        // answered while the sum runs on another thread
+        let l = addr
+            .send(LuaMessage::from(1000))
+            .join(addr.send(LuaMessage::from("ping")));
+        Arbiter::spawn(l.map(|(sum, pong)| {
+            assert_eq!(sum, LuaMessage::from(500_500));
+            assert_eq!(pong, LuaMessage::from("pong"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn run_blocking_error() {
+        let vm = Lua::new();
+        let function = vm
+            .exec::<::rlua::String>("return string.dump(function () error('boom') end)", None)
+            .unwrap();
+        match run_blocking(function.as_bytes().to_vec(), LuaMessage::Nil) {
+            Err(ActixLuaError::RuntimeError { traceback }) => assert!(traceback.contains("boom")),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn lua_actor_self() {
+        let system = System::new("test");
+
+        let worker = lua_actor_with_handle(
+            r#"
+        local answer = ctx.send(ctx.msg.reply_to, ctx.msg.n * 2)
+        assert(answer == "thanks")
+        "#,
+        )
+        .start();
+        let mut actor = lua_actor_with_handle(
+            r#"
+        if ctx.msg == "start" then
+            ctx.state.token = ctx.reply_later()
+            ctx.do_send("worker", { reply_to = ctx.self, n = 21 })
+            return
+        end
+        ctx.reply(ctx.state.token, ctx.msg)
+        return "thanks"
+        "#,
+        );
+        actor.add_lua_recipient("worker", &worker);
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::from("start"));
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from(42));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_cost_report() {
+        use std::sync::Mutex;
+
+        let system = System::new("test");
+
+        let costs = Arc::new(Mutex::new(vec![]));
+        let reported = costs.clone();
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local sum = 0
+            for i = 1, ctx.msg do
+                sum = sum + i
+            end
+            return sum
+            "#,
+            )
+            .with_cost_report(move |cost| reported.lock().unwrap().push(cost.clone()))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from(1000));
+        Arbiter::spawn(l.map(move |res| {
+            assert_eq!(res, LuaMessage::from(500500));
+            let costs = costs.lock().unwrap();
+            let hooks: Vec<_> = costs.iter().map(|cost| cost.hook.as_str()).collect();
+            assert_eq!(hooks, ["started", "handle"]);
+            // an addition and a jump per iteration of the loop
+            assert!(costs[1].instructions >= 2000, "{:?}", costs[1]);
+            assert!(costs[1].instructions > costs[0].instructions);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_slow_handler() {
+        let system = System::new("test");
+
+        let metrics = LuaActorMetrics::new();
+        let build = |threshold| {
+            LuaActorBuilder::new()
+                .on_started_with_lua("for i = 1, 100000 do end")
+                .on_handle_with_lua("for i = 1, 100000 do end")
+                .with_slow_handler_threshold(threshold)
+                .with_metrics(metrics.clone())
+                .build()
+                .unwrap()
+                .start()
+        };
+        let slow = build(Duration::from_nanos(0));
+        let fast = build(Duration::from_secs(60));
+
+        let l = slow
+            .send(LuaMessage::Nil)
+            .join(slow.send(LuaMessage::Nil))
+            .join(fast.send(LuaMessage::Nil));
+        Arbiter::spawn(l.map(move |_| {
+            // `started` doesn't handle a message
+            assert_eq!(metrics.slow_invocations(), 2);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_name() {
+        use std::sync::{Arc, Mutex};
+
+        let system = System::new("test");
+
+        let costs = Arc::new(Mutex::new(vec![]));
+        let reported = costs.clone();
+        let named = LuaActorBuilder::new()
+            .on_handle_with_lua("return { ctx.name, ctx.id }")
+            .with_name("billing")
+            .with_cost_report(move |cost| reported.lock().unwrap().push(cost.actor.clone()))
+            .build()
+            .unwrap()
+            .start();
+        let anonymous = LuaActorBuilder::new()
+            .on_handle_with_lua("return { name = ctx.name, id = ctx.id }")
+            .build()
+            .unwrap()
+            .start();
+
+        let l = named.send(LuaMessage::Nil).join(anonymous.send(LuaMessage::Nil));
+        Arbiter::spawn(l.map(move |(named, anonymous)| {
+            let (name, id) = match (named, anonymous) {
+                (LuaMessage::Table(named), LuaMessage::Table(anonymous)) => {
+                    assert!(!anonymous.contains_key("name"));
+                    assert_ne!(anonymous["id"], named["2"]);
+                    (named["1"].clone(), named["2"].clone())
+                }
+                res => panic!("unexpected result {:?}", res),
+            };
+            assert_eq!(name, LuaMessage::from("billing"));
+            match id {
+                LuaMessage::String(id) => assert_eq!(id.len(), 36),
+                id => panic!("unexpected id {:?}", id),
+            }
+            assert_eq!(*costs.lock().unwrap(), ["billing", "billing"]);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_tenants() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.n = 0"#)
+            .on_handle_with_lua(
+                r#"
+            ctx.state.n = ctx.state.n + 1
+            return ctx.msg.tenant .. ctx.state.n
+            "#,
+            )
+            .with_tenants(2, |msg| match msg {
+                LuaMessage::Table(t) => match t.get("tenant") {
+                    Some(LuaMessage::String(tenant)) => tenant.clone(),
+                    _ => String::new(),
+                },
+                _ => String::new(),
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let sends: Vec<_> = ["a", "b", "a", "c", "b", "a"]
+            .iter()
+            .map(|tenant| {
+                let mut msg = HashMap::new();
+                msg.insert("tenant".to_string(), LuaMessage::from(*tenant));
+                addr.send(LuaMessage::from(msg))
+            })
+            .collect();
+        Arbiter::spawn(future::join_all(sends).map(|res| {
+            // `b` was evicted for `c`, then `a` for `b`
+            let expected: Vec<_> = ["a1", "b1", "a2", "c1", "b1", "a1"]
+                .iter()
+                .map(|reply| LuaMessage::from(*reply))
+                .collect();
+            assert_eq!(res, expected);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_batch() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_batch_with_lua(
+                r#"
+            local replies = {}
+            for i = 1, ctx.msg.n do
+                replies[i] = ctx.msg[i] * 10 + ctx.msg.n
+            end
+            return replies
+            "#,
+            )
+            .with_batch_size(4)
+            .build()
+            .unwrap()
+            .start();
+
+        let sends: Vec<_> = (1..=10).map(|i| addr.send(LuaMessage::from(i))).collect();
+        Arbiter::spawn(future::join_all(sends).map(|res| {
+            // two full batches, then the two messages left in the mailbox
+            let expected: Vec<_> = (1..=10)
+                .map(|i| LuaMessage::from(i * 10 + if i <= 8 { 4 } else { 2 }))
+                .collect();
+            assert_eq!(res, expected);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_reply_later() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == "ask" then
+            ctx.state.token = ctx.reply_later()
+            return "ignored"
+        end
+        ctx.reply(ctx.state.token, 42)
+        return ctx.reply(ctx.state.token, 43)
+        "#,
+        ).start();
+
+        let l = addr
+            .send(LuaMessage::from("ask"))
+            .join(addr.send(LuaMessage::from("answer")));
+        Arbiter::spawn(l.map(|(ask, answer)| {
+            assert_eq!(ask, LuaMessage::from(42));
+            // the message was answered already
+            assert_eq!(answer, LuaMessage::from(false));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_tell() {
+        use message::Tell;
+        use metrics::LuaActorMetrics;
+
+        let system = System::new("test");
+
+        let metrics = LuaActorMetrics::new();
+        let build = |accept| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                ctx.state.told = (ctx.state.told or 0) + (ctx.msg == "tell" and 1 or 0)
+                return ctx.state.told
+                "#,
+                )
+                .accept_tell(accept)
+                .with_metrics(metrics.clone())
+                .build()
+                .unwrap()
+                .start()
+        };
+        let (accepting, refusing) = (build(true), build(false));
+        accepting.do_send(Tell(LuaMessage::from("tell")));
+        refusing.do_send(Tell(LuaMessage::from("tell")));
+
+        let l = accepting
+            .send(LuaMessage::from("count"))
+            .join(refusing.send(LuaMessage::from("count")));
+        Arbiter::spawn(l.map(move |(accepted, refused)| {
+            assert_eq!(accepted, LuaMessage::from(1));
+            assert_eq!(refused, LuaMessage::from(0));
+            assert_eq!(metrics.requests(), 2);
+            assert_eq!(metrics.notifications(), 1);
+            assert_eq!(metrics.rejected_notifications(), 1);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_userdata() {
+        use rlua::{UserData, UserDataMethods};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Clone)]
+        struct Counter(Arc<AtomicUsize>);
+
+        impl UserData for Counter {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("incr", |_, this, ()| {
+                    Ok(this.0.fetch_add(1, Ordering::SeqCst) + 1)
+                });
+            }
+        }
+
+        let system = System::new("test");
+
+        let counter = Counter(Arc::new(AtomicUsize::new(0)));
+        let build = || {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(r#"return counter:incr()"#)
+                .with_userdata("counter", counter.clone())
+                .build()
+                .unwrap()
+                .start()
+        };
+        let addr = build();
+        let addr2 = build();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(l.and_then(move |_| addr2.send(LuaMessage::Nil)).map(move |res| {
+            assert_eq!(res, LuaMessage::from(2));
+            assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_shared_data() {
+        let system = System::new("test");
+
+        let mut t = HashMap::new();
+        t.insert("tw".to_string(), LuaMessage::from("Taiwan"));
+        let data = Arc::new(LuaMessage::from(t));
+
+        let build = |data| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(r#"return countries[ctx.msg]"#)
+                .with_shared_data("countries", data)
+                .build()
+                .unwrap()
+                .start()
+        };
+        let addr = build(data.clone());
+        let addr2 = build(data.clone());
+
+        let l = addr.send(LuaMessage::from("tw"));
+        let l2 = addr2.send(LuaMessage::from("tw"));
+        Arbiter::spawn(l.join(l2).map(|(res, res2)| {
+            assert_eq!(res, LuaMessage::from("Taiwan"));
+            assert_eq!(res2, LuaMessage::from("Taiwan"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn lua_actor_with_config_file() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return config.workers * config.limits.rate"#)
+            .with_config_file("src/lua/test/config.toml")
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from(2.0));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_args() {
+        let system = System::new("test");
+
+        let build = |shard| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(r#"return arg[2] .. "-" .. arg[1] .. "/" .. #arg"#)
+                .with_args(vec![LuaMessage::from(shard), LuaMessage::from("eu")])
+                .build()
+                .unwrap()
+                .start()
+        };
+        let (addr, addr2) = (build(1), build(2));
+
+        let l = addr.send(LuaMessage::Nil).join(addr2.send(LuaMessage::Nil));
+        Arbiter::spawn(l.map(|(res, res2)| {
+            assert_eq!(res, LuaMessage::from("eu-1/2"));
+            assert_eq!(res2, LuaMessage::from("eu-2/2"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_pubsub() {
+        let system = System::new("test");
+
+        let subscriber = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.subscribe("orders.*")"#)
+            .on_handle_with_lua(
+                r#"
+            if ctx.topic then
+                ctx.state.received = ctx.topic .. ":" .. ctx.msg
+            end
+            return ctx.state.received
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let publisher = lua_actor_with_handle(r#"ctx.publish("orders.created", ctx.msg)"#).start();
+
+        let delay = Delay::new(Duration::from_millis(500))
+            .map_err(|e| println!("timer error {}", e))
+            .and_then(move |()| {
+                publisher
+                    .send(LuaMessage::from("order-1"))
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .and_then(|_| Delay::new(Duration::from_millis(500)).map_err(|_| ()))
+            .and_then(move |()| {
+                subscriber
+                    .send(LuaMessage::Nil)
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("orders.created:order-1"));
+                System::current().stop();
+            });
+        Arbiter::spawn(delay);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_broadcast() {
+        let system = System::new("test");
+
+        let member = || {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                if ctx.msg == "invalidate" then
+                    ctx.state.invalidated = true
+                end
+                return ctx.state.invalidated
+                "#,
+                )
+                .build()
+                .unwrap()
+                .start()
+        };
+        let member1 = member();
+        let member2 = member();
+        let group = LuaGroup::new("cache");
+        group.add(member1.clone());
+        group.add(member2.clone());
+
+        let sender = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.join("cache")"#)
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "invalidate" then
+                ctx.state.invalidated = true
+            else
+                ctx.broadcast("cache", "invalidate")
+            end
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_millis(500))
+            .map_err(|e| println!("timer error {}", e))
+            .and_then(move |()| {
+                sender
+                    .send(LuaMessage::Nil)
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .and_then(|_| Delay::new(Duration::from_millis(500)).map_err(|_| ()))
+            .and_then(move |()| {
+                member1
+                    .send(LuaMessage::Nil)
+                    .join(member2.send(LuaMessage::Nil))
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .map(|(res1, res2)| {
+                assert_eq!(res1, LuaMessage::from(true));
+                assert_eq!(res2, LuaMessage::from(true));
+                System::current().stop();
+            });
+        Arbiter::spawn(delay);
+
+        system.run();
+    }
 }
@@ -0,0 +1,80 @@
+use actix::prelude::*;
+use futures::{future, Future};
+
+use std::collections::HashMap;
+
+use message::{Hop, LuaMessage};
+
+// The running `LuaActor`s by their `ctx.self`, so a script can send messages to an actor whose
+// address it got in a message rather than from `add_lua_recipient`.
+#[derive(Default)]
+pub(crate) struct LuaAddresses {
+    actors: HashMap<String, Recipient<Hop>>,
+}
+
+impl Actor for LuaAddresses {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LuaAddresses {}
+
+impl SystemService for LuaAddresses {}
+
+pub(crate) struct Register {
+    pub(crate) address: String,
+    pub(crate) recipient: Recipient<Hop>,
+}
+
+impl Message for Register {
+    type Result = ();
+}
+
+pub(crate) struct Unregister(pub(crate) String);
+
+impl Message for Unregister {
+    type Result = ();
+}
+
+// send `hop` to the actor at `address`, an error reply is returned as the error
+pub(crate) struct Forward {
+    pub(crate) address: String,
+    pub(crate) hop: Hop,
+}
+
+impl Message for Forward {
+    type Result = Result<LuaMessage, String>;
+}
+
+impl Handler<Register> for LuaAddresses {
+    type Result = ();
+
+    fn handle(&mut self, reg: Register, _: &mut Context<Self>) {
+        self.actors.insert(reg.address, reg.recipient);
+    }
+}
+
+impl Handler<Unregister> for LuaAddresses {
+    type Result = ();
+
+    fn handle(&mut self, Unregister(address): Unregister, _: &mut Context<Self>) {
+        self.actors.remove(&address);
+    }
+}
+
+impl Handler<Forward> for LuaAddresses {
+    type Result = ResponseFuture<LuaMessage, String>;
+
+    fn handle(&mut self, forward: Forward, _: &mut Context<Self>) -> Self::Result {
+        match self.actors.get(&forward.address) {
+            Some(rec) => Box::new(rec.send(forward.hop).then(|res| match res {
+                Ok(LuaMessage::Error(e)) => Err(e.to_string()),
+                Ok(msg) => Ok(msg),
+                Err(e) => Err(e.to_string()),
+            })),
+            None => Box::new(future::err(format!(
+                "unknown recipient `{}`",
+                forward.address
+            ))),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use actix::prelude::*;
+use futures::sync::mpsc;
+use futures::{Async, Future, Poll, Stream};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use actor::{LuaActor, StreamItem, StreamRequest};
+use cancel::CancelToken;
+use error::{ActixLuaError, AskFuture};
+use message::{Hop, LuaMessage};
+
+/// Send a message to a Lua actor and convert its reply.
+///
+/// ```rust,ignore
+/// let total: AskFuture<i64> = addr.ask(order, Duration::from_secs(1));
+/// ```
+///
+/// The future fails with `ActixLuaError::Timeout` if the reply doesn't arrive within `timeout`,
+/// and with `ActixLuaError::ActorStopped` if the actor is gone. A `LuaMessage::Error` reply is
+/// returned as the error, and a reply which can't be converted to `T` as
+/// `ActixLuaError::ConversionError`.
+pub trait Ask {
+    fn ask<M, T>(&self, msg: M, timeout: Duration) -> AskFuture<T>
+    where
+        M: Into<LuaMessage>,
+        T: TryFrom<LuaMessage> + 'static,
+        T::Error: Into<ActixLuaError>;
+}
+
+impl Ask for Addr<LuaActor> {
+    fn ask<M, T>(&self, msg: M, timeout: Duration) -> AskFuture<T>
+    where
+        M: Into<LuaMessage>,
+        T: TryFrom<LuaMessage> + 'static,
+        T::Error: Into<ActixLuaError>,
+    {
+        convert_reply(self.send(msg.into()).timeout(timeout))
+    }
+}
+
+impl Ask for Recipient<LuaMessage> {
+    fn ask<M, T>(&self, msg: M, timeout: Duration) -> AskFuture<T>
+    where
+        M: Into<LuaMessage>,
+        T: TryFrom<LuaMessage> + 'static,
+        T::Error: Into<ActixLuaError>,
+    {
+        convert_reply(self.send(msg.into()).timeout(timeout))
+    }
+}
+
+/// Send a message to a Lua actor along with its metadata, which scripts get as `ctx.meta`.
+///
+/// ```rust,ignore
+/// let mut meta = HashMap::new();
+/// meta.insert("trace_id".to_string(), LuaMessage::from("4bf92f35"));
+/// addr.send_with_meta(LuaMessage::from("order"), meta)
+/// ```
+pub trait SendWithMeta {
+    fn send_with_meta(
+        &self,
+        msg: LuaMessage,
+        meta: HashMap<String, LuaMessage>,
+    ) -> Request<LuaActor, Hop>;
+}
+
+impl SendWithMeta for Addr<LuaActor> {
+    fn send_with_meta(
+        &self,
+        msg: LuaMessage,
+        meta: HashMap<String, LuaMessage>,
+    ) -> Request<LuaActor, Hop> {
+        self.send(Hop { msg, hops: 0, meta })
+    }
+}
+
+/// Send a message to a Lua actor and get the values its `handle` hook emits as a stream.
+///
+/// The hook emits values with `ctx.emit(value)`, and the stream ends when it returns, with the
+/// value it returned unless it is `nil`. It fails with the error the hook raised, and with
+/// `ActixLuaError::ActorStopped` if the actor stopped before the hook returned.
+///
+/// Dropping the stream before it ends cancels the hook, like a `Cancellable` message, and
+/// messages are checked against the contracts declared with `ctx.contract`.
+///
+/// ```rust,ignore
+/// addr.send_stream(query).for_each(|row| render(row))
+/// ```
+pub trait SendStream {
+    fn send_stream<M: Into<LuaMessage>>(&self, msg: M) -> LuaStream;
+}
+
+impl SendStream for Addr<LuaActor> {
+    fn send_stream<M: Into<LuaMessage>>(&self, msg: M) -> LuaStream {
+        let (tx, rx) = mpsc::unbounded();
+        let token = CancelToken::new();
+        self.do_send(StreamRequest {
+            msg: msg.into(),
+            tx,
+            token: token.clone(),
+        });
+        LuaStream {
+            rx,
+            token,
+            done: false,
+        }
+    }
+}
+
+/// The values emitted by a `handle` hook, see `SendStream`.
+pub struct LuaStream {
+    rx: mpsc::UnboundedReceiver<StreamItem>,
+    token: CancelToken,
+    done: bool,
+}
+
+// nobody reads what the hook emits anymore
+impl Drop for LuaStream {
+    fn drop(&mut self) {
+        if !self.done {
+            self.token.cancel();
+        }
+    }
+}
+
+impl Stream for LuaStream {
+    type Item = LuaMessage;
+    type Error = ActixLuaError;
+
+    fn poll(&mut self) -> Poll<Option<LuaMessage>, ActixLuaError> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match self.rx.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Some(Ok(Some(msg))))) => Ok(Async::Ready(Some(msg))),
+            Ok(Async::Ready(Some(Ok(None)))) => {
+                self.done = true;
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::Ready(Some(Err(e)))) => {
+                self.done = true;
+                Err(e)
+            }
+            // the actor dropped the stream before the hook returned
+            Ok(Async::Ready(None)) | Err(()) => {
+                self.done = true;
+                Err(ActixLuaError::ActorStopped)
+            }
+        }
+    }
+}
+
+pub(crate) fn convert_reply<F, T>(reply: F) -> AskFuture<T>
+where
+    F: Future<Item = LuaMessage, Error = MailboxError> + 'static,
+    T: TryFrom<LuaMessage> + 'static,
+    T::Error: Into<ActixLuaError>,
+{
+    Box::new(reply.then(|res| match res {
+        Ok(LuaMessage::Error(e)) => Err(e),
+        Ok(msg) => T::try_from(msg).map_err(Into::into),
+        Err(MailboxError::Timeout) => Err(ActixLuaError::Timeout),
+        Err(MailboxError::Closed) => Err(ActixLuaError::ActorStopped),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use error::ErrorPolicy;
+
+    #[test]
+    fn ask() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "wait" then
+                ctx.reply_later()
+                return
+            end
+            return ctx.msg + 1
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let timeout = Duration::from_millis(100);
+        let l = addr.ask::<_, i64>(41, timeout).then(Ok::<_, ()>).join3(
+            addr.ask::<_, String>(1, timeout).then(Ok),
+            addr.ask::<_, LuaMessage>("wait", timeout).then(Ok),
+        );
+        Arbiter::spawn(l.map(|(answer, conversion, wait)| {
+            assert_eq!(answer, Ok(42));
+            match conversion {
+                Err(ActixLuaError::ConversionError { .. }) => {}
+                res => panic!("unexpected reply {:?}", res),
+            }
+            assert_eq!(wait, Err(ActixLuaError::Timeout));
+            System::current().stop();
+        }));
+
+        system.run();
+    }
+
+    #[test]
+    fn send_with_meta() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local msg, meta = ...
+            if meta.user then
+                return msg .. " from " .. ctx.meta.user
+            end
+            return msg
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let mut meta = HashMap::new();
+        meta.insert("user".to_string(), LuaMessage::from("alice"));
+        let l = addr
+            .send_with_meta(LuaMessage::from("order"), meta)
+            .join(addr.send(LuaMessage::from("order")));
+        Arbiter::spawn(l.map(|(with_meta, without)| {
+            assert_eq!(with_meta, LuaMessage::from("order from alice"));
+            assert_eq!(without, LuaMessage::from("order"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn send_stream() {
+        let system = System::new("test");
+
+        let echo = LuaActorBuilder::new()
+            .on_handle_with_lua("return ctx.msg")
+            .build()
+            .unwrap()
+            .start();
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            for i = 1, 2 do
+                ctx.emit(i)
+            end
+            -- the stream goes on after a yield
+            ctx.emit(ctx.send("echo", 3))
+            if ctx.msg == "fail" then
+                error("boom")
+            end
+            return "done"
+            "#,
+            )
+            .build()
+            .unwrap();
+        actor.add_lua_recipient("echo", &echo);
+        let addr = actor.start();
+
+        let l = addr
+            .send_stream("ok")
+            .collect()
+            .join(addr.send_stream("fail").then(Ok).collect());
+        Arbiter::spawn(l.map(|(ok, fail)| {
+            let expected: Vec<_> = vec![1.into(), 2.into(), 3.into(), "done".into()];
+            assert_eq!(ok, expected);
+            assert_eq!(fail[..3], [Ok(1.into()), Ok(2.into()), Ok(3.into())]);
+            match fail[3] {
+                Err(ActixLuaError::RuntimeError { ref traceback }) => {
+                    assert!(traceback.contains("boom"))
+                }
+                ref res => panic!("unexpected item {:?}", res),
+            }
+            assert_eq!(fail.len(), 4);
+            System::current().stop();
+        }).map_err(|e| println!("stream failed {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn send_stream_contract_and_cancel() {
+        let system = System::new("test");
+
+        // the error policy would stop the actor for a failed script
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.contract("integer?")"#)
+            .on_handle_with_lua(
+                r#"
+            ctx.emit("started")
+            if ctx.msg == nil then
+                while true do end
+            end
+            return ctx.msg
+            "#,
+            )
+            .with_error_policy(ErrorPolicy::Stop)
+            .build()
+            .unwrap()
+            .start();
+
+        // dropped before the actor gets to it, the hook would spin forever otherwise
+        drop(addr.send_stream(LuaMessage::Nil));
+        let l = addr
+            .send_stream("one")
+            .collect()
+            .then(Ok::<_, ActixLuaError>)
+            .join(addr.send_stream(1).collect());
+        Arbiter::spawn(l.map(|(invalid, valid)| {
+            match invalid {
+                Err(ActixLuaError::InvalidMessage { .. }) => {}
+                res => panic!("unexpected stream {:?}", res),
+            }
+            assert_eq!(valid, vec![LuaMessage::from("started"), LuaMessage::from(1)]);
+            System::current().stop();
+        }).map_err(|e| println!("stream failed {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,169 @@
+use actix::prelude::*;
+use actix_broker::{Broker, BrokerMsg, BrokerSubscribe};
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use actor::LuaActor;
+use bus::Publish;
+use message::LuaMessage;
+
+pub type BrokerSubscription = dyn Fn(&Addr<LuaActor>) + Send + Sync;
+pub type BrokerIssuers = HashMap<String, Box<dyn Fn(LuaMessage) + Send + Sync>>;
+
+/// Start forwarding every broker message of type `M` to `actor` as a `Publish` with `topic`.
+pub fn subscribe<M>(topic: &str) -> Box<BrokerSubscription>
+where
+    M: BrokerMsg + Into<LuaMessage>,
+{
+    let topic = topic.to_string();
+    Box::new(move |actor| {
+        BrokerForwarder::<M> {
+            topic: topic.clone(),
+            actor: actor.clone(),
+            _msg: PhantomData,
+        }.start();
+    })
+}
+
+/// Build an issuer which converts a `LuaMessage` into `M` and issues it on the broker.
+pub fn issuer<M>() -> Box<dyn Fn(LuaMessage) + Send + Sync>
+where
+    M: BrokerMsg + From<LuaMessage>,
+{
+    Box::new(|msg| Broker::issue_async(M::from(msg)))
+}
+
+/// Register `__broker_issue(name, msg)` in the VM, used by `ctx.broker_issue`.
+pub fn register_issuers(vm: &::rlua::Lua, issuers: Arc<BrokerIssuers>) -> ::rlua::Result<()> {
+    let broker_issue = vm.create_function(move |_, (name, msg): (String, LuaMessage)| {
+        match issuers.get(&name) {
+            Some(issue) => {
+                issue(msg);
+                Ok(())
+            }
+            None => Err(::rlua::Error::RuntimeError(format!(
+                "no broker message registered as `{}`",
+                name
+            ))),
+        }
+    })?;
+    vm.globals().set("__broker_issue", broker_issue)
+}
+
+// LuaActor can't implement `Handler<M>` for every broker message type, so a small actor
+// subscribes on its behalf and forwards the messages.
+struct BrokerForwarder<M> {
+    topic: String,
+    actor: Addr<LuaActor>,
+    _msg: PhantomData<M>,
+}
+
+impl<M: BrokerMsg + Into<LuaMessage>> Actor for BrokerForwarder<M> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.subscribe_async::<M>(ctx);
+    }
+}
+
+impl<M: BrokerMsg + Into<LuaMessage>> Handler<M> for BrokerForwarder<M> {
+    type Result = ();
+
+    fn handle(&mut self, msg: M, ctx: &mut Context<Self>) {
+        if !self.actor.connected() {
+            ctx.stop();
+            return;
+        }
+        self.actor.do_send(Publish {
+            topic: self.topic.clone(),
+            msg: msg.into(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures_timer::Delay;
+    use std::time::Duration;
+    use tokio::prelude::Future;
+
+    #[derive(Clone, Message)]
+    struct OrderCreated(i64);
+
+    impl From<OrderCreated> for LuaMessage {
+        fn from(o: OrderCreated) -> LuaMessage {
+            LuaMessage::from(o.0)
+        }
+    }
+
+    #[derive(Clone, Message)]
+    struct OrderShipped(LuaMessage);
+
+    impl From<LuaMessage> for OrderShipped {
+        fn from(msg: LuaMessage) -> OrderShipped {
+            OrderShipped(msg)
+        }
+    }
+
+    impl From<OrderShipped> for LuaMessage {
+        fn from(o: OrderShipped) -> LuaMessage {
+            o.0
+        }
+    }
+
+    #[test]
+    fn lua_actor_broker() {
+        let system = System::new("test");
+
+        let shipping = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.topic == "order.created" then
+                ctx.broker_issue("order.shipped", ctx.msg)
+            end
+            "#,
+            )
+            .subscribe_broker::<OrderCreated>("order.created")
+            .issue_broker::<OrderShipped>("order.shipped")
+            .build()
+            .unwrap()
+            .start();
+        let audit = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.topic then
+                ctx.state.last = ctx.topic .. ":" .. ctx.msg
+            end
+            return ctx.state.last
+            "#,
+            )
+            .subscribe_broker::<OrderShipped>("order.shipped")
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_millis(500))
+            .map_err(|e| println!("timer error {}", e))
+            .and_then(move |()| {
+                Broker::issue_async(OrderCreated(7));
+                Delay::new(Duration::from_millis(500)).map_err(|_| ())
+            })
+            .and_then(move |()| {
+                audit
+                    .send(LuaMessage::Nil)
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .map(move |res| {
+                let _ = shipping;
+                assert_eq!(res, LuaMessage::from("order.shipped:7"));
+                System::current().stop();
+            });
+        Arbiter::spawn(delay);
+
+        system.run();
+    }
+}
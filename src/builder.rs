@@ -1,27 +1,216 @@
+#[cfg(any(feature = "actix", feature = "moonscript"))]
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::prelude::*;
+#[cfg(feature = "actix")]
+use std::mem;
+use std::path::Path;
+#[cfg(feature = "moonscript")]
+use std::ptr;
+use std::sync::Arc;
+use std::time::Duration;
 
+#[cfg(feature = "actix")]
+use actix::{Context, Supervisor};
+use futures::Future;
+
+#[cfg(feature = "actix")]
 use actor::LuaActor;
-use rlua::{Error as LuaError, Lua};
+#[cfg(feature = "broker")]
+use actix_broker::BrokerMsg;
+#[cfg(feature = "broker")]
+use broker::{self, BrokerIssuers, BrokerSubscription};
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use config;
+use bundle::LuaBundle;
+#[cfg(feature = "debugger")]
+use debugger::LuaDebugger;
+use error::{ActixLuaError, ErrorPolicy};
+#[cfg(feature = "fennel")]
+use fennel::Fennel;
+#[cfg(feature = "moonscript")]
+use moonscript::MoonScript;
+#[cfg(feature = "teal")]
+use teal::Teal;
+use gc::{self, GcConfig};
+use lint;
+use message::{LuaMessage, MAX_TABLE_DEPTH};
+use metrics::{InvocationCost, LuaActorMetrics};
+#[cfg(feature = "actix")]
+use pool::LuaActorPool;
+#[cfg(feature = "actix")]
+use remote::{MessageEncoder, DEFAULT_BUFFER_CAPACITY};
+use rlua::{Error as LuaError, Lua, UserData};
+#[cfg(feature = "moonscript")]
+use rlua::Table;
+#[cfg(all(feature = "actix", feature = "json"))]
+use schema::Schema;
+#[cfg(all(feature = "actix", feature = "json"))]
+use serde_json::Value;
+use shared::{LuaSharedState, SharedTable};
+#[cfg(feature = "task")]
+use task::{LuaTask, LuaTaskHandle};
+#[cfg(feature = "actix")]
+use tenant::Tenants;
+#[cfg(feature = "actix")]
+use version::MessageVersion;
+use vm::{self, catch_panic};
+
+#[cfg(feature = "actix")]
+pub type InitializeVM = dyn Fn(&Lua) -> Result<(), LuaError> + Send;
+// a callback of `with_vm` or `with_userdata`, shared by the VMs of a template
+type ConfigureVM = dyn Fn(&Lua) -> Result<(), LuaError> + Send + Sync;
+pub type ApplyVM = dyn FnOnce(&Lua) -> Result<(), LuaError> + Send;
+pub type AsyncInitializeVM = dyn Future<Item = Box<ApplyVM>, Error = LuaError> + Send;
+#[cfg(feature = "actix")]
+pub type HandleFn = dyn Fn(&LuaMessage, &mut Context<LuaActor>) -> Option<LuaMessage> + Send + Sync;
+#[cfg(feature = "actix")]
+pub type NewVM = dyn Fn() -> Result<Lua, ActixLuaError> + Send;
+#[cfg(feature = "actix")]
+pub type PanicFn = dyn Fn(&ActixLuaError, &mut Context<LuaActor>) + Send + Sync;
+pub type CostFn = dyn Fn(&InvocationCost) + Send + Sync;
+#[cfg(feature = "actix")]
+pub type TenantFn = dyn Fn(&LuaMessage) -> String + Send + Sync;
+#[cfg(feature = "actix")]
+pub type MigrateFn = dyn Fn(i64, LuaMessage) -> Result<LuaMessage, ActixLuaError> + Send + Sync;
+
+pub(crate) const DEFAULT_MAX_HOPS: usize = 64;
 
-pub type InitializeVM = Fn(&Lua) -> Result<(), LuaError>;
+pub(crate) const DEFAULT_BATCH_SIZE: usize = 64;
 
 /// `LuaActorBuilder` creates a new `LuaActor` with given Lua script.
 pub struct LuaActorBuilder {
-    started: Option<String>,
-    handle: Option<String>,
-    stopped: Option<String>,
-    initialize_vm: Option<Box<InitializeVM>>,
+    started: Option<Arc<str>>,
+    handle: Option<Arc<str>>,
+    handle_batch: Option<Arc<str>>,
+    stopping: Option<Arc<str>>,
+    stopped: Option<Arc<str>>,
+    script_error: Option<ActixLuaError>,
+    #[cfg(feature = "actix")]
+    handle_fn: Option<Box<HandleFn>>,
+    #[cfg(feature = "actix")]
+    panic_fn: Option<Box<PanicFn>>,
+    initialize_vm: Vec<Arc<ConfigureVM>>,
+    initialize_vm_async: Vec<Box<AsyncInitializeVM>>,
+    shared_data: Vec<(String, Arc<LuaMessage>)>,
+    shared_state: Option<LuaSharedState>,
+    args: Option<Vec<LuaMessage>>,
+    strict_globals: Option<HashSet<String>>,
+    userdata: Vec<Arc<ConfigureVM>>,
+    error_policy: ErrorPolicy,
+    max_message_size: Option<usize>,
+    max_table_depth: Option<usize>,
+    max_hops: usize,
+    batch_size: usize,
+    accept_tell: bool,
+    eval: bool,
+    name: Option<String>,
+    start_arg: Option<LuaMessage>,
+    metrics: Option<LuaActorMetrics>,
+    cost_fn: Option<Box<CostFn>>,
+    count_instructions: bool,
+    gc: Option<GcConfig>,
+    gc_metrics_interval: Option<Duration>,
+    slow_handler_threshold: Option<Duration>,
+    #[cfg(feature = "actix")]
+    buffer_capacity: usize,
+    #[cfg(feature = "actix")]
+    tenants: Option<Tenants>,
+    // the modules of `on_handle_bundle`: their names, chunk names and sources
+    modules: Vec<(String, String, Arc<str>)>,
+    bundle: Option<LuaBundle>,
+    #[cfg(all(feature = "actix", feature = "json"))]
+    schemas: HashMap<String, Schema>,
+    #[cfg(feature = "actix")]
+    versions: HashMap<String, MessageVersion>,
+    #[cfg(feature = "debugger")]
+    debugger: Option<LuaDebugger>,
+    // the chunk names and paths of the scripts read from files
+    #[cfg(feature = "debugger")]
+    sources: Vec<(String, String)>,
+    #[cfg(feature = "fennel")]
+    fennel: Option<Fennel>,
+    #[cfg(feature = "moonscript")]
+    moonscript: Option<MoonScript>,
+    #[cfg(feature = "teal")]
+    teal: Option<Teal>,
+    // the errors of Teal hooks after the first one, which is the script error
+    #[cfg(feature = "teal")]
+    type_errors: Vec<ActixLuaError>,
+    // the compiled Lua code of MoonScript hooks, and the lines in their sources
+    #[cfg(feature = "moonscript")]
+    line_maps: HashMap<&'static str, (Arc<str>, Vec<usize>)>,
+    #[cfg(feature = "broker")]
+    broker_subscriptions: Vec<Box<BrokerSubscription>>,
+    #[cfg(feature = "broker")]
+    broker_issuers: BrokerIssuers,
 }
 
 impl Default for LuaActorBuilder {
     fn default() -> LuaActorBuilder {
-        let noop = Some("return".to_string());
+        let noop: Option<Arc<str>> = Some(Arc::from("return"));
         LuaActorBuilder {
             started: noop.clone(),
             handle: noop.clone(),
+            handle_batch: None,
+            stopping: None,
             stopped: noop.clone(),
-            initialize_vm: None,
+            script_error: None,
+            #[cfg(feature = "actix")]
+            handle_fn: None,
+            #[cfg(feature = "actix")]
+            panic_fn: None,
+            initialize_vm: vec![],
+            initialize_vm_async: vec![],
+            shared_data: vec![],
+            shared_state: None,
+            args: None,
+            strict_globals: None,
+            userdata: vec![],
+            error_policy: ErrorPolicy::default(),
+            max_message_size: None,
+            max_table_depth: None,
+            max_hops: DEFAULT_MAX_HOPS,
+            batch_size: DEFAULT_BATCH_SIZE,
+            accept_tell: true,
+            eval: false,
+            name: None,
+            start_arg: None,
+            metrics: None,
+            cost_fn: None,
+            count_instructions: false,
+            gc: None,
+            gc_metrics_interval: None,
+            slow_handler_threshold: None,
+            #[cfg(feature = "actix")]
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            #[cfg(feature = "actix")]
+            tenants: None,
+            modules: vec![],
+            bundle: None,
+            #[cfg(all(feature = "actix", feature = "json"))]
+            schemas: HashMap::new(),
+            #[cfg(feature = "actix")]
+            versions: HashMap::new(),
+            #[cfg(feature = "debugger")]
+            debugger: None,
+            #[cfg(feature = "debugger")]
+            sources: vec![],
+            #[cfg(feature = "fennel")]
+            fennel: None,
+            #[cfg(feature = "moonscript")]
+            moonscript: None,
+            #[cfg(feature = "teal")]
+            teal: None,
+            #[cfg(feature = "teal")]
+            type_errors: vec![],
+            #[cfg(feature = "moonscript")]
+            line_maps: HashMap::new(),
+            #[cfg(feature = "broker")]
+            broker_subscriptions: vec![],
+            #[cfg(feature = "broker")]
+            broker_issuers: BrokerIssuers::new(),
         }
     }
 }
@@ -34,66 +223,1175 @@ impl LuaActorBuilder {
 
     /// create a `started` hook with given lua file
     pub fn on_started(mut self, filename: &str) -> Self {
-        self.started = Some(read_to_string(filename));
+        self.started = self.read_script("started", filename);
         self
     }
 
     /// create a `started` hook with given lua script
-    pub fn on_started_with_lua(mut self, script: &str) -> Self {
-        self.started = Some(script.to_string());
+    ///
+    /// Pass an `Arc<str>` to build many actors from the same script without copying it.
+    pub fn on_started_with_lua<S: Into<Arc<str>>>(mut self, script: S) -> Self {
+        self.started = Some(script.into());
         self
     }
 
     /// handle message with given lua file
     pub fn on_handle(mut self, filename: &str) -> Self {
-        self.handle = Some(read_to_string(filename));
+        self.handle = self.read_script("handle", filename);
         self
     }
 
     /// handle message with given lua script
-    pub fn on_handle_with_lua(mut self, script: &str) -> Self {
-        self.handle = Some(script.to_string());
+    ///
+    /// Pass an `Arc<str>` to build many actors from the same script without copying it.
+    pub fn on_handle_with_lua<S: Into<Arc<str>>>(mut self, script: S) -> Self {
+        self.handle = Some(script.into());
+        self
+    }
+
+    /// handle messages with the first of `files`, which can `require` the others as modules.
+    ///
+    /// The modules are named by their paths relative to the directory of the first file,
+    /// without the extension and with dots for slashes: `lib/validate.lua` is
+    /// `require("lib.validate")`. They are read here and embedded in the VM, and their paths
+    /// are their chunk names in tracebacks.
+    pub fn on_handle_bundle(mut self, files: &[&str]) -> Self {
+        let (handle, modules) = match files.split_first() {
+            Some(split) => split,
+            None => return self,
+        };
+        self.handle = self.read_script("handle", handle);
+        let dir = Path::new(handle).parent().unwrap_or_else(|| Path::new(""));
+        for file in modules {
+            let path = Path::new(file);
+            let chunk_name = path.strip_prefix(dir).unwrap_or(path);
+            let name = chunk_name.with_extension("");
+            let name = name.to_string_lossy().replace('/', ".");
+            match read_to_string(file) {
+                Ok(source) => {
+                    let chunk_name = chunk_name.to_string_lossy().into_owned();
+                    #[cfg(feature = "debugger")]
+                    self.sources.push((chunk_name.clone(), file.to_string()));
+                    self.modules.push((name, chunk_name, source.into()));
+                }
+                Err(e) => {
+                    self.script_error.get_or_insert(e);
+                }
+            }
+        }
+        self
+    }
+
+    /// handle messages in batches with given lua file, see `on_handle_batch_with_lua`.
+    pub fn on_handle_batch(mut self, filename: &str) -> Self {
+        self.handle_batch = self.read_script("handle_batch", filename);
+        self
+    }
+
+    /// handle messages in batches with given lua script instead of the `handle` hook.
+    ///
+    /// The actor queues up to `with_batch_size` messages and passes them to the script at once
+    /// as the array `ctx.msg`, with their count in `ctx.msg.n`. A batch is handled once it is
+    /// full, or when the messages waiting in the mailbox ran out. The script returns an array
+    /// with the reply of every message, any other value is the reply of all of them.
+    ///
+    /// Messages from the `LuaBus` and `actix-broker` are still handled by the `handle` hook.
+    pub fn on_handle_batch_with_lua<S: Into<Arc<str>>>(mut self, script: S) -> Self {
+        self.handle_batch = Some(script.into());
+        self
+    }
+
+    /// handle message with given closure before the lua `handle` hook.
+    ///
+    /// The closure answers the message by returning `Some(reply)`. Messages it returns `None`
+    /// for are passed on to the lua script, so hot paths can be handled natively.
+    #[cfg(feature = "actix")]
+    pub fn on_handle_with_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&LuaMessage, &mut Context<LuaActor>) -> Option<LuaMessage> + Send + Sync + 'static,
+    {
+        self.handle_fn = Some(Box::new(f));
+        self
+    }
+
+    /// call `f` with an `ActixLuaError::Panic` when Rust code panics while the actor runs a hook.
+    ///
+    /// Panics in message conversions, `with_vm` callbacks and functions called by the scripts
+    /// are caught instead of taking down the arbiter. The message is answered with the error,
+    /// and the error policy applies.
+    #[cfg(feature = "actix")]
+    pub fn on_panic<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ActixLuaError, &mut Context<LuaActor>) + Send + Sync + 'static,
+    {
+        self.panic_fn = Some(Box::new(f));
+        self
+    }
+
+    /// create a `stopping` hook with given lua file, see `on_stopping_with_lua`.
+    pub fn on_stopping(mut self, filename: &str) -> Self {
+        self.stopping = self.read_script("stopping", filename);
+        self
+    }
+
+    /// create a `stopping` hook with given lua script, run when the actor is asked to stop.
+    ///
+    /// It gets the reason it would stop with as `ctx.msg`, like the `stopped` hook, and returns
+    /// `"continue"` to keep the actor running, e.g. until its current batch is done. The actor
+    /// can then stop itself with `ctx.terminate()`, which doesn't run the hook again.
+    pub fn on_stopping_with_lua<S: Into<Arc<str>>>(mut self, script: S) -> Self {
+        self.stopping = Some(script.into());
         self
     }
 
     /// create a `stopped` hook with given lua file.
+    ///
+    /// The hook gets why the actor stopped as `ctx.msg`, e.g. `"restart"` after an error.
     pub fn on_stopped(mut self, filename: &str) -> Self {
-        self.stopped = Some(read_to_string(filename));
+        self.stopped = self.read_script("stopped", filename);
         self
     }
 
     /// create a `stopped` hook with given lua script
-    pub fn on_stopped_with_lua(mut self, script: &str) -> Self {
-        self.stopped = Some(script.to_string());
+    ///
+    /// Pass an `Arc<str>` to build many actors from the same script without copying it.
+    pub fn on_stopped_with_lua<S: Into<Arc<str>>>(mut self, script: S) -> Self {
+        self.stopped = Some(script.into());
         self
     }
 
     /// config the actor's lua VM
-    pub fn with_vm<F: Fn(&Lua) -> Result<(), LuaError> + 'static>(mut self, callback: F) -> Self {
-        self.initialize_vm = Some(Box::new(callback));
+    ///
+    /// Can be called multiple times, the callbacks run in the order they were added. A callback
+    /// which panics fails the build with `ActixLuaError::Panic`.
+    pub fn with_vm<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Lua) -> Result<(), LuaError> + Send + Sync + 'static,
+    {
+        self.initialize_vm.push(Arc::new(callback));
+        self
+    }
+
+    /// config the actor's lua VM asynchronously.
+    ///
+    /// `init` is polled when the actor starts, e.g. to fetch remote configuration, and resolves
+    /// to a callback which configures the VM. The actor doesn't process messages until every
+    /// `init` is applied, and the `started` hook runs after them.
+    pub fn with_vm_async<I, F>(mut self, init: I) -> Self
+    where
+        I: Future<Item = F, Error = LuaError> + Send + 'static,
+        F: FnOnce(&Lua) -> Result<(), LuaError> + Send + 'static,
+    {
+        self.initialize_vm_async
+            .push(Box::new(init.map(|f| Box::new(f) as Box<ApplyVM>)));
+        self
+    }
+
+    /// expose `data` to the actor as a read-only global `name`.
+    ///
+    /// The data is not copied into the VM. Build many actors with clones of the same `Arc`
+    /// to share one copy of a large dataset between them.
+    pub fn with_shared_data(mut self, name: &str, data: Arc<LuaMessage>) -> Self {
+        self.shared_data.push((name.to_string(), data));
+        self
+    }
+
+    /// expose the TOML or YAML file `filename` to the actor as a read-only global `config`.
+    ///
+    /// The format is picked by the extension, `.toml` with the `toml` feature, `.yaml` or `.yml`
+    /// with the `yaml` feature. Arrays become tables keyed `"1"`..`"n"`. Like with
+    /// `with_shared_data`, the values are kept once for every actor built from this builder.
+    #[cfg(any(feature = "toml", feature = "yaml"))]
+    pub fn with_config_file(mut self, filename: &str) -> Self {
+        match config::load(filename) {
+            Ok(config) => self.shared_data.push(("config".to_string(), Arc::new(config))),
+            Err(e) => {
+                self.script_error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// expose `state` to the actor as the `shared` global.
+    ///
+    /// Actors are isolated by default. Building a pool of actors with clones of the same
+    /// `LuaSharedState` gives them a single mutable table for pool-wide counters and caches.
+    pub fn with_shared_state(mut self, state: LuaSharedState) -> Self {
+        self.shared_state = Some(state);
+        self
+    }
+
+    /// expose `args` to the scripts as the global array `arg`, like the command line arguments
+    /// of a standalone Lua script.
+    ///
+    /// Lets actors built from the same scripts be parameterized, e.g. with a shard id.
+    ///
+    /// ```rust,ignore
+    /// LuaActorBuilder::new()
+    ///     .on_handle("shard.lua") // reads `arg[1]`
+    ///     .with_args(vec![LuaMessage::from(3), LuaMessage::from("eu")])
+    /// ```
+    pub fn with_args<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<LuaMessage>,
+    {
+        self.args = Some(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// install `value` as the userdata global `name`, with the methods of its `UserData` impl.
+    ///
+    /// Every VM built by this builder gets its own clone of `value`. Wrap handles which should
+    /// be shared between actors, like connection pools or caches, in an `Arc`.
+    pub fn with_userdata<T>(mut self, name: &str, value: T) -> Self
+    where
+        T: UserData + Clone + Send + Sync + 'static,
+    {
+        let name = name.to_string();
+        self.userdata
+            .push(Arc::new(move |vm: &Lua| vm.globals().set(name.as_str(), value.clone())));
+        self
+    }
+
+    /// deliver every `actix-broker` message of type `M` to the `handle` hook, with `ctx.topic`
+    /// set to `topic`.
+    #[cfg(feature = "broker")]
+    pub fn subscribe_broker<M: BrokerMsg + Into<LuaMessage>>(mut self, topic: &str) -> Self {
+        self.broker_subscriptions
+            .push(broker::subscribe::<M>(topic));
+        self
+    }
+
+    /// allow the script to issue `actix-broker` messages of type `M` with
+    /// `ctx.broker_issue(name, msg)`.
+    #[cfg(feature = "broker")]
+    pub fn issue_broker<M: BrokerMsg + From<LuaMessage>>(mut self, name: &str) -> Self {
+        self.broker_issuers
+            .insert(name.to_string(), broker::issuer::<M>());
+        self
+    }
+
+    /// compile hook files ending with `.fnl` from Fennel to Lua with `compiler`, the source of
+    /// `fennel.lua`. Requires the `fennel` feature.
+    ///
+    /// Call it before the hooks are given. A Fennel compile error is returned by `build` as
+    /// `ActixLuaError::CompileError` with the line in the Fennel script, and the lines of
+    /// runtime errors are the lines of the Fennel script as well.
+    #[cfg(feature = "fennel")]
+    pub fn with_fennel(mut self, compiler: &str) -> Self {
+        match Fennel::new(compiler) {
+            Ok(fennel) => self.fennel = Some(fennel),
+            Err(e) => {
+                self.script_error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// compile hook files ending with `.moon` from MoonScript to Lua with `compiler`, the
+    /// source of a Lua module with the `to_lua` function of `moonscript.base`, e.g. MoonScript
+    /// bundled with LuLPeg. Requires the `moonscript` feature.
+    ///
+    /// Call it before the hooks are given. The lines in runtime errors and tracebacks are
+    /// translated to the lines of the MoonScript script.
+    #[cfg(feature = "moonscript")]
+    pub fn with_moonscript(mut self, compiler: &str) -> Self {
+        match MoonScript::new(compiler) {
+            Ok(moonscript) => self.moonscript = Some(moonscript),
+            Err(e) => {
+                self.script_error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// type-check hook files ending with `.tl` and compile them from Teal to Lua with
+    /// `compiler`, the source of `tl.lua`. Requires the `teal` feature.
+    ///
+    /// Call it before the hooks are given. `build` fails with the first syntax or type error
+    /// as `ActixLuaError::CompileError`, and `validate` returns all of them.
+    #[cfg(feature = "teal")]
+    pub fn with_teal(mut self, compiler: &str) -> Self {
+        match Teal::new(compiler) {
+            Ok(teal) => self.teal = Some(teal),
+            Err(e) => {
+                self.script_error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// embed the libraries of `bundle` which the hooks `require`, see [`LuaBundle`].
+    ///
+    /// The hooks can't load modules from the file system then, and `build` fails with
+    /// `ActixLuaError::ConfigError` if they require a module which isn't in the bundle.
+    /// Requires are found in the source, so a module required with a computed name must be
+    /// required by name somewhere as well.
+    ///
+    /// [`LuaBundle`]: struct.LuaBundle.html
+    pub fn with_bundle(mut self, bundle: LuaBundle) -> Self {
+        self.bundle = Some(bundle);
+        self
+    }
+
+    /// debug the scripts of the actor with `debugger`, see [`LuaDebugger`].
+    ///
+    /// [`LuaDebugger`]: struct.LuaDebugger.html
+    #[cfg(feature = "debugger")]
+    pub fn with_debugger(mut self, debugger: LuaDebugger) -> Self {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    /// check the messages of the named handler `handler` against the JSON Schema `schema`
+    /// before the `handle` hook runs, with the `json` feature.
+    ///
+    /// The handlers are the ones of `impl_lua_handler!`. Messages which don't match are
+    /// answered with `ActixLuaError::InvalidMessage` and every violation in them. The keywords
+    /// checked are `type`, `enum`, `const`, `minimum`, `maximum`, `exclusiveMinimum`,
+    /// `exclusiveMaximum`, `minLength`, `maxLength`, `pattern`, `properties`, `required`,
+    /// `additionalProperties`, `items`, `minItems`, `maxItems`, `allOf`, `anyOf`, `oneOf` and
+    /// `not`, other keywords are ignored but `$ref`, which fails the build.
+    #[cfg(all(feature = "actix", feature = "json"))]
+    pub fn with_schema(mut self, handler: &str, schema: Value) -> Self {
+        match Schema::new(&schema) {
+            Ok(schema) => {
+                self.schemas.insert(handler.to_string(), schema);
+            }
+            Err(message) => {
+                self.script_error.get_or_insert(ActixLuaError::InvalidSchema {
+                    handler: handler.to_string(),
+                    message,
+                });
+            }
+        }
+        self
+    }
+
+    /// expect the messages of the named handler `handler` at `version`, and up-convert older
+    /// ones with `migrate` before the `handle` hook sees them.
+    ///
+    /// The version of a message is the integer in its `version` field. `migrate` is called with
+    /// the version and the message, and returns the message at the next version, until it is at
+    /// `version`. Messages without a `version` are passed on as they are, and newer ones are
+    /// answered with `ActixLuaError::UnsupportedVersion`. The migrated messages are checked
+    /// against the schema of `with_schema`.
+    #[cfg(feature = "actix")]
+    pub fn with_message_version<F>(mut self, handler: &str, version: i64, migrate: F) -> Self
+    where
+        F: Fn(i64, LuaMessage) -> Result<LuaMessage, ActixLuaError> + Send + Sync + 'static,
+    {
+        self.versions.insert(
+            handler.to_string(),
+            MessageVersion {
+                current: version,
+                migrate: Box::new(migrate),
+            },
+        );
+        self
+    }
+
+    /// reject scripts which access undeclared globals when building the actor.
+    ///
+    /// Globals defined in the VM by the time the hooks are loaded, like the standard library,
+    /// `ctx` and globals set up with `with_vm`, are declared. `declared` adds names which are
+    /// created later, e.g. by `require`d modules. Reads and writes in every function of the
+    /// hooks are checked without running them.
+    pub fn strict_globals(mut self, declared: &[&str]) -> Self {
+        self.strict_globals = Some(declared.iter().map(|name| name.to_string()).collect());
         self
     }
 
+    /// set what the actor does after a hook raised an error, see `ErrorPolicy`.
+    ///
+    /// `ErrorPolicy::Restart` builds the new VM with every option of this builder, but the
+    /// callbacks of `with_vm_async` are only applied to the first VM.
+    pub fn with_error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// reject messages larger than `bytes` before they reach the VM.
+    ///
+    /// The size is estimated with `LuaMessage::estimated_size`. Rejected messages are answered
+    /// with `ActixLuaError::MessageTooLarge`, messages from the `LuaBus` are dropped.
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = Some(bytes);
+        self
+    }
+
+    /// reject tables nested more than `depth` levels deep, see `LuaMessage::depth`.
+    ///
+    /// Applies to the messages sent to the actor, which are answered with
+    /// `ActixLuaError::TableTooDeep`, and to the tables its scripts reply or send, which raise
+    /// it. Without a limit a deeply nested table can overflow the stack while it is converted.
+    pub fn with_max_table_depth(mut self, depth: usize) -> Self {
+        self.max_table_depth = Some(depth);
+        self
+    }
+
+    /// reject messages which were passed through more than `hops` actors, see `Hop`.
+    ///
+    /// Defaults to 64.
+    pub fn with_max_hops(mut self, hops: usize) -> Self {
+        self.max_hops = hops;
+        self
+    }
+
+    /// pass at most `messages` messages to the `handle_batch` hook at once.
+    ///
+    /// Defaults to 64.
+    pub fn with_batch_size(mut self, messages: usize) -> Self {
+        self.batch_size = messages;
+        self
+    }
+
+    /// set whether the actor handles `Tell`s, messages sent without waiting for a reply.
+    ///
+    /// Refused `Tell`s are dropped and counted by `LuaActorMetrics::rejected_notifications`, so
+    /// callers have to send a `LuaMessage` and wait for room in the mailbox. Defaults to `true`.
+    pub fn accept_tell(mut self, accept: bool) -> Self {
+        self.accept_tell = accept;
+        self
+    }
+
+    /// evaluate the code of `Eval` messages in the VM of the actor, e.g. from a `LuaRepl`.
+    ///
+    /// The code can do anything the scripts can, so only enable it for actors whose operators
+    /// are trusted.
+    pub fn with_eval(mut self) -> Self {
+        self.eval = true;
+        self
+    }
+
+    /// name the actor, for `ctx.name` and its logs.
+    ///
+    /// The actors built from a template have the same name, and differ by `ctx.id`.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// pass `arg` to the `started` hook as `ctx.msg`, e.g. the settings of this instance.
+    ///
+    /// It is passed again whenever the hook runs in a fresh VM, after a restart or for a tenant.
+    ///
+    /// ```rust,ignore
+    /// LuaActorBuilder::new()
+    ///     .on_started_with_lua("ctx.state.shard = ctx.msg.shard")
+    ///     .with_start_arg(settings)
+    /// ```
+    pub fn with_start_arg<M: Into<LuaMessage>>(mut self, arg: M) -> Self {
+        self.start_arg = Some(arg.into());
+        self
+    }
+
+    /// count the messages handled by the actor in `metrics`.
+    pub fn with_metrics(mut self, metrics: LuaActorMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// tune the garbage collector of the actor's VM.
+    pub fn with_gc(mut self, config: GcConfig) -> Self {
+        self.gc = Some(config);
+        self
+    }
+
+    /// handle the messages of each tenant in a VM of its own, so the state of the scripts isn't
+    /// shared between them. `tenant` extracts the tenant of a message.
+    ///
+    /// The VM of a tenant is created with its first message, and the hooks run in it from
+    /// `started` on. At most `max_vms` VMs are kept, the least recently used one is dropped
+    /// after running its `stopped` hook.
+    #[cfg(feature = "actix")]
+    pub fn with_tenants<F>(mut self, max_vms: usize, tenant: F) -> Self
+    where
+        F: Fn(&LuaMessage) -> String + Send + Sync + 'static,
+    {
+        self.tenants = Some(Tenants::new(Box::new(tenant), max_vms));
+        self
+    }
+
+    /// sample the memory used and the collection cycles completed by the actor's VM into its
+    /// metrics every `interval`. See `LuaActorMetrics::heap_bytes`.
+    pub fn with_gc_metrics(mut self, interval: Duration) -> Self {
+        self.gc_metrics_interval = Some(interval);
+        self
+    }
+
+    /// log a warning for every message the actor took longer than `threshold` to handle, and
+    /// count it in `LuaActorMetrics::slow_invocations`.
+    ///
+    /// The warning names the handler or topic of the message, and the time taken.
+    pub fn with_slow_handler_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_handler_threshold = Some(threshold);
+        self
+    }
+
+    /// set how many bytes the actor allocates at once to encode the messages it sends to remote
+    /// actors, see `LuaNode`.
+    ///
+    /// The messages are encoded one after another in the same buffer until it is full, rather
+    /// than in a buffer of their own. Defaults to 64 KiB.
+    #[cfg(feature = "actix")]
+    pub fn with_buffer_capacity(mut self, bytes: usize) -> Self {
+        self.buffer_capacity = bytes;
+        self
+    }
+
+    /// report the instructions run and the time taken by every run of a hook to `f`.
+    ///
+    /// Instructions are counted only with a cost report, since counting slows scripts down.
+    pub fn with_cost_report<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&InvocationCost) + Send + Sync + 'static,
+    {
+        self.cost_fn = Some(Box::new(f));
+        self.count_instructions = true;
+        self
+    }
+
+    /// compile every hook and report all of the failures, without building the actor.
+    ///
+    /// With `strict_globals`, accesses of undeclared globals are reported as well.
+    pub fn validate(&self) -> Result<(), Vec<ActixLuaError>> {
+        if let Some(ref e) = self.script_error {
+            #[cfg_attr(not(feature = "teal"), allow(unused_mut))]
+            let mut errors = vec![e.clone()];
+            #[cfg(feature = "teal")]
+            errors.extend(self.type_errors.iter().cloned());
+            return Err(errors);
+        }
+        let vm = self.prepare_vm().map_err(|e| vec![e])?;
+
+        let mut errors = vec![];
+        for (hook, script) in self.hooks().iter() {
+            if let Some(script) = script {
+                if let Err(e) = vm.load(script, Some(hook)) {
+                    errors.push(ActixLuaError::compile(hook, &e));
+                }
+            }
+        }
+        if errors.is_empty() {
+            errors = self.lint(&vm).map_err(|e| vec![e])?;
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     /// build the actor
-    pub fn build(self) -> Result<LuaActor, LuaError> {
-        LuaActor::new(
-            self.started.clone(),
-            self.handle.clone(),
-            self.stopped.clone(),
-            self.initialize_vm,
-        )
+    #[cfg(feature = "actix")]
+    pub fn build(mut self) -> Result<LuaActor, ActixLuaError> {
+        if let Some(e) = self.script_error.take() {
+            return Err(e);
+        }
+        let handle_fn = self.handle_fn.take();
+        let panic_fn = self.panic_fn.take();
+        let initialize_vm_async = mem::take(&mut self.initialize_vm_async);
+        let error_policy = self.error_policy;
+        let max_message_size = self.max_message_size;
+        let max_table_depth = self.max_table_depth;
+        let max_hops = self.max_hops;
+        let batch_size = self.handle_batch.as_ref().map(|_| self.batch_size.max(1));
+        let accept_tell = self.accept_tell;
+        let eval = self.eval;
+        let name = self.name.take();
+        let start_arg = self.start_arg.take().unwrap_or(LuaMessage::Nil);
+        let metrics = self.metrics.take().unwrap_or_default();
+        let cost_fn = self.cost_fn.take();
+        let gc_metrics_interval = self.gc_metrics_interval;
+        let slow_handler_threshold = self.slow_handler_threshold;
+        let buffer_capacity = self.buffer_capacity;
+        let tenants = self.tenants.take();
+        #[cfg(feature = "json")]
+        let schemas = mem::take(&mut self.schemas);
+        let versions = mem::take(&mut self.versions);
+        #[cfg(feature = "broker")]
+        let broker_subscriptions = mem::take(&mut self.broker_subscriptions);
+        #[cfg(feature = "broker")]
+        let broker_issuers = Arc::new(mem::take(&mut self.broker_issuers));
+
+        // kept by the actor to restart with a fresh VM, and to create the VMs of tenants
+        let new_vm = move || {
+            let vm = self.prepare_vm()?;
+            if let Some(e) = self.lint(&vm)?.into_iter().next() {
+                return Err(e);
+            }
+            #[cfg(feature = "broker")]
+            broker::register_issuers(&vm, broker_issuers.clone())?;
+            vm::load_hooks(&vm, &self.hooks())?;
+            Ok(vm)
+        };
+
+        let mut actor = LuaActor::from_vm(new_vm()?);
+        actor.handle_fn = handle_fn;
+        actor.panic_fn = panic_fn;
+        actor.initialize_vm_async = initialize_vm_async;
+        actor.error_policy = error_policy;
+        actor.max_message_size = max_message_size;
+        actor.max_table_depth = max_table_depth;
+        actor.max_hops = max_hops;
+        actor.batch_size = batch_size;
+        actor.accept_tell = accept_tell;
+        actor.eval = eval;
+        actor.name = name;
+        actor.start_arg = start_arg;
+        actor.metrics = metrics;
+        actor.cost_fn = cost_fn;
+        actor.gc_metrics_interval = gc_metrics_interval;
+        actor.slow_handler_threshold = slow_handler_threshold;
+        actor.encoder = MessageEncoder::new(buffer_capacity);
+        actor.rebuild_vm = Some(Box::new(new_vm));
+        actor.tenants = tenants;
+        actor.versions = versions;
+        #[cfg(feature = "json")]
+        {
+            actor.schemas = Arc::new(schemas);
+        }
+        #[cfg(feature = "broker")]
+        {
+            actor.broker_subscriptions = broker_subscriptions;
+        }
+
+        Ok(actor)
+    }
+
+    /// compile and check the hooks once into a [`LuaActorTemplate`], which builds many
+    /// identical actors, e.g. one per connection, without reading or checking them again.
+    ///
+    /// The closures given to the builder are shared by the actors, which call them concurrently,
+    /// and so are the counters of `with_metrics`. Fails with `ActixLuaError::ConfigError` if
+    /// the builder was given callbacks with `with_vm_async`, which can only configure one VM.
+    ///
+    /// [`LuaActorTemplate`]: struct.LuaActorTemplate.html
+    #[cfg(feature = "actix")]
+    pub fn compile(mut self) -> Result<LuaActorTemplate, ActixLuaError> {
+        if let Some(e) = self.script_error.take() {
+            return Err(e);
+        }
+        if !self.initialize_vm_async.is_empty() {
+            return Err(ActixLuaError::ConfigError {
+                path: "with_vm_async".to_string(),
+                message: "a template can't be configured asynchronously".to_string(),
+            });
+        }
+        // the VMs of the actors are built the same way, and don't need to be checked again
+        let vm = self.prepare_vm()?;
+        if let Some(e) = self.lint(&vm)?.into_iter().next() {
+            return Err(e);
+        }
+        vm::load_hooks(&vm, &self.hooks())?;
+        let chunks = vm::dump_hooks(&vm, &self.hooks())?;
+        let names: Vec<_> = self.modules()?.into_iter().map(|(name, _, _)| name).collect();
+        let modules = vm::dump_modules(&vm, names)?;
+
+        let tenants = self.tenants.take();
+        let template = Template {
+            setup: self.vm_setup(),
+            modules,
+            chunks,
+            error_policy: self.error_policy,
+            max_message_size: self.max_message_size,
+            max_table_depth: self.max_table_depth,
+            max_hops: self.max_hops,
+            batch_size: self.handle_batch.as_ref().map(|_| self.batch_size.max(1)),
+            accept_tell: self.accept_tell,
+            eval: self.eval,
+            name: self.name.take(),
+            start_arg: self.start_arg.take().unwrap_or(LuaMessage::Nil),
+            metrics: self.metrics.take(),
+            gc_metrics_interval: self.gc_metrics_interval,
+            slow_handler_threshold: self.slow_handler_threshold,
+            buffer_capacity: self.buffer_capacity,
+            handle_fn: self.handle_fn.take().map(Arc::from),
+            panic_fn: self.panic_fn.take().map(Arc::from),
+            cost_fn: self.cost_fn.take().map(Arc::from),
+            tenant_fn: tenants.map(|t| (Arc::from(t.tenant_fn), t.max_vms)),
+            versions: self
+                .versions
+                .drain()
+                .map(|(handler, v)| (handler, v.current, Arc::from(v.migrate)))
+                .collect(),
+            #[cfg(feature = "json")]
+            schemas: Arc::new(mem::take(&mut self.schemas)),
+            #[cfg(feature = "broker")]
+            broker_subscriptions: mem::take(&mut self.broker_subscriptions)
+                .into_iter()
+                .map(Arc::from)
+                .collect(),
+            #[cfg(feature = "broker")]
+            broker_issuers: Arc::new(mem::take(&mut self.broker_issuers)),
+        };
+        Ok(LuaActorTemplate {
+            template: Arc::new(template),
+        })
+    }
+
+    /// start `n` actors built from the same template, see `compile`, under supervision.
+    ///
+    /// A member which stops, e.g. after an error with `ErrorPolicy::Stop`, is restarted at the
+    /// same address with a fresh VM, and its `started` hook runs again. Must be called in a
+    /// running actix system.
+    #[cfg(feature = "actix")]
+    pub fn build_pool(self, n: usize) -> Result<LuaActorPool, ActixLuaError> {
+        let template = self.compile()?;
+        (0..n)
+            .map(|_| {
+                let mut actor = template.build()?;
+                actor.supervised = true;
+                Ok(Supervisor::start(move |_| actor))
+            })
+            .collect()
+    }
+
+    /// build the hooks into a [`LuaTask`] run without actix, and the handle to send it
+    /// messages with. Requires the `task` feature.
+    ///
+    /// The mailbox has room for `capacity` messages, and one more for each handle.
+    ///
+    /// [`LuaTask`]: struct.LuaTask.html
+    #[cfg(feature = "task")]
+    pub fn build_task(
+        mut self,
+        capacity: usize,
+    ) -> Result<(LuaTask, LuaTaskHandle), ActixLuaError> {
+        if let Some(e) = self.script_error.take() {
+            return Err(e);
+        }
+        let vm = self.prepare_vm()?;
+        if let Some(e) = self.lint(&vm)?.into_iter().next() {
+            return Err(e);
+        }
+        vm::load_hooks(&vm, &self.hooks())?;
+        Ok(LuaTask::new(vm, capacity))
+    }
+
+    fn read_script(&mut self, hook: &'static str, filename: &str) -> Option<Arc<str>> {
+        #[cfg(feature = "debugger")]
+        self.sources.push((hook.to_string(), filename.to_string()));
+        match self.load_script(hook, filename) {
+            Ok(script) => Some(script),
+            Err(e) => {
+                // reported by `build`, so the builder methods can still be chained
+                self.script_error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    // read a hook file, compiled to Lua if it is written in another language
+    #[cfg_attr(
+        not(any(feature = "fennel", feature = "moonscript", feature = "teal")),
+        allow(unused_variables)
+    )]
+    fn load_script(
+        &mut self,
+        hook: &'static str,
+        filename: &str,
+    ) -> Result<Arc<str>, ActixLuaError> {
+        let script = read_to_string(filename)?;
+        #[cfg(feature = "fennel")]
+        {
+            if filename.ends_with(".fnl") {
+                return self.compile_fennel(hook, filename, &script);
+            }
+        }
+        #[cfg(feature = "moonscript")]
+        {
+            if filename.ends_with(".moon") {
+                return self.compile_moonscript(hook, filename, &script);
+            }
+        }
+        #[cfg(feature = "teal")]
+        {
+            if filename.ends_with(".tl") {
+                return self.compile_teal(hook, filename, &script);
+            }
+        }
+        Ok(script.into())
+    }
+
+    #[cfg(feature = "teal")]
+    fn compile_teal(
+        &mut self,
+        hook: &str,
+        filename: &str,
+        script: &str,
+    ) -> Result<Arc<str>, ActixLuaError> {
+        let res = match self.teal {
+            Some(ref teal) => teal.compile(hook, script),
+            None => {
+                return Err(ActixLuaError::ConfigError {
+                    path: filename.to_string(),
+                    message: "Teal scripts need `LuaActorBuilder::with_teal`".to_string(),
+                })
+            }
+        };
+        match res {
+            Ok(lua) => Ok(lua.into()),
+            Err(mut errors) => {
+                let first = errors.remove(0);
+                // reported by `validate`
+                self.type_errors.extend(errors);
+                Err(first)
+            }
+        }
+    }
+
+    #[cfg(feature = "fennel")]
+    fn compile_fennel(
+        &self,
+        hook: &str,
+        filename: &str,
+        script: &str,
+    ) -> Result<Arc<str>, ActixLuaError> {
+        match self.fennel {
+            Some(ref fennel) => fennel.compile(hook, script).map(Arc::from),
+            None => Err(ActixLuaError::ConfigError {
+                path: filename.to_string(),
+                message: "Fennel scripts need `LuaActorBuilder::with_fennel`".to_string(),
+            }),
+        }
+    }
+
+    #[cfg(feature = "moonscript")]
+    fn compile_moonscript(
+        &mut self,
+        hook: &'static str,
+        filename: &str,
+        script: &str,
+    ) -> Result<Arc<str>, ActixLuaError> {
+        let compiled = match self.moonscript {
+            Some(ref moonscript) => moonscript.compile(hook, script)?,
+            None => {
+                return Err(ActixLuaError::ConfigError {
+                    path: filename.to_string(),
+                    message: "MoonScript scripts need `LuaActorBuilder::with_moonscript`"
+                        .to_string(),
+                })
+            }
+        };
+        let lua: Arc<str> = compiled.lua.into();
+        self.line_maps.insert(hook, (lua.clone(), compiled.lines));
+        Ok(lua)
+    }
+
+    fn hooks(&self) -> [(&'static str, Option<&str>); 5] {
+        [
+            ("started", self.started.as_deref()),
+            ("handle", self.handle.as_deref()),
+            ("handle_batch", self.handle_batch.as_deref()),
+            ("stopping", self.stopping.as_deref()),
+            ("stopped", self.stopped.as_deref()),
+        ]
+    }
+
+    // create a VM with everything but the hooks loaded
+    fn prepare_vm(&self) -> Result<Lua, ActixLuaError> {
+        let modules = self.modules()?;
+        self.vm_setup().new_vm(|vm| vm::preload_modules(vm, &modules))
+    }
+
+    // the modules of `on_handle_bundle` and the bundle libraries the hooks require, by name,
+    // chunk name and source
+    fn modules(&self) -> Result<Vec<(&str, &str, &str)>, ActixLuaError> {
+        let mut modules: Vec<_> = self
+            .modules
+            .iter()
+            .map(|(name, chunk_name, source)| (name.as_str(), chunk_name.as_str(), &**source))
+            .collect();
+        if let Some(ref bundle) = self.bundle {
+            let mut scripts: Vec<_> = self
+                .hooks()
+                .iter()
+                .filter_map(|&(hook, script)| Some((hook, script?)))
+                .collect();
+            scripts.extend(modules.iter().map(|&(name, _, source)| (name, source)));
+            let provided: Vec<_> = modules.iter().map(|&(name, _, _)| name).collect();
+            let libraries = bundle.libraries(&scripts, &provided)?;
+            modules.extend(libraries.into_iter().map(|(name, source)| (name, name, source)));
+        }
+        Ok(modules)
+    }
+
+    fn vm_setup(&self) -> VmSetup {
+        VmSetup {
+            count_instructions: self.count_instructions,
+            #[cfg(feature = "debugger")]
+            debugger: self.debugger.clone(),
+            #[cfg(feature = "debugger")]
+            sources: Arc::from(self.sources.clone()),
+            gc: self.gc,
+            count_gc_cycles: self.gc_metrics_interval.is_some(),
+            max_table_depth: self.max_table_depth,
+            shared_data: Arc::from(self.shared_data.clone()),
+            shared_state: self.shared_state.clone(),
+            args: self.args.clone().map(Arc::from),
+            isolated: self.bundle.is_some(),
+            userdata: Arc::from(self.userdata.clone()),
+            initialize_vm: Arc::from(self.initialize_vm.clone()),
+            #[cfg(feature = "moonscript")]
+            line_maps: self
+                .hooks()
+                .iter()
+                .filter_map(|&(hook, script)| match (self.line_maps.get(hook), script) {
+                    // the hook is still the script compiled with the map
+                    (Some((lua, lines)), Some(script)) if ptr::eq(lua.as_ref(), script) => {
+                        Some((hook, lines.clone()))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn lint(&self, vm: &Lua) -> Result<Vec<ActixLuaError>, ActixLuaError> {
+        let mut declared = match self.strict_globals {
+            Some(ref declared) => declared.clone(),
+            None => return Ok(vec![]),
+        };
+        declared.extend(lint::defined_globals(vm)?);
+
+        let mut errors = vec![];
+        for (hook, script) in self.hooks().iter() {
+            if let Some(script) = script {
+                for global in lint::undeclared_globals(vm, hook, script, &declared)? {
+                    errors.push(global.into());
+                }
+            }
+        }
+        Ok(errors)
+    }
+}
+
+// the settings the VMs of an actor are created with, but for the modules and hooks loaded into
+// them. A template shares them between its VMs
+#[derive(Clone)]
+struct VmSetup {
+    count_instructions: bool,
+    #[cfg(feature = "debugger")]
+    debugger: Option<LuaDebugger>,
+    #[cfg(feature = "debugger")]
+    sources: Arc<[(String, String)]>,
+    gc: Option<GcConfig>,
+    count_gc_cycles: bool,
+    max_table_depth: Option<usize>,
+    shared_data: Arc<[(String, Arc<LuaMessage>)]>,
+    shared_state: Option<LuaSharedState>,
+    args: Option<Arc<[LuaMessage]>>,
+    // whether the VMs can only load the modules of a bundle
+    isolated: bool,
+    userdata: Arc<[Arc<ConfigureVM>]>,
+    initialize_vm: Arc<[Arc<ConfigureVM>]>,
+    // the lines of the MoonScript sources of the hooks, by hook
+    #[cfg(feature = "moonscript")]
+    line_maps: Arc<[(&'static str, Vec<usize>)]>,
+}
+
+impl VmSetup {
+    // create a VM with everything but the hooks loaded, `preload` preloads the modules
+    fn new_vm<F>(&self, preload: F) -> Result<Lua, ActixLuaError>
+    where
+        F: FnOnce(&Lua) -> Result<(), ActixLuaError>,
+    {
+        let vm = vm::new_vm()?;
+        if self.count_instructions {
+            vm::count_instructions(&vm)?;
+        }
+        #[cfg(feature = "debugger")]
+        {
+            if let Some(ref debugger) = self.debugger {
+                debugger.install(&vm, &self.sources)?;
+            }
+        }
+        if let Some(ref gc) = self.gc {
+            gc.apply(&vm)?;
+        }
+        if self.count_gc_cycles {
+            gc::count_cycles(&vm)?;
+        }
+        if let Some(depth) = self.max_table_depth {
+            vm.set_named_registry_value(MAX_TABLE_DEPTH, depth)?;
+        }
+        for (name, data) in self.shared_data.iter() {
+            SharedTable::install(&vm, name, data.clone())?;
+        }
+        if let Some(ref state) = self.shared_state {
+            vm.globals().set("shared", state.clone())?;
+        }
+        if let Some(ref args) = self.args {
+            let arg = vm.create_sequence_from(args.iter().cloned())?;
+            vm.globals().set("arg", arg)?;
+        }
+        if self.isolated {
+            LuaBundle::isolate(&vm)?;
+        }
+        preload(&vm)?;
+        // a callback which panics fails the build with `ActixLuaError::Panic`
+        for install in self.userdata.iter() {
+            catch_panic(|| Ok(install(&vm)?))?;
+        }
+        for initialize_vm in self.initialize_vm.iter() {
+            catch_panic(|| Ok(initialize_vm(&vm)?))?;
+        }
+        vm::load_prelude(&vm)?;
+        #[cfg(feature = "moonscript")]
+        {
+            let line_maps: Table = vm.globals().get("__line_maps")?;
+            for (hook, lines) in self.line_maps.iter() {
+                line_maps.set(*hook, vm.create_sequence_from(lines.iter().cloned())?)?;
+            }
+        }
+        Ok(vm)
+    }
+}
+
+/// Builds identical actors from the hooks and options of a `LuaActorBuilder`, compiled and
+/// checked once by `LuaActorBuilder::compile`.
+///
+/// Building an actor from a template only creates its VM and loads the bytecode of the modules
+/// and hooks, compiled once, into it. Nothing is locked while it is built, the template is cheap
+/// to clone, and its clones can build actors on other threads.
+///
+/// ```rust,ignore
+/// let template = LuaActorBuilder::new().on_handle("session.lua").compile()?;
+/// for stream in listener.incoming() {
+///     let addr = template.build()?.start();
+/// }
+/// ```
+#[cfg(feature = "actix")]
+#[derive(Clone)]
+pub struct LuaActorTemplate {
+    template: Arc<Template>,
+}
+
+// what is taken from a compiled builder, shared by the actors. The closures are called
+// concurrently, nothing is locked to create a VM
+#[cfg(feature = "actix")]
+struct Template {
+    setup: VmSetup,
+    // the bytecode of the modules and of the hooks, loaded into the VM of each actor
+    modules: Vec<(String, Vec<u8>)>,
+    chunks: Vec<(&'static str, Vec<u8>)>,
+    error_policy: ErrorPolicy,
+    max_message_size: Option<usize>,
+    max_table_depth: Option<usize>,
+    max_hops: usize,
+    batch_size: Option<usize>,
+    accept_tell: bool,
+    eval: bool,
+    name: Option<String>,
+    start_arg: LuaMessage,
+    // every actor counts in its own metrics unless the builder was given some
+    metrics: Option<LuaActorMetrics>,
+    gc_metrics_interval: Option<Duration>,
+    slow_handler_threshold: Option<Duration>,
+    buffer_capacity: usize,
+    handle_fn: Option<Arc<HandleFn>>,
+    panic_fn: Option<Arc<PanicFn>>,
+    cost_fn: Option<Arc<CostFn>>,
+    tenant_fn: Option<(Arc<TenantFn>, usize)>,
+    versions: Vec<(String, i64, Arc<MigrateFn>)>,
+    #[cfg(feature = "json")]
+    schemas: Arc<HashMap<String, Schema>>,
+    #[cfg(feature = "broker")]
+    broker_subscriptions: Vec<Arc<BrokerSubscription>>,
+    #[cfg(feature = "broker")]
+    broker_issuers: Arc<BrokerIssuers>,
+}
+
+#[cfg(feature = "actix")]
+impl Template {
+    fn new_vm(&self) -> Result<Lua, ActixLuaError> {
+        let vm = self.setup.new_vm(|vm| vm::load_dumped_modules(vm, &self.modules))?;
+        #[cfg(feature = "broker")]
+        broker::register_issuers(&vm, self.broker_issuers.clone())?;
+        vm::load_dumped_hooks(&vm, &self.chunks)?;
+        Ok(vm)
     }
 }
 
-fn read_to_string(filename: &str) -> String {
-    let mut f = File::open(filename).expect("File not found");
+#[cfg(feature = "actix")]
+impl LuaActorTemplate {
+    /// build an actor from the template
+    pub fn build(&self) -> Result<LuaActor, ActixLuaError> {
+        let t = &self.template;
+        let mut actor = LuaActor::from_vm(t.new_vm()?);
+        actor.error_policy = t.error_policy;
+        actor.max_message_size = t.max_message_size;
+        actor.max_table_depth = t.max_table_depth;
+        actor.max_hops = t.max_hops;
+        actor.batch_size = t.batch_size;
+        actor.accept_tell = t.accept_tell;
+        actor.eval = t.eval;
+        actor.name = t.name.clone();
+        actor.start_arg = t.start_arg.clone();
+        actor.metrics = t.metrics.clone().unwrap_or_default();
+        actor.gc_metrics_interval = t.gc_metrics_interval;
+        actor.slow_handler_threshold = t.slow_handler_threshold;
+        actor.encoder = MessageEncoder::new(t.buffer_capacity);
+        if let Some(ref f) = t.handle_fn {
+            let f = f.clone();
+            actor.handle_fn = Some(Box::new(move |msg, ctx| f(msg, ctx)));
+        }
+        if let Some(ref f) = t.panic_fn {
+            let f = f.clone();
+            actor.panic_fn = Some(Box::new(move |e, ctx| f(e, ctx)));
+        }
+        if let Some(ref f) = t.cost_fn {
+            let f = f.clone();
+            actor.cost_fn = Some(Box::new(move |cost| f(cost)));
+        }
+        if let Some((ref f, max_vms)) = t.tenant_fn {
+            let f = f.clone();
+            let tenant_fn = move |msg: &LuaMessage| f(msg);
+            actor.tenants = Some(Tenants::new(Box::new(tenant_fn), max_vms));
+        }
+        for (handler, current, migrate) in &t.versions {
+            let migrate = migrate.clone();
+            let version = MessageVersion {
+                current: *current,
+                migrate: Box::new(move |version, msg| migrate(version, msg)),
+            };
+            actor.versions.insert(handler.clone(), version);
+        }
+        #[cfg(feature = "json")]
+        {
+            actor.schemas = t.schemas.clone();
+        }
+        #[cfg(feature = "broker")]
+        {
+            for subscribe in &t.broker_subscriptions {
+                let subscribe = subscribe.clone();
+                actor
+                    .broker_subscriptions
+                    .push(Box::new(move |addr| subscribe(addr)));
+            }
+        }
+        let template = t.clone();
+        actor.rebuild_vm = Some(Box::new(move || template.new_vm()));
+        Ok(actor)
+    }
+}
+
+fn read_to_string(filename: &str) -> Result<String, ActixLuaError> {
+    let not_found = |_| ActixLuaError::ScriptNotFound {
+        path: filename.to_string(),
+    };
+    let mut f = File::open(filename).map_err(not_found)?;
     let mut body = String::new();
-    f.read_to_string(&mut body).expect("Failed to read file");
+    f.read_to_string(&mut body).map_err(not_found)?;
 
-    body
+    Ok(body)
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "actix"))]
 mod tests {
     use super::*;
     use std::mem::discriminant;
@@ -106,7 +1404,11 @@ mod tests {
 
         if let Err(e) = res {
             assert_eq!(
-                discriminant(&LuaError::RuntimeError("unexpected symbol".to_string())),
+                discriminant(&ActixLuaError::CompileError {
+                    hook: "handle".to_string(),
+                    line: Some(1),
+                    message: "unexpected symbol".to_string(),
+                }),
                 discriminant(&e)
             );
         // ok
@@ -115,4 +1417,400 @@ mod tests {
         }
     }
 
+    #[test]
+    fn build_vm_callback_panic() {
+        let res = LuaActorBuilder::new()
+            .with_vm(|_| panic!("boom"))
+            .build()
+            .map(|_| ());
+        assert_eq!(
+            res,
+            Err(ActixLuaError::Panic {
+                message: "boom".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn validate_hooks() {
+        let builder = LuaActorBuilder::new()
+            .on_started_with_lua("local x = 1")
+            .on_handle_with_lua("return 1 +")
+            .on_stopped_with_lua("print('bye')\nlocal = 2");
+
+        let errors = builder.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        match (&errors[0], &errors[1]) {
+            (
+                ActixLuaError::CompileError { hook, line, .. },
+                ActixLuaError::CompileError {
+                    hook: hook2,
+                    line: line2,
+                    ..
+                },
+            ) => {
+                assert_eq!((hook.as_str(), *line), ("handle", Some(1)));
+                assert_eq!((hook2.as_str(), *line2), ("stopped", Some(2)));
+            }
+            _ => panic!("should return compile errors"),
+        }
+        assert!(errors[1].to_string().starts_with("stopped:2: "));
+
+        assert!(LuaActorBuilder::new().validate().is_ok());
+    }
+
+    #[test]
+    fn build_strict_globals() {
+        let res = LuaActorBuilder::new()
+            .on_handle_with_lua("ctx.state.x = helper(ctx.msg)\nreturn string.upper(ctx.mgs)")
+            .with_vm(|vm| {
+                let helper = vm.create_function(|_, x: i64| Ok(x))?;
+                vm.globals().set("helper", helper)
+            })
+            .strict_globals(&[])
+            .build();
+        assert!(res.is_ok());
+
+        let res = LuaActorBuilder::new()
+            .on_started_with_lua("cache = {}")
+            .on_handle_with_lua("return json.encode(ctx.msg)")
+            .strict_globals(&["json"])
+            .build();
+        match res {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "started:1: write to undeclared global `cache`"
+            ),
+            _ => panic!("should return error"),
+        }
+    }
+
+    #[test]
+    fn build_script_not_found() {
+        let res = LuaActorBuilder::new()
+            .on_handle("src/lua/test/missing.lua")
+            .build();
+
+        match res {
+            Err(ActixLuaError::ScriptNotFound { path }) => {
+                assert_eq!(path, "src/lua/test/missing.lua")
+            }
+            _ => panic!("should return error"),
+        }
+    }
+
+    #[test]
+    fn build_shared_script() {
+        let script: Arc<str> = Arc::from("return ctx.msg");
+        let actors = (0..3)
+            .map(|_| {
+                LuaActorBuilder::new()
+                    .on_handle_with_lua(script.clone())
+                    .with_error_policy(ErrorPolicy::Restart)
+                    .build()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // every actor keeps the script to restart, without a copy of its own
+        assert_eq!(Arc::strong_count(&script), 1 + actors.len());
+    }
+
+    #[test]
+    fn start_arg() {
+        use actix::prelude::*;
+        use std::collections::HashMap;
+
+        let system = System::new("test");
+
+        let mut settings = HashMap::new();
+        settings.insert("shard".to_string(), LuaMessage::from(3));
+        let configured = LuaActorBuilder::new()
+            .on_started_with_lua("local settings = ... ctx.state.shard = settings.shard")
+            .on_handle_with_lua("return ctx.state.shard")
+            .with_start_arg(settings)
+            .build()
+            .unwrap()
+            .start();
+        let default = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.none = ctx.msg == nil")
+            .on_handle_with_lua("return ctx.state.none")
+            .build()
+            .unwrap()
+            .start();
+
+        let l = configured.send(LuaMessage::Nil).join(default.send(LuaMessage::Nil));
+        Arbiter::spawn(l.map(|(shard, none)| {
+            assert_eq!(shard, LuaMessage::from(3));
+            assert_eq!(none, LuaMessage::from(true));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn build_template() {
+        use actix::prelude::*;
+        use std::thread;
+
+        let system = System::new("test");
+
+        let template = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.n = 0")
+            .on_handle_with_lua("ctx.state.n = ctx.state.n + ctx.msg return ctx.state.n")
+            .on_handle_with_fn(|msg, _| match msg {
+                LuaMessage::String(s) if s == "ping" => Some(LuaMessage::from("pong")),
+                _ => None,
+            })
+            .compile()
+            .unwrap();
+        let other = template.clone();
+        assert!(thread::spawn(move || other.build().is_ok()).join().unwrap());
+        let first = template.build().unwrap().start();
+        let second = template.build().unwrap().start();
+
+        let l = first
+            .send(LuaMessage::from(1))
+            .join(first.send(LuaMessage::from(2)))
+            .join(second.send(LuaMessage::from(5)))
+            .join(second.send(LuaMessage::from("ping")))
+            .join(second.send(LuaMessage::from("fail")));
+        Arbiter::spawn(l.map(|((((one, three), five), pong), failed)| {
+            // every actor has its own VM
+            assert_eq!(one, LuaMessage::from(1));
+            assert_eq!(three, LuaMessage::from(3));
+            assert_eq!(five, LuaMessage::from(5));
+            assert_eq!(pong, LuaMessage::from("pong"));
+            // the bytecode of the hooks keeps their chunk names and lines
+            match failed {
+                LuaMessage::Error(ActixLuaError::RuntimeError { traceback }) => {
+                    assert!(traceback.contains("[string \"handle\"]:1:"), "{}", traceback)
+                }
+                res => panic!("unexpected reply {:?}", res),
+            }
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+
+        let res = LuaActorBuilder::new().on_handle_with_lua("return 1 +").compile();
+        match res {
+            Err(ActixLuaError::CompileError { hook, .. }) => assert_eq!(hook, "handle"),
+            _ => panic!("should return error"),
+        }
+    }
+
+    #[test]
+    fn build_template_modules() {
+        use actix::prelude::*;
+        use std::collections::HashMap;
+
+        let mut refund = HashMap::new();
+        refund.insert("total".to_string(), LuaMessage::from(-1));
+
+        let system = System::new("test");
+
+        let modules = LuaActorBuilder::new()
+            .on_handle_bundle(&[
+                "src/lua/test/multi/handle.lua",
+                "src/lua/test/multi/lib/validate.lua",
+                "src/lua/test/multi/lib/format.lua",
+            ]).compile()
+            .unwrap();
+        let bundle = LuaActorBuilder::new()
+            .with_bundle(LuaBundle::from_manifest("src/lua/test/bundle/manifest.lua").unwrap())
+            .on_handle_with_lua(r#"return require("greet").hello(ctx.msg)"#)
+            .compile()
+            .unwrap();
+        let first = modules.build().unwrap().start();
+        let second = bundle.build().unwrap().start();
+
+        let l = first
+            .send(LuaMessage::from(refund))
+            .join(second.send(LuaMessage::from("world")));
+        Arbiter::spawn(l.map(|(failed, hello)| {
+            // the bytecode of the modules keeps their chunk names
+            match failed {
+                LuaMessage::Error(ActixLuaError::RuntimeError { traceback }) => assert!(
+                    traceback.starts_with(r#"[string "lib/validate.lua"]:5: negative total"#)
+                ),
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(hello, LuaMessage::from("hello WORLD"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[cfg(feature = "moonscript")]
+    #[test]
+    fn build_moonscript() {
+        use actix::prelude::*;
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_moonscript(include_str!("lua/test/moonscript.lua"))
+            .on_handle("src/lua/test/handle.moon")
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(1))
+            .join(addr.send(LuaMessage::from("fail")));
+        Arbiter::spawn(l.map(|(res, failed)| {
+            assert_eq!(res, LuaMessage::from(1));
+            match failed {
+                // the error is on the third line of the MoonScript script
+                LuaMessage::Error(ActixLuaError::RuntimeError { traceback }) => {
+                    assert!(traceback.starts_with(r#"[string "handle"]:3: boom"#))
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn build_handle_bundle() {
+        use actix::prelude::*;
+        use std::collections::HashMap;
+
+        let mut order = HashMap::new();
+        order.insert("total".to_string(), LuaMessage::from(12.5));
+        let mut refund = HashMap::new();
+        refund.insert("total".to_string(), LuaMessage::from(-1));
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_bundle(&[
+                "src/lua/test/multi/handle.lua",
+                "src/lua/test/multi/lib/validate.lua",
+                "src/lua/test/multi/lib/format.lua",
+            ]).build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(order))
+            .join(addr.send(LuaMessage::from(refund)));
+        Arbiter::spawn(l.map(|(total, failed)| {
+            assert_eq!(total, LuaMessage::from("12.50"));
+            match failed {
+                LuaMessage::Error(ActixLuaError::RuntimeError { traceback }) => assert!(
+                    traceback.starts_with(r#"[string "lib/validate.lua"]:5: negative total"#)
+                ),
+                res => panic!("unexpected result {:?}", res),
+            }
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn build_bundle() {
+        use actix::prelude::*;
+
+        let bundle = || LuaBundle::from_manifest("src/lua/test/bundle/manifest.lua").unwrap();
+        // modules outside of the bundle can't be loaded
+        match LuaActorBuilder::new()
+            .with_bundle(bundle())
+            .on_handle_with_lua("return require('lua/test/module').incr(1)")
+            .build()
+        {
+            Err(ActixLuaError::ConfigError { .. }) => {}
+            _ => panic!("should return error"),
+        }
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_bundle(bundle())
+            .on_handle_with_lua(r#"return require("greet").hello(ctx.msg)"#)
+            .build()
+            .unwrap()
+            .start();
+
+        Arbiter::spawn(addr.send(LuaMessage::from("world")).map(|res| {
+            assert_eq!(res, LuaMessage::from("hello WORLD"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[cfg(feature = "teal")]
+    #[test]
+    fn build_teal() {
+        use actix::prelude::*;
+
+        let errors = LuaActorBuilder::new()
+            .with_teal(include_str!("lua/test/tl.lua"))
+            .on_handle("src/lua/test/type_error.tl")
+            .validate()
+            .unwrap_err();
+        let lines: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                ActixLuaError::CompileError { hook, line, .. } if hook == "handle" => *line,
+                e => panic!("unexpected error {:?}", e),
+            })
+            .collect();
+        assert_eq!(lines, [Some(1), Some(2)]);
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_teal(include_str!("lua/test/tl.lua"))
+            .on_handle("src/lua/test/handle.tl")
+            .build()
+            .unwrap()
+            .start();
+
+        Arbiter::spawn(addr.send(LuaMessage::from("teal")).map(|res| {
+            assert_eq!(res, LuaMessage::from("hello teal"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[cfg(feature = "fennel")]
+    #[test]
+    fn build_fennel() {
+        use actix::prelude::*;
+
+        match LuaActorBuilder::new()
+            .on_handle("src/lua/test/handle.fnl")
+            .build()
+        {
+            Err(ActixLuaError::ConfigError { path, .. }) => {
+                assert_eq!(path, "src/lua/test/handle.fnl")
+            }
+            _ => panic!("should return error"),
+        }
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_fennel(include_str!("lua/test/fennel.lua"))
+            .on_handle("src/lua/test/handle.fnl")
+            .build()
+            .unwrap()
+            .start();
+
+        Arbiter::spawn(addr.send(LuaMessage::from(1)).map(|res| {
+            assert_eq!(res, LuaMessage::from(2));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
 }
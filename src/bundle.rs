@@ -0,0 +1,198 @@
+use regex::Regex;
+use rlua::{Error as LuaError, FromLua, Lua, Table};
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use error::ActixLuaError;
+use message::LuaMessage;
+
+struct Module {
+    version: String,
+    source: String,
+}
+
+/// Vendored pure-Lua libraries, resolved for the `require`s of the hooks and embedded in the
+/// VMs of the actors, so they don't depend on a system LuaRocks tree.
+///
+/// The libraries are declared in a manifest, a Lua file returning a table of modules by name
+/// with their `version`, the `path` of their source relative to the manifest, and the `hash`
+/// of the source which pins them:
+///
+/// ```lua
+/// return {
+///     inspect = { version = "3.1.3", path = "vendor/inspect.lua", hash = "5f7a2c14d0e9b3a8" },
+/// }
+/// ```
+///
+/// A library whose source doesn't match its hash fails to load, and the error gives the hash
+/// of the source. Give the bundle to `LuaActorBuilder::with_bundle`.
+pub struct LuaBundle {
+    manifest: String,
+    modules: HashMap<String, Module>,
+}
+
+impl LuaBundle {
+    /// Load the manifest at `path` and the libraries it declares.
+    pub fn from_manifest(path: &str) -> Result<LuaBundle, ActixLuaError> {
+        let error = |message: String| ActixLuaError::ConfigError {
+            path: path.to_string(),
+            message,
+        };
+        let body = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let vm = Lua::new();
+        let manifest = vm
+            .load(&body, Some("manifest"))
+            .and_then(|f| f.call(()))
+            .and_then(|value| LuaMessage::from_lua(value, &vm))
+            .map_err(|e| error(e.to_string()))?;
+        let declared = match manifest {
+            LuaMessage::Table(t) => t,
+            _ => return Err(error("the manifest doesn't return a table".to_string())),
+        };
+
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let mut modules = HashMap::new();
+        for (name, entry) in declared {
+            let mut entry = match entry {
+                LuaMessage::Table(t) => t,
+                _ => return Err(error(format!("module `{}` isn't a table", name))),
+            };
+            let mut field = |key: &str| match entry.remove(key) {
+                Some(LuaMessage::String(s)) => Ok(s),
+                _ => Err(error(format!("module `{}` has no `{}`", name, key))),
+            };
+            let (version, source_path) = (field("version")?, field("path")?);
+            let hash = field("hash");
+            let source_path = dir.join(source_path);
+            let source = fs::read_to_string(&source_path)
+                .map_err(|e| error(format!("{}: {}", source_path.display(), e)))?;
+            let actual = script_hash(&source);
+            if hash.as_ref() != Ok(&actual) {
+                return Err(error(format!(
+                    "module `{}` {} doesn't match its hash, the source has hash `{}`",
+                    name, version, actual
+                )));
+            }
+            modules.insert(name, Module { version, source });
+        }
+        Ok(LuaBundle {
+            manifest: path.to_string(),
+            modules,
+        })
+    }
+
+    /// The names and versions of the libraries, by name.
+    pub fn versions(&self) -> Vec<(&str, &str)> {
+        let mut versions: Vec<_> = self
+            .modules
+            .iter()
+            .map(|(name, module)| (name.as_str(), module.version.as_str()))
+            .collect();
+        versions.sort();
+        versions
+    }
+
+    // the libraries required by `scripts`, by the libraries they require and so on, but for the
+    // modules which are `provided` otherwise
+    fn resolve(
+        &self,
+        scripts: &[(&str, &str)],
+        provided: &[&str],
+    ) -> Result<BTreeSet<&str>, ActixLuaError> {
+        let re = Regex::new(r#"\brequire\s*\(?\s*["']([^"']+)["']"#).unwrap();
+        let mut resolved = BTreeSet::new();
+        let mut pending: Vec<(String, &str)> = scripts
+            .iter()
+            .map(|&(name, script)| (name.to_string(), script))
+            .collect();
+        while let Some((requirer, script)) = pending.pop() {
+            for cap in re.captures_iter(script) {
+                if provided.contains(&&cap[1]) {
+                    continue;
+                }
+                let (name, module) = match self.modules.get_key_value(&cap[1]) {
+                    Some(found) => found,
+                    None => {
+                        return Err(ActixLuaError::ConfigError {
+                            path: self.manifest.clone(),
+                            message: format!(
+                                "`{}` requires `{}`, which isn't in the bundle",
+                                requirer, &cap[1]
+                            ),
+                        })
+                    }
+                };
+                if resolved.insert(name.as_str()) {
+                    pending.push((name.clone(), &module.source));
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    // the names and sources of the libraries `scripts` require, to embed in the VMs
+    pub(crate) fn libraries(
+        &self,
+        scripts: &[(&str, &str)],
+        provided: &[&str],
+    ) -> Result<Vec<(&str, &str)>, ActixLuaError> {
+        Ok(self
+            .resolve(scripts, provided)?
+            .into_iter()
+            .map(|name| (name, self.modules[name].source.as_str()))
+            .collect())
+    }
+
+    // stop `vm` from loading modules from files, so it only gets the embedded libraries
+    pub(crate) fn isolate(vm: &Lua) -> Result<(), LuaError> {
+        let package: Table = vm.globals().get("package")?;
+        package.set("path", "")?;
+        package.set("cpath", "")
+    }
+}
+
+// FNV-1a, a stable hash to tell versions of a script apart
+pub(crate) fn script_hash(script: &str) -> String {
+    let hash = script.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundle_resolve() {
+        let bundle = LuaBundle::from_manifest("src/lua/test/bundle/manifest.lua").unwrap();
+        assert_eq!(
+            bundle.versions(),
+            [("greet", "1.0.0"), ("text.upper", "0.2.1"), ("unused", "2.0.0")]
+        );
+        let handle = r#"local greet = require("greet")"#;
+        let resolved: Vec<_> = bundle
+            .resolve(&[("handle", handle)], &[])
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(resolved, ["greet", "text.upper"]);
+        let resolved = bundle.resolve(&[("handle", "require 'json'")], &["json"]).unwrap();
+        assert!(resolved.is_empty());
+
+        match bundle.resolve(&[("handle", "require 'json'")], &[]) {
+            Err(ActixLuaError::ConfigError { message, .. }) => {
+                assert_eq!(message, "`handle` requires `json`, which isn't in the bundle")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        match LuaBundle::from_manifest("src/lua/test/bundle/unpinned.lua") {
+            Err(ActixLuaError::ConfigError { message, .. }) => assert!(message.contains(
+                "module `greet` 1.1.0 doesn't match its hash, the source has hash"
+            )),
+            _ => panic!("should return error"),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use actix::prelude::*;
+
+use std::collections::HashMap;
+
+use message::LuaMessage;
+
+/// A system-wide publish/subscribe bus.
+///
+/// Topics are dot-separated names such as `orders.created`. Subscription patterns match
+/// topics segment by segment, where `*` matches exactly one segment and `**` matches any
+/// number of segments, including none.
+///
+/// Lua actors use the bus through `ctx.subscribe(pattern)` and `ctx.publish(topic, msg)`.
+/// Rust code can send `Subscribe` and `Publish` to `LuaBus::from_registry()` directly.
+/// `LuaActor` delivers published messages to its `handle` hook, with `ctx.topic` set to the
+/// topic of the message.
+///
+/// The bus also keeps named broadcast groups, see [`LuaGroup`].
+///
+/// [`LuaGroup`]: struct.LuaGroup.html
+#[derive(Default)]
+pub struct LuaBus {
+    subscribers: Vec<(String, Recipient<Publish>)>,
+    groups: HashMap<String, Vec<Recipient<LuaMessage>>>,
+}
+
+impl Actor for LuaBus {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LuaBus {}
+
+impl SystemService for LuaBus {}
+
+/// Subscribe `recipient` to every topic matching `pattern`.
+pub struct Subscribe {
+    pub pattern: String,
+    pub recipient: Recipient<Publish>,
+}
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+/// Publish `msg` to every subscriber of `topic`.
+#[derive(Clone)]
+pub struct Publish {
+    pub topic: String,
+    pub msg: LuaMessage,
+}
+
+impl Message for Publish {
+    type Result = ();
+}
+
+impl Handler<Subscribe> for LuaBus {
+    type Result = ();
+
+    fn handle(&mut self, sub: Subscribe, _: &mut Context<Self>) {
+        let exists = self
+            .subscribers
+            .iter()
+            .any(|(pattern, rec)| *pattern == sub.pattern && *rec == sub.recipient);
+        if !exists {
+            self.subscribers.push((sub.pattern, sub.recipient));
+        }
+    }
+}
+
+impl Handler<Publish> for LuaBus {
+    type Result = ();
+
+    fn handle(&mut self, publish: Publish, _: &mut Context<Self>) {
+        // drop subscribers which are no longer alive
+        self.subscribers.retain(|(pattern, rec)| {
+            if !topic_matches(pattern, &publish.topic) {
+                return true;
+            }
+            !matches!(rec.do_send(publish.clone()), Err(SendError::Closed(_)))
+        });
+    }
+}
+
+/// A named group of recipients on the [`LuaBus`]. Broadcasting to a group sends the message to
+/// every member.
+///
+/// Lua actors join a group with `ctx.join(group)` and broadcast with `ctx.broadcast(group, msg)`.
+///
+/// [`LuaBus`]: struct.LuaBus.html
+#[derive(Clone, Debug)]
+pub struct LuaGroup {
+    name: String,
+}
+
+impl LuaGroup {
+    pub fn new(name: &str) -> Self {
+        LuaGroup {
+            name: name.to_string(),
+        }
+    }
+
+    /// Add `recipient` to the group.
+    pub fn add<R: Into<Recipient<LuaMessage>>>(&self, recipient: R) {
+        LuaBus::from_registry().do_send(JoinGroup {
+            group: self.name.clone(),
+            recipient: recipient.into(),
+        });
+    }
+
+    /// Remove `recipient` from the group.
+    pub fn remove<R: Into<Recipient<LuaMessage>>>(&self, recipient: R) {
+        LuaBus::from_registry().do_send(LeaveGroup {
+            group: self.name.clone(),
+            recipient: recipient.into(),
+        });
+    }
+
+    /// Send `msg` to every member of the group.
+    pub fn broadcast(&self, msg: LuaMessage) {
+        LuaBus::from_registry().do_send(Broadcast {
+            group: self.name.clone(),
+            msg,
+        });
+    }
+}
+
+/// Add `recipient` to `group`.
+pub struct JoinGroup {
+    pub group: String,
+    pub recipient: Recipient<LuaMessage>,
+}
+
+impl Message for JoinGroup {
+    type Result = ();
+}
+
+/// Remove `recipient` from `group`.
+pub struct LeaveGroup {
+    pub group: String,
+    pub recipient: Recipient<LuaMessage>,
+}
+
+impl Message for LeaveGroup {
+    type Result = ();
+}
+
+/// Send `msg` to every member of `group`.
+pub struct Broadcast {
+    pub group: String,
+    pub msg: LuaMessage,
+}
+
+impl Message for Broadcast {
+    type Result = ();
+}
+
+impl Handler<JoinGroup> for LuaBus {
+    type Result = ();
+
+    fn handle(&mut self, join: JoinGroup, _: &mut Context<Self>) {
+        let members = self.groups.entry(join.group).or_default();
+        if !members.contains(&join.recipient) {
+            members.push(join.recipient);
+        }
+    }
+}
+
+impl Handler<LeaveGroup> for LuaBus {
+    type Result = ();
+
+    fn handle(&mut self, leave: LeaveGroup, _: &mut Context<Self>) {
+        if let Some(members) = self.groups.get_mut(&leave.group) {
+            members.retain(|rec| *rec != leave.recipient);
+        }
+    }
+}
+
+impl Handler<Broadcast> for LuaBus {
+    type Result = ();
+
+    fn handle(&mut self, broadcast: Broadcast, _: &mut Context<Self>) {
+        if let Some(members) = self.groups.get_mut(&broadcast.group) {
+            // drop members which are no longer alive
+            members.retain(|rec| {
+                !matches!(rec.do_send(broadcast.msg.clone()), Err(SendError::Closed(_)))
+            });
+        }
+    }
+}
+
+fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let topic: Vec<&str> = topic.split('.').collect();
+    segments_match(&pattern, &topic)
+}
+
+fn segments_match(pattern: &[&str], topic: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => topic.is_empty(),
+        Some((&"**", rest)) => (0..=topic.len()).any(|i| segments_match(rest, &topic[i..])),
+        Some((p, rest)) => match topic.split_first() {
+            Some((t, topic_rest)) => (*p == "*" || p == t) && segments_match(rest, topic_rest),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(topic_matches("orders.created", "orders.created"));
+        assert!(!topic_matches("orders.created", "orders.deleted"));
+        assert!(topic_matches("orders.*", "orders.created"));
+        assert!(!topic_matches("orders.*", "orders"));
+        assert!(!topic_matches("orders.*", "orders.created.eu"));
+        assert!(topic_matches("*.created", "orders.created"));
+        assert!(topic_matches("orders.**", "orders"));
+        assert!(topic_matches("orders.**", "orders.created.eu"));
+        assert!(topic_matches("**.eu", "orders.created.eu"));
+        assert!(!topic_matches("**.eu", "orders.created.us"));
+    }
+}
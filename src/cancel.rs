@@ -0,0 +1,185 @@
+#[cfg(feature = "actix")]
+use actix::prelude::*;
+use rlua::{AnyUserData, Error as LuaError, Function, Lua};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "actix")]
+use actor::{LuaActor, LuaReply};
+use error::ActixLuaError;
+#[cfg(feature = "actix")]
+use message::{Hop, LuaMessage};
+use message::OpaqueHandle;
+
+/// Cancels a `Cancellable` message, from any thread. Clones share the same state.
+///
+/// A script sees the cancellation with `ctx.cancelled()`. A running `handle` hook is aborted
+/// with `ActixLuaError::Cancelled` after at most a thousand more instructions, and a thread
+/// suspended in `ctx.send` or `ctx.spawn_blocking` when it is resumed. A blocking Rust callback
+/// can't be interrupted.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Send `msg` to a `LuaActor` with a token which cancels it, e.g. when nobody waits for the
+/// reply anymore. A cancelled message is answered with `ActixLuaError::Cancelled`, without the
+/// error policy of the actor being applied. Cancellable messages aren't batched.
+///
+/// ```rust,ignore
+/// let token = CancelToken::new();
+/// let reply = addr.send(Cancellable {
+///     msg: LuaMessage::from("report"),
+///     token: token.clone(),
+/// });
+/// // later, from any thread
+/// token.cancel();
+/// ```
+#[cfg(feature = "actix")]
+pub struct Cancellable {
+    pub msg: LuaMessage,
+    pub token: CancelToken,
+}
+
+#[cfg(feature = "actix")]
+impl Message for Cancellable {
+    type Result = LuaMessage;
+}
+
+#[cfg(feature = "actix")]
+impl Handler<Cancellable> for LuaActor {
+    type Result = LuaReply;
+
+    fn handle(&mut self, c: Cancellable, ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.add_request();
+        self.count_handled(ctx);
+        if c.token.is_cancelled() {
+            return LuaReply::Now(LuaMessage::Error(ActixLuaError::Cancelled));
+        }
+        if let Err(e) = self.select_tenant(&c.msg, ctx) {
+            return LuaReply::Now(LuaMessage::Error(e));
+        }
+        self.cancel = Some(c.token);
+        let reply = self.handle_message(Hop::from(c.msg), LuaMessage::Nil, ctx);
+        self.cancel = None;
+        reply
+    }
+}
+
+// the functions of the prelude reading the token of a message: whether it is cancelled, and
+// raising `ActixLuaError::Cancelled` if it is
+pub(crate) fn functions(vm: &Lua) -> Result<(Function<'_>, Function<'_>), LuaError> {
+    let cancelled = vm.create_function(|_, token: AnyUserData| is_cancelled(&token))?;
+    let check = vm.create_function(|_, token: AnyUserData| {
+        if is_cancelled(&token)? {
+            return Err(ActixLuaError::Cancelled.into());
+        }
+        Ok(())
+    })?;
+    Ok((cancelled, check))
+}
+
+fn is_cancelled(token: &AnyUserData) -> Result<bool, LuaError> {
+    let handle = token.borrow::<OpaqueHandle>()?;
+    Ok(handle
+        .downcast_ref::<CancelToken>()
+        .is_some_and(CancelToken::is_cancelled))
+}
+
+#[cfg(all(test, feature = "actix"))]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use error::ErrorPolicy;
+    use futures::Future;
+
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn cancellable_not_cancelled() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg .. tostring(ctx.cancelled())"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        let l = addr
+            .send(Cancellable {
+                msg: LuaMessage::from("cancelled: "),
+                token: CancelToken::new(),
+            })
+            .join(addr.send(Cancellable {
+                msg: LuaMessage::from("never run"),
+                token: cancelled,
+            }))
+            .join(addr.send(LuaMessage::from("plain: ")));
+        Arbiter::spawn(l.map(|((res, cancelled), plain)| {
+            assert_eq!(res, LuaMessage::from("cancelled: false"));
+            assert_eq!(cancelled, LuaMessage::Error(ActixLuaError::Cancelled));
+            assert_eq!(plain, LuaMessage::from("plain: false"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn cancellable_aborts_running_script() {
+        let system = System::new("test");
+
+        // the error policy would stop the actor for a failed script
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "spin" then
+                    while true do end
+                end
+                return ctx.msg
+                "#,
+            )
+            .with_error_policy(ErrorPolicy::Stop)
+            .build()
+            .unwrap()
+            .start();
+
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+        let l = addr
+            .send(Cancellable {
+                msg: LuaMessage::from("spin"),
+                token,
+            })
+            .and_then(move |res| {
+                assert_eq!(res, LuaMessage::Error(ActixLuaError::Cancelled));
+                addr.send(LuaMessage::from("still running"))
+            });
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from("still running"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use error::ActixLuaError;
+use message::LuaMessage;
+
+// Load a TOML file with the `toml` feature, or a YAML file with the `yaml` feature, as a table.
+// The format is picked by the extension of the file. Arrays become tables keyed `"1"`..`"n"`.
+pub fn load(path: &str) -> Result<LuaMessage, ActixLuaError> {
+    let error = |message: String| ActixLuaError::ConfigError {
+        path: path.to_string(),
+        message,
+    };
+    let body = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        #[cfg(feature = "toml")]
+        Some("toml") => {
+            let value: ::toml::Value =
+                ::toml::from_str(&body).map_err(|e| error(e.to_string()))?;
+            Ok(from_toml(value))
+        }
+        #[cfg(feature = "yaml")]
+        Some("yaml") | Some("yml") => {
+            let value: ::serde_yaml::Value =
+                ::serde_yaml::from_str(&body).map_err(|e| error(e.to_string()))?;
+            Ok(from_yaml(value))
+        }
+        _ => Err(error("unsupported config format".to_string())),
+    }
+}
+
+fn sequence<I: IntoIterator<Item = LuaMessage>>(values: I) -> LuaMessage {
+    LuaMessage::Table(
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| ((i + 1).to_string(), v))
+            .collect(),
+    )
+}
+
+#[cfg(feature = "toml")]
+fn from_toml(value: ::toml::Value) -> LuaMessage {
+    use toml::Value;
+
+    match value {
+        Value::String(s) => LuaMessage::String(s),
+        Value::Integer(i) => LuaMessage::Integer(i),
+        Value::Float(f) => LuaMessage::Number(f),
+        Value::Boolean(b) => LuaMessage::Boolean(b),
+        Value::Datetime(d) => LuaMessage::String(d.to_string()),
+        Value::Array(a) => sequence(a.into_iter().map(from_toml)),
+        Value::Table(t) => LuaMessage::Table(
+            t.into_iter()
+                .map(|(k, v)| (k, from_toml(v)))
+                .collect::<HashMap<_, _>>(),
+        ),
+    }
+}
+
+#[cfg(feature = "yaml")]
+fn from_yaml(value: ::serde_yaml::Value) -> LuaMessage {
+    use serde_yaml::Value;
+
+    match value {
+        Value::Null => LuaMessage::Nil,
+        Value::Bool(b) => LuaMessage::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => LuaMessage::Integer(i),
+            None => LuaMessage::Number(n.as_f64().unwrap_or(0.0)),
+        },
+        Value::String(s) => LuaMessage::String(s),
+        Value::Sequence(s) => sequence(s.into_iter().map(from_yaml)),
+        Value::Mapping(m) => LuaMessage::Table(
+            m.into_iter()
+                .map(|(k, v)| {
+                    // keys are strings in Lua messages
+                    let k = match k {
+                        Value::String(s) => s,
+                        k => ::serde_yaml::to_string(&k)
+                            .map(|s| s.trim_start_matches("---").trim().to_string())
+                            .unwrap_or_default(),
+                    };
+                    (k, from_yaml(v))
+                })
+                .collect::<HashMap<_, _>>(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(msg: LuaMessage) -> HashMap<String, LuaMessage> {
+        match msg {
+            LuaMessage::Table(t) => t,
+            msg => panic!("unexpected config {:?}", msg),
+        }
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn load_toml() {
+        let mut config = table(load("src/lua/test/config.toml").unwrap());
+        assert_eq!(config["workers"], LuaMessage::from(4));
+        let mut limits = table(config.remove("limits").unwrap());
+        assert_eq!(limits["rate"], LuaMessage::from(0.5));
+        let hosts = table(limits.remove("hosts").unwrap());
+        assert_eq!(hosts["2"], LuaMessage::from("b"));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn load_yaml() {
+        let mut config = table(load("src/lua/test/config.yml").unwrap());
+        assert_eq!(config["workers"], LuaMessage::from(4));
+        let mut limits = table(config.remove("limits").unwrap());
+        assert_eq!(limits["1"], LuaMessage::from("one"));
+        let hosts = table(limits.remove("hosts").unwrap());
+        assert_eq!(hosts["1"], LuaMessage::from("a"));
+    }
+
+    #[test]
+    fn load_errors() {
+        assert_eq!(
+            load("src/lua/test/module.lua"),
+            Err(ActixLuaError::ConfigError {
+                path: "src/lua/test/module.lua".to_string(),
+                message: "unsupported config format".to_string(),
+            })
+        );
+        match load("missing.toml") {
+            Err(ActixLuaError::ConfigError { path, .. }) => assert_eq!(path, "missing.toml"),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
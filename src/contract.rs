@@ -0,0 +1,203 @@
+use rlua::{Error as LuaError, Lua, Table, Value};
+
+use std::collections::HashMap;
+
+use error::{ActixLuaError, SchemaViolation};
+use message::LuaMessage;
+
+// Checks the messages for the `handle` hook against the contracts the scripts declared with
+// `ctx.contract`, before the hook runs.
+//
+// A contract is a type, like `"integer"`, or a table of the contracts of the fields of a table.
+// A type ending with `?` accepts `nil` as well. Fields which aren't in the contract are allowed.
+
+// check `msg`, sent with `topic`, against the contract of the topic or of every message
+pub(crate) fn check(vm: &Lua, topic: &LuaMessage, msg: &LuaMessage) -> Result<(), ActixLuaError> {
+    let contracts: Table = vm.globals().raw_get("__contracts")?;
+    let mut contract = Value::Nil;
+    if let LuaMessage::String(topic) = topic {
+        let topics: Table = contracts.raw_get("topics")?;
+        contract = topics.raw_get(topic.as_str())?;
+    }
+    if let Value::Nil = contract {
+        contract = contracts.raw_get("all")?;
+    }
+    if let Value::Nil = contract {
+        return Ok(());
+    }
+
+    let mut violations = vec![];
+    validate(&contract, msg, "", &mut violations)?;
+    if violations.is_empty() {
+        return Ok(());
+    }
+    violations.sort_by(|a, b| a.path.cmp(&b.path));
+    Err(ActixLuaError::InvalidMessage {
+        handler: match topic {
+            LuaMessage::String(topic) => topic.clone(),
+            _ => "handle".to_string(),
+        },
+        violations,
+    })
+}
+
+fn validate(
+    contract: &Value,
+    value: &LuaMessage,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) -> Result<(), LuaError> {
+    let mut violation = |message: String| {
+        violations.push(SchemaViolation {
+            path: path.to_string(),
+            message,
+        })
+    };
+    match contract {
+        Value::String(t) => {
+            let t = t.to_str()?;
+            let (t, optional) = match t.strip_suffix('?') {
+                Some(t) => (t, true),
+                None => (t, false),
+            };
+            match value {
+                LuaMessage::Nil if !optional => violation("is required".to_string()),
+                LuaMessage::Nil => {}
+                value if !is_type(value, t) => violation(format!("must be of type {}", t)),
+                _ => {}
+            }
+        }
+        Value::Table(fields) => {
+            let empty = HashMap::new();
+            let table = match value {
+                LuaMessage::Table(table) => table,
+                LuaMessage::Nil => &empty,
+                _ => {
+                    violation("must be of type table".to_string());
+                    return Ok(());
+                }
+            };
+            for pair in fields.clone().pairs::<Value, Value>() {
+                let (name, contract) = pair?;
+                let name = match name {
+                    Value::Integer(i) => i.to_string(),
+                    Value::String(name) => name.to_str()?.to_string(),
+                    _ => continue,
+                };
+                let field = table.get(&name).unwrap_or(&LuaMessage::Nil);
+                let path = SchemaViolation::pointer(path, &name);
+                validate(&contract, field, &path, violations)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_type(value: &LuaMessage, t: &str) -> bool {
+    match (t, value) {
+        ("any", _) | ("boolean", LuaMessage::Boolean(_)) | ("table", LuaMessage::Table(_)) => true,
+        ("integer", LuaMessage::Integer(_)) | ("number", LuaMessage::Integer(_)) => true,
+        ("integer", LuaMessage::Number(n)) => n.fract() == 0.0,
+        ("number", LuaMessage::Number(_)) => true,
+        ("string", LuaMessage::String(_)) | ("string", LuaMessage::Bytes(_)) => true,
+        ("array", LuaMessage::Table(t)) => (1..=t.len()).all(|i| t.contains_key(&i.to_string())),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::prelude::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+    // `handler` is declared after this module
+    use impl_lua_handler;
+    use vm;
+
+    struct Refund(i64);
+
+    impl Message for Refund {
+        type Result = Result<i64, ActixLuaError>;
+    }
+
+    impl From<Refund> for LuaMessage {
+        fn from(refund: Refund) -> Self {
+            LuaMessage::from(refund.0)
+        }
+    }
+
+    impl_lua_handler!(Refund => "refund");
+
+    fn order(id: LuaMessage, items: LuaMessage) -> LuaMessage {
+        let mut t = HashMap::new();
+        t.insert("order_id".to_string(), id);
+        t.insert("items".to_string(), items);
+        LuaMessage::from(t)
+    }
+
+    #[test]
+    fn contract_violations() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+                ctx.contract { order_id = "integer", items = "array", note = "string?" }
+                ctx.contract("refund", "integer")
+                "#,
+            )
+            .on_handle_with_lua("if ctx.topic then return ctx.msg end return ctx.msg.order_id")
+            .build()
+            .unwrap()
+            .start();
+
+        let mut items = HashMap::new();
+        items.insert("1".to_string(), LuaMessage::from("a"));
+        let items = LuaMessage::from(items);
+        let l = addr
+            .send(order(7.into(), items))
+            .join(addr.send(order("7".into(), "a".into())))
+            .join(addr.send(LuaMessage::from(1)))
+            .join(addr.send(Refund(42)));
+        Arbiter::spawn(l.map(|(((valid, invalid), untyped), refund)| {
+            assert_eq!(valid, LuaMessage::from(7));
+            let violations = match invalid {
+                LuaMessage::Error(ActixLuaError::InvalidMessage {
+                    handler,
+                    violations,
+                }) => {
+                    assert_eq!(handler, "handle");
+                    violations
+                }
+                res => panic!("unexpected result {:?}", res),
+            };
+            let violations: Vec<_> = violations.iter().map(|v| v.to_string()).collect();
+            assert_eq!(
+                violations,
+                ["/items: must be of type array", "/order_id: must be of type integer"]
+            );
+            match untyped {
+                LuaMessage::Error(e) => assert_eq!(
+                    e.to_string(),
+                    "invalid `handle` message: must be of type table"
+                ),
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(refund, Ok(42));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+
+        let vm = vm::new_vm().unwrap();
+        vm::load_prelude(&vm).unwrap();
+        let err = vm
+            .exec::<()>(r#"ctx.contract { items = { sku = "text" } }"#, Some("started"))
+            .unwrap_err();
+        assert!(err.to_string().contains(
+            r#"[string "started"]:1: unknown type `text` in contract at `/items/sku`"#
+        ));
+    }
+}
@@ -0,0 +1,483 @@
+use rlua::{Error as LuaError, Function, Lua, Table};
+use serde_json::Value;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use error::ActixLuaError;
+
+/// A [Debug Adapter Protocol] server to debug the scripts of `LuaActor`s live, with breakpoints,
+/// stepping and the inspection of variables, from an editor such as VS Code. Requires the
+/// `debugger` feature.
+///
+/// Give it to `LuaActorBuilder::with_debugger` and attach the editor to its address. The hooks
+/// and the modules of `on_handle_bundle` read from files are known by their paths, the ones
+/// given as strings by their chunk names, e.g. `handle`. A script stopped at a breakpoint
+/// blocks the thread of its actor until it continues, and every line of the scripts is
+/// checked against the breakpoints, so only debug actors which aren't serving production
+/// traffic.
+///
+/// ```rust,ignore
+/// let debugger = LuaDebugger::listen("127.0.0.1:4711")?;
+/// let addr = LuaActorBuilder::new()
+///     .on_handle("handle.lua")
+///     .with_debugger(debugger)
+///     .build()?
+///     .start();
+/// ```
+///
+/// [Debug Adapter Protocol]: https://microsoft.github.io/debug-adapter-protocol/
+#[derive(Clone)]
+pub struct LuaDebugger {
+    inner: Arc<Inner>,
+}
+
+// a request which needs a stopped script
+struct Request {
+    command: String,
+    arguments: Value,
+    reply: mpsc::Sender<Result<Value, String>>,
+}
+
+struct Inner {
+    addr: SocketAddr,
+    // the connected editor
+    client: Mutex<Option<TcpStream>>,
+    seq: AtomicU64,
+    // the lines with a breakpoint, by chunk name
+    breakpoints: Mutex<HashMap<String, HashSet<u64>>>,
+    // the paths of the scripts read from files, by chunk name
+    paths: Mutex<HashMap<String, String>>,
+    // stop at the next line run
+    pause: AtomicBool,
+    stopped: AtomicBool,
+    requests: Mutex<mpsc::Receiver<Request>>,
+    tx: Mutex<mpsc::Sender<Request>>,
+}
+
+impl LuaDebugger {
+    /// Listen for an editor on `addr`, one at a time.
+    pub fn listen(addr: &str) -> Result<LuaDebugger, ActixLuaError> {
+        let error = |e: io::Error| ActixLuaError::ConfigError {
+            path: addr.to_string(),
+            message: e.to_string(),
+        };
+        let listener = TcpListener::bind(addr).map_err(error)?;
+        let (tx, rx) = mpsc::channel();
+        let inner = Arc::new(Inner {
+            addr: listener.local_addr().map_err(error)?,
+            client: Mutex::new(None),
+            seq: AtomicU64::new(1),
+            breakpoints: Mutex::new(HashMap::new()),
+            paths: Mutex::new(HashMap::new()),
+            pause: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            requests: Mutex::new(rx),
+            tx: Mutex::new(tx),
+        });
+        let server = inner.clone();
+        thread::spawn(move || server.serve(listener));
+        Ok(LuaDebugger { inner })
+    }
+
+    /// The address the debugger listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.addr
+    }
+
+    // debug the coroutines of `vm`, in which the hooks run. `sources` are the chunk names and
+    // paths of the scripts read from files.
+    pub(crate) fn install(&self, vm: &Lua, sources: &[(String, String)]) -> Result<(), LuaError> {
+        self.inner
+            .paths
+            .lock()
+            .unwrap()
+            .extend(sources.iter().cloned());
+        let inner = self.inner.clone();
+        let check = vm.create_function(move |_, (source, line): (String, u64)| {
+            Ok(inner.check(&source, line))
+        })?;
+        let inner = self.inner.clone();
+        let stop = vm.create_function(
+            move |_, (reason, frames, variables): (String, Table, Function)| {
+                Ok(inner.stop(&reason, &frames, &variables))
+            },
+        )?;
+        let sethook: Function = vm.named_registry_value("sethook")?;
+        let gethook: Function = vm.named_registry_value("gethook")?;
+        let getinfo: Function = vm.named_registry_value("getinfo")?;
+        let getlocal: Function = vm.named_registry_value("getlocal")?;
+        let getupvalue: Function = vm.named_registry_value("getupvalue")?;
+        vm.load(include_str!("lua/debugger.lua"), Some("Debugger"))?
+            .call((sethook, gethook, getinfo, getlocal, getupvalue, check, stop))
+    }
+}
+
+impl Inner {
+    fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream.and_then(|s| s.try_clone().map(|c| (s, c))) {
+                Ok((stream, client)) => {
+                    *self.client.lock().unwrap() = Some(client);
+                    stream
+                }
+                Err(e) => {
+                    error!("lua debugger failed to accept an editor: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.session(stream) {
+                warn!("lua debugger session failed: {}", e);
+            }
+            // a stopped script continues once the editor is gone
+            *self.client.lock().unwrap() = None;
+            self.breakpoints.lock().unwrap().clear();
+            self.pause.store(false, Ordering::SeqCst);
+            if self.stopped.load(Ordering::SeqCst) {
+                let _ = self.forward("continue", Value::Null);
+            }
+        }
+    }
+
+    fn session(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        while let Some(request) = read_message(&mut reader)? {
+            let command = request["command"].as_str().unwrap_or_default().to_string();
+            let arguments = request["arguments"].clone();
+            let body = match command.as_str() {
+                "initialize" => Ok(json!({ "supportsConfigurationDoneRequest": true })),
+                "launch" | "attach" | "configurationDone" | "disconnect" => Ok(json!({})),
+                "setBreakpoints" => Ok(self.set_breakpoints(&arguments)),
+                "threads" => Ok(json!({ "threads": [{ "id": 1, "name": "lua" }] })),
+                "pause" => {
+                    self.pause.store(true, Ordering::SeqCst);
+                    Ok(json!({}))
+                }
+                "stackTrace" | "scopes" | "variables" | "continue" | "next" | "stepIn"
+                | "stepOut" => self.forward(&command, arguments),
+                _ => Err(format!("`{}` isn't supported", command)),
+            };
+            self.respond(&request, &command, body)?;
+            match command.as_str() {
+                "initialize" => self.event("initialized", json!({}))?,
+                "disconnect" => return Ok(()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // run a request by the stopped script
+    fn forward(&self, command: &str, arguments: Value) -> Result<Value, String> {
+        if !self.stopped.load(Ordering::SeqCst) {
+            return Err("no script is stopped".to_string());
+        }
+        let (reply, rx) = mpsc::channel();
+        let request = Request {
+            command: command.to_string(),
+            arguments,
+            reply,
+        };
+        let _ = self.tx.lock().unwrap().send(request);
+        rx.recv()
+            .unwrap_or_else(|_| Err("the script continued".to_string()))
+    }
+
+    fn set_breakpoints(&self, arguments: &Value) -> Value {
+        let source = &arguments["source"];
+        let chunk = source["path"].as_str().and_then(|path| {
+            let paths = self.paths.lock().unwrap();
+            paths
+                .iter()
+                .find(|(_, p)| p.as_str() == path)
+                .map(|(chunk, _)| chunk.clone())
+        });
+        let chunk = chunk.or_else(|| source["name"].as_str().map(str::to_string));
+        let lines: Vec<u64> = arguments["breakpoints"]
+            .as_array()
+            .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).collect())
+            .unwrap_or_default();
+        if let Some(ref chunk) = chunk {
+            let mut breakpoints = self.breakpoints.lock().unwrap();
+            breakpoints.insert(chunk.clone(), lines.iter().cloned().collect());
+        }
+        let breakpoints: Vec<_> = lines
+            .iter()
+            .map(|line| json!({ "verified": chunk.is_some(), "line": line }))
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // whether the script stops at `line` of the chunk `source`, and why
+    fn check(&self, source: &str, line: u64) -> Option<&'static str> {
+        if self.pause.swap(false, Ordering::SeqCst) {
+            return Some("pause");
+        }
+        let breakpoints = self.breakpoints.lock().unwrap();
+        match breakpoints.get(source) {
+            Some(lines) if lines.contains(&line) => Some("breakpoint"),
+            _ => None,
+        }
+    }
+
+    // answer the requests of the editor until the script continues, with the step to take
+    fn stop(&self, reason: &str, frames: &Table, variables: &Function) -> String {
+        // the editor asks for the stack as soon as it hears of the stop
+        self.stopped.store(true, Ordering::SeqCst);
+        let event = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+        if let Err(e) = self.event("stopped", event) {
+            warn!("lua debugger failed to report a stop: {}", e);
+            self.stopped.store(false, Ordering::SeqCst);
+            return "continue".to_string();
+        }
+        let requests = self.requests.lock().unwrap();
+        let mode = loop {
+            let request = match requests.recv() {
+                Ok(request) => request,
+                Err(_) => break "continue".to_string(),
+            };
+            let body = match request.command.as_str() {
+                "stackTrace" => self.stack_trace(frames),
+                "scopes" => scopes(frames, &request.arguments),
+                "variables" => variables_of(variables, &request.arguments),
+                _ => {
+                    let _ = request.reply.send(Ok(json!({ "allThreadsContinued": true })));
+                    break request.command;
+                }
+            };
+            let _ = request.reply.send(body.map_err(|e| e.to_string()));
+        };
+        self.stopped.store(false, Ordering::SeqCst);
+        mode
+    }
+
+    fn stack_trace(&self, frames: &Table) -> Result<Value, LuaError> {
+        let paths = self.paths.lock().unwrap();
+        let mut stack_frames = vec![];
+        for (i, frame) in frames.clone().sequence_values::<Table>().enumerate() {
+            let frame = frame?;
+            let chunk: String = frame.get("source")?;
+            let mut source = json!({ "name": chunk });
+            if let Some(path) = paths.get(&chunk) {
+                source["path"] = json!(path);
+            }
+            stack_frames.push(json!({
+                "id": i + 1,
+                "name": frame.get::<_, String>("name")?,
+                "source": source,
+                "line": frame.get::<_, i64>("line")?,
+                "column": 1,
+            }));
+        }
+        Ok(json!({ "totalFrames": stack_frames.len(), "stackFrames": stack_frames }))
+    }
+
+    fn respond(
+        &self,
+        request: &Value,
+        command: &str,
+        body: Result<Value, String>,
+    ) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq.fetch_add(1, Ordering::SeqCst));
+        match *self.client.lock().unwrap() {
+            Some(ref mut client) => write_message(client, &message),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "no editor")),
+        }
+    }
+}
+
+fn scopes(frames: &Table, arguments: &Value) -> Result<Value, LuaError> {
+    let id = arguments["frameId"].as_i64().unwrap_or_default();
+    let frame: Table = frames.get(id)?;
+    let scope = |name: &str, key: &str| -> Result<Value, LuaError> {
+        let reference: i64 = frame.get(key)?;
+        Ok(json!({ "name": name, "variablesReference": reference, "expensive": false }))
+    };
+    Ok(json!({ "scopes": [scope("Locals", "locals")?, scope("Upvalues", "upvalues")?] }))
+}
+
+fn variables_of(variables: &Function, arguments: &Value) -> Result<Value, LuaError> {
+    let id = arguments["variablesReference"].as_i64().unwrap_or_default();
+    let vars: Table = variables.call(id)?;
+    let mut list = vec![];
+    for var in vars.sequence_values::<Table>() {
+        let var = var?;
+        list.push(json!({
+            "name": var.get::<_, String>("name")?,
+            "value": var.get::<_, String>("value")?,
+            "type": var.get::<_, String>("type")?,
+            "variablesReference": var.get::<_, Option<i64>>("ref")?.unwrap_or(0),
+        }));
+    }
+    Ok(json!({ "variables": list }))
+}
+
+// a message of the protocol, after its `Content-Length` header
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut body = vec![0; length.ok_or_else(|| invalid("no Content-Length"))?];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid(&e.to_string()))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    // in a single write, which isn't delayed waiting for the acknowledgement of the previous one
+    let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    writer.write_all(message.as_bytes())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::prelude::*;
+    use futures::Future;
+    use std::collections::VecDeque;
+
+    use builder::LuaActorBuilder;
+    use message::LuaMessage;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        seq: u64,
+        // the events received while waiting for a response
+        events: VecDeque<Value>,
+    }
+
+    impl Client {
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut self.writer, &request).unwrap();
+            loop {
+                let message = read_message(&mut self.reader).unwrap().unwrap();
+                if message["type"] == "event" {
+                    self.events.push_back(message);
+                } else if message["request_seq"] == self.seq {
+                    assert_eq!(message["success"], true, "{} failed: {}", command, message);
+                    return message["body"].clone();
+                }
+            }
+        }
+
+        fn stopped(&mut self) -> Value {
+            loop {
+                let message = match self.events.pop_front() {
+                    Some(event) => event,
+                    None => read_message(&mut self.reader).unwrap().unwrap(),
+                };
+                if message["event"] == "stopped" {
+                    return message["body"].clone();
+                }
+            }
+        }
+
+        // the value of the local `name` of the top frame
+        fn local(&mut self, name: &str) -> Value {
+            let scopes = self.request("scopes", json!({ "frameId": 1 }));
+            let locals = scopes["scopes"][0]["variablesReference"].clone();
+            let vars = self.request("variables", json!({ "variablesReference": locals }));
+            let vars = vars["variables"].as_array().unwrap();
+            match vars.iter().find(|var| var["name"] == name) {
+                Some(var) => var["value"].clone(),
+                None => Value::Null,
+            }
+        }
+    }
+
+    #[test]
+    fn debugger_breakpoint() {
+        let debugger = LuaDebugger::listen("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(debugger.local_addr()).unwrap();
+        let mut client = Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            seq: 0,
+            events: VecDeque::new(),
+        };
+        client.request("initialize", json!({ "adapterID": "lua" }));
+        let breakpoints = json!({ "source": { "name": "handle" }, "breakpoints": [{ "line": 2 }] });
+        let set = client.request("setBreakpoints", breakpoints);
+        assert_eq!(set["breakpoints"][0]["verified"], true);
+        client.request("configurationDone", json!({}));
+
+        let editor = thread::spawn(move || {
+            assert_eq!(client.stopped()["reason"], "breakpoint");
+            let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+            let top = &trace["stackFrames"][0];
+            assert_eq!(top["name"], "main chunk");
+            assert_eq!(top["source"]["name"], "handle");
+            assert_eq!(top["line"], 2);
+            assert_eq!(client.local("n"), "2");
+            assert_eq!(client.local("m"), Value::Null);
+
+            client.request("next", json!({ "threadId": 1 }));
+            assert_eq!(client.stopped()["reason"], "step");
+            assert_eq!(client.local("m"), "4");
+            client.request("continue", json!({ "threadId": 1 }));
+        });
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua("local n = ctx.msg + 1\nlocal m = n * 2\nreturn m")
+            .with_debugger(debugger)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from(1));
+        Arbiter::spawn(l.map(|res| {
+            assert_eq!(res, LuaMessage::from(4));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+        editor.join().unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+use futures::Future;
+use regex::Regex;
+use rlua::Error as LuaError;
+
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// The reply of a request made with `Ask::ask` or `LuaTaskHandle::send`.
+pub type AskFuture<T> = Box<dyn Future<Item = T, Error = ActixLuaError>>;
+
+/// Errors returned by `actix-lua`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActixLuaError {
+    /// A script file given to the builder couldn't be read.
+    ScriptNotFound { path: String },
+    /// A config file given to the builder couldn't be read or parsed.
+    ConfigError { path: String, message: String },
+    /// A hook script failed to compile, or failed the strict-globals lint.
+    CompileError {
+        /// The hook the script belongs to: `started`, `handle`, `handle_batch`, `stopping` or
+        /// `stopped`.
+        hook: String,
+        /// The line of the error in the script, if Lua reported one.
+        line: Option<usize>,
+        message: String,
+    },
+    /// A script raised an error while running.
+    RuntimeError { traceback: String },
+    /// A script ran longer than it was allowed to, or a reply didn't arrive in time.
+    Timeout,
+    /// A script allocated more memory than it was allowed to. Not returned yet: the VMs of rlua
+    /// 0.14 can't limit their memory, this is reserved for when they can.
+    MemoryLimit,
+    /// A value couldn't be converted between Lua and Rust.
+    ConversionError { message: String },
+    /// A message was larger than the actor accepts, see `LuaMessage::estimated_size`.
+    MessageTooLarge { size: usize, max: usize },
+    /// A table was nested deeper than the actor accepts, see `LuaMessage::depth`.
+    TableTooDeep { max: usize },
+    /// A table returned or sent by a script contains itself. `path` is the keys from the table
+    /// to the reference back to it or to one of the tables it is nested in, like `a.b`.
+    CyclicTable { path: String },
+    /// A message was passed through more actors than allowed, see `Hop`.
+    TooManyHops { max: usize },
+    /// Rust code panicked while running a hook.
+    Panic { message: String },
+    /// Code panicked while it held the lock of a `LuaSharedState`, which may have left its data
+    /// half updated.
+    PoisonedState,
+    /// The actor stopped before it replied to a message.
+    ActorStopped,
+    /// The sender cancelled the message with its `CancelToken` before the script was done.
+    Cancelled,
+    /// A message was sent to a tenant without scripts, see `LuaTenantRegistry`.
+    UnknownTenant { tenant: String },
+    /// `LuaActorSpawner` or `LuaWatchdog` couldn't spawn an actor as `name`.
+    SpawnError { name: String, message: String },
+    /// An `Eval` was sent to an actor which wasn't built with `LuaActorBuilder::with_eval`.
+    EvalDisabled,
+    /// A message didn't match the schema of its handler, see `LuaActorBuilder::with_schema`, or
+    /// the contract the scripts declared for it with `ctx.contract`. `handler` is the name of
+    /// the handler, or `handle` for a message without one.
+    InvalidMessage {
+        handler: String,
+        violations: Vec<SchemaViolation>,
+    },
+    /// A schema given to `LuaActorBuilder::with_schema` isn't valid, or uses `$ref`.
+    InvalidSchema { handler: String, message: String },
+    /// A message had a newer `version` than its handler knows, see
+    /// `LuaActorBuilder::with_message_version`.
+    UnsupportedVersion {
+        handler: String,
+        version: i64,
+        current: i64,
+    },
+}
+
+/// A value of a message which doesn't match the schema of its handler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaViolation {
+    /// The JSON pointer to the value in the message, like `/items/0/sku`, empty for the message.
+    pub path: String,
+    pub message: String,
+}
+
+impl SchemaViolation {
+    // the JSON pointer to the field `name` of the value at `path`
+    #[cfg(feature = "actix")]
+    pub(crate) fn pointer(path: &str, name: &str) -> String {
+        format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"))
+    }
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// What a `LuaActor` does after one of its hooks raised an error.
+///
+/// The error is logged in every case, and a message which failed is answered with
+/// `LuaMessage::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorPolicy {
+    /// Keep running with the same VM. This is the default.
+    #[default]
+    Ignore,
+    /// Replace the VM with a fresh one built from the same configuration, and run the `started`
+    /// hook again. The state of the scripts is lost. Stops the actor if `started` fails.
+    Restart,
+    /// Stop the actor.
+    Stop,
+}
+
+impl ActixLuaError {
+    pub(crate) fn compile(hook: &str, err: &LuaError) -> Self {
+        let message = match err {
+            LuaError::SyntaxError { message, .. } => message.clone(),
+            LuaError::RuntimeError(message) => message.clone(),
+            e => e.to_string(),
+        };
+        let (line, message) = match split_location(&message) {
+            Some((_, line, message)) => (Some(line), message),
+            None => (None, message),
+        };
+        ActixLuaError::CompileError {
+            hook: hook.to_string(),
+            line,
+            message,
+        }
+    }
+}
+
+// lua prefixes errors with their location: `[string "<chunk name>"]:<line>: <message>`
+static LOCATION: OnceLock<Regex> = OnceLock::new();
+
+fn split_location(message: &str) -> Option<(String, usize, String)> {
+    let location = LOCATION
+        .get_or_init(|| Regex::new(r#"^\[string "([^"]*)"\]:(\d+): (?s)(.*)$"#).unwrap());
+    let cap = location.captures(message)?;
+    Some((cap[1].to_string(), cap[2].parse().ok()?, cap[3].to_string()))
+}
+
+impl From<LuaError> for ActixLuaError {
+    fn from(err: LuaError) -> Self {
+        match err {
+            LuaError::SyntaxError { ref message, .. } => {
+                let hook = match split_location(message) {
+                    Some((hook, _, _)) => hook,
+                    None => "unknown".to_string(),
+                };
+                ActixLuaError::compile(&hook, &err)
+            }
+            LuaError::RuntimeError(traceback) => ActixLuaError::RuntimeError { traceback },
+            LuaError::CallbackError { traceback, cause } => match (*cause).clone() {
+                LuaError::ExternalError(cause) => match cause.downcast_ref::<ActixLuaError>() {
+                    Some(e) => e.clone(),
+                    None => ActixLuaError::RuntimeError {
+                        traceback: format!("{}\n{}", cause, traceback),
+                    },
+                },
+                cause => ActixLuaError::RuntimeError {
+                    traceback: format!("{}\n{}", cause, traceback),
+                },
+            },
+            e @ LuaError::FromLuaConversionError { .. }
+            | e @ LuaError::ToLuaConversionError { .. } => ActixLuaError::ConversionError {
+                message: e.to_string(),
+            },
+            LuaError::ExternalError(cause) => match cause.downcast_ref::<ActixLuaError>() {
+                Some(e) => e.clone(),
+                None => ActixLuaError::RuntimeError {
+                    traceback: cause.to_string(),
+                },
+            },
+            e => ActixLuaError::RuntimeError {
+                traceback: e.to_string(),
+            },
+        }
+    }
+}
+
+// for `TryFrom<LuaMessage> for LuaMessage`
+impl From<Infallible> for ActixLuaError {
+    fn from(never: Infallible) -> Self {
+        match never {}
+    }
+}
+
+impl From<ActixLuaError> for LuaError {
+    fn from(err: ActixLuaError) -> Self {
+        LuaError::external(err)
+    }
+}
+
+impl fmt::Display for ActixLuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActixLuaError::ScriptNotFound { path } => write!(f, "script `{}` not found", path),
+            ActixLuaError::ConfigError { path, message } => {
+                write!(f, "config `{}`: {}", path, message)
+            }
+            ActixLuaError::CompileError {
+                hook,
+                line: Some(line),
+                message,
+            } => write!(f, "{}:{}: {}", hook, line, message),
+            ActixLuaError::CompileError {
+                hook,
+                line: None,
+                message,
+            } => write!(f, "{}: {}", hook, message),
+            ActixLuaError::RuntimeError { traceback } => write!(f, "{}", traceback),
+            ActixLuaError::Timeout => write!(f, "script timed out"),
+            ActixLuaError::MemoryLimit => write!(f, "script exceeded its memory limit"),
+            ActixLuaError::ConversionError { message } => write!(f, "{}", message),
+            ActixLuaError::MessageTooLarge { size, max } => {
+                write!(f, "message of {} bytes exceeds the limit of {} bytes", size, max)
+            }
+            ActixLuaError::TableTooDeep { max } => {
+                write!(f, "table is nested deeper than {} levels", max)
+            }
+            ActixLuaError::CyclicTable { path } => {
+                write!(f, "table contains itself at `{}`", path)
+            }
+            ActixLuaError::TooManyHops { max } => write!(
+                f,
+                "message was passed through more than {} actors, the sends may loop",
+                max
+            ),
+            ActixLuaError::Panic { message } => write!(f, "panicked: {}", message),
+            ActixLuaError::PoisonedState => {
+                write!(f, "shared state poisoned by a panic while it was locked")
+            }
+            ActixLuaError::ActorStopped => write!(f, "actor stopped before replying"),
+            ActixLuaError::Cancelled => write!(f, "the message was cancelled"),
+            ActixLuaError::UnknownTenant { tenant } => write!(f, "unknown tenant `{}`", tenant),
+            ActixLuaError::SpawnError { name, message } => {
+                write!(f, "cannot spawn `{}`: {}", name, message)
+            }
+            ActixLuaError::EvalDisabled => write!(f, "the actor doesn't evaluate code"),
+            ActixLuaError::InvalidMessage {
+                handler,
+                violations,
+            } => {
+                let violations: Vec<_> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "invalid `{}` message: {}", handler, violations.join(", "))
+            }
+            ActixLuaError::InvalidSchema { handler, message } => {
+                write!(f, "invalid schema for `{}`: {}", handler, message)
+            }
+            ActixLuaError::UnsupportedVersion {
+                handler,
+                version,
+                current,
+            } => write!(
+                f,
+                "`{}` message has version {}, newer than version {}",
+                handler, version, current
+            ),
+        }
+    }
+}
+
+impl Error for ActixLuaError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    #[test]
+    fn from_lua_error() {
+        let lua = Lua::new();
+
+        let err = lua.load("return 1 +", Some("handle")).unwrap_err();
+        match ActixLuaError::from(err) {
+            ActixLuaError::CompileError { hook, line, .. } => {
+                assert_eq!(hook, "handle");
+                assert_eq!(line, Some(1));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = lua.exec::<()>("error('boom')", None).unwrap_err();
+        match ActixLuaError::from(err) {
+            ActixLuaError::RuntimeError { traceback } => assert!(traceback.contains("boom")),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = lua.eval::<i64>("'x'", None).unwrap_err();
+        match ActixLuaError::from(err) {
+            ActixLuaError::ConversionError { .. } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let not_found = ActixLuaError::ScriptNotFound {
+            path: "missing.lua".to_string(),
+        };
+        let f = lua
+            .create_function(move |_, ()| Err::<(), _>(not_found.clone().into()))
+            .unwrap();
+        lua.globals().set("f", f).unwrap();
+        let err = lua.exec::<()>("f()", None).unwrap_err();
+        assert_eq!(
+            ActixLuaError::from(err),
+            ActixLuaError::ScriptNotFound {
+                path: "missing.lua".to_string()
+            }
+        );
+    }
+}
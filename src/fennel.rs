@@ -0,0 +1,83 @@
+use regex::Regex;
+use rlua::{Error as LuaError, Function, Lua, Table};
+
+use error::ActixLuaError;
+
+// Compiles Fennel hooks to Lua with the compiler given to `LuaActorBuilder::with_fennel`, with
+// the `fennel` feature.
+//
+// The compiler runs in a VM of its own, so the VMs of the actors never see it. Scripts are
+// compiled with `correlate`, which keeps the Lua code on the lines of the Fennel code, so the
+// lines in runtime errors and tracebacks are the lines of the Fennel script.
+pub(crate) struct Fennel {
+    vm: Lua,
+}
+
+impl Fennel {
+    // load `compiler`, the source of `fennel.lua`
+    pub(crate) fn new(compiler: &str) -> Result<Fennel, ActixLuaError> {
+        let vm = Lua::new();
+        {
+            let fennel: Table = vm
+                .load(compiler, Some("fennel"))
+                .and_then(|f| f.call(()))
+                .map_err(|e| ActixLuaError::compile("fennel", &e))?;
+            let compile: Function = fennel.get("compileString")?;
+            vm.set_named_registry_value("compile_string", compile)?;
+        }
+        Ok(Fennel { vm })
+    }
+
+    pub(crate) fn compile(&self, hook: &str, source: &str) -> Result<String, ActixLuaError> {
+        let compile: Function = self.vm.named_registry_value("compile_string")?;
+        let options = self.vm.create_table()?;
+        options.set("filename", hook)?;
+        options.set("correlate", true)?;
+        compile
+            .call::<_, String>((source, options))
+            .map_err(|e| compile_error(hook, &e))
+    }
+}
+
+// fennel prefixes errors with their location: `<filename>:<line>:<column>: <message>`
+fn compile_error(hook: &str, err: &LuaError) -> ActixLuaError {
+    let message = match err {
+        LuaError::CallbackError { cause, .. } => cause.to_string(),
+        LuaError::RuntimeError(message) => message.clone(),
+        e => e.to_string(),
+    };
+    // rlua appends the traceback of the compiler
+    let message = message.split("\nstack traceback:").next().unwrap_or_default();
+    let re = Regex::new(r"^[^:\n]*:(\d+):\d+:? (?s)(.*)$").unwrap();
+    let (line, message) = match re.captures(message) {
+        Some(cap) => (cap[1].parse().ok(), cap[2].to_string()),
+        None => (None, message.to_string()),
+    };
+    ActixLuaError::CompileError {
+        hook: hook.to_string(),
+        line,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fennel_compile_error() {
+        let fennel = Fennel::new(include_str!("lua/test/fennel.lua")).unwrap();
+        assert_eq!(
+            fennel.compile("handle", "(return 1)").unwrap(),
+            "return 1"
+        );
+        assert_eq!(
+            fennel.compile("handle", "\n(launch)"),
+            Err(ActixLuaError::CompileError {
+                hook: "handle".to_string(),
+                line: Some(2),
+                message: "Compile error: unknown form `launch`".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,210 @@
+#[cfg(feature = "actix")]
+use actix::prelude::*;
+use rlua::{Error as LuaError, Function, Lua};
+
+#[cfg(feature = "actix")]
+use std::time::Instant;
+
+#[cfg(feature = "actix")]
+use actor::LuaActor;
+#[cfg(feature = "actix")]
+use error::ActixLuaError;
+
+/// Control the garbage collector of a `LuaActor`, e.g. to collect garbage while the actor is
+/// idle instead of in the middle of handling messages.
+///
+/// The reply is the memory used by the VM afterwards, in bytes.
+///
+/// ```rust,ignore
+/// addr.send(Gc::Collect).map(|bytes| println!("{} bytes in use", bytes.unwrap()))
+/// ```
+#[cfg(feature = "actix")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gc {
+    /// Run a full collection cycle.
+    Collect,
+    /// Run an incremental step of the collector, like `collectgarbage("step", kbytes)`.
+    Step(u32),
+    /// Only report the memory used.
+    Count,
+}
+
+/// Tuning of the garbage collector, applied with `LuaActorBuilder::with_gc` to every VM of an
+/// actor.
+///
+/// Lua 5.3 only has an incremental collector. A lower `pause` or a higher `step_mul` collects
+/// more often in smaller steps, see the [Lua manual].
+///
+/// ```rust,ignore
+/// let builder = LuaActorBuilder::new().with_gc(GcConfig { pause: 120, ..GcConfig::default() });
+/// ```
+///
+/// [Lua manual]: https://www.lua.org/manual/5.3/manual.html#2.5
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    /// How much the memory in use grows before a new cycle starts, in percent. Defaults to 200.
+    pub pause: u32,
+    /// How fast the collector runs compared to memory allocation, in percent. Defaults to 200.
+    pub step_mul: u32,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            pause: 200,
+            step_mul: 200,
+        }
+    }
+}
+
+impl GcConfig {
+    pub(crate) fn apply(&self, vm: &Lua) -> Result<(), LuaError> {
+        let collectgarbage: Function = vm.named_registry_value("collectgarbage")?;
+        collectgarbage.call::<_, ()>(("setpause", self.pause))?;
+        collectgarbage.call::<_, ()>(("setstepmul", self.step_mul))
+    }
+}
+
+// count the completed collection cycles of the VM, read and reset with `sample`. the finalizer
+// of a garbage object runs at the end of each cycle and leaves garbage for the next one.
+pub(crate) fn count_cycles(vm: &Lua) -> Result<(), LuaError> {
+    let take: Function = vm
+        .load(
+            r#"
+            local cycles = 0
+            local sentinel = {}
+            sentinel.__gc = function ()
+                cycles = cycles + 1
+                setmetatable({}, sentinel)
+            end
+            setmetatable({}, sentinel)
+            return function ()
+                local n = cycles
+                cycles = 0
+                return n
+            end
+            "#,
+            Some("CountCycles"),
+        )?
+        .call(())?;
+    vm.set_named_registry_value("take_gc_cycles", take)
+}
+
+// the memory used by the VM in bytes, and the cycles completed since the last sample
+#[cfg(feature = "actix")]
+pub(crate) fn sample(vm: &Lua) -> Result<(u64, u64), LuaError> {
+    let collectgarbage: Function = vm.named_registry_value("collectgarbage")?;
+    let kbytes: f64 = collectgarbage.call("count")?;
+    let take: Function = vm.named_registry_value("take_gc_cycles")?;
+    let cycles: u64 = take.call(())?;
+    Ok(((kbytes * 1024.0) as u64, cycles))
+}
+
+#[cfg(feature = "actix")]
+impl Message for Gc {
+    type Result = Result<usize, ActixLuaError>;
+}
+
+#[cfg(feature = "actix")]
+impl Handler<Gc> for LuaActor {
+    type Result = Result<usize, ActixLuaError>;
+
+    fn handle(&mut self, gc: Gc, _: &mut Context<Self>) -> Self::Result {
+        // scripts can't replace the one kept in the registry
+        let collectgarbage: Function = self.vm.named_registry_value("collectgarbage")?;
+        let start = Instant::now();
+        match gc {
+            Gc::Collect => collectgarbage.call::<_, ()>("collect")?,
+            Gc::Step(kbytes) => collectgarbage.call::<_, ()>(("step", kbytes))?,
+            Gc::Count => {}
+        }
+        if gc != Gc::Count {
+            self.metrics.add_gc_pause(start.elapsed());
+        }
+        let kbytes: f64 = collectgarbage.call("count")?;
+        Ok((kbytes * 1024.0) as usize)
+    }
+}
+
+#[cfg(all(test, feature = "actix"))]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+    use message::LuaMessage;
+
+    #[test]
+    fn gc_collect() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local garbage = {}
+            for i = 1, 10000 do
+                garbage[i] = { i }
+            end
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(move |_| addr.send(Gc::Count).join(addr.send(Gc::Collect)));
+        Arbiter::spawn(l.map(|(before, after)| {
+            assert!(after.unwrap() < before.unwrap());
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn gc_metrics() {
+        use futures_timer::Delay;
+        use metrics::LuaActorMetrics;
+        use std::time::Duration;
+
+        let system = System::new("test");
+
+        let metrics = LuaActorMetrics::new();
+        let addr = LuaActorBuilder::new()
+            .with_metrics(metrics.clone())
+            .with_gc_metrics(Duration::from_millis(10))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(Gc::Collect)
+            .join(addr.send(Gc::Collect))
+            .and_then(|_| Delay::new(Duration::from_millis(50)).map_err(|_| MailboxError::Closed));
+        Arbiter::spawn(l.map(move |_| {
+            assert!(metrics.heap_bytes() > 0);
+            assert!(metrics.gc_cycles() >= 2);
+            assert!(metrics.gc_pause_time() > Duration::from_secs(0));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn gc_config() {
+        let actor = LuaActorBuilder::new()
+            .with_gc(GcConfig {
+                pause: 120,
+                step_mul: 400,
+            })
+            .build()
+            .unwrap();
+        // `setpause` and `setstepmul` return the previous values
+        let (pause, step_mul): (u32, u32) = actor
+            .vm
+            .eval("collectgarbage('setpause', 200), collectgarbage('setstepmul', 200)", None)
+            .unwrap();
+        assert_eq!((pause, step_mul), (120, 400));
+    }
+}
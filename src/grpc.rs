@@ -0,0 +1,735 @@
+use actix::prelude::*;
+use bytes::Bytes;
+use futures::Future;
+use futures_channel::oneshot;
+use futures_util::{future as future03, stream as stream03, FutureExt, StreamExt};
+use http::header::{HeaderValue, CONTENT_TYPE};
+use prost::bytes::{Buf, BufMut};
+use prost::encoding::{self, DecodeContext, WireType};
+use prost::DecodeError;
+use tonic::body::{self, BoxBody};
+use tonic::codec::{Codec, EncodeBody, ProstCodec, Streaming};
+use tonic::server::NamedService;
+use tonic::Status;
+use tower_service::Service;
+
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::pin::Pin;
+use std::task::{self, Poll};
+
+use actor::{Named, Target};
+use error::ActixLuaError;
+use message::LuaMessage;
+
+use self::lua_value::Kind;
+
+const CALL_PATH: &str = "/actix_lua.LuaActor/Call";
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+// The messages of `src/proto/actix_lua.proto`, in the shape prost generates them.
+
+/// The request of the `Call` method: the name of the actor or handler, and the message sent to
+/// it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallRequest {
+    pub name: String,
+    pub payload: Option<LuaValue>,
+}
+
+/// The reply of the `Call` method.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallReply {
+    pub payload: Option<LuaValue>,
+}
+
+/// A `LuaMessage` on the wire. A value without a kind is `nil`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LuaValue {
+    pub kind: Option<Kind>,
+}
+
+pub mod lua_value {
+    use super::LuaTable;
+
+    /// The `kind` oneof of a `LuaValue`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Kind {
+        Boolean(bool),
+        Integer(i64),
+        Number(f64),
+        String(String),
+        Bytes(Vec<u8>),
+        Table(LuaTable),
+    }
+}
+
+/// A table of a `LuaValue`, keyed by strings like `LuaMessage::Table`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LuaTable {
+    pub entries: HashMap<String, LuaValue>,
+}
+
+impl From<LuaValue> for LuaMessage {
+    fn from(value: LuaValue) -> Self {
+        match value.kind {
+            None => LuaMessage::Nil,
+            Some(Kind::Boolean(b)) => LuaMessage::Boolean(b),
+            Some(Kind::Integer(i)) => LuaMessage::Integer(i),
+            Some(Kind::Number(n)) => LuaMessage::Number(n),
+            Some(Kind::String(s)) => LuaMessage::String(s),
+            Some(Kind::Bytes(b)) => LuaMessage::Bytes(Bytes::from(b)),
+            Some(Kind::Table(t)) => LuaMessage::Table(
+                t.entries
+                    .into_iter()
+                    .map(|(k, v)| (k, LuaMessage::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl TryFrom<LuaMessage> for LuaValue {
+    type Error = Status;
+
+    fn try_from(msg: LuaMessage) -> Result<Self, Status> {
+        let kind = match msg {
+            LuaMessage::Nil => None,
+            LuaMessage::Boolean(b) => Some(Kind::Boolean(b)),
+            LuaMessage::Integer(i) => Some(Kind::Integer(i)),
+            LuaMessage::Number(n) => Some(Kind::Number(n)),
+            LuaMessage::String(s) => Some(Kind::String(s)),
+            LuaMessage::Bytes(b) => Some(Kind::Bytes(b.to_vec())),
+            LuaMessage::Table(t) => {
+                let mut entries = HashMap::with_capacity(t.len());
+                for (k, v) in t {
+                    entries.insert(k, LuaValue::try_from(v)?);
+                }
+                Some(Kind::Table(LuaTable { entries }))
+            }
+            LuaMessage::ThreadYield(_) => {
+                return Err(Status::internal(
+                    "handler suspended before returning a result",
+                ))
+            }
+            LuaMessage::Error(e) => return Err(status(&e)),
+            LuaMessage::Opaque(_) => {
+                return Err(Status::internal("an opaque value can't be sent over gRPC"))
+            }
+        };
+        Ok(LuaValue { kind })
+    }
+}
+
+impl prost::Message for CallRequest {
+    fn encode_raw(&self, buf: &mut impl BufMut) {
+        if !self.name.is_empty() {
+            encoding::string::encode(1, &self.name, buf);
+        }
+        if let Some(ref payload) = self.payload {
+            encoding::message::encode(2, payload, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => encoding::string::merge(wire_type, &mut self.name, buf, ctx),
+            2 => {
+                let payload = self.payload.get_or_insert_with(LuaValue::default);
+                encoding::message::merge(wire_type, payload, buf, ctx)
+            }
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if !self.name.is_empty() {
+            len += encoding::string::encoded_len(1, &self.name);
+        }
+        if let Some(ref payload) = self.payload {
+            len += encoding::message::encoded_len(2, payload);
+        }
+        len
+    }
+
+    fn clear(&mut self) {
+        self.name.clear();
+        self.payload = None;
+    }
+}
+
+impl prost::Message for CallReply {
+    fn encode_raw(&self, buf: &mut impl BufMut) {
+        if let Some(ref payload) = self.payload {
+            encoding::message::encode(1, payload, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => {
+                let payload = self.payload.get_or_insert_with(LuaValue::default);
+                encoding::message::merge(wire_type, payload, buf, ctx)
+            }
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.payload
+            .as_ref()
+            .map_or(0, |payload| encoding::message::encoded_len(1, payload))
+    }
+
+    fn clear(&mut self) {
+        self.payload = None;
+    }
+}
+
+impl prost::Message for LuaValue {
+    fn encode_raw(&self, buf: &mut impl BufMut) {
+        match self.kind {
+            None => {}
+            Some(Kind::Boolean(ref b)) => encoding::bool::encode(1, b, buf),
+            Some(Kind::Integer(ref i)) => encoding::int64::encode(2, i, buf),
+            Some(Kind::Number(ref n)) => encoding::double::encode(3, n, buf),
+            Some(Kind::String(ref s)) => encoding::string::encode(4, s, buf),
+            Some(Kind::Bytes(ref b)) => encoding::bytes::encode(5, b, buf),
+            Some(Kind::Table(ref t)) => encoding::message::encode(6, t, buf),
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        // like the generated code, the last field of the oneof on the wire wins
+        match tag {
+            1 => {
+                let mut b = false;
+                encoding::bool::merge(wire_type, &mut b, buf, ctx)?;
+                self.kind = Some(Kind::Boolean(b));
+            }
+            2 => {
+                let mut i = 0;
+                encoding::int64::merge(wire_type, &mut i, buf, ctx)?;
+                self.kind = Some(Kind::Integer(i));
+            }
+            3 => {
+                let mut n = 0.0;
+                encoding::double::merge(wire_type, &mut n, buf, ctx)?;
+                self.kind = Some(Kind::Number(n));
+            }
+            4 => {
+                let mut s = String::new();
+                encoding::string::merge(wire_type, &mut s, buf, ctx)?;
+                self.kind = Some(Kind::String(s));
+            }
+            5 => {
+                let mut b = Vec::new();
+                encoding::bytes::merge(wire_type, &mut b, buf, ctx)?;
+                self.kind = Some(Kind::Bytes(b));
+            }
+            6 => {
+                let mut t = match self.kind.take() {
+                    Some(Kind::Table(t)) => t,
+                    _ => LuaTable::default(),
+                };
+                encoding::message::merge(wire_type, &mut t, buf, ctx)?;
+                self.kind = Some(Kind::Table(t));
+            }
+            _ => encoding::skip_field(wire_type, tag, buf, ctx)?,
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self.kind {
+            None => 0,
+            Some(Kind::Boolean(ref b)) => encoding::bool::encoded_len(1, b),
+            Some(Kind::Integer(ref i)) => encoding::int64::encoded_len(2, i),
+            Some(Kind::Number(ref n)) => encoding::double::encoded_len(3, n),
+            Some(Kind::String(ref s)) => encoding::string::encoded_len(4, s),
+            Some(Kind::Bytes(ref b)) => encoding::bytes::encoded_len(5, b),
+            Some(Kind::Table(ref t)) => encoding::message::encoded_len(6, t),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.kind = None;
+    }
+}
+
+impl prost::Message for LuaTable {
+    fn encode_raw(&self, buf: &mut impl BufMut) {
+        encoding::hash_map::encode(
+            encoding::string::encode,
+            encoding::string::encoded_len,
+            encoding::message::encode,
+            encoding::message::encoded_len,
+            1,
+            &self.entries,
+            buf,
+        );
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => {
+                encoding::check_wire_type(WireType::LengthDelimited, wire_type)?;
+                encoding::hash_map::merge(
+                    encoding::string::merge,
+                    encoding::message::merge,
+                    &mut self.entries,
+                    buf,
+                    ctx,
+                )
+            }
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        encoding::hash_map::encoded_len(
+            encoding::string::encoded_len,
+            encoding::message::encoded_len,
+            1,
+            &self.entries,
+        )
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// A [gRPC] adapter for Lua actors, with the service of `src/proto/actix_lua.proto`:
+///
+/// ```protobuf
+/// service LuaActor {
+///     rpc Call (CallRequest) returns (CallReply);
+/// }
+/// ```
+///
+/// Every registered recipient is exposed under a name, or a named handler of an actor, see
+/// `register_handler`. The `payload` of a `CallRequest` is sent to the recipient selected by its
+/// `name`, and its reply is returned in the `CallReply`. A `LuaValue` maps to a `LuaMessage` one
+/// to one, a missing payload is sent as `nil`.
+///
+/// `into_service` returns a tonic service, which the application adds to the
+/// `tonic::transport::Server` running on its own tokio runtime:
+///
+/// ```rust,ignore
+/// let service = GrpcServer::new()
+///     .register("greet", greeter.recipient())
+///     .register_handler("orders.cancel", orders.recipient(), "cancel")
+///     .into_service();
+/// // on the tokio runtime of the application
+/// Server::builder().add_service(service).serve(addr).await?;
+/// ```
+///
+/// Errors are returned as gRPC statuses: `NOT_FOUND` for unknown names, `INVALID_ARGUMENT` for
+/// messages the actor rejects, e.g. for their size or schema, `DEADLINE_EXCEEDED` and
+/// `CANCELLED` for timeouts and cancellations, `UNAVAILABLE` if the actor stopped, and `INTERNAL`
+/// for the errors of the scripts. Requires the `grpc` feature.
+///
+/// [gRPC]: https://grpc.io
+pub struct GrpcServer {
+    methods: HashMap<String, Target>,
+    max_message_size: usize,
+}
+
+impl Default for GrpcServer {
+    fn default() -> Self {
+        GrpcServer {
+            methods: HashMap::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl GrpcServer {
+    pub fn new() -> Self {
+        GrpcServer::default()
+    }
+
+    /// Reject requests and replies larger than `bytes`. Defaults to 4 MiB, like other gRPC
+    /// implementations.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Expose `recipient` as `name`.
+    pub fn register(mut self, name: &str, recipient: Recipient<LuaMessage>) -> Self {
+        self.methods.insert(name.to_string(), Target::Actor(recipient));
+        self
+    }
+
+    /// Expose the named handler `handler` of an actor as `name`. The `handle` hook sees the
+    /// payload with `ctx.topic` set to `handler`, see `Named`.
+    pub fn register_handler(
+        mut self,
+        name: &str,
+        recipient: Recipient<Named>,
+        handler: &str,
+    ) -> Self {
+        let target = Target::Handler(recipient, handler.to_string());
+        self.methods.insert(name.to_string(), target);
+        self
+    }
+
+    /// Start the adapter and return the service calling it. Must be called in a running actix
+    /// system, which the calls are dispatched in. The adapter stops once every clone of the
+    /// service is dropped.
+    pub fn into_service(self) -> LuaActorService {
+        let max_message_size = self.max_message_size;
+        LuaActorService {
+            server: self.start(),
+            max_message_size,
+        }
+    }
+}
+
+impl Actor for GrpcServer {
+    type Context = Context<Self>;
+}
+
+// a call received by the service, answered on `tx`
+struct Dispatch {
+    name: String,
+    msg: LuaMessage,
+    tx: oneshot::Sender<Result<LuaMessage, Status>>,
+}
+
+impl Message for Dispatch {
+    type Result = ();
+}
+
+impl Handler<Dispatch> for GrpcServer {
+    type Result = ();
+
+    fn handle(&mut self, call: Dispatch, _: &mut Self::Context) {
+        let tx = call.tx;
+        let target = match self.methods.get(&call.name) {
+            Some(target) => target,
+            None => {
+                let _ = tx.send(Err(Status::not_found(format!("`{}` not found", call.name))));
+                return;
+            }
+        };
+        Arbiter::spawn(target.send(call.msg).then(move |res| {
+            let res = match res {
+                Ok(LuaMessage::Error(e)) => Err(status(&e)),
+                Ok(msg) => Ok(msg),
+                Err(MailboxError::Timeout) => Err(Status::deadline_exceeded("actor timed out")),
+                Err(MailboxError::Closed) => Err(Status::unavailable("actor stopped")),
+            };
+            let _ = tx.send(res);
+            Ok(())
+        }));
+    }
+}
+
+fn status(e: &ActixLuaError) -> Status {
+    let message = e.to_string();
+    match e {
+        ActixLuaError::ConversionError { .. }
+        | ActixLuaError::MessageTooLarge { .. }
+        | ActixLuaError::TableTooDeep { .. }
+        | ActixLuaError::CyclicTable { .. }
+        | ActixLuaError::InvalidMessage { .. }
+        | ActixLuaError::UnsupportedVersion { .. }
+        | ActixLuaError::UnknownTenant { .. } => Status::invalid_argument(message),
+        ActixLuaError::Timeout => Status::deadline_exceeded(message),
+        ActixLuaError::Cancelled => Status::cancelled(message),
+        ActixLuaError::ActorStopped => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+type ResponseFuture =
+    Pin<Box<dyn std::future::Future<Output = Result<http::Response<BoxBody>, Infallible>> + Send>>;
+
+/// The tonic service of a `GrpcServer`, the `actix_lua.LuaActor` service of
+/// `src/proto/actix_lua.proto`. Clones call the same adapter.
+#[derive(Clone)]
+pub struct LuaActorService {
+    server: Addr<GrpcServer>,
+    max_message_size: usize,
+}
+
+impl NamedService for LuaActorService {
+    const NAME: &'static str = "actix_lua.LuaActor";
+}
+
+impl Service<http::Request<BoxBody>> for LuaActorService {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> ResponseFuture {
+        if req.uri().path() != CALL_PATH {
+            let unknown = Status::unimplemented(format!("unknown method {}", req.uri().path()));
+            return Box::pin(future03::ready(Ok(unknown.into_http())));
+        }
+        let server = self.server.clone();
+        let max_message_size = self.max_message_size;
+        let mut codec = ProstCodec::<CallReply, CallRequest>::default();
+        let request = Streaming::new_request(
+            codec.decoder(),
+            req.into_body(),
+            None,
+            Some(max_message_size),
+        );
+        let response = request
+            .into_future()
+            .then(move |(first, _)| -> ResponseFuture {
+                let call = match first {
+                    Some(Ok(call)) => call,
+                    Some(Err(status)) => return Box::pin(future03::ready(Ok(status.into_http()))),
+                    None => {
+                        let missing = Status::invalid_argument("missing request message");
+                        return Box::pin(future03::ready(Ok(missing.into_http())));
+                    }
+                };
+                let (tx, rx) = oneshot::channel();
+                server.do_send(Dispatch {
+                    name: call.name,
+                    msg: LuaMessage::from(call.payload.unwrap_or_default()),
+                    tx,
+                });
+                Box::pin(rx.map(move |res| {
+                    let reply = match res {
+                        Ok(Ok(msg)) => msg,
+                        Ok(Err(status)) => return Ok(status.into_http()),
+                        Err(_) => return Ok(Status::unavailable("server stopped").into_http()),
+                    };
+                    Ok(respond(codec, reply, max_message_size))
+                }))
+            });
+        Box::pin(response)
+    }
+}
+
+fn respond(
+    mut codec: ProstCodec<CallReply, CallRequest>,
+    reply: LuaMessage,
+    max_message_size: usize,
+) -> http::Response<BoxBody> {
+    let payload = match LuaValue::try_from(reply) {
+        Ok(payload) => payload,
+        Err(status) => return status.into_http(),
+    };
+    let reply = CallReply {
+        payload: Some(payload),
+    };
+    let source = stream03::once(future03::ready(Ok(reply)));
+    let body = EncodeBody::new_server(
+        codec.encoder(),
+        source,
+        None,
+        Default::default(),
+        Some(max_message_size),
+    );
+    let mut response = http::Response::new(body::boxed(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use http::uri::PathAndQuery;
+    use prost::Message as ProstMessage;
+    use tokio1::runtime::Runtime;
+    use tonic::client::Grpc;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+    use tonic::{Code, Request, Response};
+
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    type Replies = mpsc::Receiver<Vec<Result<LuaMessage, Code>>>;
+
+    // serve `service` on a tokio runtime and call it, like an application would, then stop the
+    // system
+    fn call(service: LuaActorService, calls: Vec<(&'static str, LuaMessage)>) -> Replies {
+        let system = System::current();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let runtime = Runtime::new().unwrap();
+            let incoming = {
+                let _runtime = runtime.enter();
+                let listener = tokio1::net::TcpListener::from_std(listener).unwrap();
+                TcpIncoming::from_listener(listener, true, None).unwrap()
+            };
+            runtime.spawn(
+                Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming(incoming),
+            );
+            let endpoint = Channel::from_shared(format!("http://{}", addr)).unwrap();
+            let mut client = Grpc::new(runtime.block_on(endpoint.connect()).unwrap());
+            let mut res = vec![];
+            for (name, msg) in calls {
+                runtime.block_on(client.ready()).unwrap();
+                let req = Request::new(CallRequest {
+                    name: name.to_string(),
+                    payload: Some(LuaValue::try_from(msg).unwrap()),
+                });
+                let path = PathAndQuery::from_static(CALL_PATH);
+                let reply: Result<Response<CallReply>, Status> =
+                    runtime.block_on(client.unary(req, path, ProstCodec::default()));
+                res.push(
+                    reply
+                        .map(|r| LuaMessage::from(r.into_inner().payload.unwrap_or_default()))
+                        .map_err(|status| status.code()),
+                );
+            }
+            tx.send(res).unwrap();
+            system.stop();
+        });
+        rx
+    }
+
+    #[test]
+    fn grpc_calls() {
+        let system = System::new("test");
+
+        let add = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == nil then return "nothing" end
+                return ctx.msg.a + ctx.msg.b
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let echo = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return { msg = ctx.msg, ok = true }"#)
+            .build()
+            .unwrap()
+            .start();
+        let orders = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.topic == "cancel" then return "cancelled " .. ctx.msg end
+                if ctx.topic == "fail" then error("no such order") end
+                return ctx.msg
+                "#,
+            )
+            .with_max_message_size(64)
+            .build()
+            .unwrap()
+            .start();
+        let service = GrpcServer::new()
+            .register("add", add.recipient())
+            .register("echo", echo.recipient())
+            .register("orders.raw", orders.clone().recipient())
+            .register_handler("orders.cancel", orders.clone().recipient(), "cancel")
+            .register_handler("orders.fail", orders.recipient(), "fail")
+            .into_service();
+
+        let mut args = HashMap::new();
+        args.insert("a".to_string(), LuaMessage::from(40));
+        args.insert("b".to_string(), LuaMessage::from(2));
+        let res = call(
+            service,
+            vec![
+                ("add", LuaMessage::from(args)),
+                ("add", LuaMessage::Nil),
+                ("echo", LuaMessage::from(1.5)),
+                ("sub", LuaMessage::Nil),
+                ("orders.cancel", LuaMessage::from("42")),
+                ("orders.fail", LuaMessage::from("42")),
+                ("orders.raw", LuaMessage::from("a message over the limit of the orders actor")),
+                ("orders.raw", LuaMessage::Bytes(Bytes::from(vec![0xff, 0, 1]))),
+            ],
+        );
+
+        system.run();
+        let res = res.recv().unwrap();
+        assert_eq!(res[0], Ok(LuaMessage::from(42)));
+        assert_eq!(res[1], Ok(LuaMessage::from("nothing")));
+        let mut echoed = HashMap::new();
+        echoed.insert("msg".to_string(), LuaMessage::from(1.5));
+        echoed.insert("ok".to_string(), LuaMessage::from(true));
+        assert_eq!(res[2], Ok(LuaMessage::from(echoed)));
+        assert_eq!(res[3], Err(Code::NotFound));
+        assert_eq!(res[4], Ok(LuaMessage::from("cancelled 42")));
+        assert_eq!(res[5], Err(Code::Internal));
+        assert_eq!(res[6], Err(Code::InvalidArgument));
+        assert_eq!(res[7], Ok(LuaMessage::Bytes(Bytes::from(vec![0xff, 0, 1]))));
+    }
+
+    #[test]
+    fn grpc_wire_format() {
+        // the bytes protoc-generated code produces for the same messages
+        let req = CallRequest {
+            name: "add".to_string(),
+            payload: Some(LuaValue {
+                kind: Some(Kind::Integer(42)),
+            }),
+        };
+        let bytes = vec![0x0a, 3, b'a', b'd', b'd', 0x12, 2, 0x10, 42];
+        assert_eq!(req.encode_to_vec(), bytes);
+        assert_eq!(CallRequest::decode(&bytes[..]).unwrap(), req);
+
+        let mut entries = HashMap::new();
+        entries.insert(
+            "k".to_string(),
+            LuaValue {
+                kind: Some(Kind::Boolean(true)),
+            },
+        );
+        let table = LuaValue {
+            kind: Some(Kind::Table(LuaTable { entries })),
+        };
+        let bytes = vec![0x32, 9, 0x0a, 7, 0x0a, 1, b'k', 0x12, 2, 0x08, 1];
+        assert_eq!(table.encode_to_vec(), bytes);
+        assert_eq!(LuaValue::decode(&bytes[..]).unwrap(), table);
+
+        // a zero is still a set value, and an unset one is nil
+        let zero = LuaValue {
+            kind: Some(Kind::Integer(0)),
+        };
+        assert_eq!(zero.encode_to_vec(), vec![0x10, 0]);
+        assert_eq!(LuaMessage::from(LuaValue::default()), LuaMessage::Nil);
+    }
+}
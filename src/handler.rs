@@ -0,0 +1,233 @@
+// What the handlers generated by `impl_lua_handler!` need from the crate, not a public API.
+
+use actix::MailboxError;
+use futures::{future, Future};
+
+use std::convert::TryFrom;
+
+use actor::LuaReply;
+use ask::convert_reply;
+use error::{ActixLuaError, AskFuture};
+use message::LuaMessage;
+
+pub use futures::Future as FutureExt;
+#[cfg(feature = "json")]
+pub use serde_json::{from_value, to_value, Value};
+
+/// Implement `Handler<M>` for `LuaActor`, so a typed actix message can be sent to a Lua actor.
+///
+/// The message is converted with `Into<LuaMessage>` and handled by the `handle` hook with
+/// `ctx.topic` set to the name, so a script can tell the types of messages apart. The result
+/// type of the message must be `Result<T, ActixLuaError>`, where the reply is converted with
+/// `TryFrom<LuaMessage>` and a `LuaMessage::Error` reply is returned as the error.
+///
+/// With the `json` feature, `serde` converts with `Serialize` and `Deserialize` instead.
+///
+/// ```rust,ignore
+/// struct Charge { amount: i64 }
+///
+/// impl Message for Charge {
+///     type Result = Result<bool, ActixLuaError>;
+/// }
+///
+/// impl_lua_handler!(Charge => "charge");
+/// impl_lua_handler!(Refund => "refund", serde);
+///
+/// // if ctx.topic == "charge" then return ctx.msg.amount < 100 end
+/// let accepted = addr.send(Charge { amount: 42 });
+/// ```
+#[macro_export]
+macro_rules! impl_lua_handler {
+    ($msg:ty => $name:expr) => {
+        impl ::actix::Handler<$msg> for $crate::LuaActor {
+            type Result = ::actix::ResponseFuture<
+                <<$msg as ::actix::Message>::Result as $crate::handler::LuaResult>::Item,
+                $crate::ActixLuaError,
+            >;
+
+            fn handle(&mut self, msg: $msg, ctx: &mut ::actix::Context<Self>) -> Self::Result {
+                let reply = self.handle_named($name, $crate::LuaMessage::from(msg), ctx);
+                $crate::handler::convert(reply)
+            }
+        }
+    };
+    ($msg:ty => $name:expr, serde) => {
+        impl ::actix::Handler<$msg> for $crate::LuaActor {
+            type Result = ::actix::ResponseFuture<
+                <<$msg as ::actix::Message>::Result as $crate::handler::LuaResult>::Item,
+                $crate::ActixLuaError,
+            >;
+
+            fn handle(&mut self, msg: $msg, ctx: &mut ::actix::Context<Self>) -> Self::Result {
+                use $crate::handler::FutureExt;
+
+                let msg = match $crate::handler::to_value(&msg) {
+                    Ok(value) => $crate::LuaMessage::from(value),
+                    Err(e) => return $crate::handler::failed(e),
+                };
+                let reply = self.handle_named($name, msg, ctx);
+                Box::new(
+                    $crate::handler::convert::<$crate::LuaMessage>(reply).and_then(|msg| {
+                        let value = $crate::handler::Value::from(msg);
+                        $crate::handler::from_value(value)
+                            .map_err($crate::handler::conversion_error)
+                    }),
+                )
+            }
+        }
+    };
+}
+
+/// The `T` of a `Result<T, ActixLuaError>` message result.
+pub trait LuaResult {
+    type Item;
+}
+
+impl<T> LuaResult for Result<T, ActixLuaError> {
+    type Item = T;
+}
+
+pub fn convert<T>(reply: LuaReply) -> AskFuture<T>
+where
+    T: TryFrom<LuaMessage> + 'static,
+    T::Error: Into<ActixLuaError>,
+{
+    match reply {
+        LuaReply::Now(msg) => convert_reply(future::ok(msg)),
+        // the actor stopped before replying
+        LuaReply::Later(rx) => convert_reply(rx.map_err(|_| MailboxError::Closed)),
+    }
+}
+
+pub fn conversion_error<E: ToString>(e: E) -> ActixLuaError {
+    ActixLuaError::ConversionError {
+        message: e.to_string(),
+    }
+}
+
+pub fn failed<T: 'static, E: ToString>(e: E) -> AskFuture<T> {
+    Box::new(future::err(conversion_error(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+    use futures::Future;
+
+    use std::collections::HashMap;
+
+    use builder::LuaActorBuilder;
+    use error::ActixLuaError;
+    use message::LuaMessage;
+
+    struct Charge {
+        amount: i64,
+    }
+
+    impl Message for Charge {
+        type Result = Result<bool, ActixLuaError>;
+    }
+
+    impl From<Charge> for LuaMessage {
+        fn from(charge: Charge) -> Self {
+            let mut t = HashMap::new();
+            t.insert("amount".to_string(), LuaMessage::from(charge.amount));
+            LuaMessage::from(t)
+        }
+    }
+
+    impl_lua_handler!(Charge => "charge");
+
+    #[cfg(feature = "json")]
+    #[derive(Serialize)]
+    struct Refund {
+        order: String,
+        amount: i64,
+    }
+
+    #[cfg(feature = "json")]
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Receipt {
+        order: String,
+        refunded: i64,
+    }
+
+    #[cfg(feature = "json")]
+    impl Message for Refund {
+        type Result = Result<Receipt, ActixLuaError>;
+    }
+
+    #[cfg(feature = "json")]
+    impl_lua_handler!(Refund => "refund", serde);
+
+    #[test]
+    fn lua_handler() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.topic == "charge" then
+                    if ctx.msg.amount < 0 then error("negative amount") end
+                    return ctx.msg.amount < 100
+                end
+                return ctx.topic
+                "#,
+            ).build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(Charge { amount: 42 })
+            .join3(
+                addr.send(Charge { amount: 420 }),
+                addr.send(Charge { amount: -1 }),
+            ).join(addr.send(LuaMessage::Nil));
+        Arbiter::spawn(l.map(|((small, large, negative), untyped)| {
+            assert_eq!(small, Ok(true));
+            assert_eq!(large, Ok(false));
+            match negative {
+                Err(ActixLuaError::RuntimeError { traceback }) => {
+                    assert!(traceback.contains("negative amount"))
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(untyped, LuaMessage::Nil);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn lua_handler_serde() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.topic == "refund" then
+                    return { order = ctx.msg.order, refunded = ctx.msg.amount }
+                end
+                "#,
+            ).build()
+            .unwrap()
+            .start();
+
+        let refund = Refund {
+            order: "A-1".to_string(),
+            amount: 42,
+        };
+        Arbiter::spawn(addr.send(refund).map(|receipt| {
+            let expected = Receipt {
+                order: "A-1".to_string(),
+                refunded: 42,
+            };
+            assert_eq!(receipt, Ok(expected));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,281 @@
+use actix::prelude::*;
+use bytes::Bytes;
+use rlua::{Error as LuaError, Lua, Table, Value};
+
+use std::collections::HashMap;
+
+use actor::LuaActor;
+use error::ActixLuaError;
+use message::LuaMessage;
+use vm::catch_panic;
+
+// the globals of the Lua 5.3 standard library, left out of snapshots of the globals
+const STANDARD_GLOBALS: &[&str] = &[
+    "_G",
+    "_VERSION",
+    "assert",
+    "collectgarbage",
+    "coroutine",
+    "dofile",
+    "error",
+    "getmetatable",
+    "io",
+    "ipairs",
+    "load",
+    "loadfile",
+    "math",
+    "next",
+    "os",
+    "package",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "require",
+    "select",
+    "setmetatable",
+    "string",
+    "table",
+    "tonumber",
+    "tostring",
+    "type",
+    "utf8",
+    "xpcall",
+];
+
+/// Take a snapshot of the globals of a `LuaActor`, or of the value at `path`, for peeking at
+/// the state of a live actor.
+///
+/// `path` is a list of keys separated by dots, starting from a global, or from a field of
+/// `ctx` if there is no such global: `state.orders` is `ctx.state.orders`. The snapshot of the
+/// globals leaves out the standard library and the internals of the prelude.
+///
+/// Reading the VM doesn't run any script or metamethod. Functions, coroutines and userdata are
+/// replaced by `"<function>"`, `"<thread>"` and `"<userdata>"`, a table containing itself by
+/// `"<cycle>"`, and tables more than `max_depth` levels deep by `"<table>"`. Once `max_entries`
+/// entries are taken, the tables get a `"..."` key instead of the rest of their entries.
+///
+/// ```rust,ignore
+/// addr.send(Inspect { path: Some("state.orders".to_string()), ..Inspect::default() })
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inspect {
+    pub path: Option<String>,
+    /// Defaults to 4.
+    pub max_depth: usize,
+    /// Defaults to 1000.
+    pub max_entries: usize,
+}
+
+impl Default for Inspect {
+    fn default() -> Self {
+        Inspect {
+            path: None,
+            max_depth: 4,
+            max_entries: 1000,
+        }
+    }
+}
+
+impl Message for Inspect {
+    type Result = Result<LuaMessage, ActixLuaError>;
+}
+
+impl Handler<Inspect> for LuaActor {
+    type Result = Result<LuaMessage, ActixLuaError>;
+
+    fn handle(&mut self, inspect: Inspect, _: &mut Context<Self>) -> Self::Result {
+        let vm = &self.vm;
+        catch_panic(|| {
+            let mut snapshot = Snapshot {
+                ancestors: vm.create_table()?,
+                max_depth: inspect.max_depth,
+                entries: inspect.max_entries,
+            };
+            Ok(match inspect.path {
+                Some(path) => snapshot.value(lookup(vm, &path)?, 0)?,
+                None => snapshot.globals(vm)?,
+            })
+        })
+    }
+}
+
+// the value at `path`, or nil
+fn lookup<'lua>(vm: &'lua Lua, path: &str) -> Result<Value<'lua>, LuaError> {
+    let mut keys = path.split('.');
+    let first = keys.next().unwrap_or_default();
+    let mut value: Value = vm.globals().raw_get(first)?;
+    if let Value::Nil = value {
+        if let Value::Table(ctx) = vm.globals().raw_get("ctx")? {
+            value = ctx.raw_get(first)?;
+        }
+    }
+    for key in keys {
+        let table = match value {
+            Value::Table(table) => table,
+            _ => return Ok(Value::Nil),
+        };
+        value = table.raw_get(key)?;
+        if let (Value::Nil, Ok(i)) = (&value, key.parse::<i64>()) {
+            value = table.raw_get(i)?;
+        }
+    }
+    Ok(value)
+}
+
+struct Snapshot<'lua> {
+    // the tables being read, to find cycles
+    ancestors: Table<'lua>,
+    max_depth: usize,
+    // the entries left to take
+    entries: usize,
+}
+
+impl<'lua> Snapshot<'lua> {
+    fn globals(&mut self, vm: &'lua Lua) -> Result<LuaMessage, LuaError> {
+        let mut globals = HashMap::new();
+        for pair in vm.globals().pairs::<Value, Value>() {
+            let (k, v) = pair?;
+            let k = key(k);
+            if k.starts_with("__") || STANDARD_GLOBALS.contains(&k.as_str()) {
+                continue;
+            }
+            if self.entries == 0 {
+                globals.insert("...".to_string(), LuaMessage::from("<truncated>"));
+                break;
+            }
+            self.entries -= 1;
+            globals.insert(k, self.value(v, 1)?);
+        }
+        Ok(LuaMessage::Table(globals))
+    }
+
+    fn value(&mut self, v: Value<'lua>, depth: usize) -> Result<LuaMessage, LuaError> {
+        Ok(match v {
+            Value::Nil => LuaMessage::Nil,
+            Value::Boolean(b) => LuaMessage::Boolean(b),
+            Value::Integer(i) => LuaMessage::Integer(i),
+            Value::Number(n) => LuaMessage::Number(n),
+            Value::String(s) => match s.to_str() {
+                Ok(s) => LuaMessage::String(s.to_string()),
+                Err(_) => LuaMessage::Bytes(Bytes::from(s.as_bytes())),
+            },
+            Value::Table(t) => self.table(t, depth)?,
+            Value::Function(_) => LuaMessage::from("<function>"),
+            Value::Thread(_) => LuaMessage::from("<thread>"),
+            Value::UserData(_) | Value::LightUserData(_) => LuaMessage::from("<userdata>"),
+            Value::Error(_) => LuaMessage::from("<error>"),
+        })
+    }
+
+    fn table(&mut self, t: Table<'lua>, depth: usize) -> Result<LuaMessage, LuaError> {
+        if depth >= self.max_depth {
+            return Ok(LuaMessage::from("<table>"));
+        }
+        if let Value::Boolean(true) = self.ancestors.raw_get(t.clone())? {
+            return Ok(LuaMessage::from("<cycle>"));
+        }
+        self.ancestors.raw_set(t.clone(), true)?;
+        let mut table = HashMap::new();
+        for pair in t.clone().pairs::<Value, Value>() {
+            if self.entries == 0 {
+                table.insert("...".to_string(), LuaMessage::from("<truncated>"));
+                break;
+            }
+            self.entries -= 1;
+            let (k, v) = pair?;
+            table.insert(key(k), self.value(v, depth + 1)?);
+        }
+        self.ancestors.raw_set(t, Value::Nil)?;
+        Ok(LuaMessage::Table(table))
+    }
+}
+
+fn key(k: Value) -> String {
+    match k {
+        Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Table(_) => "<table>".to_string(),
+        Value::Function(_) => "<function>".to_string(),
+        _ => "<userdata>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+
+    fn table(msg: LuaMessage) -> HashMap<String, LuaMessage> {
+        match msg {
+            LuaMessage::Table(t) => t,
+            msg => panic!("not a table: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn inspect_state() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            config = { retries = 3, on_error = function () end }
+            ctx.state.orders = { { id = 7, items = { { sku = "a" } } } }
+            ctx.state.orders.all = ctx.state.orders
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let orders = Inspect {
+            path: Some("state.orders".to_string()),
+            ..Inspect::default()
+        };
+        let shallow = Inspect {
+            path: Some("state.orders.1".to_string()),
+            max_depth: 1,
+            ..Inspect::default()
+        };
+        let few = Inspect {
+            max_entries: 1,
+            ..Inspect::default()
+        };
+        let l = addr
+            .send(Inspect::default())
+            .join(addr.send(orders))
+            .join(addr.send(shallow))
+            .join(addr.send(few));
+        Arbiter::spawn(l.map(|(((globals, orders), shallow), few)| {
+            let globals = table(globals.unwrap());
+            assert!(!globals.contains_key("string"));
+            assert!(!globals.contains_key("__scripts"));
+            let config = table(globals["config"].clone());
+            assert_eq!(config["retries"], LuaMessage::from(3));
+            assert_eq!(config["on_error"], LuaMessage::from("<function>"));
+
+            let orders = table(orders.unwrap());
+            assert_eq!(orders["all"], LuaMessage::from("<cycle>"));
+            let order = table(orders["1"].clone());
+            assert_eq!(order["id"], LuaMessage::from(7));
+            let item = table(table(order["items"].clone())["1"].clone());
+            assert_eq!(item["sku"], LuaMessage::from("a"));
+
+            let shallow = table(shallow.unwrap());
+            assert_eq!(shallow["items"], LuaMessage::from("<table>"));
+            let few = table(few.unwrap());
+            assert_eq!(few.len(), 2);
+            assert_eq!(few["..."], LuaMessage::from("<truncated>"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
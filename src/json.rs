@@ -0,0 +1,102 @@
+use serde_json::{Map, Number, Value};
+
+use message::LuaMessage;
+
+// Conversions between `LuaMessage` and `serde_json::Value`, with the `json` feature.
+//
+// Arrays become tables keyed `"1"`..`"n"`, like Lua sequences, and `null` becomes `Nil`, also
+// inside arrays. Converting back, a non-empty table whose keys are exactly `"1"`..`"n"` becomes
+// an array and any other table an object, so an empty table is an empty object.
+
+impl From<Value> for LuaMessage {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => LuaMessage::Nil,
+            Value::Bool(b) => LuaMessage::Boolean(b),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => LuaMessage::Integer(i),
+                None => LuaMessage::Number(n.as_f64().unwrap_or(0.0)),
+            },
+            Value::String(s) => LuaMessage::String(s),
+            Value::Array(a) => LuaMessage::Table(
+                a.into_iter()
+                    .enumerate()
+                    .map(|(i, v)| ((i + 1).to_string(), LuaMessage::from(v)))
+                    .collect(),
+            ),
+            Value::Object(o) => LuaMessage::Table(
+                o.into_iter()
+                    .map(|(k, v)| (k, LuaMessage::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// Bytes which aren't valid UTF-8 are converted lossily. Numbers JSON can't represent, suspended
+// threads, errors and opaque values become `null`.
+impl From<LuaMessage> for Value {
+    fn from(msg: LuaMessage) -> Self {
+        match msg {
+            LuaMessage::Nil
+            | LuaMessage::ThreadYield(_)
+            | LuaMessage::Error(_)
+            | LuaMessage::Opaque(_) => Value::Null,
+            LuaMessage::Boolean(b) => Value::Bool(b),
+            LuaMessage::Integer(i) => Value::from(i),
+            LuaMessage::Number(n) => Number::from_f64(n).map_or(Value::Null, Value::Number),
+            LuaMessage::String(s) => Value::String(s),
+            LuaMessage::Bytes(b) => Value::String(String::from_utf8_lossy(&b).into_owned()),
+            LuaMessage::Table(mut t) => {
+                let is_sequence =
+                    !t.is_empty() && (1..=t.len()).all(|i| t.contains_key(&i.to_string()));
+                if is_sequence {
+                    Value::Array(
+                        (1..=t.len())
+                            .map(|i| Value::from(t.remove(&i.to_string()).unwrap()))
+                            .collect(),
+                    )
+                } else {
+                    Value::Object(
+                        t.into_iter()
+                            .map(|(k, v)| (k, Value::from(v)))
+                            .collect::<Map<_, _>>(),
+                    )
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_roundtrip() {
+        let value = json!({
+            "name": "order",
+            "total": 12.5,
+            "count": 3,
+            "paid": false,
+            "note": null,
+            "items": ["a", null, {"id": 1}],
+            "tags": {},
+        });
+        let msg = LuaMessage::from(value.clone());
+        match msg {
+            LuaMessage::Table(ref t) => {
+                assert_eq!(t["note"], LuaMessage::Nil);
+                match t["items"] {
+                    LuaMessage::Table(ref items) => {
+                        assert_eq!(items["1"], LuaMessage::from("a"));
+                        assert_eq!(items["2"], LuaMessage::Nil);
+                    }
+                    ref items => panic!("unexpected items {:?}", items),
+                }
+            }
+            ref msg => panic!("unexpected message {:?}", msg),
+        }
+        assert_eq!(Value::from(msg), value);
+    }
+}
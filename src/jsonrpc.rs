@@ -0,0 +1,521 @@
+use actix::io::{FramedWrite, WriteHandler};
+use actix::prelude::*;
+use bytes::BytesMut;
+use futures::{future, Future};
+use serde_json::Value;
+use tokio::codec::{Decoder, Encoder, FramedRead, LinesCodec};
+use tokio::io::{AsyncRead, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use actor::{Named, Target};
+use message::LuaMessage;
+use remote::DEFAULT_BUFFER_CAPACITY;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+const MAX_LINE_LEN: usize = 16 * 1024 * 1024;
+
+/// A [JSON-RPC 2.0] server for Lua actors.
+///
+/// Every registered recipient is exposed as a method, or a named handler of an actor, see
+/// `register_handler`. The `params` of a request, either an object or an array, are sent to the
+/// recipient as a table and its reply becomes the `result`.
+/// Arrays are converted to tables keyed `"1"`..`"n"`, and tables with such keys are returned as
+/// arrays.
+///
+/// `listen` serves newline-delimited requests over TCP. Other transports, e.g. HTTP, can send
+/// the request body to the server as a [`JsonRpcCall`].
+///
+/// ```rust,ignore
+/// let server = JsonRpcServer::new()
+///     .register("greet", greeter.recipient())
+///     .register_handler("orders.cancel", orders.recipient(), "cancel")
+///     .listen(&"127.0.0.1:4000".parse().unwrap())?;
+/// ```
+///
+/// Requires the `jsonrpc` feature.
+///
+/// [JSON-RPC 2.0]: https://www.jsonrpc.org/specification
+/// [`JsonRpcCall`]: struct.JsonRpcCall.html
+pub struct JsonRpcServer {
+    methods: Arc<HashMap<String, Target>>,
+    buffer_capacity: usize,
+}
+
+impl Default for JsonRpcServer {
+    fn default() -> Self {
+        JsonRpcServer {
+            methods: Default::default(),
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+        }
+    }
+}
+
+impl JsonRpcServer {
+    pub fn new() -> Self {
+        JsonRpcServer::default()
+    }
+
+    /// Make room for `bytes` in the write buffer of a TCP connection before serializing a
+    /// response.
+    ///
+    /// Responses are serialized straight into the buffer, which grows by this much when it runs
+    /// low rather than a little for every response. Defaults to 64 KiB.
+    pub fn buffer_capacity(mut self, bytes: usize) -> Self {
+        self.buffer_capacity = bytes;
+        self
+    }
+
+    /// Expose `recipient` as the method `method`.
+    pub fn register(mut self, method: &str, recipient: Recipient<LuaMessage>) -> Self {
+        Arc::make_mut(&mut self.methods).insert(method.to_string(), Target::Actor(recipient));
+        self
+    }
+
+    /// Expose the named handler `handler` of an actor as the method `method`. The `handle`
+    /// hook sees the params with `ctx.topic` set to `handler`, see `Named`.
+    pub fn register_handler(
+        mut self,
+        method: &str,
+        recipient: Recipient<Named>,
+        handler: &str,
+    ) -> Self {
+        let target = Target::Handler(recipient, handler.to_string());
+        Arc::make_mut(&mut self.methods).insert(method.to_string(), target);
+        self
+    }
+
+    /// Start the server and accept connections on `addr`.
+    pub fn listen(self, addr: &SocketAddr) -> Result<Addr<JsonRpcServer>, io::Error> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(JsonRpcServer::create(move |ctx| {
+            ctx.add_stream(listener.incoming());
+            self
+        }))
+    }
+
+    fn call(&self, body: &str) -> Box<dyn Future<Item = Option<Value>, Error = ()>> {
+        let value = match serde_json::from_str(body) {
+            Ok(value) => value,
+            Err(e) => {
+                return Box::new(future::ok(Some(error_response(
+                    Value::Null,
+                    PARSE_ERROR,
+                    &e.to_string(),
+                ))))
+            }
+        };
+
+        match value {
+            Value::Array(ref batch) if batch.is_empty() => Box::new(future::ok(Some(
+                error_response(Value::Null, INVALID_REQUEST, "empty batch"),
+            ))),
+            Value::Array(batch) => {
+                let calls: Vec<_> = batch.into_iter().map(|req| self.request(req)).collect();
+                Box::new(future::join_all(calls).map(|responses| {
+                    let responses: Vec<Value> = responses.into_iter().flatten().collect();
+                    // a batch of notifications gets no response at all
+                    if responses.is_empty() {
+                        None
+                    } else {
+                        Some(Value::Array(responses))
+                    }
+                }))
+            }
+            req => self.request(req),
+        }
+    }
+
+    fn request(&self, req: Value) -> Box<dyn Future<Item = Option<Value>, Error = ()>> {
+        let mut req = match req {
+            Value::Object(req) => req,
+            _ => {
+                return Box::new(future::ok(Some(error_response(
+                    Value::Null,
+                    INVALID_REQUEST,
+                    "request must be an object",
+                ))))
+            }
+        };
+
+        let valid_id = match req.get("id") {
+            Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_)) | None => true,
+            Some(_) => false,
+        };
+        // an invalid id can't be echoed, the error is answered with a null id
+        let id = match req.remove("id") {
+            Some(_) if !valid_id => Some(Value::Null),
+            id => id,
+        };
+        // notifications are never answered, even on errors
+        let respond = move |res: Result<Value, (i64, String)>| {
+            id.map(|id| match res {
+                Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+                Err((code, message)) => error_response(id, code, &message),
+            })
+        };
+
+        let method = match (req.remove("method"), req.get("jsonrpc")) {
+            (Some(Value::String(ref method)), Some(Value::String(ref version)))
+                if valid_id && version == "2.0" =>
+            {
+                method.clone()
+            }
+            _ => {
+                return Box::new(future::ok(respond(Err((
+                    INVALID_REQUEST,
+                    "invalid request".to_string(),
+                )))))
+            }
+        };
+
+        let msg = match req.remove("params") {
+            None => LuaMessage::Nil,
+            Some(params @ Value::Object(_)) | Some(params @ Value::Array(_)) => {
+                LuaMessage::from(params)
+            }
+            Some(_) => {
+                return Box::new(future::ok(respond(Err((
+                    INVALID_PARAMS,
+                    "params must be an object or an array".to_string(),
+                )))))
+            }
+        };
+
+        let target = match self.methods.get(&method) {
+            Some(target) => target,
+            None => {
+                return Box::new(future::ok(respond(Err((
+                    METHOD_NOT_FOUND,
+                    format!("method `{}` not found", method),
+                )))))
+            }
+        };
+
+        Box::new(target.send(msg).then(move |res| {
+            let res = match res {
+                Ok(LuaMessage::ThreadYield(_)) => Err((
+                    INTERNAL_ERROR,
+                    "handler suspended before returning a result".to_string(),
+                )),
+                Ok(LuaMessage::Error(e)) => Err((INTERNAL_ERROR, e.to_string())),
+                Ok(msg) => Ok(Value::from(msg)),
+                Err(e) => Err((INTERNAL_ERROR, e.to_string())),
+            };
+            Ok(respond(res))
+        }))
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+impl Actor for JsonRpcServer {
+    type Context = Context<Self>;
+}
+
+/// Handle a raw JSON-RPC request body, a single request or a batch.
+///
+/// Resolves to the response body, or `None` if the body only contained notifications.
+pub struct JsonRpcCall(pub String);
+
+impl Message for JsonRpcCall {
+    type Result = Result<Option<String>, ()>;
+}
+
+impl Handler<JsonRpcCall> for JsonRpcServer {
+    type Result = ResponseFuture<Option<String>, ()>;
+
+    fn handle(&mut self, call: JsonRpcCall, _: &mut Context<Self>) -> Self::Result {
+        Box::new(
+            self.call(&call.0)
+                .map(|res| res.map(|value| value.to_string())),
+        )
+    }
+}
+
+// A request line of a TCP connection, answered with a value the connection serializes itself.
+struct LineCall(String);
+
+impl Message for LineCall {
+    type Result = Result<Option<Value>, ()>;
+}
+
+impl Handler<LineCall> for JsonRpcServer {
+    type Result = ResponseFuture<Option<Value>, ()>;
+
+    fn handle(&mut self, call: LineCall, _: &mut Context<Self>) -> Self::Result {
+        self.call(&call.0)
+    }
+}
+
+impl StreamHandler<TcpStream, io::Error> for JsonRpcServer {
+    fn handle(&mut self, stream: TcpStream, ctx: &mut Context<Self>) {
+        let server = ctx.address();
+        let capacity = self.buffer_capacity;
+        JsonRpcConnection::create(move |conn_ctx| {
+            let (r, w) = stream.split();
+            conn_ctx.add_stream(FramedRead::new(r, JsonCodec::new(0)));
+            JsonRpcConnection {
+                server,
+                writer: FramedWrite::new(w, JsonCodec::new(capacity), conn_ctx),
+            }
+        });
+    }
+
+    fn error(&mut self, _: io::Error, _: &mut Context<Self>) -> Running {
+        // a failed accept shouldn't stop the server
+        Running::Continue
+    }
+}
+
+struct JsonRpcConnection {
+    server: Addr<JsonRpcServer>,
+    writer: FramedWrite<WriteHalf<TcpStream>, JsonCodec>,
+}
+
+impl Actor for JsonRpcConnection {
+    type Context = Context<Self>;
+}
+
+impl WriteHandler<io::Error> for JsonRpcConnection {}
+
+impl StreamHandler<String, io::Error> for JsonRpcConnection {
+    fn handle(&mut self, line: String, ctx: &mut Context<Self>) {
+        if line.trim().is_empty() {
+            return;
+        }
+        self.server
+            .send(LineCall(line))
+            .into_actor(self)
+            .map(|res, act, _| {
+                if let Ok(Some(body)) = res {
+                    act.writer.write(body);
+                }
+            })
+            .map_err(|_, _, _| ())
+            .spawn(ctx);
+    }
+}
+
+// Reads request lines and writes responses as newline-delimited JSON. Responses are serialized
+// straight into the write buffer, which is given room for `capacity` more bytes when less than a
+// quarter of that is left.
+struct JsonCodec {
+    lines: LinesCodec,
+    capacity: usize,
+}
+
+impl JsonCodec {
+    fn new(capacity: usize) -> JsonCodec {
+        JsonCodec {
+            lines: LinesCodec::new_with_max_length(MAX_LINE_LEN),
+            capacity,
+        }
+    }
+}
+
+// writes to a `BytesMut`, which grows as needed
+struct Append<'a>(&'a mut BytesMut);
+
+impl<'a> io::Write for Append<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Decoder for JsonCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        self.lines.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        self.lines.decode_eof(src)
+    }
+}
+
+impl Encoder for JsonCodec {
+    type Item = Value;
+    type Error = io::Error;
+
+    fn encode(&mut self, value: Value, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if dst.capacity() - dst.len() < self.capacity / 4 {
+            dst.reserve(self.capacity);
+        }
+        let start = dst.len();
+        match serde_json::to_writer(Append(dst), &value) {
+            Ok(()) => {
+                dst.extend_from_slice(b"\n");
+                Ok(())
+            }
+            Err(e) => {
+                dst.truncate(start);
+                Err(io::Error::from(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use tokio::io::{read_until, write_all};
+
+    fn server() -> JsonRpcServer {
+        let add = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg.a + ctx.msg.b"#)
+            .build()
+            .unwrap()
+            .start();
+        let reverse = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return { ctx.msg["3"], ctx.msg["2"], ctx.msg["1"] }"#)
+            .build()
+            .unwrap()
+            .start();
+        let orders = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.topic .. " " .. ctx.msg.order"#)
+            .build()
+            .unwrap()
+            .start();
+        JsonRpcServer::new()
+            .register("add", add.recipient())
+            .register("reverse", reverse.recipient())
+            .register_handler("orders.cancel", orders.recipient(), "cancel")
+    }
+
+    #[test]
+    fn json_rpc_calls() {
+        let system = System::new("test");
+
+        let bodies = vec![
+            r#"{"jsonrpc": "2.0", "method": "add", "params": {"a": 1, "b": 2}, "id": 1}"#,
+            r#"{"jsonrpc": "2.0", "method": "reverse", "params": ["a", "b", "c"], "id": "r"}"#,
+            r#"{"jsonrpc": "2.0", "method": "add", "params": {"a": 1, "b": 2}}"#,
+            "{",
+            "[]",
+            r#"{"method": "add", "id": 1}"#,
+            r#"{"jsonrpc": "2.0", "method": "sub", "id": 1}"#,
+            r#"{"jsonrpc": "2.0", "method": "add", "params": 1, "id": 1}"#,
+            r#"[
+                {"jsonrpc": "2.0", "method": "add", "params": {"a": 1, "b": 1}, "id": 1},
+                {"jsonrpc": "2.0", "method": "add", "params": {"a": 1, "b": 1}},
+                {"jsonrpc": "2.0", "method": "sub", "id": 2},
+                1
+            ]"#,
+            r#"[{"jsonrpc": "2.0", "method": "add", "params": {"a": 1, "b": 1}}]"#,
+            r#"{"jsonrpc": "2.0", "method": "add", "params": {"a": 1, "b": 2}, "id": [1]}"#,
+            r#"{"jsonrpc": "2.0", "method": "orders.cancel", "params": {"order": 7}, "id": 1}"#,
+        ];
+
+        let server = server().start();
+        let calls: Vec<_> = bodies
+            .into_iter()
+            .map(|body| server.send(JsonRpcCall(body.to_string())))
+            .collect();
+        Arbiter::spawn(future::join_all(calls).map(|res| {
+            let res: Vec<Option<Value>> = res
+                .into_iter()
+                .map(|r| r.unwrap().map(|body| serde_json::from_str(&body).unwrap()))
+                .collect();
+            let error_code = |i: usize| res[i].as_ref().unwrap()["error"]["code"].clone();
+
+            assert_eq!(res[0], Some(json!({"jsonrpc": "2.0", "result": 3, "id": 1})));
+            assert_eq!(
+                res[1],
+                Some(json!({"jsonrpc": "2.0", "result": ["c", "b", "a"], "id": "r"}))
+            );
+            assert_eq!(res[2], None);
+            assert_eq!(error_code(3), json!(PARSE_ERROR));
+            assert_eq!(error_code(4), json!(INVALID_REQUEST));
+            assert_eq!(error_code(5), json!(INVALID_REQUEST));
+            assert_eq!(error_code(6), json!(METHOD_NOT_FOUND));
+            assert_eq!(error_code(7), json!(INVALID_PARAMS));
+
+            let batch = res[8].as_ref().unwrap().as_array().unwrap();
+            assert_eq!(batch.len(), 3);
+            assert_eq!(batch[0], json!({"jsonrpc": "2.0", "result": 2, "id": 1}));
+            assert_eq!(batch[1]["error"]["code"], json!(METHOD_NOT_FOUND));
+            assert_eq!(batch[2]["error"]["code"], json!(INVALID_REQUEST));
+            assert_eq!(res[9], None);
+            assert_eq!(
+                res[10],
+                Some(json!({
+                    "jsonrpc": "2.0",
+                    "error": { "code": INVALID_REQUEST, "message": "invalid request" },
+                    "id": null,
+                }))
+            );
+            assert_eq!(
+                res[11],
+                Some(json!({"jsonrpc": "2.0", "result": "cancel 7", "id": 1}))
+            );
+
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn json_rpc_tcp() {
+        let system = System::new("test");
+
+        let addr = "127.0.0.1:17320".parse().unwrap();
+        server().listen(&addr).unwrap();
+        let req = b"{\"jsonrpc\": \"2.0\", \"method\": \"add\", \"params\": {\"a\": 40, \"b\": 2}, \"id\": 7}\n";
+        let fut = TcpStream::connect(&addr)
+            .and_then(move |stream| write_all(stream, &req[..]))
+            .and_then(|(stream, _)| read_until(io::BufReader::new(stream), b'\n', vec![]))
+            .map(|(_, line)| {
+                let res: Value = serde_json::from_slice(&line).unwrap();
+                assert_eq!(res, json!({"jsonrpc": "2.0", "result": 42, "id": 7}));
+                System::current().stop();
+            })
+            .map_err(|e| println!("connection failed {}", e));
+        Arbiter::spawn(fut);
+
+        system.run();
+    }
+
+    #[test]
+    fn json_codec() {
+        let mut codec = JsonCodec::new(1024);
+        let mut buf = BytesMut::new();
+
+        // the responses are written one after another in the room made for the first
+        codec.encode(json!({"result": 1}), &mut buf).unwrap();
+        assert!(buf.capacity() >= 1024);
+        let (start, capacity) = (buf.as_ptr(), buf.capacity());
+        codec.encode(json!([1, 2]), &mut buf).unwrap();
+        assert_eq!((buf.as_ptr(), buf.capacity()), (start, capacity));
+
+        codec.encode(Value::String("x".repeat(4096)), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), r#"{"result":1}"#);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "[1,2]");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), 4098);
+    }
+}
@@ -32,19 +32,164 @@
 //! [`LuaActor`]: struct.LuaActor.html
 //! [`LuaActorBuilder`]: struct.LuaActorBuilder.html
 //! [`LuaMessage`]: enum.LuaMessage.html
+#[cfg(feature = "actix")]
 extern crate actix;
+#[cfg(feature = "broker")]
+extern crate actix_broker;
+extern crate bytes;
+extern crate futures;
+#[cfg(feature = "grpc")]
+extern crate futures_channel;
+#[cfg(feature = "grpc")]
+extern crate futures_util;
+#[cfg(feature = "grpc")]
+extern crate http;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "grpc")]
+extern crate prost;
 extern crate regex;
 extern crate rlua;
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "json")]
+#[cfg_attr(any(test, feature = "debugger", feature = "jsonrpc"), macro_use)]
+extern crate serde_json;
 extern crate tokio;
+#[cfg(feature = "grpc")]
+extern crate tonic;
+#[cfg(feature = "grpc")]
+extern crate tower_service;
 extern crate uuid;
 
 #[cfg(test)]
 extern crate futures_timer;
+#[cfg(all(test, feature = "json"))]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "grpc"))]
+extern crate tokio1;
 
+#[cfg(feature = "actix")]
 mod actor;
+#[cfg(feature = "actix")]
+mod address;
+#[cfg(feature = "actix")]
+mod ask;
 mod builder;
+mod bundle;
+#[cfg(any(feature = "toml", feature = "yaml"))]
+mod config;
+#[cfg(feature = "broker")]
+mod broker;
+#[cfg(feature = "actix")]
+mod bus;
+mod cancel;
+#[cfg(feature = "actix")]
+mod contract;
+#[cfg(feature = "debugger")]
+mod debugger;
+mod error;
+#[cfg(feature = "fennel")]
+mod fennel;
+mod gc;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "actix")]
+mod inspect;
+#[cfg(feature = "actix")]
+#[doc(hidden)]
+#[macro_use]
+pub mod handler;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "jsonrpc")]
+mod jsonrpc;
+mod lint;
+#[cfg(feature = "actix")]
+mod mailbox;
 mod message;
+mod metrics;
+#[cfg(feature = "moonscript")]
+mod moonscript;
+#[cfg(feature = "actix")]
+mod pool;
+#[cfg(feature = "actix")]
+mod profiler;
+#[cfg(feature = "actix")]
+mod registry;
+#[cfg(all(feature = "actix", feature = "json"))]
+mod schema;
+#[cfg(feature = "actix")]
+mod remote;
+#[cfg(feature = "actix")]
+mod repl;
+mod shared;
+#[cfg(feature = "actix")]
+mod spawner;
+#[cfg(feature = "task")]
+mod task;
+#[cfg(feature = "teal")]
+mod teal;
+#[cfg(feature = "actix")]
+mod tenant;
+#[cfg(feature = "actix")]
+mod version;
+mod vm;
+#[cfg(feature = "actix")]
+mod watchdog;
+#[cfg(feature = "actix")]
+mod worker;
 
-pub use actor::LuaActor;
+#[cfg(feature = "actix")]
+pub use actor::{Drain, LuaActor, LuaReply, Named};
+#[cfg(feature = "actix")]
+pub use ask::{Ask, LuaStream, SendStream, SendWithMeta};
 pub use builder::LuaActorBuilder;
-pub use message::LuaMessage;
+#[cfg(feature = "actix")]
+pub use builder::LuaActorTemplate;
+pub use bundle::LuaBundle;
+#[cfg(feature = "actix")]
+pub use bus::{Broadcast, JoinGroup, LeaveGroup, LuaBus, LuaGroup, Publish, Subscribe};
+#[cfg(feature = "actix")]
+pub use cancel::Cancellable;
+pub use cancel::CancelToken;
+#[cfg(feature = "debugger")]
+pub use debugger::LuaDebugger;
+pub use error::{ActixLuaError, AskFuture, ErrorPolicy, SchemaViolation};
+#[cfg(feature = "actix")]
+pub use gc::Gc;
+pub use gc::GcConfig;
+#[cfg(feature = "grpc")]
+pub use grpc::{lua_value, CallReply, CallRequest, GrpcServer, LuaActorService, LuaTable, LuaValue};
+#[cfg(feature = "actix")]
+pub use inspect::Inspect;
+#[cfg(feature = "jsonrpc")]
+pub use jsonrpc::{JsonRpcCall, JsonRpcServer};
+#[cfg(feature = "actix")]
+pub use mailbox::{LuaAddr, Queued};
+pub use message::{Hop, LuaMessage, OpaqueHandle, Optional, Tell, Values};
+pub use metrics::{InvocationCost, LuaActorMetrics};
+#[cfg(feature = "actix")]
+pub use pool::LuaActorPool;
+#[cfg(feature = "actix")]
+pub use profiler::{Flamegraph, Profile};
+#[cfg(feature = "actix")]
+pub use registry::{
+    DeleteTenant, LuaTenantRegistry, PutTenantScripts, TenantMessage, TenantScripts,
+};
+#[cfg(feature = "actix")]
+pub use repl::{Eval, LuaRepl};
+#[cfg(feature = "actix")]
+pub use remote::{Listen, LuaNode, RegisterActor, RemoteError, RemoteSend, SetBufferCapacity};
+pub use shared::LuaSharedState;
+#[cfg(feature = "actix")]
+pub use spawner::{ActorInfo, ListActors, LookupActor, LuaActorSpawner, SpawnActor};
+#[cfg(feature = "task")]
+pub use task::{LuaTask, LuaTaskHandle};
+#[cfg(feature = "actix")]
+pub use watchdog::{LookupWatched, LuaWatchdog, Watch, WatchdogAlert};
+#[cfg(feature = "actix")]
+pub use worker::LuaWorkerPool;
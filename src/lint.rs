@@ -0,0 +1,267 @@
+use rlua::{Error as LuaError, Function, Lua, Table, Value};
+
+use error::ActixLuaError;
+
+use std::collections::HashSet;
+
+// A strict-globals lint for hook scripts.
+//
+// Scripts are compiled and dumped with `string.dump`, then every global access is found in the
+// Lua 5.3 bytecode: `GETTABUP` and `SETTABUP` instructions on the `_ENV` upvalue with a
+// constant string key. This catches accesses in branches which never run in tests, and
+// doesn't execute the script.
+
+const OP_GETTABUP: u32 = 6;
+const OP_SETTABUP: u32 = 8;
+const BITRK: u32 = 1 << 8;
+
+/// A read or write of a global which isn't declared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndeclaredGlobal {
+    pub hook: String,
+    pub line: Option<usize>,
+    pub name: String,
+    pub write: bool,
+}
+
+impl From<UndeclaredGlobal> for ActixLuaError {
+    fn from(global: UndeclaredGlobal) -> Self {
+        let access = if global.write { "write to" } else { "read of" };
+        ActixLuaError::CompileError {
+            hook: global.hook,
+            line: global.line,
+            message: format!("{} undeclared global `{}`", access, global.name),
+        }
+    }
+}
+
+/// Find every access of a global not in `declared` in `script`.
+pub fn undeclared_globals(
+    vm: &Lua,
+    hook: &str,
+    script: &str,
+    declared: &HashSet<String>,
+) -> Result<Vec<UndeclaredGlobal>, LuaError> {
+    let f = vm.load(script, Some(hook))?;
+    let string: Table = vm.globals().get("string")?;
+    let dump: Function = string.get("dump")?;
+    let bytecode: ::rlua::String = dump.call(f)?;
+
+    let mut accesses = vec![];
+    let mut reader = Reader {
+        buf: bytecode.as_bytes(),
+        pos: 0,
+    };
+    reader
+        .chunk(&mut accesses)
+        .ok_or_else(|| LuaError::RuntimeError("unsupported Lua bytecode".to_string()))?;
+
+    Ok(accesses
+        .into_iter()
+        .filter(|(name, _, _)| !declared.contains(name))
+        .map(|(name, line, write)| UndeclaredGlobal {
+            hook: hook.to_string(),
+            line,
+            name,
+            write,
+        })
+        .collect())
+}
+
+/// The names of every global currently defined in `vm`.
+pub fn defined_globals(vm: &Lua) -> Result<HashSet<String>, LuaError> {
+    let mut names = HashSet::new();
+    for pair in vm.globals().pairs::<Value, Value>() {
+        if let (Value::String(name), _) = pair? {
+            names.insert(name.to_str()?.to_string());
+        }
+    }
+    Ok(names)
+}
+
+enum Constant {
+    String(String),
+    Other,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn int(&mut self) -> Option<u32> {
+        let b = self.bytes(4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn size(&mut self) -> Option<usize> {
+        let b = self.bytes(8)?;
+        let mut size = [0; 8];
+        size.copy_from_slice(b);
+        Some(u64::from_le_bytes(size) as usize)
+    }
+
+    fn string(&mut self) -> Option<Option<String>> {
+        let size = match self.byte()? {
+            0xff => self.size()?,
+            size => size as usize,
+        };
+        if size == 0 {
+            return Some(None);
+        }
+        let bytes = self.bytes(size - 1)?;
+        Some(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+
+    fn chunk(&mut self, accesses: &mut Vec<(String, Option<usize>, bool)>) -> Option<()> {
+        // signature, version 5.3, official format, LUAC_DATA
+        if self.bytes(12)? != b"\x1bLua\x53\x00\x19\x93\r\n\x1a\n" {
+            return None;
+        }
+        // sizes of int, size_t, Instruction, lua_Integer and lua_Number
+        if self.bytes(5)? != [4, 8, 4, 8, 8] {
+            return None;
+        }
+        // LUAC_INT and LUAC_NUM, used to check endianness
+        if self.bytes(8)? != 0x5678i64.to_le_bytes() {
+            return None;
+        }
+        self.bytes(8)?;
+        // number of upvalues of the main closure
+        self.byte()?;
+        self.function(accesses)
+    }
+
+    fn function(&mut self, accesses: &mut Vec<(String, Option<usize>, bool)>) -> Option<()> {
+        self.string()?; // source
+        self.int()?; // linedefined
+        self.int()?; // lastlinedefined
+        self.bytes(3)?; // numparams, is_vararg, maxstacksize
+
+        let code = (0..self.int()?)
+            .map(|_| self.int())
+            .collect::<Option<Vec<u32>>>()?;
+
+        let mut constants = vec![];
+        for _ in 0..self.int()? {
+            let constant = match self.byte()? {
+                0 => Constant::Other,
+                1 => {
+                    self.byte()?;
+                    Constant::Other
+                }
+                3 | 19 => {
+                    self.bytes(8)?;
+                    Constant::Other
+                }
+                4 | 20 => match self.string()? {
+                    Some(s) => Constant::String(s),
+                    None => Constant::Other,
+                },
+                _ => return None,
+            };
+            constants.push(constant);
+        }
+
+        let upvalues = self.int()?;
+        self.bytes(2 * upvalues as usize)?;
+
+        // nested functions come before the debug info of this one, so collect their
+        // accesses separately to keep the order of the script
+        let mut nested = vec![];
+        for _ in 0..self.int()? {
+            self.function(&mut nested)?;
+        }
+
+        let lines = (0..self.int()?)
+            .map(|_| self.int())
+            .collect::<Option<Vec<u32>>>()?;
+        for _ in 0..self.int()? {
+            self.string()?; // local name
+            self.int()?; // startpc
+            self.int()?; // endpc
+        }
+        let upvalue_names = (0..self.int()?)
+            .map(|_| self.string())
+            .collect::<Option<Vec<Option<String>>>>()?;
+        let is_env = |idx: u32| {
+            upvalue_names.get(idx as usize).and_then(|n| n.as_ref().map(|n| n == "_ENV"))
+                == Some(true)
+        };
+
+        for (pc, &i) in code.iter().enumerate() {
+            let op = i & 0x3f;
+            let a = (i >> 6) & 0xff;
+            let b = (i >> 23) & 0x1ff;
+            let c = (i >> 14) & 0x1ff;
+            let (env, key, write) = match op {
+                OP_GETTABUP => (b, c, false),
+                OP_SETTABUP => (a, b, true),
+                _ => continue,
+            };
+            if !is_env(env) || key & BITRK == 0 {
+                continue;
+            }
+            if let Some(Constant::String(name)) = constants.get((key & !BITRK) as usize) {
+                let line = lines.get(pc).map(|l| *l as usize);
+                accesses.push((name.clone(), line, write));
+            }
+        }
+        accesses.append(&mut nested);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_globals() {
+        let vm = Lua::new();
+        let declared = defined_globals(&vm).unwrap();
+
+        let script = r#"
+        local count = 0
+        print(string.format("%d", count))
+        totl = count + 1
+        local function helper()
+            return undefined_thing
+        end
+        return helper()
+        "#;
+        let res = undeclared_globals(&vm, "handle", script, &declared).unwrap();
+        assert_eq!(
+            res,
+            vec![
+                UndeclaredGlobal {
+                    hook: "handle".to_string(),
+                    line: Some(4),
+                    name: "totl".to_string(),
+                    write: true,
+                },
+                UndeclaredGlobal {
+                    hook: "handle".to_string(),
+                    line: Some(6),
+                    name: "undefined_thing".to_string(),
+                    write: false,
+                },
+            ]
+        );
+        assert_eq!(
+            ActixLuaError::from(res[0].clone()).to_string(),
+            "handle:4: write to undeclared global `totl`"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+local sethook, gethook, getinfo, getlocal, getupvalue, check, stop = ...
+local create = coroutine.create
+
+-- the step requested when the debugger last stopped: its `mode`, and the `thread` and `depth`
+-- of the function stepped from
+local step
+
+-- the number of frames in the stack of the running function
+local function stack_depth()
+    -- level 1 is `stack_depth`, level 2 the hook and level 3 the running function
+    local level = 3
+    while getinfo(level, "") do
+        level = level + 1
+    end
+    return level - 3
+end
+
+-- whether the step is done at a line run by `thread`, `depth` frames deep
+local function stepped(thread, depth)
+    if step.mode == "stepIn" or coroutine.status(step.thread) == "dead" then
+        return true
+    end
+    if thread ~= step.thread then
+        return false
+    end
+    if step.mode == "next" then
+        return depth <= step.depth
+    end
+    return depth < step.depth
+end
+
+-- the name and value of each variable in `scope`, whose tables are added to `refs`
+local function variables(refs, id)
+    local entry = refs[id]
+    local vars = {}
+    local function add(name, value)
+        local ok, text = pcall(tostring, value)
+        local var = { name = tostring(name), type = type(value), value = ok and text or "?" }
+        if type(value) == "string" then
+            var.value = string.format("%q", value)
+        elseif type(value) == "table" then
+            refs[#refs + 1] = { value = value }
+            var.ref = #refs
+        end
+        vars[#vars + 1] = var
+    end
+    if entry.scope then
+        for _, var in ipairs(entry.scope) do
+            add(var.name, var.value)
+        end
+    else
+        for key, value in pairs(entry.value) do
+            add(key, value)
+        end
+        table.sort(vars, function (a, b) return a.name < b.name end)
+    end
+    return vars
+end
+
+-- the Lua functions in the stack of the running function, with the ids of their locals and
+-- upvalues in `refs`
+local function snapshot(refs)
+    local frames = {}
+    local function ref(scope)
+        refs[#refs + 1] = { scope = scope }
+        return #refs
+    end
+    -- level 1 is `snapshot`, level 2 the hook and level 3 the running function
+    local level = 3
+    while true do
+        local info = getinfo(level, "nSlf")
+        if not info then
+            break
+        end
+        if info.what ~= "C" then
+            local locals, upvalues = {}, {}
+            local i = 1
+            while true do
+                local name, value = getlocal(level, i)
+                if not name then
+                    break
+                end
+                -- leave out the internal locals such as `(for index)`
+                if name:sub(1, 1) ~= "(" then
+                    locals[#locals + 1] = { name = name, value = value }
+                end
+                i = i + 1
+            end
+            i = 1
+            while true do
+                local name, value = getupvalue(info.func, i)
+                if not name then
+                    break
+                end
+                upvalues[#upvalues + 1] = { name = name, value = value }
+                i = i + 1
+            end
+            frames[#frames + 1] = {
+                name = info.name or (info.what == "main" and "main chunk") or "?",
+                source = info.source,
+                line = info.currentline,
+                locals = ref(locals),
+                upvalues = ref(upvalues),
+            }
+        end
+        level = level + 1
+    end
+    return frames
+end
+
+-- debug `thread`, keeping the hook it has to count instructions
+local function attach(thread)
+    local prev, mask, count = gethook(thread)
+    local function hook(event, line)
+        if event ~= "line" then
+            return prev(event, line)
+        end
+        local running = coroutine.running()
+        local reason = step and stepped(running, stack_depth()) and "step"
+        reason = reason or check(getinfo(2, "S").source, line)
+        if not reason then
+            return
+        end
+        local refs = {}
+        local frames = snapshot(refs)
+        local mode = stop(reason, frames, function (id) return variables(refs, id) end)
+        if mode == "continue" then
+            step = nil
+        else
+            step = { mode = mode, thread = running, depth = stack_depth() }
+        end
+    end
+    sethook(thread, hook, "l" .. (mask or ""), count or 0)
+end
+
+coroutine.create = function (f)
+    local thread = create(f)
+    attach(thread)
+    return thread
+end
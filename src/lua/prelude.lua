@@ -1,19 +1,167 @@
+local debug_traceback, sethook, gethook, cancelled, check_cancelled = ...
+local dump = string.dump
+
 __threads = {}
 __thread_id_seq = 0
 __scripts = {}
+-- the lines in the sources of hooks compiled to Lua, by hook and line of the Lua code
+__line_maps = {}
+
+-- the traceback of `thread`, with the lines of compiled hooks translated to their sources
+local function traceback(thread, message)
+    local trace = debug_traceback(thread, message)
+    return (trace:gsub('%[string "([%w_]+)"%]:(%d+):', function (hook, line)
+        local map = __line_maps[hook]
+        local source_line = map and map[tonumber(line)]
+        if source_line then
+            return '[string "' .. hook .. '"]:' .. source_line .. ':'
+        end
+    end))
+end
 
 ctx = { state = {} }
 
-function __load(script, name)
-    local f, err = load(script, name, "bt")
-    if f == nil then
-        error(err)
+-- the actors spawned by `LuaActorSpawner`, if this actor is one of them
+ctx.actors = function ()
+    if __actors then
+        return __actors()
+    end
+    return {}
+end
+
+-- the values kept with `ctx.stash`, by id
+local stash, stash_seq = {}, 0
+
+ctx.stash = function (value)
+    stash_seq = stash_seq + 1
+    stash[stash_seq] = value
+    return stash_seq
+end
+
+ctx.take = function (id)
+    local value = stash[id]
+    stash[id] = nil
+    return value
+end
+
+-- set by the actor before every run of a hook
+__handled_since_idle = 0
+
+ctx.handled_since_idle = function ()
+    return __handled_since_idle
+end
+
+__mailbox_len = 0
+
+ctx.mailbox_len = function ()
+    return __mailbox_len
+end
+
+-- the contracts declared with `ctx.contract`, of every message and by topic
+__contracts = { topics = {} }
+
+local contract_types = {
+    any = true,
+    array = true,
+    boolean = true,
+    integer = true,
+    number = true,
+    string = true,
+    table = true,
+}
+
+-- the path and the type of the first unknown type in `spec`
+local function unknown_type(spec, path)
+    if type(spec) == "table" then
+        for field, field_spec in pairs(spec) do
+            local field_path, t = unknown_type(field_spec, path .. "/" .. tostring(field))
+            if field_path then
+                return field_path, t
+            end
+        end
+    elseif type(spec) ~= "string" or not contract_types[spec:gsub("%?$", "")] then
+        return path, tostring(spec)
+    end
+end
+
+ctx.contract = function (topic, spec)
+    if spec == nil then
+        topic, spec = nil, topic
+    end
+    local path, t = unknown_type(spec, "")
+    if path then
+        local at = path ~= "" and " at `" .. path .. "`" or ""
+        error("unknown type `" .. t .. "` in contract" .. at, 2)
+    end
+    if topic == nil then
+        __contracts.all = spec
+    else
+        __contracts.topics[topic] = spec
+    end
+end
+
+-- the number of actors the message being handled was passed through
+local hops = 0
+-- the stream of the message being handled, if it was sent with `send_stream`
+local stream = nil
+-- the token of the message being handled, if it was sent as a `Cancellable`
+local cancel = nil
+-- the instructions between two checks of the token of a running thread
+local cancel_step = 1000
+
+ctx.cancelled = function ()
+    return cancel ~= nil and cancelled(cancel)
+end
+
+-- raise an error in `thread` once `token` is cancelled, keeping the hook it has to count
+-- instructions, profile or debug
+local function watch_cancel(thread, token)
+    local prev, mask, count = gethook(thread)
+    local function hook(event, line)
+        if event == "count" then
+            check_cancelled(token)
+            if count > 0 then
+                return prev(event, line)
+            end
+        elseif prev then
+            return prev(event, line)
+        end
+    end
+    sethook(thread, hook, mask, count > 0 and count or cancel_step)
+end
+
+-- the error a thread is resumed with once its token is cancelled
+local function cancel_error(token)
+    local _, err = pcall(check_cancelled, token)
+    return err
+end
+
+-- return the result of a coroutine, or nil and the traceback of its error
+local function result(thread, ok, ret)
+    if ok then
+        return ret
+    end
+    -- errors and panics of rust functions are passed on to rust as they are
+    if type(ret) == "userdata" then
+        error(ret, 0)
+    end
+    return nil, traceback(thread, tostring(ret))
+end
+
+-- end the stream of a thread which is done with its result
+local function end_thread_stream(thread, ok, ret)
+    if stream == nil then
+        return
+    end
+    if ok then
+        end_stream(stream, ret)
+    else
+        end_stream(stream, nil, traceback(thread, tostring(ret)))
     end
-    __scripts[name] = f
 end
 
 -- create a new coroutine from given script
-function __run(script_name, msg, thread_id)
+function __run(script_name, msg, topic, msg_hops, meta, msg_stream, all_values, msg_cancel)
     ctx.thread_id = __thread_id_seq
     __thread_id_seq = __thread_id_seq + 1
 
@@ -22,37 +170,126 @@ function __run(script_name, msg, thread_id)
     ctx.new_actor = function (path)
         return __new_actor(path)
     end
-    ctx.send = function (recipient_name, msg)
-        send(recipient_name, msg, ctx.thread_id)
-        return coroutine.yield("__suspended__" .. ctx.thread_id)
+    ctx.send = function (recipient_name, msg, meta)
+        send(recipient_name, msg, ctx.thread_id, hops + 1, meta)
+        local result, err = coroutine.yield("__suspended__" .. ctx.thread_id)
+        if err then
+            error(err, 2)
+        end
+        return result
+    end
+    ctx.do_send = function (recipient_name, msg, meta)
+        do_send(recipient_name, msg, hops + 1, meta)
+    end
+    ctx.spawn_blocking = function (f, arg)
+        spawn_blocking(dump(f), arg, ctx.thread_id)
+        local result, err = coroutine.yield("__suspended__" .. ctx.thread_id)
+        if err then
+            error(err, 2)
+        end
+        return result
+    end
+    ctx.emit = function (value)
+        if stream == nil then
+            error("the message wasn't sent with `send_stream`", 2)
+        end
+        return emit(stream, value)
+    end
+    ctx.reply_later = function ()
+        return reply_later()
+    end
+    ctx.reply = function (token, msg)
+        return reply(token, msg)
     end
-    ctx.do_send = do_send
     ctx.terminate = terminate
+    ctx.subscribe = subscribe
+    ctx.publish = publish
+    ctx.join = join
+    ctx.broadcast = broadcast
+    ctx.broker_issue = __broker_issue
 
     ctx.msg = msg
+    ctx.meta = meta or {}
+    ctx.topic = topic
+    hops = msg_hops or 0
+    stream = msg_stream
+    cancel = msg_cancel
 
     local thread = coroutine.create(__scripts[script_name])
+    if cancel ~= nil then
+        watch_cancel(thread, cancel)
+    end
 
-    local ok, ret = coroutine.resume(thread)
+    local res = table.pack(coroutine.resume(thread, msg, ctx.meta))
+    local ok, ret = res[1], res[2]
     -- save the thread and its context if the thread yielded
     if coroutine.status(thread) == "suspended" then
-        __threads[ctx.thread_id] = { thread = thread, msg = msg }
+        __threads[ctx.thread_id] = {
+            thread = thread,
+            msg = msg,
+            meta = ctx.meta,
+            topic = topic,
+            hops = hops,
+            stream = stream,
+            cancel = cancel,
+        }
+    else
+        end_thread_stream(thread, ok, ret)
     end
     ctx.msg = nil
+    ctx.meta = nil
+    ctx.topic = nil
     ctx.thread_id = nil
-    return ret
+    hops = 0
+    stream = nil
+    cancel = nil
+    -- every value the thread returned, for `Values`
+    if all_values and ok and coroutine.status(thread) == "dead" then
+        return table.pack(table.unpack(res, 2, res.n))
+    end
+    return result(thread, ok, ret)
+end
+
+-- run the `handle_batch` hook with the array of queued messages, followed by their metadata
+function __run_batch(msg_hops, n, ...)
+    local args = table.pack(...)
+    local msgs, metas = { n = n }, {}
+    for i = 1, n do
+        msgs[i] = args[i]
+        metas[i] = args[n + i] or {}
+    end
+    return __run("handle_batch", msgs, nil, msg_hops, metas)
 end
 
 -- resume a existing coroutine
-function __resume(thread_id, args)
+function __resume(thread_id, args, err)
     local thread = __threads[thread_id]
+    -- the thread may have died from an error while a response was still in flight
+    if thread == nil then
+        return nil
+    end
     ctx.thread_id = thread_id
     ctx.msg = thread.msg
-    local ok, ret = coroutine.resume(thread.thread, args)
+    ctx.meta = thread.meta
+    ctx.topic = thread.topic
+    hops = thread.hops
+    stream = thread.stream
+    cancel = thread.cancel
+    -- `ctx.send` and `ctx.spawn_blocking` raise the error
+    if cancel ~= nil and cancelled(cancel) then
+        args, err = nil, cancel_error(cancel)
+    end
+    local ok, ret = coroutine.resume(thread.thread, args, err)
     if coroutine.status(thread.thread) == "dead" then
         __threads[ctx.thread_id] = nil
+        end_thread_stream(thread.thread, ok, ret)
     end
     ctx.msg = nil
+    ctx.meta = nil
+    ctx.topic = nil
     ctx.thread_id = nil
-    return ret
+    hops = 0
+    stream = nil
+    cancel = nil
+    return result(thread.thread, ok, ret)
 end
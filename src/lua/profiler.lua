@@ -0,0 +1,160 @@
+local sethook, gethook, getinfo, clock = ...
+local create = coroutine.create
+local main = coroutine.running()
+
+local enabled = false
+-- the statistics of the functions called, by function
+local functions = {}
+-- the self time spent in each stack of calls, by the names of the frames joined with `;`
+local stacks = {}
+
+-- the frames of the functions called in a thread and not returned yet, and their number by
+-- function, so recursive calls add their time once
+local function new_stack()
+    return { frames = {}, active = {} }
+end
+
+local function enter(stack, now)
+    -- level 1 is `enter`, level 2 the hook and level 3 the function called
+    local info = getinfo(3, "fnS")
+    local stats = functions[info.func]
+    if not stats then
+        stats = {
+            name = info.name or (info.what == "main" and "main chunk") or "?",
+            source = info.short_src,
+            line = info.linedefined,
+            calls = 0,
+            total = 0,
+            self = 0,
+        }
+        stats.frame = stats.name .. " " .. stats.source .. ":" .. stats.line
+        functions[info.func] = stats
+    end
+    stats.calls = stats.calls + 1
+    stack.active[stats] = (stack.active[stats] or 0) + 1
+    local frames = stack.frames
+    local parent = frames[#frames]
+    frames[#frames + 1] = {
+        func = info.func,
+        stats = stats,
+        path = parent and parent.path .. ";" .. stats.frame or stats.frame,
+        start = now,
+        children = 0,
+    }
+end
+
+local function pop(stack, now)
+    local frames = stack.frames
+    local frame = table.remove(frames)
+    local stats, elapsed = frame.stats, now - frame.start
+    stack.active[stats] = stack.active[stats] - 1
+    if stack.active[stats] == 0 then
+        stats.total = stats.total + elapsed
+    end
+    stats.self = stats.self + elapsed - frame.children
+    stacks[frame.path] = (stacks[frame.path] or 0) + elapsed - frame.children
+    local parent = frames[#frames]
+    if parent then
+        parent.children = parent.children + elapsed
+    end
+    return frame
+end
+
+local function leave(stack, now)
+    local func = getinfo(3, "f").func
+    local frames = stack.frames
+    for i = #frames, 1, -1 do
+        if frames[i].func == func then
+            -- the functions above didn't return, they raised an error caught below
+            while pop(stack, now).func ~= func do end
+            return
+        end
+    end
+    -- the function was called before the profile started
+end
+
+-- profile `thread`, keeping the hook it has to count instructions or debug
+local function attach(thread)
+    local prev, mask, count = gethook(thread)
+    local stack = new_stack()
+    local function hook(event, line)
+        if event == "count" or event == "line" then
+            return prev(event, line)
+        end
+        if not enabled then
+            if prev then
+                sethook(prev, mask, count)
+            else
+                sethook()
+            end
+            return
+        end
+        local now = clock()
+        if event == "return" then
+            leave(stack, now)
+        else
+            -- the caller of a tail call doesn't return
+            if event == "tail call" and #stack.frames > 0 then
+                pop(stack, now)
+            end
+            enter(stack, now)
+        end
+    end
+    sethook(thread, hook, "cr" .. (mask or ""), count or 0)
+end
+
+coroutine.create = function (f)
+    local thread = create(f)
+    if enabled then
+        attach(thread)
+    end
+    return thread
+end
+
+local function report()
+    local list = {}
+    for _, stats in pairs(functions) do
+        list[#list + 1] = {
+            name = stats.name,
+            source = stats.source,
+            line = stats.line,
+            calls = stats.calls,
+            total = stats.total,
+            self = stats.self,
+        }
+    end
+    table.sort(list, function (a, b) return a.total > b.total end)
+    return list
+end
+
+-- the stacks in the collapsed format of flamegraph.pl, with their self time in microseconds
+local function collapsed()
+    local lines = {}
+    for path, time in pairs(stacks) do
+        local micros = math.floor(time * 1e6 + 0.5)
+        if micros > 0 then
+            lines[#lines + 1] = path .. " " .. micros
+        end
+    end
+    table.sort(lines)
+    return table.concat(lines, "\n")
+end
+
+return {
+    start = function ()
+        functions = {}
+        stacks = {}
+        if not enabled then
+            enabled = true
+            attach(main)
+        end
+        return report()
+    end,
+    stop = function ()
+        -- the hook of the main thread is removed when this returns
+        enabled = false
+        return report()
+    end,
+    report = report,
+    collapsed = collapsed,
+}
@@ -0,0 +1,6 @@
+-- the vendored libraries of the bundle tests
+return {
+    greet = { version = "1.0.0", path = "vendor/greet.lua", hash = "fcae16e925379998" },
+    ["text.upper"] = { version = "0.2.1", path = "vendor/text/upper.lua", hash = "12bc907a30123039" },
+    unused = { version = "2.0.0", path = "vendor/unused.lua", hash = "37634a222171f3e5" },
+}
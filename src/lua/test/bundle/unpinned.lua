@@ -0,0 +1,4 @@
+-- a manifest whose hash of `greet` is out of date
+return {
+    greet = { version = "1.1.0", path = "vendor/greet.lua", hash = "0000000000000000" },
+}
@@ -0,0 +1,23 @@
+-- a stand-in for `fennel.lua` in tests, which only compiles `(return <lua expression>)` forms
+local fennel = {}
+
+function fennel.compileString(source, options)
+    local lines = {}
+    local n = 0
+    for line in (source .. "\n"):gmatch("(.-)\n") do
+        n = n + 1
+        local expr = line:match("^%s*%(return (.*)%)%s*$")
+        if expr then
+            lines[n] = "return " .. expr
+        elseif line:match("^%s*;") or line:match("^%s*$") then
+            -- keep the lines, like `correlate`
+            lines[n] = ""
+        else
+            local form = line:match("^%s*%(([^%s%)]*)") or line
+            error(options.filename .. ":" .. n .. ":0: Compile error: unknown form `" .. form .. "`", 0)
+        end
+    end
+    return table.concat(lines, "\n")
+end
+
+return fennel
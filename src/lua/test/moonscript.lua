@@ -0,0 +1,22 @@
+-- a stand-in for MoonScript in tests, which passes Lua code through without its comments and
+-- blank lines, and fails on lines starting with `!`
+local moonscript = {}
+
+function moonscript.to_lua(source)
+    local lines, positions = {}, {}
+    local n, pos = 0, 1
+    for line in (source .. "\n"):gmatch("(.-)\n") do
+        n = n + 1
+        if line:match("^!") then
+            return nil, "Failed to parse:\n [" .. n .. "] >>    " .. line
+        end
+        if not line:match("^%s*%-%-") and not line:match("^%s*$") then
+            lines[#lines + 1] = line
+            positions[#lines] = pos
+        end
+        pos = pos + #line + 1
+    end
+    return table.concat(lines, "\n"), positions
+end
+
+return moonscript
@@ -0,0 +1,5 @@
+local validate = require("lib.validate")
+local format = require("lib.format")
+
+validate.order(ctx.msg)
+return format.total(ctx.msg)
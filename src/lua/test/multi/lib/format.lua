@@ -0,0 +1,7 @@
+local format = {}
+
+function format.total(order)
+    return string.format("%.2f", order.total)
+end
+
+return format
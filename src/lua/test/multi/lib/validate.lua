@@ -0,0 +1,9 @@
+local validate = {}
+
+function validate.order(order)
+    if order.total < 0 then
+        error("negative total")
+    end
+end
+
+return validate
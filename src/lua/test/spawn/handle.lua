@@ -0,0 +1,13 @@
+if ctx.msg == "die" then
+    ctx.terminate()
+    return
+end
+if ctx.msg == "actors" then
+    local names = {}
+    for _, actor in ipairs(ctx.actors()) do
+        names[#names + 1] = actor.name .. ":" .. actor.requests
+    end
+    return table.concat(names, ",")
+end
+ctx.state.n = ctx.state.n + 1
+return arg[1] .. ctx.state.n
@@ -0,0 +1 @@
+ctx.state.n = 0
@@ -0,0 +1 @@
+ctx.terminate()
@@ -0,0 +1,38 @@
+-- a stand-in for `tl.lua` in tests, which only checks the types of literals in annotated local
+-- declarations, and fails to parse lines starting with `!`
+local tl = {}
+
+local function literal_type(value)
+    if value:match('^"') then
+        return "string"
+    elseif value:match("^%d+$") then
+        return "integer"
+    end
+end
+
+function tl.gen(source)
+    local result = { syntax_errors = {}, type_errors = {} }
+    local lines = {}
+    local n = 0
+    for line in (source .. "\n"):gmatch("(.-)\n") do
+        n = n + 1
+        local name, type, value = line:match("^local (%w+)%s*:%s*(%w+)%s*=%s*(.-)%s*$")
+        if line:match("^!") then
+            table.insert(result.syntax_errors, { y = n, x = 1, msg = "syntax error" })
+        elseif name then
+            local got = literal_type(value)
+            if got and got ~= type then
+                table.insert(result.type_errors, {
+                    y = n,
+                    x = line:find(value, 1, true),
+                    msg = "in local declaration: " .. name .. ": got " .. got .. ", expected " .. type,
+                })
+            end
+            line = "local " .. name .. " = " .. value
+        end
+        lines[n] = line
+    end
+    return table.concat(lines, "\n"), result
+end
+
+return tl
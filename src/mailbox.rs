@@ -0,0 +1,149 @@
+use actix::dev::Request;
+use actix::prelude::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actor::{LuaActor, Named};
+use cancel::Cancellable;
+use message::{Hop, LuaMessage, Optional, Tell, Values};
+
+/// The address of a started `LuaActor` which counts the messages sent through it until the
+/// actor handles them, for the scripts to read with `ctx.mailbox_len()`.
+///
+/// Messages sent through the `Addr` of the actor, e.g. by other actors, aren't counted, so the
+/// count is a lower bound of the backlog when the actor is also reached otherwise.
+///
+/// ```rust,ignore
+/// let addr = LuaAddr::start(
+///     LuaActorBuilder::new()
+///         .on_handle_with_lua("if ctx.mailbox_len() > 100 then return 'busy' end")
+///         .build()?,
+/// );
+/// addr.do_send(Tell(LuaMessage::from("work")));
+/// ```
+#[derive(Clone)]
+pub struct LuaAddr {
+    addr: Addr<LuaActor>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// A message sent through a `LuaAddr`, handled like `M`.
+pub struct Queued<M> {
+    msg: M,
+    counted: Counted,
+}
+
+// a message counted by a `LuaAddr`, until it is handled or dropped unhandled
+struct Counted(Arc<AtomicUsize>);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LuaAddr {
+    /// start `actor` in the current arbiter.
+    pub fn start(actor: LuaActor) -> LuaAddr {
+        let queued = actor.queued.clone();
+        LuaAddr {
+            addr: actor.start(),
+            queued,
+        }
+    }
+
+    /// the address of the actor, whose messages aren't counted
+    pub fn addr(&self) -> &Addr<LuaActor> {
+        &self.addr
+    }
+
+    /// the messages sent and not handled yet
+    pub fn mailbox_len(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// send `msg` like `Addr::send`, counting it until it is handled
+    pub fn send<M>(&self, msg: M) -> Request<LuaActor, Queued<M>>
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        LuaActor: Handler<Queued<M>>,
+    {
+        self.addr.send(self.queue(msg))
+    }
+
+    /// send `msg` like `Addr::do_send`, counting it until it is handled
+    pub fn do_send<M>(&self, msg: M)
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        LuaActor: Handler<Queued<M>>,
+    {
+        self.addr.do_send(self.queue(msg))
+    }
+
+    fn queue<M>(&self, msg: M) -> Queued<M> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Queued {
+            msg,
+            counted: Counted(self.queued.clone()),
+        }
+    }
+}
+
+impl<M: Message> Message for Queued<M> {
+    type Result = M::Result;
+}
+
+macro_rules! impl_queued_handler {
+    ($($msg:ty),*) => {$(
+        impl Handler<Queued<$msg>> for LuaActor {
+            type Result = <LuaActor as Handler<$msg>>::Result;
+
+            fn handle(&mut self, queued: Queued<$msg>, ctx: &mut Context<Self>) -> Self::Result {
+                // the message leaves the count as soon as the actor takes it
+                let Queued { msg, counted } = queued;
+                drop(counted);
+                <LuaActor as Handler<$msg>>::handle(self, msg, ctx)
+            }
+        }
+    )*};
+}
+
+impl_queued_handler!(LuaMessage, Hop, Tell, Values, Optional, Named, Cancellable);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures::{future, Future};
+
+    #[test]
+    fn mailbox_len() {
+        let system = System::new("test");
+
+        let addr = LuaAddr::start(
+            LuaActorBuilder::new()
+                .on_handle_with_lua("return ctx.mailbox_len()")
+                .build()
+                .unwrap(),
+        );
+
+        // the messages are all queued before the actor handles the first one
+        addr.do_send(Tell(LuaMessage::Nil));
+        let sends: Vec<_> = (0..4).map(|_| addr.send(LuaMessage::Nil)).collect();
+        assert_eq!(addr.mailbox_len(), 5);
+        let l = future::join_all(sends).and_then(move |queued| {
+            assert_eq!(queued, (0..4).rev().map(LuaMessage::from).collect::<Vec<_>>());
+            assert_eq!(addr.mailbox_len(), 0);
+            addr.send(LuaMessage::Nil)
+        });
+        Arbiter::spawn(l.map(|queued| {
+            assert_eq!(queued, LuaMessage::from(0));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
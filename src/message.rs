@@ -1,10 +1,22 @@
+#[cfg(feature = "actix")]
 use actix::dev::{MessageResponse, ResponseChannel};
+#[cfg(feature = "actix")]
 use actix::prelude::*;
-use regex::Regex;
+use bytes::Bytes;
 use rlua::Result as LuaResult;
-use rlua::{FromLua, Lua, ToLua, Value};
+use rlua::{FromLua, Lua, RegistryKey, String as LuaString, Table, ToLua, UserData, Value};
 
+use std::any::Any;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+#[cfg(feature = "actix")]
+use std::mem;
+use std::str;
+use std::sync::Arc;
+
+use error::ActixLuaError;
+use shared::SharedTable;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum LuaMessage {
@@ -14,9 +26,88 @@ pub enum LuaMessage {
     Boolean(bool),
     Nil,
     Table(HashMap<String, LuaMessage>),
+    /// A byte string. Clones share the buffer, which makes large payloads cheap to send to
+    /// many actors. Lua strings which aren't valid UTF-8 are converted to it.
+    Bytes(Bytes),
     ThreadYield(String),
+    /// The reply of a handler which raised an error.
+    Error(ActixLuaError),
+    /// A Rust value passed through scripts untouched, see `OpaqueHandle`.
+    Opaque(OpaqueHandle),
+}
+
+/// A Rust value, like a connection or a session, handed to scripts without being converted.
+///
+/// Scripts get it as a userdata without methods. They can store it in tables and send or
+/// return it, and Rust gets back the same value. Clones share the value, and handles are equal
+/// if they share it.
+///
+/// ```rust,ignore
+/// let session = LuaMessage::opaque(Session::new(user));
+/// let reply = addr.send(session).wait()?;
+/// let session = OpaqueHandle::try_from(reply)?;
+/// let user = &session.downcast_ref::<Session>().unwrap().user;
+/// ```
+#[derive(Clone)]
+pub struct OpaqueHandle(Arc<dyn Any + Send + Sync>);
+
+impl OpaqueHandle {
+    pub fn new<T: Any + Send + Sync>(value: T) -> Self {
+        OpaqueHandle(Arc::new(value))
+    }
+
+    /// The value, if it is a `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+}
+
+impl PartialEq for OpaqueHandle {
+    fn eq(&self, other: &OpaqueHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
+impl fmt::Debug for OpaqueHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OpaqueHandle({:p})", Arc::as_ptr(&self.0) as *const u8)
+    }
+}
+
+impl UserData for OpaqueHandle {}
+
+impl LuaMessage {
+    /// Wrap `value` in a `LuaMessage::Opaque`.
+    pub fn opaque<T: Any + Send + Sync>(value: T) -> Self {
+        LuaMessage::Opaque(OpaqueHandle::new(value))
+    }
+
+    /// A rough estimate of the memory the message takes once converted to Lua, in bytes.
+    pub fn estimated_size(&self) -> usize {
+        // sizes of a value, and of the headers of a string and a table in Lua 5.3
+        const VALUE: usize = 16;
+        const STRING: usize = 24;
+        const TABLE: usize = 56;
+        match self {
+            LuaMessage::String(s) | LuaMessage::ThreadYield(s) => VALUE + STRING + s.len(),
+            LuaMessage::Bytes(b) => VALUE + STRING + b.len(),
+            LuaMessage::Table(t) => t.iter().fold(VALUE + TABLE, |size, (k, v)| {
+                size + VALUE + STRING + k.len() + v.estimated_size()
+            }),
+            _ => VALUE,
+        }
+    }
+
+    /// How many tables deep the message is nested, 0 for a message which isn't a table.
+    pub fn depth(&self) -> usize {
+        match self {
+            LuaMessage::Table(t) => 1 + t.values().map(LuaMessage::depth).max().unwrap_or(0),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
 impl<A, M> MessageResponse<A, M> for LuaMessage
 where
     A: Actor,
@@ -29,10 +120,106 @@ where
     }
 }
 
+#[cfg(feature = "actix")]
 impl Message for LuaMessage {
     type Result = LuaMessage;
 }
 
+/// A `LuaMessage` with the number of actors it was passed through, sent between `LuaActor`s.
+///
+/// Each `ctx.send` and `ctx.do_send` to another `LuaActor` adds a hop. Actors reject messages
+/// with more hops than their limit, so a loop of sends fails instead of running forever.
+/// A `LuaMessage` sent from Rust has no hops.
+///
+/// `meta` carries metadata of the message apart from its payload, like trace ids or auth
+/// claims. Scripts get it as `ctx.meta`, see `SendWithMeta`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hop {
+    pub msg: LuaMessage,
+    pub hops: usize,
+    pub meta: HashMap<String, LuaMessage>,
+}
+
+impl Hop {
+    // the metadata as passed to the hooks, `nil` if there is none
+    #[cfg(feature = "actix")]
+    pub(crate) fn meta_message(&mut self) -> LuaMessage {
+        if self.meta.is_empty() {
+            LuaMessage::Nil
+        } else {
+            LuaMessage::Table(mem::take(&mut self.meta))
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
+impl Message for Hop {
+    type Result = LuaMessage;
+}
+
+impl From<LuaMessage> for Hop {
+    fn from(msg: LuaMessage) -> Self {
+        Hop {
+            msg,
+            hops: 0,
+            meta: HashMap::new(),
+        }
+    }
+}
+
+/// A `LuaMessage` sent to a `LuaActor` without waiting for a reply.
+///
+/// Send it with `Addr::do_send`, which doesn't wait for room in the mailbox either. Sending a
+/// `LuaMessage` with `Addr::send` waits for both, so busy actors slow their callers down.
+/// Actors can refuse `Tell`s with `LuaActorBuilder::accept_tell`, and `LuaActorMetrics` counts
+/// them apart from requests.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Tell(pub LuaMessage);
+
+#[cfg(feature = "actix")]
+impl Message for Tell {
+    type Result = ();
+}
+
+/// A `LuaMessage` whose reply is every value the `handle` hook returns.
+///
+/// `return a, b` replies `[a, b]` and a bare `return` an empty `Vec`. A reply sent with
+/// `ctx.reply` is a single value, errors are replied as a single `LuaMessage::Error`. The
+/// message skips the batch of a `handle_batch` hook.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Values(pub LuaMessage);
+
+#[cfg(feature = "actix")]
+impl Message for Values {
+    type Result = Vec<LuaMessage>;
+}
+
+/// A `LuaMessage` whose reply is `None` if the script returns `nil`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Optional(pub LuaMessage);
+
+#[cfg(feature = "actix")]
+impl Message for Optional {
+    type Result = Option<LuaMessage>;
+}
+
+// the values packed by the prelude with `table.pack`
+#[cfg(feature = "actix")]
+pub(crate) fn unpack_values(msg: LuaMessage) -> Vec<LuaMessage> {
+    match msg {
+        LuaMessage::Table(mut t) => {
+            let n = match t.remove("n") {
+                Some(LuaMessage::Integer(n)) => n.max(0),
+                _ => 0,
+            };
+            (1..=n)
+                .map(|i| t.remove(&i.to_string()).unwrap_or(LuaMessage::Nil))
+                .collect()
+        }
+        msg => vec![msg],
+    }
+}
+
 impl From<bool> for LuaMessage {
     fn from(s: bool) -> Self {
         LuaMessage::Boolean(s)
@@ -81,6 +268,18 @@ impl From<isize> for LuaMessage {
     }
 }
 
+impl From<Bytes> for LuaMessage {
+    fn from(b: Bytes) -> Self {
+        LuaMessage::Bytes(b)
+    }
+}
+
+impl From<Vec<u8>> for LuaMessage {
+    fn from(b: Vec<u8>) -> Self {
+        LuaMessage::Bytes(Bytes::from(b))
+    }
+}
+
 impl From<HashMap<String, LuaMessage>> for LuaMessage {
     fn from(s: HashMap<String, LuaMessage>) -> Self {
         LuaMessage::Table(s)
@@ -100,26 +299,174 @@ macro_rules! lua_message_convert_float {
 lua_message_convert_float!(f32);
 lua_message_convert_float!(f64);
 
+// The conversions back fail with `ActixLuaError::ConversionError` on a message of another
+// type, and with the error itself on `LuaMessage::Error`.
+macro_rules! lua_message_try_convert {
+    ($x:ty, $name:expr, $($pat:pat => $res:expr),+) => {
+        impl TryFrom<LuaMessage> for $x {
+            type Error = ActixLuaError;
+
+            fn try_from(msg: LuaMessage) -> Result<Self, ActixLuaError> {
+                match msg {
+                    $($pat => Ok($res),)+
+                    LuaMessage::Error(e) => Err(e),
+                    msg => Err(ActixLuaError::ConversionError {
+                        message: format!("expected {}, got {:?}", $name, msg),
+                    }),
+                }
+            }
+        }
+    };
+}
+
+lua_message_try_convert!(bool, "a boolean", LuaMessage::Boolean(b) => b);
+lua_message_try_convert!(i64, "an integer", LuaMessage::Integer(i) => i);
+lua_message_try_convert!(
+    f64,
+    "a number",
+    LuaMessage::Number(n) => n,
+    LuaMessage::Integer(i) => i as f64
+);
+lua_message_try_convert!(String, "a string", LuaMessage::String(s) => s);
+lua_message_try_convert!(
+    Bytes,
+    "a string",
+    LuaMessage::Bytes(b) => b,
+    LuaMessage::String(s) => Bytes::from(s)
+);
+lua_message_try_convert!(
+    HashMap<String, LuaMessage>,
+    "a table",
+    LuaMessage::Table(t) => t
+);
+lua_message_try_convert!(OpaqueHandle, "an opaque value", LuaMessage::Opaque(h) => h);
+
+// the prefix of the value a script yields while it waits for `ctx.send`
+const SUSPENDED: &[u8] = b"__suspended__";
+
+// the name of the registry value holding the limit of `LuaActorBuilder::with_max_table_depth`
+pub(crate) const MAX_TABLE_DEPTH: &str = "max_table_depth";
+
+// Creates a Lua string from any bytes, in one copy. rlua 0.14 only creates strings from a `&str`,
+// which it passes to `lua_pushlstring` as a pointer and a length without reading it.
+pub(crate) fn create_bytes<'lua>(lua: &'lua Lua, bytes: &[u8]) -> LuaResult<LuaString<'lua>> {
+    // SAFETY: the `&str` is only used by `create_string`, which doesn't rely on it being UTF-8
+    lua.create_string(unsafe { str::from_utf8_unchecked(bytes) })
+}
+
+// Converts a table and the tables nested in it. A table which contains itself, directly or
+// through other tables, fails with `ActixLuaError::CyclicTable` rather than recursing forever.
+// A table referenced twice but not from within itself is copied twice.
+struct TableConverter<'lua> {
+    lua: &'lua Lua,
+    max_depth: Option<usize>,
+    // the tables being converted as keys, created for the first nested table
+    ancestors: Option<Table<'lua>>,
+    // the keys from the converted table down to the table being converted
+    path: Vec<String>,
+}
+
+impl<'lua> TableConverter<'lua> {
+    fn convert(&mut self, t: Table<'lua>) -> LuaResult<LuaMessage> {
+        if let Some(max) = self.max_depth {
+            if self.path.len() >= max {
+                return Err(ActixLuaError::TableTooDeep { max }.into());
+            }
+        }
+        let mut table = HashMap::new();
+        for pair in t.clone().pairs::<Value, Value>() {
+            let (k, v) = pair?;
+            // integer keys are formatted directly, not coerced to a string in the VM
+            let k = match k {
+                Value::String(s) => s.to_str()?.to_string(),
+                Value::Integer(i) => i.to_string(),
+                k => String::from_lua(k, self.lua)?,
+            };
+            let v = match v {
+                Value::Table(child) => self.convert_nested(&t, &k, child)?,
+                v => LuaMessage::from_lua(v, self.lua)?,
+            };
+            table.insert(k, v);
+        }
+        Ok(LuaMessage::Table(table))
+    }
+
+    fn convert_nested(
+        &mut self,
+        parent: &Table<'lua>,
+        key: &str,
+        t: Table<'lua>,
+    ) -> LuaResult<LuaMessage> {
+        let ancestors = match self.ancestors {
+            Some(ref ancestors) => ancestors.clone(),
+            None => {
+                // no other table was nested yet, so `parent` is the converted table
+                let ancestors = self.lua.create_table()?;
+                ancestors.raw_set(parent.clone(), true)?;
+                self.ancestors = Some(ancestors.clone());
+                ancestors
+            }
+        };
+        self.path.push(key.to_string());
+        if let Value::Boolean(true) = ancestors.raw_get(t.clone())? {
+            return Err(ActixLuaError::CyclicTable {
+                path: self.path.join("."),
+            }
+            .into());
+        }
+        ancestors.raw_set(t.clone(), true)?;
+        let msg = self.convert(t.clone())?;
+        ancestors.raw_set(t, Value::Nil)?;
+        self.path.pop();
+        Ok(msg)
+    }
+}
+
 impl<'lua> FromLua<'lua> for LuaMessage {
     fn from_lua(v: Value, lua: &'lua Lua) -> LuaResult<LuaMessage> {
         match v {
             Value::String(x) => {
-                let re = Regex::new(r"__suspended__(.+)").unwrap();
-                let s = Value::String(x);
-                if let Some(cap) = re.captures(&String::from_lua(s.clone(), lua)?) {
-                    let tid = cap.get(1).unwrap().as_str();
-                    Ok(LuaMessage::ThreadYield(tid.to_string()))
-                } else {
-                    Ok(LuaMessage::String(String::from_lua(s.clone(), lua)?))
+                // the string is copied out of the VM once, without an intermediate `String`
+                let bytes = x.as_bytes();
+                if bytes.starts_with(SUSPENDED) {
+                    let tid = String::from_utf8_lossy(&bytes[SUSPENDED.len()..]);
+                    return Ok(LuaMessage::ThreadYield(tid.into_owned()));
+                }
+                match str::from_utf8(bytes) {
+                    Ok(s) => Ok(LuaMessage::String(s.to_string())),
+                    Err(_) => Ok(LuaMessage::Bytes(Bytes::from(bytes))),
                 }
             }
-            Value::Integer(_) => Ok(LuaMessage::Integer(lua.coerce_integer(v)? as i64)),
-            Value::Number(_) => Ok(LuaMessage::Number(lua.coerce_number(v)? as f64)),
+            Value::Integer(_) => Ok(LuaMessage::Integer(lua.coerce_integer(v)?)),
+            Value::Number(_) => Ok(LuaMessage::Number(lua.coerce_number(v)?)),
             Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
             Value::Nil => Ok(LuaMessage::Nil),
-            Value::Table(t) => Ok(LuaMessage::Table(HashMap::from_lua(Value::Table(t), lua)?)),
-
-            _ => unimplemented!(),
+            Value::UserData(ud) if ud.is::<OpaqueHandle>()? => {
+                Ok(LuaMessage::Opaque(ud.borrow::<OpaqueHandle>()?.clone()))
+            }
+            Value::UserData(ud) if ud.is::<SharedTable>()? => {
+                Ok(ud.borrow::<SharedTable>()?.to_message())
+            }
+            Value::Table(t) => {
+                let mut converter = TableConverter {
+                    lua,
+                    max_depth: lua.named_registry_value(MAX_TABLE_DEPTH)?,
+                    ancestors: None,
+                    path: vec![],
+                };
+                converter.convert(t)
+            }
+            v => {
+                let type_name = match v {
+                    Value::Function(_) => "a function",
+                    Value::Thread(_) => "a coroutine",
+                    _ => "a userdata",
+                };
+                Err(ActixLuaError::ConversionError {
+                    message: format!("can't send {} in a message", type_name),
+                }
+                .into())
+            }
         }
     }
 }
@@ -128,14 +475,60 @@ impl<'lua> ToLua<'lua> for LuaMessage {
     fn to_lua(self, lua: &'lua Lua) -> LuaResult<Value<'lua>> {
         match self {
             LuaMessage::String(x) => Ok(Value::String(lua.create_string(&x)?)),
+            LuaMessage::Bytes(x) => Ok(Value::String(create_bytes(lua, &x)?)),
             LuaMessage::Integer(x) => Ok(Value::Integer(x)),
             LuaMessage::Number(x) => Ok(Value::Number(x)),
             LuaMessage::Boolean(x) => Ok(Value::Boolean(x)),
             LuaMessage::Nil => Ok(Value::Nil),
             LuaMessage::Table(x) => Ok(Value::Table(lua.create_table_from(x)?)),
+            LuaMessage::Error(e) => Err(e.into()),
+            LuaMessage::Opaque(h) => Ok(Value::UserData(lua.create_userdata(h)?)),
+            LuaMessage::ThreadYield(_) => Err(ActixLuaError::ConversionError {
+                message: "a suspended thread can't be passed to a script".to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+const KEY_CACHE_SIZE: usize = 1024;
+
+// Lua strings of the table keys converted to a VM, so messages with the same schema don't
+// create the key strings again. It belongs to a single VM and holds up to `KEY_CACHE_SIZE`
+// keys.
+#[derive(Default)]
+pub(crate) struct KeyCache {
+    keys: HashMap<String, RegistryKey>,
+}
+
+impl KeyCache {
+    pub(crate) fn convert<'lua>(
+        &mut self,
+        msg: LuaMessage,
+        lua: &'lua Lua,
+    ) -> LuaResult<Value<'lua>> {
+        match msg {
+            LuaMessage::Table(t) => {
+                let table = lua.create_table()?;
+                for (k, v) in t {
+                    let k = self.key(k, lua)?;
+                    table.set(k, self.convert(v, lua)?)?;
+                }
+                Ok(Value::Table(table))
+            }
+            msg => msg.to_lua(lua),
+        }
+    }
 
-            _ => unimplemented!(),
+    fn key<'lua>(&mut self, key: String, lua: &'lua Lua) -> LuaResult<::rlua::String<'lua>> {
+        if let Some(k) = self.keys.get(&key) {
+            return lua.registry_value(k);
         }
+        let s = lua.create_string(&key)?;
+        if self.keys.len() < KEY_CACHE_SIZE {
+            self.keys.insert(key, lua.create_registry_value(s.clone())?);
+        }
+        Ok(s)
     }
 }
 
@@ -162,6 +555,24 @@ mod tests {
         assert_eq!(LuaMessage::from(t), LuaMessage::Table(t2));
     }
 
+    #[test]
+    fn try_from() {
+        assert_eq!(i64::try_from(LuaMessage::from(42)), Ok(42));
+        assert_eq!(f64::try_from(LuaMessage::from(42)), Ok(42.0));
+        assert_eq!(String::try_from(LuaMessage::from("foo")), Ok("foo".to_string()));
+        assert_eq!(Bytes::try_from(LuaMessage::from("foo")), Ok(Bytes::from("foo")));
+        assert_eq!(
+            bool::try_from(LuaMessage::from(1)),
+            Err(ActixLuaError::ConversionError {
+                message: "expected a boolean, got Integer(1)".to_string()
+            })
+        );
+        assert_eq!(
+            i64::try_from(LuaMessage::Error(ActixLuaError::Timeout)),
+            Err(ActixLuaError::Timeout)
+        );
+    }
+
     #[test]
     fn to_lua() {
         // we only check if they have the correct variant
@@ -174,6 +585,27 @@ mod tests {
             discriminant(&LuaMessage::String("foo".to_string()).to_lua(&lua).unwrap()),
             discriminant(&Value::String(lua.create_string("foo").unwrap()))
         );
+        match LuaMessage::from(vec![0xff, 0x00]).to_lua(&lua).unwrap() {
+            Value::String(s) => assert_eq!(s.as_bytes(), b"\xff\x00"),
+            v => panic!("unexpected value {:?}", v),
+        }
+        let mut bytes = b"caf\xc3\xa9 \xc3".to_vec();
+        bytes.extend((0..10_000).map(|i| (i % 256) as u8));
+        bytes.extend_from_slice(b"\xe2\x82 end");
+        match LuaMessage::from(bytes.clone()).to_lua(&lua).unwrap() {
+            Value::String(s) => assert_eq!(s.as_bytes(), &bytes[..]),
+            v => panic!("unexpected value {:?}", v),
+        }
+        // the bytes are pushed as one string, without a Lua value per byte on the way
+        lua.exec::<()>("collectgarbage('stop') before = collectgarbage('count')", None)
+            .unwrap();
+        let bytes: Vec<u8> = (0..1 << 20).map(|i| (i % 256) as u8).collect();
+        let value = LuaMessage::from(bytes.clone()).to_lua(&lua).unwrap();
+        lua.globals().set("value", value).unwrap();
+        let used: f64 = lua.eval("(collectgarbage('count') - before) * 1024", None).unwrap();
+        assert!(used < (2 << 20) as f64, "{} bytes used", used);
+        let value: LuaString = lua.globals().get("value").unwrap();
+        assert_eq!(value.as_bytes(), &bytes[..]);
         assert_eq!(
             discriminant(&LuaMessage::Number(42.5).to_lua(&lua).unwrap()),
             discriminant(&Value::Number(42.5))
@@ -214,6 +646,18 @@ mod tests {
             ).unwrap()),
             discriminant(&LuaMessage::String("foo".to_string()))
         );
+        assert_eq!(
+            LuaMessage::from_lua(LuaMessage::from(vec![0xff, 0x00]).to_lua(&lua).unwrap(), &lua)
+                .unwrap(),
+            LuaMessage::from(vec![0xff, 0x00])
+        );
+        assert_eq!(
+            LuaMessage::from_lua(
+                Value::String(lua.create_string("__suspended__3").unwrap()),
+                &lua
+            ).unwrap(),
+            LuaMessage::ThreadYield("3".to_string())
+        );
         assert_eq!(
             discriminant(&LuaMessage::from_lua(Value::Boolean(true), &lua).unwrap()),
             discriminant(&LuaMessage::Boolean(true))
@@ -231,5 +675,97 @@ mod tests {
             ),
             discriminant(&LuaMessage::Table(t))
         );
+
+        // values which can't be sent are errors
+        let conversion = |res: LuaResult<LuaMessage>| match res.map_err(ActixLuaError::from) {
+            Err(ActixLuaError::ConversionError { message }) => message,
+            res => panic!("unexpected conversion {:?}", res),
+        };
+        let f = lua.eval::<Value>("return print", None).unwrap();
+        assert_eq!(
+            conversion(LuaMessage::from_lua(f, &lua)),
+            "can't send a function in a message"
+        );
+        let co = lua.eval::<Value>("return coroutine.create(print)", None).unwrap();
+        assert_eq!(
+            conversion(LuaMessage::from_lua(co, &lua)),
+            "can't send a coroutine in a message"
+        );
+        let yielded = LuaMessage::ThreadYield("3".to_string())
+            .to_lua(&lua)
+            .map(|_| LuaMessage::Nil);
+        assert_eq!(conversion(yielded), "a suspended thread can't be passed to a script");
+    }
+
+    #[test]
+    fn from_lua_cycle() {
+        let lua = Lua::new();
+
+        let cyclic = lua
+            .exec::<LuaMessage>("local t = { a = { b = {} } } t.a.b.c = t.a return t", None)
+            .map_err(ActixLuaError::from);
+        assert_eq!(
+            cyclic,
+            Err(ActixLuaError::CyclicTable {
+                path: "a.b.c".to_string()
+            })
+        );
+
+        // a table referenced twice is copied
+        let shared = lua
+            .exec::<LuaMessage>("local s = { 1 } return { x = s, y = { s } }", None)
+            .unwrap();
+        let mut s = HashMap::new();
+        s.insert("1".to_string(), LuaMessage::from(1));
+        let mut y = HashMap::new();
+        y.insert("1".to_string(), LuaMessage::from(s.clone()));
+        let mut t = HashMap::new();
+        t.insert("x".to_string(), LuaMessage::from(s));
+        t.insert("y".to_string(), LuaMessage::from(y));
+        assert_eq!(shared, LuaMessage::from(t));
+    }
+
+    #[test]
+    fn opaque() {
+        struct Session {
+            user: String,
+        }
+
+        let lua = Lua::new();
+        let session = LuaMessage::opaque(Session {
+            user: "ann".to_string(),
+        });
+        lua.globals()
+            .set("session", session.clone().to_lua(&lua).unwrap())
+            .unwrap();
+        let t = lua
+            .exec::<LuaMessage>("return { s = session, kind = type(session) }", None)
+            .unwrap();
+        let mut t = HashMap::try_from(t).unwrap();
+        assert_eq!(t["kind"], LuaMessage::from("userdata"));
+        let handle = OpaqueHandle::try_from(t.remove("s").unwrap()).unwrap();
+        assert_eq!(LuaMessage::Opaque(handle.clone()), session);
+        assert_eq!(handle.downcast_ref::<Session>().unwrap().user, "ann");
+        assert!(handle.downcast_ref::<String>().is_none());
+        assert_ne!(session, LuaMessage::opaque(0));
+    }
+
+    #[test]
+    fn key_cache() {
+        let lua = Lua::new();
+        let mut keys = KeyCache::default();
+
+        let mut inner = HashMap::new();
+        inner.insert("id".to_string(), LuaMessage::from(1));
+        let mut t = HashMap::new();
+        t.insert("id".to_string(), LuaMessage::from(2));
+        t.insert("inner".to_string(), LuaMessage::from(inner));
+        let msg = LuaMessage::from(t);
+
+        for _ in 0..2 {
+            let v = keys.convert(msg.clone(), &lua).unwrap();
+            assert_eq!(LuaMessage::from_lua(v, &lua).unwrap(), msg);
+        }
+        assert_eq!(keys.keys.len(), 2);
     }
 }
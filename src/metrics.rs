@@ -0,0 +1,135 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counters of the messages handled by one or more `LuaActor`s.
+///
+/// Pass a handle to `LuaActorBuilder::with_metrics` and read the counters from anywhere.
+/// Building a pool of actors with clones of the same handle sums up the whole pool.
+///
+/// ```rust,ignore
+/// let metrics = LuaActorMetrics::new();
+/// let addr = LuaActorBuilder::new()
+///     .on_handle("worker.lua")
+///     .with_metrics(metrics.clone())
+///     .build()?
+///     .start();
+/// println!("{} requests, {} notifications", metrics.requests(), metrics.notifications());
+/// ```
+///
+/// With `LuaActorBuilder::with_gc_metrics`, the actors also report the activity of their
+/// garbage collectors.
+#[derive(Clone, Debug, Default)]
+pub struct LuaActorMetrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    notifications: AtomicU64,
+    rejected_notifications: AtomicU64,
+    heap_bytes: AtomicU64,
+    gc_cycles: AtomicU64,
+    gc_pause_nanos: AtomicU64,
+    slow_invocations: AtomicU64,
+}
+
+impl LuaActorMetrics {
+    pub fn new() -> Self {
+        LuaActorMetrics::default()
+    }
+
+    /// The number of `LuaMessage`s and `Hop`s received, which are answered with a reply.
+    pub fn requests(&self) -> u64 {
+        self.inner.requests.load(Ordering::Relaxed)
+    }
+
+    /// The number of `Tell`s received and handled.
+    pub fn notifications(&self) -> u64 {
+        self.inner.notifications.load(Ordering::Relaxed)
+    }
+
+    /// The number of `Tell`s dropped by actors which don't accept them.
+    pub fn rejected_notifications(&self) -> u64 {
+        self.inner.rejected_notifications.load(Ordering::Relaxed)
+    }
+
+    /// The memory used by the VMs of the actors when they were last sampled, in bytes.
+    pub fn heap_bytes(&self) -> u64 {
+        self.inner.heap_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The number of garbage collection cycles completed, as of the last samples.
+    pub fn gc_cycles(&self) -> u64 {
+        self.inner.gc_cycles.load(Ordering::Relaxed)
+    }
+
+    /// The time spent in collections requested with `Gc` messages.
+    ///
+    /// The collector also runs in small steps while scripts allocate, which can't be timed apart
+    /// from the scripts.
+    pub fn gc_pause_time(&self) -> Duration {
+        Duration::from_nanos(self.inner.gc_pause_nanos.load(Ordering::Relaxed))
+    }
+
+    /// The number of messages handled slower than the threshold set with
+    /// `LuaActorBuilder::with_slow_handler_threshold`.
+    pub fn slow_invocations(&self) -> u64 {
+        self.inner.slow_invocations.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "actix")]
+impl LuaActorMetrics {
+    pub(crate) fn add_request(&self) {
+        self.inner.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_notification(&self) {
+        self.inner.notifications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_rejected_notification(&self) {
+        self.inner
+            .rejected_notifications
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // replace the heap size `old` last reported by an actor with `new`
+    pub(crate) fn update_heap(&self, old: u64, new: u64) {
+        if new >= old {
+            self.inner.heap_bytes.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            self.inner.heap_bytes.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn add_slow_invocation(&self) {
+        self.inner.slow_invocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_gc_cycles(&self, cycles: u64) {
+        self.inner.gc_cycles.fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_gc_pause(&self, pause: Duration) {
+        self.inner
+            .gc_pause_nanos
+            .fetch_add(pause.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The cost of one run of a hook, reported to `LuaActorBuilder::with_cost_report`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvocationCost {
+    /// The name of the actor, see `LuaActorBuilder::with_name`, or its id if it has none.
+    pub actor: String,
+    /// The hook which ran, or `resume` for a hook continuing once the reply to its `ctx.send`
+    /// arrived.
+    pub hook: String,
+    /// The number of Lua instructions run, counted in steps of 100.
+    pub instructions: u64,
+    /// The wall time spent in the VM, including the Rust callbacks.
+    pub elapsed: Duration,
+}
@@ -0,0 +1,100 @@
+use regex::Regex;
+use rlua::{Function, Lua, Table, Value};
+
+use error::ActixLuaError;
+
+// Compiles MoonScript hooks to Lua with the compiler given to
+// `LuaActorBuilder::with_moonscript`, with the `moonscript` feature.
+//
+// The compiler runs in a VM of its own. Its line table maps every line of the Lua code to a
+// position in the MoonScript code, which is turned into the line map the prelude translates
+// tracebacks with.
+pub(crate) struct MoonScript {
+    vm: Lua,
+}
+
+// the Lua code of a hook, and the line in the MoonScript code of each of its lines
+pub(crate) struct Compiled {
+    pub(crate) lua: String,
+    pub(crate) lines: Vec<usize>,
+}
+
+impl MoonScript {
+    // load `compiler`, the source of a module with the `to_lua` function of `moonscript.base`
+    pub(crate) fn new(compiler: &str) -> Result<MoonScript, ActixLuaError> {
+        let vm = Lua::new();
+        {
+            let moonscript: Table = vm
+                .load(compiler, Some("moonscript"))
+                .and_then(|f| f.call(()))
+                .map_err(|e| ActixLuaError::compile("moonscript", &e))?;
+            let to_lua: Function = moonscript.get("to_lua")?;
+            vm.set_named_registry_value("to_lua", to_lua)?;
+        }
+        Ok(MoonScript { vm })
+    }
+
+    pub(crate) fn compile(&self, hook: &str, source: &str) -> Result<Compiled, ActixLuaError> {
+        let to_lua: Function = self.vm.named_registry_value("to_lua")?;
+        // `nil` and the error if it fails
+        let (lua, table) = to_lua.call::<_, (Option<String>, Value)>(source)?;
+        let (lua, table) = match (lua, table) {
+            (Some(lua), Value::Table(table)) => (lua, table),
+            (_, err) => return Err(compile_error(hook, err)),
+        };
+        // the lines start at these positions
+        let starts: Vec<usize> = Some(0)
+            .into_iter()
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let mut lines = Vec::new();
+        let mut line = 1;
+        for i in 1..=lua.lines().count() {
+            // lines without a position belong to the line before
+            if let Some(pos) = table.get::<_, Option<usize>>(i)? {
+                // positions count from 1
+                line = starts.iter().take_while(|&&start| start < pos).count().max(1);
+            }
+            lines.push(line);
+        }
+        Ok(Compiled { lua, lines })
+    }
+}
+
+// moonscript reports the line of a parse error in brackets: `Failed to parse:\n [<line>] >> ...`
+fn compile_error(hook: &str, err: Value) -> ActixLuaError {
+    let message = match err {
+        Value::String(s) => s.to_str().unwrap_or_default().to_string(),
+        _ => "compile failed".to_string(),
+    };
+    let re = Regex::new(r"\[(\d+)\] >>").unwrap();
+    let line = re.captures(&message).and_then(|cap| cap[1].parse().ok());
+    ActixLuaError::CompileError {
+        hook: hook.to_string(),
+        line,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moonscript_lines() {
+        let moonscript = MoonScript::new(include_str!("lua/test/moonscript.lua")).unwrap();
+        let compiled = moonscript
+            .compile("handle", "-- comment\n\nx = 1\n\nreturn x")
+            .unwrap();
+        assert_eq!(compiled.lua, "x = 1\nreturn x");
+        assert_eq!(compiled.lines, [3, 5]);
+
+        match moonscript.compile("handle", "x = 1\n! x") {
+            Err(ActixLuaError::CompileError { hook, line, .. }) => {
+                assert_eq!(hook, "handle");
+                assert_eq!(line, Some(2));
+            }
+            res => panic!("unexpected result {:?}", res.map(|c| c.lua)),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use actix::prelude::*;
+use futures::{future, Future};
+
+use std::iter::FromIterator;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actor::LuaActor;
+use message::LuaMessage;
+
+/// A group of `LuaActor`s doing the same work.
+///
+/// `LuaActorBuilder::build_pool` starts the actors under supervision, so a member which stops
+/// is restarted with a fresh VM at the same address.
+///
+/// ```rust,ignore
+/// let pool = LuaActorBuilder::new().on_handle("worker.lua").build_pool(4)?;
+/// pool.notify_all(LuaMessage::from("reload"));
+/// let reply = pool.send(LuaMessage::from("job"));
+/// ```
+#[derive(Clone, Default)]
+pub struct LuaActorPool {
+    members: Vec<Addr<LuaActor>>,
+    // the member `send` sends to next, shared by the clones
+    next: Arc<AtomicUsize>,
+}
+
+impl LuaActorPool {
+    pub fn new() -> Self {
+        LuaActorPool::default()
+    }
+
+    /// Add `addr` to the pool.
+    pub fn push(&mut self, addr: Addr<LuaActor>) {
+        self.members.push(addr);
+    }
+
+    /// The addresses of the actors in the pool.
+    pub fn members(&self) -> &[Addr<LuaActor>] {
+        &self.members
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Send `msg` to the members in turn, and wait for the reply.
+    ///
+    /// Fails with `MailboxError::Closed` if the pool is empty.
+    pub fn send(
+        &self,
+        msg: LuaMessage,
+    ) -> Box<dyn Future<Item = LuaMessage, Error = MailboxError>> {
+        if self.members.is_empty() {
+            return Box::new(future::err(MailboxError::Closed));
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+        Box::new(self.members[i].send(msg))
+    }
+
+    /// Send `msg` to every actor in the pool without waiting for the replies.
+    ///
+    /// Like `Addr::do_send`, this ignores the mailbox capacity of the actors.
+    pub fn notify_all(&self, msg: LuaMessage) {
+        if let Some((last, rest)) = self.members.split_last() {
+            for addr in rest {
+                addr.do_send(msg.clone());
+            }
+            last.do_send(msg);
+        }
+    }
+}
+
+impl From<Vec<Addr<LuaActor>>> for LuaActorPool {
+    fn from(members: Vec<Addr<LuaActor>>) -> Self {
+        LuaActorPool {
+            members,
+            next: Arc::default(),
+        }
+    }
+}
+
+impl FromIterator<Addr<LuaActor>> for LuaActorPool {
+    fn from_iter<I: IntoIterator<Item = Addr<LuaActor>>>(iter: I) -> Self {
+        LuaActorPool::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use error::ErrorPolicy;
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    #[test]
+    fn pool_notify_all() {
+        let system = System::new("test");
+
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let pool: LuaActorPool = (0..3)
+            .map(|_| {
+                let reloads = reloads.clone();
+                LuaActorBuilder::new()
+                    .on_handle_with_fn(move |msg, _| {
+                        if *msg == LuaMessage::from("reload") {
+                            reloads.fetch_add(1, Ordering::SeqCst);
+                        }
+                        None
+                    })
+                    .build()
+                    .unwrap()
+                    .start()
+            })
+            .collect();
+        assert_eq!(pool.len(), 3);
+        pool.notify_all(LuaMessage::from("reload"));
+
+        let l = Delay::new(Duration::from_millis(100)).map(move |()| {
+            assert_eq!(reloads.load(Ordering::SeqCst), 3);
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("timer error {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn build_pool() {
+        let system = System::new("test");
+
+        let pool = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "crash" then error("boom") end
+                ctx.state.n = (ctx.state.n or 0) + 1
+                return ctx.state.n
+                "#,
+            )
+            .with_error_policy(ErrorPolicy::Stop)
+            .build_pool(2)
+            .unwrap();
+        let first = pool.members()[0].clone();
+
+        let l = pool
+            .send(LuaMessage::Nil)
+            .join3(pool.send(LuaMessage::Nil), pool.send(LuaMessage::Nil))
+            .and_then(move |counts| {
+                first
+                    .send(LuaMessage::from("crash"))
+                    .and_then(move |_| first.send(LuaMessage::Nil))
+                    .map(move |restarted| (counts, restarted))
+            });
+        Arbiter::spawn(l.map(|(counts, restarted)| {
+            // the messages are sent to the members in turn
+            assert_eq!(counts, (1.into(), 1.into(), 2.into()));
+            // the first member stopped on the error and was restarted with a fresh VM
+            assert_eq!(restarted, LuaMessage::from(1));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,252 @@
+use actix::prelude::*;
+use rlua::{Error as LuaError, FromLua, Function, Lua, Table, Value};
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use actor::LuaActor;
+use error::ActixLuaError;
+use message::LuaMessage;
+
+/// Profile the Lua functions run by a `LuaActor`, to find out which parts of its hooks are
+/// slow.
+///
+/// While the profile runs, a debug hook counts the calls of every function and times them. The
+/// reply is the report of the profile so far: an array of the functions called, slowest first,
+/// as tables with their `name`, their `source` and the `line` they are defined at, the number
+/// of `calls`, and their `total` time and `self` time in seconds. The self time leaves out the
+/// functions they call. The times are wall times, so a hook waiting for the reply to a
+/// `ctx.send` counts the wait.
+///
+/// Only the hooks run after the profile started are profiled, in the VM of the actor but not
+/// in the VMs of its tenants.
+///
+/// ```rust,ignore
+/// addr.send(Profile::Start)
+///     .and_then(move |_| addr.send(LuaMessage::from("work")).map(move |_| addr))
+///     .and_then(|addr| addr.send(Profile::Stop))
+///     .map(|report| println!("{:?}", report.unwrap()))
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    /// Start a new profile, leaving out the functions called so far.
+    Start,
+    /// Stop profiling.
+    Stop,
+    /// Only report the functions called so far.
+    Report,
+}
+
+/// Export the profile of a `LuaActor` as collapsed stacks, the input of [inferno] and
+/// [flamegraph.pl], to find out where the time goes in deep call chains.
+///
+/// Every line is a stack of calls, the names of its functions with their source and line
+/// separated by `;`, followed by the self time of its last function in microseconds. The stacks
+/// of a coroutine start at the function it runs. The reply is the collapsed stacks of the
+/// profile started with `Profile::Start`, which are written to `path` as well if it is given.
+///
+/// ```rust,ignore
+/// addr.send(Flamegraph { path: Some("actor.folded".into()) })
+/// ```
+///
+/// [inferno]: https://github.com/jonhoo/inferno
+/// [flamegraph.pl]: https://github.com/brendangregg/FlameGraph
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Flamegraph {
+    pub path: Option<PathBuf>,
+}
+
+// load the profiler of the VM, after the instructions are counted so it keeps their hooks
+pub(crate) fn install(vm: &Lua) -> Result<(), LuaError> {
+    let start = Instant::now();
+    let clock = vm.create_function(move |_, ()| Ok(start.elapsed().as_secs_f64()))?;
+    let sethook: Function = vm.named_registry_value("sethook")?;
+    let gethook: Function = vm.named_registry_value("gethook")?;
+    let getinfo: Function = vm.named_registry_value("getinfo")?;
+    let profiler: Table = vm
+        .load(include_str!("lua/profiler.lua"), Some("Profiler"))?
+        .call((sethook, gethook, getinfo, clock))?;
+    vm.set_named_registry_value("profiler", profiler)
+}
+
+impl Message for Profile {
+    type Result = Result<LuaMessage, ActixLuaError>;
+}
+
+impl Handler<Profile> for LuaActor {
+    type Result = Result<LuaMessage, ActixLuaError>;
+
+    fn handle(&mut self, profile: Profile, _: &mut Context<Self>) -> Self::Result {
+        let profiler: Table = self.vm.named_registry_value("profiler")?;
+        let f: Function = profiler.get(match profile {
+            Profile::Start => "start",
+            Profile::Stop => "stop",
+            Profile::Report => "report",
+        })?;
+        let report: Value = f.call(())?;
+        Ok(LuaMessage::from_lua(report, &self.vm)?)
+    }
+}
+
+impl Message for Flamegraph {
+    type Result = Result<String, ActixLuaError>;
+}
+
+impl Handler<Flamegraph> for LuaActor {
+    type Result = Result<String, ActixLuaError>;
+
+    fn handle(&mut self, flamegraph: Flamegraph, _: &mut Context<Self>) -> Self::Result {
+        let profiler: Table = self.vm.named_registry_value("profiler")?;
+        let collapsed: Function = profiler.get("collapsed")?;
+        let stacks: String = collapsed.call(())?;
+        if let Some(path) = flamegraph.path {
+            fs::write(&path, &stacks).map_err(|e| ActixLuaError::ConfigError {
+                path: path.display().to_string(),
+                message: e.to_string(),
+            })?;
+        }
+        Ok(stacks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+
+    #[test]
+    fn profile_functions() {
+        use std::sync::{Arc, Mutex};
+
+        let system = System::new("test");
+
+        let instructions = Arc::new(Mutex::new(0));
+        let counted = instructions.clone();
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local function slow()
+                local n = 0
+                for i = 1, 100000 do
+                    n = n + i
+                end
+                return n
+            end
+
+            local function fast()
+                return 1
+            end
+
+            for i = 1, 3 do
+                slow()
+                fast()
+            end
+            "#,
+            )
+            // the profiler keeps the hook counting instructions
+            .with_cost_report(move |cost| *counted.lock().unwrap() += cost.instructions)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(Profile::Start).and_then(move |_| {
+            addr.send(LuaMessage::Nil)
+                .and_then(move |_| addr.send(Profile::Stop).join(addr.send(Profile::Report)))
+        });
+        Arbiter::spawn(l.map(move |(report, after)| {
+            assert!(*instructions.lock().unwrap() > 100000);
+            let report = match report.unwrap() {
+                LuaMessage::Table(t) => t,
+                res => panic!("unexpected report {:?}", res),
+            };
+            let field = |name: &str, key: &str| {
+                let stats = report.values().find(|stats| match stats {
+                    LuaMessage::Table(t) => t.get("name") == Some(&LuaMessage::from(name)),
+                    _ => false,
+                });
+                match stats {
+                    Some(LuaMessage::Table(t)) => t[key].clone(),
+                    _ => panic!("`{}` isn't in the report {:?}", name, report),
+                }
+            };
+            assert_eq!(field("slow", "calls"), LuaMessage::from(3));
+            assert_eq!(field("fast", "calls"), LuaMessage::from(3));
+            assert_eq!(field("slow", "line"), LuaMessage::from(2));
+            assert_eq!(field("slow", "source"), LuaMessage::from(r#"[string "handle"]"#));
+            let seconds = |msg: LuaMessage| match msg {
+                LuaMessage::Number(n) => n,
+                msg => panic!("unexpected time {:?}", msg),
+            };
+            let (slow, fast) = (seconds(field("slow", "total")), seconds(field("fast", "total")));
+            assert!(slow > fast);
+            // the main chunk spends its time in `slow`
+            let main = field("main chunk", "total");
+            assert!(seconds(main) >= slow);
+            assert!(seconds(field("main chunk", "self")) < slow);
+            // nothing is called after the profile stopped
+            assert_eq!(after.unwrap(), LuaMessage::Table(report.clone()));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn profile_flamegraph() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local function leaf()
+                local n = 0
+                for i = 1, 100000 do
+                    n = n + i
+                end
+            end
+
+            local function branch()
+                leaf()
+            end
+
+            branch()
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let path = ::std::env::temp_dir().join("actix-lua-profile.folded");
+        let flamegraph = Flamegraph {
+            path: Some(path.clone()),
+        };
+        let l = addr.send(Profile::Start).and_then(move |_| {
+            addr.send(LuaMessage::Nil)
+                .and_then(move |_| addr.send(flamegraph))
+        });
+        Arbiter::spawn(l.map(move |stacks| {
+            let stacks = stacks.unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), stacks);
+            fs::remove_file(&path).unwrap();
+
+            let leaf = stacks
+                .lines()
+                .find(|line| line.contains("leaf"))
+                .unwrap();
+            let (frames, micros) = leaf.split_at(leaf.rfind(' ').unwrap());
+            let expected = [
+                r#"main chunk [string "handle"]:0"#,
+                r#"branch [string "handle"]:9"#,
+                r#"leaf [string "handle"]:2"#,
+            ];
+            assert_eq!(frames, expected.join(";"));
+            assert!(micros.trim().parse::<u64>().unwrap() > 0);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,251 @@
+use actix::prelude::*;
+use futures::future;
+
+use std::collections::{HashMap, VecDeque};
+
+use actor::LuaActor;
+use ask::convert_reply;
+use builder::LuaActorBuilder;
+use error::ActixLuaError;
+use message::LuaMessage;
+
+/// The hook scripts of a tenant, see [`LuaTenantRegistry`].
+///
+/// [`LuaTenantRegistry`]: struct.LuaTenantRegistry.html
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenantScripts {
+    pub started: Option<String>,
+    pub handle: Option<String>,
+    pub stopped: Option<String>,
+}
+
+type ConfigureFn = dyn Fn(LuaActorBuilder) -> LuaActorBuilder;
+
+/// An actor running the scripts uploaded by tenants, e.g. rules provided by customers.
+///
+/// Each tenant gets a `LuaActor` of its own, started with its first `TenantMessage`. At most
+/// `max_actors` actors run at once, the least recently used one is dropped beyond that and
+/// stops once it handled the messages sent to it. Uploading new scripts replaces the actor of
+/// the tenant the same way.
+///
+/// ```rust,ignore
+/// let registry = LuaTenantRegistry::new(100).start();
+/// registry.send(PutTenantScripts {
+///     tenant: "acme".to_string(),
+///     scripts: TenantScripts {
+///         handle: Some("return ctx.msg.total > 100".to_string()),
+///         ..TenantScripts::default()
+///     },
+/// });
+/// registry.send(TenantMessage {
+///     tenant: "acme".to_string(),
+///     msg: order,
+/// });
+/// ```
+pub struct LuaTenantRegistry {
+    scripts: HashMap<String, TenantScripts>,
+    // the running actors, least recently used first
+    actors: VecDeque<(String, Addr<LuaActor>)>,
+    max_actors: usize,
+    configure: Option<Box<ConfigureFn>>,
+}
+
+impl LuaTenantRegistry {
+    pub fn new(max_actors: usize) -> Self {
+        LuaTenantRegistry {
+            scripts: HashMap::new(),
+            actors: VecDeque::new(),
+            max_actors: max_actors.max(1),
+            configure: None,
+        }
+    }
+
+    /// configure the builders of the tenants' actors, e.g. with `with_max_message_size`.
+    ///
+    /// The hooks are set by the registry.
+    pub fn with_builder<F>(mut self, configure: F) -> Self
+    where
+        F: Fn(LuaActorBuilder) -> LuaActorBuilder + 'static,
+    {
+        self.configure = Some(Box::new(configure));
+        self
+    }
+
+    fn builder(&self, scripts: &TenantScripts) -> LuaActorBuilder {
+        let mut builder = LuaActorBuilder::new();
+        if let Some(ref configure) = self.configure {
+            builder = configure(builder);
+        }
+        if let Some(ref started) = scripts.started {
+            builder = builder.on_started_with_lua(started.as_str());
+        }
+        if let Some(ref handle) = scripts.handle {
+            builder = builder.on_handle_with_lua(handle.as_str());
+        }
+        if let Some(ref stopped) = scripts.stopped {
+            builder = builder.on_stopped_with_lua(stopped.as_str());
+        }
+        builder
+    }
+
+    // the actor of `tenant`, started if it isn't running
+    fn actor(&mut self, tenant: &str) -> Result<Addr<LuaActor>, ActixLuaError> {
+        if let Some(i) = self.actors.iter().position(|(t, _)| t == tenant) {
+            let running = self.actors.remove(i).unwrap();
+            let addr = running.1.clone();
+            self.actors.push_back(running);
+            return Ok(addr);
+        }
+        let scripts = self
+            .scripts
+            .get(tenant)
+            .ok_or_else(|| ActixLuaError::UnknownTenant {
+                tenant: tenant.to_string(),
+            })?;
+        let addr = self.builder(scripts).build()?.start();
+        if self.actors.len() >= self.max_actors {
+            self.actors.pop_front();
+        }
+        self.actors.push_back((tenant.to_string(), addr.clone()));
+        Ok(addr)
+    }
+
+    fn drop_actor(&mut self, tenant: &str) {
+        self.actors.retain(|(t, _)| t != tenant);
+    }
+}
+
+impl Actor for LuaTenantRegistry {
+    type Context = Context<Self>;
+}
+
+/// Upload or update the scripts of `tenant`.
+///
+/// The scripts are compiled first, and a script which fails to compile is returned as
+/// `ActixLuaError::CompileError` without replacing the previous ones.
+pub struct PutTenantScripts {
+    pub tenant: String,
+    pub scripts: TenantScripts,
+}
+
+impl Message for PutTenantScripts {
+    type Result = Result<(), ActixLuaError>;
+}
+
+/// Delete the scripts of a tenant and stop its actor. Replies whether the tenant existed.
+pub struct DeleteTenant(pub String);
+
+impl Message for DeleteTenant {
+    type Result = bool;
+}
+
+/// Send `msg` to the actor of `tenant`.
+///
+/// Fails with `ActixLuaError::UnknownTenant` if the tenant has no scripts. Like `Ask::ask`, a
+/// `LuaMessage::Error` reply is returned as the error.
+pub struct TenantMessage {
+    pub tenant: String,
+    pub msg: LuaMessage,
+}
+
+impl Message for TenantMessage {
+    type Result = Result<LuaMessage, ActixLuaError>;
+}
+
+impl Handler<PutTenantScripts> for LuaTenantRegistry {
+    type Result = Result<(), ActixLuaError>;
+
+    fn handle(&mut self, put: PutTenantScripts, _: &mut Context<Self>) -> Self::Result {
+        self.builder(&put.scripts)
+            .validate()
+            .map_err(|mut errors| errors.remove(0))?;
+        self.drop_actor(&put.tenant);
+        self.scripts.insert(put.tenant, put.scripts);
+        Ok(())
+    }
+}
+
+impl Handler<DeleteTenant> for LuaTenantRegistry {
+    type Result = bool;
+
+    fn handle(&mut self, DeleteTenant(tenant): DeleteTenant, _: &mut Context<Self>) -> bool {
+        self.drop_actor(&tenant);
+        self.scripts.remove(&tenant).is_some()
+    }
+}
+
+impl Handler<TenantMessage> for LuaTenantRegistry {
+    type Result = ResponseFuture<LuaMessage, ActixLuaError>;
+
+    fn handle(&mut self, msg: TenantMessage, _: &mut Context<Self>) -> Self::Result {
+        match self.actor(&msg.tenant) {
+            Ok(addr) => convert_reply(addr.send(msg.msg)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    fn tenant_message(tenant: &str) -> TenantMessage {
+        TenantMessage {
+            tenant: tenant.to_string(),
+            msg: LuaMessage::Nil,
+        }
+    }
+
+    #[test]
+    fn tenant_registry() {
+        let system = System::new("test");
+
+        let registry = LuaTenantRegistry::new(1).start();
+        let put = |tenant: &str, handle: &str| PutTenantScripts {
+            tenant: tenant.to_string(),
+            scripts: TenantScripts {
+                started: Some("ctx.state.n = 0".to_string()),
+                handle: Some(handle.to_string()),
+                stopped: None,
+            },
+        };
+        let counter = "ctx.state.n = ctx.state.n + 1 return ctx.state.n";
+
+        let puts = future::join_all(vec![
+            registry.send(put("a", counter)),
+            registry.send(put("b", "return 'b'")),
+            registry.send(put("a", "return (")),
+        ]);
+        // `a` is stopped for `b`, and started again
+        let replies: Vec<_> = ["a", "a", "b", "a"]
+            .iter()
+            .map(|tenant| registry.send(tenant_message(tenant)))
+            .collect();
+        let l = puts.join4(
+            future::join_all(replies),
+            registry.send(DeleteTenant("a".to_string())),
+            registry.send(tenant_message("a")),
+        );
+        Arbiter::spawn(l.map(|(puts, replies, deleted, unknown)| {
+            assert_eq!(puts[..2], [Ok(()), Ok(())]);
+            match puts[2] {
+                Err(ActixLuaError::CompileError { .. }) => {}
+                ref res => panic!("unexpected result {:?}", res),
+            }
+            let expected = [1.into(), 2.into(), "b".into(), 1.into()];
+            let replies: Vec<_> = replies.into_iter().map(Result::unwrap).collect();
+            assert_eq!(replies, expected);
+            assert!(deleted);
+            assert_eq!(
+                unknown,
+                Err(ActixLuaError::UnknownTenant {
+                    tenant: "a".to_string()
+                })
+            );
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,1216 @@
+use actix::actors::resolver::{Resolve, Resolver};
+use actix::io::{FramedWrite, WriteHandler};
+use actix::prelude::*;
+use bytes::{Bytes, BytesMut};
+use futures::sync::oneshot;
+use futures::{future, Future};
+use tokio::codec::{Decoder, Encoder, FramedRead};
+use tokio::io::{AsyncRead, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use message::LuaMessage;
+
+/// Messaging between actors on different hosts.
+///
+/// A `LuaNode` is a system service which exposes registered recipients to other nodes over TCP.
+/// Remote actors are addressed as `"node@host:port/actor_name"`, both from Rust with
+/// [`RemoteSend`] and from Lua with `ctx.send` and `ctx.do_send`.
+///
+/// Host names are resolved with actix's `Resolver` service, without blocking the node.
+/// Messages are encoded with MessagePack in length-prefixed frames. The node keeps one
+/// connection per remote host and reconnects on the next send after a connection is lost.
+/// Requests which are in flight when a connection drops fail with `RemoteError::Disconnected`.
+///
+/// Connections encode their frames straight into their write buffers, see [`SetBufferCapacity`].
+/// Lua actors encode the messages they send in a buffer of their own, see
+/// [`LuaActorBuilder::with_buffer_capacity`].
+///
+/// [`RemoteSend`]: struct.RemoteSend.html
+/// [`SetBufferCapacity`]: struct.SetBufferCapacity.html
+/// [`LuaActorBuilder::with_buffer_capacity`]: struct.LuaActorBuilder.html#method.with_buffer_capacity
+pub struct LuaNode {
+    name: String,
+    actors: HashMap<String, Recipient<LuaMessage>>,
+    peers: HashMap<SocketAddr, Addr<Connection>>,
+    buffer_capacity: usize,
+}
+
+pub(crate) const DEFAULT_BUFFER_CAPACITY: usize = 64 * 1024;
+
+impl Default for LuaNode {
+    fn default() -> LuaNode {
+        LuaNode {
+            name: "node".to_string(),
+            actors: HashMap::new(),
+            peers: HashMap::new(),
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+        }
+    }
+}
+
+impl Actor for LuaNode {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LuaNode {}
+
+impl SystemService for LuaNode {}
+
+/// Errors returned to the sender of a remote message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteError {
+    /// The address is not of the `node@host:port/actor_name` form.
+    InvalidAddress(String),
+    /// The remote host does not run a node with this name.
+    UnknownNode(String),
+    /// No actor is registered with this name on the remote node.
+    UnknownActor(String),
+    /// The connection failed or was lost before a response arrived.
+    Disconnected,
+    /// The message could not be delivered, e.g. because the target actor has stopped.
+    Delivery(String),
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteError::InvalidAddress(a) => write!(f, "invalid remote address `{}`", a),
+            RemoteError::UnknownNode(n) => write!(f, "unknown node `{}`", n),
+            RemoteError::UnknownActor(a) => write!(f, "unknown actor `{}`", a),
+            RemoteError::Disconnected => write!(f, "remote node disconnected"),
+            RemoteError::Delivery(e) => write!(f, "delivery failed: {}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for RemoteError {}
+
+/// Start accepting connections on `addr` as node `name`.
+pub struct Listen {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+impl Message for Listen {
+    type Result = Result<(), io::Error>;
+}
+
+/// Make `recipient` reachable from other nodes as `name`.
+pub struct RegisterActor {
+    pub name: String,
+    pub recipient: Recipient<LuaMessage>,
+}
+
+impl Message for RegisterActor {
+    type Result = ();
+}
+
+/// Set how many bytes a connection makes room for in its write buffer before encoding a frame.
+///
+/// Frames are encoded straight into the buffer the connection writes from, which grows by this
+/// much when it runs low rather than a little for every frame. Applies to
+/// connections opened after the message is handled. Defaults to 64 KiB.
+pub struct SetBufferCapacity(pub usize);
+
+impl Message for SetBufferCapacity {
+    type Result = ();
+}
+
+/// Send `msg` to the remote actor at `address` (`node@host:port/actor_name`).
+///
+/// If `reply` is `false` the message is sent fire-and-forget and resolves to `Nil` as soon
+/// as it is written.
+pub struct RemoteSend {
+    pub address: String,
+    pub msg: LuaMessage,
+    pub reply: bool,
+}
+
+impl Message for RemoteSend {
+    type Result = Result<LuaMessage, RemoteError>;
+}
+
+/// Check if `name` is a remote address rather than the name of a local recipient.
+pub fn is_remote_address(name: &str) -> bool {
+    parse_address(name).is_ok()
+}
+
+struct RemoteAddress<'a> {
+    node: &'a str,
+    host: &'a str,
+    actor: &'a str,
+}
+
+fn parse_address(address: &str) -> Result<RemoteAddress<'_>, RemoteError> {
+    let invalid = || RemoteError::InvalidAddress(address.to_string());
+    let at = address.find('@').ok_or_else(invalid)?;
+    let (node, rest) = (&address[..at], &address[at + 1..]);
+    let slash = rest.find('/').ok_or_else(invalid)?;
+    let (host, actor) = (&rest[..slash], &rest[slash + 1..]);
+    if node.is_empty() || host.is_empty() || actor.is_empty() {
+        return Err(invalid());
+    }
+    Ok(RemoteAddress { node, host, actor })
+}
+
+impl Handler<Listen> for LuaNode {
+    type Result = Result<(), io::Error>;
+
+    fn handle(&mut self, listen: Listen, ctx: &mut Context<Self>) -> Self::Result {
+        let listener = TcpListener::bind(&listen.addr)?;
+        self.name = listen.name;
+        ctx.add_stream(listener.incoming());
+        Ok(())
+    }
+}
+
+impl StreamHandler<TcpStream, io::Error> for LuaNode {
+    fn handle(&mut self, stream: TcpStream, ctx: &mut Context<Self>) {
+        let node = ctx.address();
+        let capacity = self.buffer_capacity;
+        Connection::create(move |conn_ctx| {
+            Connection::with_stream(stream, node, capacity, conn_ctx)
+        });
+    }
+
+    fn error(&mut self, _: io::Error, _: &mut Context<Self>) -> Running {
+        // a failed accept shouldn't stop the node
+        Running::Continue
+    }
+}
+
+impl Handler<RegisterActor> for LuaNode {
+    type Result = ();
+
+    fn handle(&mut self, reg: RegisterActor, _: &mut Context<Self>) {
+        self.actors.insert(reg.name, reg.recipient);
+    }
+}
+
+impl Handler<SetBufferCapacity> for LuaNode {
+    type Result = ();
+
+    fn handle(&mut self, SetBufferCapacity(bytes): SetBufferCapacity, _: &mut Context<Self>) {
+        self.buffer_capacity = bytes;
+    }
+}
+
+impl LuaNode {
+    // the connection to `addr`, opened if there is none
+    fn peer(&mut self, addr: SocketAddr, ctx: &mut Context<Self>) -> Addr<Connection> {
+        match self.peers.get(&addr) {
+            Some(peer) if peer.connected() => peer.clone(),
+            _ => {
+                let node_addr = ctx.address();
+                let capacity = self.buffer_capacity;
+                let peer = Connection::create(move |conn_ctx| {
+                    Connection::connect(addr, node_addr, capacity, conn_ctx)
+                });
+                self.peers.insert(addr, peer.clone());
+                peer
+            }
+        }
+    }
+}
+
+// the address of `host`, looked up by the `Resolver` unless it is an IP address
+fn resolve(
+    host: &str,
+    address: String,
+) -> Box<dyn Future<Item = SocketAddr, Error = RemoteError>> {
+    if let Ok(addr) = host.parse() {
+        return Box::new(future::ok(addr));
+    }
+    Box::new(
+        Resolver::from_registry()
+            .send(Resolve::host(host))
+            .then(move |res| match res {
+                Ok(Ok(mut addrs)) => addrs
+                    .pop_front()
+                    .ok_or(RemoteError::InvalidAddress(address)),
+                _ => Err(RemoteError::InvalidAddress(address)),
+            }),
+    )
+}
+
+impl Handler<RemoteSend> for LuaNode {
+    type Result = ResponseActFuture<Self, LuaMessage, RemoteError>;
+
+    fn handle(&mut self, send: RemoteSend, _: &mut Context<Self>) -> Self::Result {
+        self.call(send.address, Payload::Message(send.msg), send.reply)
+    }
+}
+
+// `RemoteSend` with a message the sending actor encoded, see `MessageEncoder`
+pub(crate) struct SendEncoded {
+    pub(crate) address: String,
+    pub(crate) msg: Bytes,
+    pub(crate) reply: bool,
+}
+
+impl Message for SendEncoded {
+    type Result = Result<LuaMessage, RemoteError>;
+}
+
+impl Handler<SendEncoded> for LuaNode {
+    type Result = ResponseActFuture<Self, LuaMessage, RemoteError>;
+
+    fn handle(&mut self, send: SendEncoded, _: &mut Context<Self>) -> Self::Result {
+        self.call(send.address, Payload::Encoded(send.msg), send.reply)
+    }
+}
+
+impl LuaNode {
+    // send `msg` to the remote actor at `address` over the connection to its host
+    fn call(
+        &self,
+        address: String,
+        msg: Payload,
+        reply: bool,
+    ) -> ResponseActFuture<Self, LuaMessage, RemoteError> {
+        let (node, actor, host) = match parse_address(&address) {
+            Ok(a) => (a.node.to_string(), a.actor.to_string(), a.host.to_string()),
+            Err(e) => return Box::new(actix::fut::err(e)),
+        };
+        Box::new(
+            resolve(&host, address)
+                .into_actor(self)
+                .and_then(move |addr, act, ctx| {
+                    let call = act.peer(addr, ctx).send(Call {
+                        node,
+                        actor,
+                        msg,
+                        reply,
+                    });
+                    actix::fut::wrap_future(call.then(|res| match res {
+                        Ok(res) => res,
+                        Err(_) => Err(RemoteError::Disconnected),
+                    }))
+                }),
+        )
+    }
+}
+
+// A request from a remote node to a local actor.
+struct Deliver {
+    node: String,
+    actor: String,
+    msg: LuaMessage,
+}
+
+impl Message for Deliver {
+    type Result = Result<LuaMessage, RemoteError>;
+}
+
+impl Handler<Deliver> for LuaNode {
+    type Result = ResponseFuture<LuaMessage, RemoteError>;
+
+    fn handle(&mut self, deliver: Deliver, _: &mut Context<Self>) -> Self::Result {
+        if deliver.node != self.name {
+            return Box::new(future::err(RemoteError::UnknownNode(deliver.node)));
+        }
+        match self.actors.get(&deliver.actor) {
+            Some(rec) => Box::new(
+                rec.send(deliver.msg).then(|res| match res {
+                    Ok(LuaMessage::Error(e)) => Err(RemoteError::Delivery(e.to_string())),
+                    Ok(msg) => Ok(msg),
+                    Err(e) => Err(RemoteError::Delivery(e.to_string())),
+                }),
+            ),
+            None => Box::new(future::err(RemoteError::UnknownActor(deliver.actor))),
+        }
+    }
+}
+
+// A TCP connection to another node. Both sides of a connection can send requests.
+struct Connection {
+    node: Addr<LuaNode>,
+    writer: Option<FramedWrite<WriteHalf<TcpStream>, FrameCodec>>,
+    pending: HashMap<u64, oneshot::Sender<Result<LuaMessage, RemoteError>>>,
+    next_id: u64,
+    buffer_capacity: usize,
+}
+
+impl Connection {
+    fn new(node: Addr<LuaNode>, buffer_capacity: usize) -> Connection {
+        Connection {
+            node,
+            writer: None,
+            pending: HashMap::new(),
+            next_id: 1,
+            buffer_capacity,
+        }
+    }
+
+    fn with_stream(
+        stream: TcpStream,
+        node: Addr<LuaNode>,
+        buffer_capacity: usize,
+        ctx: &mut Context<Self>,
+    ) -> Self {
+        let mut conn = Connection::new(node, buffer_capacity);
+        conn.attach(stream, ctx);
+        conn
+    }
+
+    fn connect(
+        addr: SocketAddr,
+        node: Addr<LuaNode>,
+        buffer_capacity: usize,
+        ctx: &mut Context<Self>,
+    ) -> Self {
+        // block the mailbox until connected, so calls are queued instead of failing
+        ctx.wait(
+            actix::fut::wrap_future::<_, Connection>(TcpStream::connect(&addr))
+                .map(|stream, act, ctx| act.attach(stream, ctx))
+                .map_err(|_, _, ctx| ctx.stop()),
+        );
+        Connection::new(node, buffer_capacity)
+    }
+
+    fn attach(&mut self, stream: TcpStream, ctx: &mut Context<Self>) {
+        let (r, w) = stream.split();
+        ctx.add_stream(FramedRead::new(r, FrameCodec::new(0)));
+        let codec = FrameCodec::new(self.buffer_capacity);
+        self.writer = Some(FramedWrite::new(w, codec, ctx));
+    }
+
+    fn write(&mut self, frame: Frame) {
+        if let Some(ref mut writer) = self.writer {
+            writer.write(frame);
+        }
+    }
+}
+
+impl Actor for Connection {
+    type Context = Context<Self>;
+}
+
+impl WriteHandler<io::Error> for Connection {}
+
+struct Call {
+    node: String,
+    actor: String,
+    msg: Payload,
+    reply: bool,
+}
+
+impl Message for Call {
+    type Result = Result<LuaMessage, RemoteError>;
+}
+
+impl Handler<Call> for Connection {
+    type Result = ResponseFuture<LuaMessage, RemoteError>;
+
+    fn handle(&mut self, call: Call, _: &mut Context<Self>) -> Self::Result {
+        if let Payload::Message(ref msg) = call.msg {
+            if let Err(e) = check_encodable(msg) {
+                return Box::new(future::err(RemoteError::Delivery(e.to_string())));
+            }
+        }
+        if !call.reply {
+            self.write(Frame::Request {
+                id: 0,
+                node: call.node,
+                actor: call.actor,
+                msg: call.msg,
+            });
+            return Box::new(future::ok(LuaMessage::Nil));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(id, tx);
+        self.write(Frame::Request {
+            id,
+            node: call.node,
+            actor: call.actor,
+            msg: call.msg,
+        });
+
+        // the sender is dropped along with the connection if it is lost
+        Box::new(rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => Err(RemoteError::Disconnected),
+        }))
+    }
+}
+
+impl StreamHandler<Frame, io::Error> for Connection {
+    fn handle(&mut self, frame: Frame, ctx: &mut Context<Self>) {
+        match frame {
+            Frame::Request {
+                id,
+                node,
+                actor,
+                msg: Payload::Message(msg),
+            } => {
+                self.node
+                    .send(Deliver { node, actor, msg })
+                    .into_actor(self)
+                    .map(move |res, act, _| {
+                        if id == 0 {
+                            return;
+                        }
+                        let frame = match res {
+                            Ok(msg) => Frame::Response { id, msg },
+                            Err(error) => Frame::Error { id, error },
+                        };
+                        act.write(frame);
+                    })
+                    .map_err(|_, _, _| ())
+                    .spawn(ctx);
+            }
+            // frames are decoded with their messages, encoded ones are only written
+            Frame::Request {
+                msg: Payload::Encoded(_),
+                ..
+            } => {}
+            Frame::Response { id, msg } => {
+                if let Some(tx) = self.pending.remove(&id) {
+                    let _ = tx.send(Ok(msg));
+                }
+            }
+            Frame::Error { id, error } => {
+                if let Some(tx) = self.pending.remove(&id) {
+                    let _ = tx.send(Err(error));
+                }
+            }
+        }
+    }
+}
+
+// Wire format: a big-endian u32 length followed by a MessagePack array:
+//
+// * request:  [0, id, node, actor, msg] (id 0 means no response is expected)
+// * response: [1, id, msg]
+// * error:    [2, id, kind, message], see `ERROR_DELIVERY`
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+// how deep tables can be nested in a frame, so a frame can't overflow the stack of the decoder
+const MAX_DEPTH: usize = 128;
+
+// the kinds of errors in error frames, with the name of the node or the actor, or the message
+// of a failed delivery
+const ERROR_DELIVERY: i64 = 0;
+const ERROR_UNKNOWN_NODE: i64 = 1;
+const ERROR_UNKNOWN_ACTOR: i64 = 2;
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Request {
+        id: u64,
+        node: String,
+        actor: String,
+        msg: Payload,
+    },
+    Response {
+        id: u64,
+        msg: LuaMessage,
+    },
+    Error {
+        id: u64,
+        error: RemoteError,
+    },
+}
+
+// The message of a request, or the MessagePack encoding of one.
+#[derive(Debug, PartialEq)]
+enum Payload {
+    Message(LuaMessage),
+    Encoded(Bytes),
+}
+
+// Encodes the messages a Lua actor sends to remote actors. They are split off a buffer of at
+// least `capacity` bytes and share its allocation, a new one is allocated when it is full.
+pub(crate) struct MessageEncoder {
+    buf: BytesMut,
+    capacity: usize,
+}
+
+impl MessageEncoder {
+    pub(crate) fn new(capacity: usize) -> MessageEncoder {
+        MessageEncoder {
+            buf: BytesMut::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn encode(&mut self, msg: &LuaMessage) -> Result<Bytes, io::Error> {
+        // the estimated size of a message is more than its encoded size
+        let size = msg.estimated_size();
+        if self.buf.capacity() < size {
+            self.buf.reserve(size.max(self.capacity));
+        }
+        match write_message(&mut self.buf, msg) {
+            Ok(()) => Ok(self.buf.take().freeze()),
+            Err(e) => {
+                self.buf.clear();
+                Err(e)
+            }
+        }
+    }
+}
+
+// Frames are encoded straight into the write buffer, which is given room for `capacity` more
+// bytes when less than a quarter of that is left. Their length is written in front of them once
+// it is known.
+struct FrameCodec {
+    capacity: usize,
+}
+
+impl FrameCodec {
+    fn new(capacity: usize) -> FrameCodec {
+        FrameCodec { capacity }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = (src[0] as usize) << 24
+            | (src[1] as usize) << 16
+            | (src[2] as usize) << 8
+            | src[3] as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data("frame too large"));
+        }
+        if src.len() < 4 + len {
+            // the declared length is not trusted: the buffer at most doubles with the data
+            // which arrived, instead of growing to the whole frame before it is sent
+            let missing = 4 + len - src.len();
+            src.reserve(missing.min(src.len()));
+            return Ok(None);
+        }
+        src.split_to(4);
+        let body = src.split_to(len);
+        let mut reader = Reader {
+            buf: &body,
+            pos: 0,
+            depth: 0,
+        };
+        reader.frame().map(Some)
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if dst.capacity() - dst.len() < self.capacity / 4 {
+            dst.reserve(self.capacity);
+        }
+        let start = dst.len();
+        dst.extend_from_slice(&[0; 4]);
+        let res = write_frame(dst, frame).and_then(|()| match dst.len() - start - 4 {
+            len if len > MAX_FRAME_LEN => Err(invalid_data("frame too large")),
+            len => {
+                dst[start..start + 4].copy_from_slice(&(len as u32).to_be_bytes());
+                Ok(())
+            }
+        });
+        if res.is_err() {
+            dst.truncate(start);
+        }
+        res
+    }
+}
+
+fn write_frame(body: &mut BytesMut, frame: Frame) -> Result<(), io::Error> {
+    match frame {
+        Frame::Request {
+            id,
+            node,
+            actor,
+            msg,
+        } => {
+            write_array_len(body, 5);
+            write_int(body, 0);
+            write_int(body, id as i64);
+            write_str(body, &node);
+            write_str(body, &actor);
+            match msg {
+                Payload::Message(msg) => write_message(body, &msg)?,
+                Payload::Encoded(msg) => body.extend_from_slice(&msg),
+            }
+        }
+        Frame::Response { id, msg } => {
+            write_array_len(body, 3);
+            write_int(body, 1);
+            write_int(body, id as i64);
+            write_message(body, &msg)?;
+        }
+        Frame::Error { id, error } => {
+            let (kind, message) = match error {
+                RemoteError::UnknownNode(node) => (ERROR_UNKNOWN_NODE, node),
+                RemoteError::UnknownActor(actor) => (ERROR_UNKNOWN_ACTOR, actor),
+                RemoteError::Delivery(message) => (ERROR_DELIVERY, message),
+                e => (ERROR_DELIVERY, e.to_string()),
+            };
+            write_array_len(body, 4);
+            write_int(body, 2);
+            write_int(body, id as i64);
+            write_int(body, kind);
+            write_str(body, &message);
+        }
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// check that `msg` can be encoded before queueing it on a connection
+fn check_encodable(msg: &LuaMessage) -> Result<(), io::Error> {
+    match msg {
+        LuaMessage::Table(t) => t.values().try_for_each(check_encodable),
+        LuaMessage::ThreadYield(_) => Err(invalid_data(SUSPENDED_THREAD)),
+        LuaMessage::Error(e) => Err(invalid_data(&e.to_string())),
+        LuaMessage::Opaque(_) => Err(invalid_data(OPAQUE_VALUE)),
+        _ => Ok(()),
+    }
+}
+
+const SUSPENDED_THREAD: &str = "a suspended thread can't be sent to a remote node";
+const OPAQUE_VALUE: &str = "an opaque value can't be sent to a remote node";
+
+// the MessagePack writers append to a `BytesMut`, which grows as needed
+trait Push {
+    fn push(&mut self, byte: u8);
+}
+
+impl Push for BytesMut {
+    fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
+    }
+}
+
+fn write_message(buf: &mut BytesMut, msg: &LuaMessage) -> Result<(), io::Error> {
+    match msg {
+        LuaMessage::Nil => buf.push(0xc0),
+        LuaMessage::Boolean(false) => buf.push(0xc2),
+        LuaMessage::Boolean(true) => buf.push(0xc3),
+        LuaMessage::Integer(x) => write_int(buf, *x),
+        LuaMessage::Number(x) => {
+            buf.push(0xcb);
+            buf.extend_from_slice(&x.to_bits().to_be_bytes());
+        }
+        LuaMessage::String(s) => write_str(buf, s),
+        LuaMessage::Bytes(b) => write_bin(buf, b),
+        LuaMessage::Table(t) => {
+            let len = t.len();
+            if len < 16 {
+                buf.push(0x80 | len as u8);
+            } else if len <= 0xffff {
+                buf.push(0xde);
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            } else {
+                buf.push(0xdf);
+                buf.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+            for (k, v) in t {
+                write_str(buf, k);
+                write_message(buf, v)?;
+            }
+        }
+        LuaMessage::ThreadYield(_) => return Err(invalid_data(SUSPENDED_THREAD)),
+        LuaMessage::Error(e) => return Err(invalid_data(&e.to_string())),
+        LuaMessage::Opaque(_) => return Err(invalid_data(OPAQUE_VALUE)),
+    }
+    Ok(())
+}
+
+fn write_int(buf: &mut BytesMut, x: i64) {
+    if (0..0x80).contains(&x) {
+        buf.push(x as u8);
+    } else if (-32..0).contains(&x) {
+        buf.push(x as i8 as u8);
+    } else {
+        buf.push(0xd3);
+        buf.extend_from_slice(&x.to_be_bytes());
+    }
+}
+
+fn write_str(buf: &mut BytesMut, s: &str) {
+    let len = s.len();
+    if len < 32 {
+        buf.push(0xa0 | len as u8);
+    } else if len <= 0xff {
+        buf.push(0xd9);
+        buf.push(len as u8);
+    } else if len <= 0xffff {
+        buf.push(0xda);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(0xdb);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_bin(buf: &mut BytesMut, b: &[u8]) {
+    let len = b.len();
+    if len <= 0xff {
+        buf.push(0xc4);
+        buf.push(len as u8);
+    } else if len <= 0xffff {
+        buf.push(0xc5);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(0xc6);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(b);
+}
+
+fn write_array_len(buf: &mut BytesMut, len: usize) {
+    buf.push(0x90 | len as u8);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    // the tables the value being read is nested in
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], io::Error> {
+        if self.pos + n > self.buf.len() {
+            return Err(invalid_data("unexpected end of frame"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn be(&mut self, n: usize) -> Result<u64, io::Error> {
+        Ok(self.take(n)?.iter().fold(0, |acc, b| acc << 8 | u64::from(*b)))
+    }
+
+    fn frame(&mut self) -> Result<Frame, io::Error> {
+        let len = match self.byte()? {
+            b @ 0x90..=0x9f => b & 0x0f,
+            _ => return Err(invalid_data("frame is not an array")),
+        };
+        let frame = match (self.int()?, len) {
+            (0, 5) => Frame::Request {
+                id: self.int()? as u64,
+                node: self.string()?,
+                actor: self.string()?,
+                msg: Payload::Message(self.message()?),
+            },
+            (1, 3) => Frame::Response {
+                id: self.int()? as u64,
+                msg: self.message()?,
+            },
+            (2, 4) => {
+                let id = self.int()? as u64;
+                let error = match (self.int()?, self.string()?) {
+                    (ERROR_UNKNOWN_NODE, node) => RemoteError::UnknownNode(node),
+                    (ERROR_UNKNOWN_ACTOR, actor) => RemoteError::UnknownActor(actor),
+                    (_, message) => RemoteError::Delivery(message),
+                };
+                Frame::Error { id, error }
+            }
+            _ => return Err(invalid_data("unknown frame type")),
+        };
+        Ok(frame)
+    }
+
+    fn int(&mut self) -> Result<i64, io::Error> {
+        match self.message()? {
+            LuaMessage::Integer(x) => Ok(x),
+            _ => Err(invalid_data("expected an integer")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, io::Error> {
+        match self.message()? {
+            LuaMessage::String(s) => Ok(s),
+            _ => Err(invalid_data("expected a string")),
+        }
+    }
+
+    fn str_of_len(&mut self, len: usize) -> Result<LuaMessage, io::Error> {
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map(LuaMessage::String)
+            .map_err(|_| invalid_data("string is not valid utf-8"))
+    }
+
+    // the capacity for `len` values, which a frame announcing more values than it has bytes left
+    // can't inflate
+    fn capacity(&self, len: usize) -> usize {
+        len.min(self.buf.len() - self.pos)
+    }
+
+    // read a table with `read`, at most `MAX_DEPTH` tables deep
+    fn nested<F>(&mut self, read: F) -> Result<LuaMessage, io::Error>
+    where
+        F: FnOnce(&mut Self) -> Result<HashMap<String, LuaMessage>, io::Error>,
+    {
+        if self.depth >= MAX_DEPTH {
+            return Err(invalid_data("tables nested too deep"));
+        }
+        self.depth += 1;
+        let t = read(self)?;
+        self.depth -= 1;
+        Ok(LuaMessage::Table(t))
+    }
+
+    fn table_of_len(&mut self, len: usize) -> Result<LuaMessage, io::Error> {
+        self.nested(|r| r.table_entries(len))
+    }
+
+    fn table_entries(&mut self, len: usize) -> Result<HashMap<String, LuaMessage>, io::Error> {
+        let mut t = HashMap::with_capacity(self.capacity(len));
+        for _ in 0..len {
+            let k = match self.message()? {
+                LuaMessage::String(s) => s,
+                LuaMessage::Integer(i) => i.to_string(),
+                _ => return Err(invalid_data("table keys must be strings")),
+            };
+            t.insert(k, self.message()?);
+        }
+        Ok(t)
+    }
+
+    // arrays are decoded the same way lua sequences are converted, with "1".."n" as keys
+    fn array_of_len(&mut self, len: usize) -> Result<LuaMessage, io::Error> {
+        self.nested(|r| {
+            let mut t = HashMap::with_capacity(r.capacity(len));
+            for i in 1..=len {
+                t.insert(i.to_string(), r.message()?);
+            }
+            Ok(t)
+        })
+    }
+
+    fn message(&mut self) -> Result<LuaMessage, io::Error> {
+        let msg = match self.byte()? {
+            b @ 0x00..=0x7f => LuaMessage::Integer(i64::from(b)),
+            b @ 0x80..=0x8f => self.table_of_len(usize::from(b & 0x0f))?,
+            b @ 0x90..=0x9f => self.array_of_len(usize::from(b & 0x0f))?,
+            b @ 0xa0..=0xbf => self.str_of_len(usize::from(b & 0x1f))?,
+            0xc0 => LuaMessage::Nil,
+            0xc2 => LuaMessage::Boolean(false),
+            0xc3 => LuaMessage::Boolean(true),
+            0xc4 => {
+                let len = self.be(1)? as usize;
+                LuaMessage::from(self.take(len)?.to_vec())
+            }
+            0xc5 => {
+                let len = self.be(2)? as usize;
+                LuaMessage::from(self.take(len)?.to_vec())
+            }
+            0xc6 => {
+                let len = self.be(4)? as usize;
+                LuaMessage::from(self.take(len)?.to_vec())
+            }
+            0xca => LuaMessage::Number(f64::from(f32::from_bits(self.be(4)? as u32))),
+            0xcb => LuaMessage::Number(f64::from_bits(self.be(8)?)),
+            0xcc => LuaMessage::Integer(self.be(1)? as i64),
+            0xcd => LuaMessage::Integer(self.be(2)? as i64),
+            0xce => LuaMessage::Integer(self.be(4)? as i64),
+            0xcf => LuaMessage::Integer(self.be(8)? as i64),
+            0xd0 => LuaMessage::Integer(i64::from(self.be(1)? as u8 as i8)),
+            0xd1 => LuaMessage::Integer(i64::from(self.be(2)? as u16 as i16)),
+            0xd2 => LuaMessage::Integer(i64::from(self.be(4)? as u32 as i32)),
+            0xd3 => LuaMessage::Integer(self.be(8)? as i64),
+            0xd9 => {
+                let len = self.be(1)? as usize;
+                self.str_of_len(len)?
+            }
+            0xda => {
+                let len = self.be(2)? as usize;
+                self.str_of_len(len)?
+            }
+            0xdb => {
+                let len = self.be(4)? as usize;
+                self.str_of_len(len)?
+            }
+            0xdc => {
+                let len = self.be(2)? as usize;
+                self.array_of_len(len)?
+            }
+            0xdd => {
+                let len = self.be(4)? as usize;
+                self.array_of_len(len)?
+            }
+            0xde => {
+                let len = self.be(2)? as usize;
+                self.table_of_len(len)?
+            }
+            0xdf => {
+                let len = self.be(4)? as usize;
+                self.table_of_len(len)?
+            }
+            b @ 0xe0..=0xff => LuaMessage::Integer(i64::from(b as i8)),
+            _ => return Err(invalid_data("unsupported MessagePack type")),
+        };
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use bytes::BufMut;
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    fn roundtrip(frame: Frame) -> Frame {
+        let mut buf = BytesMut::new();
+        let mut codec = FrameCodec::new(DEFAULT_BUFFER_CAPACITY);
+        codec.encode(frame, &mut buf).unwrap();
+        codec.decode(&mut buf).unwrap().unwrap()
+    }
+
+    #[test]
+    fn codec_roundtrip() {
+        let mut t = HashMap::new();
+        t.insert("name".to_string(), LuaMessage::from("x".repeat(300)));
+        t.insert("small".to_string(), LuaMessage::from(-3));
+        t.insert("big".to_string(), LuaMessage::from(1i64 << 40));
+        t.insert("neg".to_string(), LuaMessage::from(-1000));
+        t.insert("ratio".to_string(), LuaMessage::from(0.25));
+        t.insert("ok".to_string(), LuaMessage::from(true));
+        t.insert("raw".to_string(), LuaMessage::from(vec![0xff; 300]));
+        let msg = LuaMessage::from(t);
+
+        let request = |msg| Frame::Request {
+            id: 42,
+            node: "node".to_string(),
+            actor: "worker".to_string(),
+            msg,
+        };
+        let frame = request(Payload::Message(msg.clone()));
+        assert_eq!(roundtrip(frame), request(Payload::Message(msg.clone())));
+        // a message encoded by an actor is written as it is
+        let encoded = MessageEncoder::new(0).encode(&msg).unwrap();
+        let frame = request(Payload::Encoded(encoded));
+        assert_eq!(roundtrip(frame), request(Payload::Message(msg)));
+        for error in &[
+            RemoteError::Delivery("oops".to_string()),
+            RemoteError::UnknownNode("node-b".to_string()),
+            RemoteError::UnknownActor("missing".to_string()),
+        ] {
+            let frame = Frame::Error {
+                id: 1,
+                error: error.clone(),
+            };
+            assert_eq!(
+                roundtrip(frame),
+                Frame::Error {
+                    id: 1,
+                    error: error.clone()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn codec_hostile_frames() {
+        let decode = |body: &[u8]| {
+            let mut buf = BytesMut::with_capacity(4 + body.len());
+            buf.put_u32_be(body.len() as u32);
+            buf.put_slice(body);
+            FrameCodec::new(0).decode(&mut buf)
+        };
+        // a response whose message is an array nested a thousand times
+        let mut nested = vec![0x93, 0x01, 0x01];
+        nested.extend(vec![0x91; 1000]);
+        nested.push(0xc0);
+        let err = decode(&nested).unwrap_err();
+        assert_eq!(err.to_string(), "tables nested too deep");
+
+        // a response announcing a table of u32::MAX entries in a few bytes
+        let err = decode(&[0x93, 0x01, 0x01, 0xdf, 0xff, 0xff, 0xff, 0xff]).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected end of frame");
+        let err = decode(&[0x93, 0x01, 0x01, 0xdd, 0xff, 0xff, 0xff, 0xff]).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected end of frame");
+
+        let mut deep = vec![0x93, 0x01, 0x01];
+        deep.extend(vec![0x91; MAX_DEPTH - 1]);
+        deep.push(0xc0);
+        assert!(decode(&deep).is_ok());
+    }
+
+    #[test]
+    fn codec_partial_frame() {
+        let mut buf = BytesMut::new();
+        FrameCodec::new(0)
+            .encode(
+                Frame::Response {
+                    id: 1,
+                    msg: LuaMessage::Nil,
+                },
+                &mut buf,
+            )
+            .unwrap();
+        let mut partial = buf.split_to(3);
+        assert_eq!(FrameCodec::new(0).decode(&mut partial).unwrap(), None);
+
+        // a header announcing the largest frame doesn't allocate it before the data arrives
+        let mut header = BytesMut::with_capacity(4);
+        header.put_u32_be(MAX_FRAME_LEN as u32);
+        assert_eq!(FrameCodec::new(0).decode(&mut header).unwrap(), None);
+        assert!(header.capacity() < 1024);
+    }
+
+    #[test]
+    fn codec_write_buffer() {
+        let mut codec = FrameCodec::new(1024);
+        let mut buf = BytesMut::new();
+        let frame = |msg: LuaMessage| Frame::Response { id: 1, msg };
+
+        // the frames are written one after another in the room made for the first
+        codec.encode(frame(LuaMessage::from("hello")), &mut buf).unwrap();
+        assert!(buf.capacity() >= 1024);
+        let (start, capacity) = (buf.as_ptr(), buf.capacity());
+        codec.encode(frame(LuaMessage::from("world")), &mut buf).unwrap();
+        assert_eq!((buf.as_ptr(), buf.capacity()), (start, capacity));
+        codec.encode(frame(LuaMessage::from("x".repeat(4096))), &mut buf).unwrap();
+
+        // a frame which fails to encode leaves nothing behind
+        let len = buf.len();
+        let mut t = HashMap::new();
+        t.insert("thread".to_string(), LuaMessage::ThreadYield("1".to_string()));
+        assert!(check_encodable(&LuaMessage::from(t.clone())).is_err());
+        assert!(codec.encode(frame(LuaMessage::from(t)), &mut buf).is_err());
+        assert_eq!(buf.len(), len);
+
+        for msg in &["hello", "world"] {
+            assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), frame(LuaMessage::from(*msg)));
+        }
+        assert_eq!(
+            codec.decode(&mut buf).unwrap().unwrap(),
+            frame(LuaMessage::from("x".repeat(4096)))
+        );
+    }
+
+    #[test]
+    fn message_encoder() {
+        let mut encoder = MessageEncoder::new(1024);
+        let hello = encoder.encode(&LuaMessage::from("hello".repeat(10))).unwrap();
+        let world = encoder.encode(&LuaMessage::from("world".repeat(10))).unwrap();
+        // both messages are in the same buffer
+        assert_eq!(hello.as_ptr() as usize + hello.len(), world.as_ptr() as usize);
+
+        // a message larger than the buffer gets a buffer of its own
+        let large = encoder.encode(&LuaMessage::from("x".repeat(4096))).unwrap();
+        assert_eq!(large.len(), 3 + 4096);
+
+        let mut t = HashMap::new();
+        t.insert("thread".to_string(), LuaMessage::ThreadYield("1".to_string()));
+        assert!(encoder.encode(&LuaMessage::from(t)).is_err());
+        let nil = encoder.encode(&LuaMessage::Nil).unwrap();
+        assert_eq!(&nil[..], &[0xc0]);
+    }
+
+    #[test]
+    fn addresses() {
+        assert!(is_remote_address("node@127.0.0.1:7000/worker"));
+        assert!(!is_remote_address("worker"));
+        assert!(!is_remote_address("node@127.0.0.1:7000"));
+        assert!(!is_remote_address("@127.0.0.1:7000/worker"));
+    }
+
+    #[test]
+    fn remote_send() {
+        let system = System::new("test");
+
+        let worker = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg .. " processed""#)
+            .build()
+            .unwrap()
+            .start();
+        let caller = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "job" then
+                ctx.state.result = ctx.send("node-a@127.0.0.1:17310/worker", ctx.msg)
+            end
+            return ctx.state.result
+            "#,
+            )
+            .with_buffer_capacity(1024)
+            .build()
+            .unwrap()
+            .start();
+
+        let node = LuaNode::from_registry();
+        node.do_send(RegisterActor {
+            name: "worker".to_string(),
+            recipient: worker.recipient(),
+        });
+        let fut = node
+            .send(Listen {
+                name: "node-a".to_string(),
+                addr: "127.0.0.1:17310".parse().unwrap(),
+            })
+            .map_err(|e| println!("node dead {}", e))
+            .and_then(move |res| {
+                res.unwrap();
+                node.send(RemoteSend {
+                    address: "node-b@127.0.0.1:17310/worker".to_string(),
+                    msg: LuaMessage::Nil,
+                    reply: true,
+                }).map_err(|e| println!("node dead {}", e))
+            })
+            .and_then(|res| {
+                assert_eq!(res, Err(RemoteError::UnknownNode("node-b".to_string())));
+                LuaNode::from_registry()
+                    .send(RemoteSend {
+                        address: "node-a@127.0.0.1:17310/missing".to_string(),
+                        msg: LuaMessage::Nil,
+                        reply: true,
+                    })
+                    .map_err(|e| println!("node dead {}", e))
+            })
+            .and_then(|res| {
+                assert_eq!(res, Err(RemoteError::UnknownActor("missing".to_string())));
+                Ok(())
+            })
+            .and_then({
+                let caller = caller.clone();
+                move |()| {
+                    caller
+                        .send(LuaMessage::from("job"))
+                        .map_err(|e| println!("actor dead {}", e))
+                }
+            })
+            .and_then(|_| Delay::new(Duration::from_millis(500)).map_err(|_| ()))
+            .and_then(move |()| {
+                caller
+                    .send(LuaMessage::Nil)
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("job processed"));
+                System::current().stop();
+            });
+        Arbiter::spawn(fut);
+
+        system.run();
+    }
+}
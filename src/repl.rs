@@ -0,0 +1,249 @@
+use actix::io::{FramedWrite, WriteHandler};
+use actix::prelude::*;
+use rlua::{Error as LuaError, FromLua, Function, MultiValue};
+use tokio::codec::{FramedRead, LinesCodec};
+use tokio::io::{AsyncRead, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+
+use std::io;
+use std::net;
+
+use actor::LuaActor;
+use error::ActixLuaError;
+use message::LuaMessage;
+use vm::catch_panic;
+
+/// Evaluate a chunk of Lua code in the VM of a `LuaActor`, for inspecting it while it runs and
+/// for one-off fixes of its state. The actor must be built with `LuaActorBuilder::with_eval`.
+///
+/// `code` is evaluated as an expression if it is one, like the Lua REPL does, and the reply is
+/// its values. It sees the globals of the scripts and `ctx.state`, but can't call the functions
+/// of `ctx` which need a message being handled, like `ctx.send`.
+///
+/// ```rust,ignore
+/// addr.send(Eval("ctx.state.retries".to_string()))
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Eval(pub String);
+
+impl Message for Eval {
+    type Result = Result<Vec<LuaMessage>, ActixLuaError>;
+}
+
+impl Handler<Eval> for LuaActor {
+    type Result = Result<Vec<LuaMessage>, ActixLuaError>;
+
+    fn handle(&mut self, Eval(code): Eval, _: &mut Context<Self>) -> Self::Result {
+        if !self.eval {
+            return Err(ActixLuaError::EvalDisabled);
+        }
+        let vm = &self.vm;
+        catch_panic(|| {
+            let expression = format!("return {}", code);
+            let chunk: Function = match vm.load(&expression, Some("repl")) {
+                Ok(chunk) => chunk,
+                Err(LuaError::SyntaxError { .. }) => vm.load(&code, Some("repl"))?,
+                Err(e) => return Err(e.into()),
+            };
+            let values: MultiValue = chunk.call(())?;
+            Ok(values
+                .into_iter()
+                .map(|value| LuaMessage::from_lua(value, vm))
+                .collect::<Result<_, _>>()?)
+        })
+    }
+}
+
+/// A REPL on a TCP socket, which evaluates every line it receives with [`Eval`] in the VM of a
+/// `LuaActor`, and answers with a line of its values separated by tabs, or of its error.
+///
+/// Anyone who can connect can run any code in the actor, so only listen on a local address.
+///
+/// ```rust,ignore
+/// LuaRepl::serve(TcpListener::bind("127.0.0.1:7070")?, addr)?;
+/// ```
+///
+/// ```text
+/// $ nc 127.0.0.1 7070
+/// ctx.state.retries
+/// 3
+/// ctx.state.retries = 0
+///
+/// ```
+///
+/// [`Eval`]: struct.Eval.html
+pub struct LuaRepl {
+    target: Addr<LuaActor>,
+}
+
+impl LuaRepl {
+    /// Accept connections on `listener` for evaluating code in `target`. Must be called in a
+    /// running actix system.
+    pub fn serve(
+        listener: net::TcpListener,
+        target: Addr<LuaActor>,
+    ) -> Result<Addr<LuaRepl>, io::Error> {
+        let listener = TcpListener::from_std(listener, &Handle::default())?;
+        Ok(LuaRepl::create(move |ctx| {
+            ctx.add_stream(listener.incoming());
+            LuaRepl { target }
+        }))
+    }
+}
+
+impl Actor for LuaRepl {
+    type Context = Context<Self>;
+}
+
+impl StreamHandler<TcpStream, io::Error> for LuaRepl {
+    fn handle(&mut self, stream: TcpStream, _: &mut Context<Self>) {
+        let target = self.target.clone();
+        Session::create(move |ctx| {
+            let (r, w) = stream.split();
+            ctx.add_stream(FramedRead::new(r, LinesCodec::new()));
+            Session {
+                target,
+                writer: FramedWrite::new(w, LinesCodec::new(), ctx),
+            }
+        });
+    }
+
+    fn error(&mut self, _: io::Error, _: &mut Context<Self>) -> Running {
+        // a failed accept shouldn't stop the REPL
+        Running::Continue
+    }
+}
+
+// a connection to the REPL
+struct Session {
+    target: Addr<LuaActor>,
+    writer: FramedWrite<WriteHalf<TcpStream>, LinesCodec>,
+}
+
+impl Actor for Session {
+    type Context = Context<Self>;
+}
+
+impl WriteHandler<io::Error> for Session {}
+
+impl StreamHandler<String, io::Error> for Session {
+    fn handle(&mut self, line: String, ctx: &mut Context<Self>) {
+        // the lines are evaluated in order
+        let eval = self.target.send(Eval(line)).into_actor(self);
+        ctx.wait(eval.then(|res, act, _| {
+            let output = match res {
+                Ok(Ok(values)) => values.iter().map(render).collect::<Vec<_>>().join("\t"),
+                Ok(Err(e)) => format!("error: {}", e),
+                Err(e) => format!("error: {}", e),
+            };
+            act.writer.write(output);
+            actix::fut::ok(())
+        }));
+    }
+}
+
+// a value the way it is written in Lua, with the keys of tables sorted
+fn render(msg: &LuaMessage) -> String {
+    match msg {
+        LuaMessage::String(s) => format!("{:?}", s),
+        LuaMessage::Integer(i) => i.to_string(),
+        LuaMessage::Number(n) => n.to_string(),
+        LuaMessage::Boolean(b) => b.to_string(),
+        LuaMessage::Nil => "nil".to_string(),
+        LuaMessage::Table(t) => {
+            let mut fields: Vec<_> = t
+                .iter()
+                .map(|(k, v)| match k.parse::<i64>() {
+                    Ok(i) => (Ok(i), format!("[{}] = {}", i, render(v))),
+                    Err(_) => (Err(k), format!("{} = {}", k, render(v))),
+                })
+                .collect();
+            fields.sort();
+            let fields: Vec<_> = fields.into_iter().map(|(_, field)| field).collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        LuaMessage::Bytes(b) => format!("<{} bytes>", b.len()),
+        LuaMessage::ThreadYield(_) => "<suspended>".to_string(),
+        LuaMessage::Error(e) => format!("error: {}", e),
+        LuaMessage::Opaque(_) => "<opaque>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+    use std::io::{BufRead, BufReader, Write};
+    use std::thread;
+
+    #[test]
+    fn eval_state() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.retries = 3")
+            .on_handle_with_lua("return ctx.state.retries")
+            .with_eval()
+            .build()
+            .unwrap()
+            .start();
+        let disabled = LuaActorBuilder::new().build().unwrap().start();
+
+        let l = addr
+            .send(Eval("ctx.state.retries, type(ctx)".to_string()))
+            .join(addr.send(Eval("ctx.state.retries = 0".to_string())))
+            .join(addr.send(Eval("error('boom')".to_string())))
+            .join(addr.send(LuaMessage::Nil))
+            .join(disabled.send(Eval("1".to_string())));
+        Arbiter::spawn(l.map(|((((read, write), failed), retries), disabled)| {
+            assert_eq!(read, Ok(vec![3.into(), "table".into()]));
+            assert_eq!(write, Ok(vec![]));
+            match failed {
+                Err(ActixLuaError::RuntimeError { traceback }) => {
+                    assert!(traceback.starts_with(r#"[string "repl"]:1: boom"#))
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(retries, LuaMessage::from(0));
+            assert_eq!(disabled, Err(ActixLuaError::EvalDisabled));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn repl_lines() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.user = { name = 'ada', 42 }")
+            .with_eval()
+            .build()
+            .unwrap()
+            .start();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        LuaRepl::serve(listener, addr).unwrap();
+
+        let sys = System::current();
+        let client = thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(local_addr).unwrap();
+            stream
+                .write_all(b"ctx.state.user\nlocal n = 1\n1 +\nctx.state.user.name, 2\n")
+                .unwrap();
+            let lines = BufReader::new(stream).lines().take(4).collect::<Result<Vec<_>, _>>();
+            sys.stop();
+            lines.unwrap()
+        });
+
+        system.run();
+        let lines = client.join().unwrap();
+        assert_eq!(lines[0], r#"{[1] = 42, name = "ada"}"#);
+        assert_eq!(lines[1], "");
+        assert!(lines[2].starts_with("error: "));
+        assert_eq!(lines[3], "\"ada\"\t2");
+    }
+}
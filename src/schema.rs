@@ -0,0 +1,431 @@
+use regex::Regex;
+use serde_json::Value;
+
+use error::{ActixLuaError, SchemaViolation};
+use message::LuaMessage;
+
+// A JSON Schema checking the messages of a named handler, with the `json` feature. See
+// `LuaActorBuilder::with_schema` for the keywords it knows.
+//
+// Messages are checked as the JSON `serde_json` converts them to, so tables keyed `"1"`..`"n"`
+// are arrays, and an empty table is both an empty array and an empty object.
+#[derive(Debug)]
+pub(crate) struct Schema {
+    // `false`, or `not: {}`
+    never: bool,
+    types: Option<Vec<String>>,
+    values: Option<Vec<Value>>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<Regex>,
+    properties: Vec<(String, Schema)>,
+    required: Vec<String>,
+    additional_properties: Option<Box<Schema>>,
+    items: Option<Box<Schema>>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    all_of: Vec<Schema>,
+    any_of: Vec<Schema>,
+    one_of: Vec<Schema>,
+    not: Option<Box<Schema>>,
+}
+
+const TYPES: &[&str] = &[
+    "null", "boolean", "integer", "number", "string", "array", "object",
+];
+
+impl Schema {
+    pub(crate) fn new(schema: &Value) -> Result<Schema, String> {
+        let mut compiled = Schema {
+            never: false,
+            types: None,
+            values: None,
+            minimum: None,
+            maximum: None,
+            exclusive_minimum: None,
+            exclusive_maximum: None,
+            min_length: None,
+            max_length: None,
+            pattern: None,
+            properties: vec![],
+            required: vec![],
+            additional_properties: None,
+            items: None,
+            min_items: None,
+            max_items: None,
+            all_of: vec![],
+            any_of: vec![],
+            one_of: vec![],
+            not: None,
+        };
+        let keywords = match schema {
+            Value::Bool(b) => {
+                compiled.never = !b;
+                return Ok(compiled);
+            }
+            Value::Object(keywords) => keywords,
+            _ => return Err("a schema must be an object or a boolean".to_string()),
+        };
+        for (keyword, value) in keywords {
+            let number = || value.as_f64().ok_or(format!("`{}` must be a number", keyword));
+            let count = || match value.as_u64() {
+                Some(n) => Ok(n as usize),
+                None => Err(format!("`{}` must be a non-negative integer", keyword)),
+            };
+            let schemas = || match value {
+                Value::Array(schemas) => schemas.iter().map(Schema::new).collect(),
+                _ => Err(format!("`{}` must be an array of schemas", keyword)),
+            };
+            match keyword.as_str() {
+                "type" => {
+                    let types = match value {
+                        Value::String(t) => vec![t.clone()],
+                        Value::Array(types) => types
+                            .iter()
+                            .map(|t| t.as_str().map(str::to_string))
+                            .collect::<Option<_>>()
+                            .ok_or("`type` must be a string or an array of strings")?,
+                        _ => return Err("`type` must be a string or an array of strings".into()),
+                    };
+                    if let Some(t) = types.iter().find(|t| !TYPES.contains(&t.as_str())) {
+                        return Err(format!("unknown type `{}`", t));
+                    }
+                    compiled.types = Some(types);
+                }
+                "enum" => match value {
+                    Value::Array(values) => compiled.values = Some(values.clone()),
+                    _ => return Err("`enum` must be an array".to_string()),
+                },
+                "const" => compiled.values = Some(vec![value.clone()]),
+                "minimum" => compiled.minimum = Some(number()?),
+                "maximum" => compiled.maximum = Some(number()?),
+                "exclusiveMinimum" => compiled.exclusive_minimum = Some(number()?),
+                "exclusiveMaximum" => compiled.exclusive_maximum = Some(number()?),
+                "minLength" => compiled.min_length = Some(count()?),
+                "maxLength" => compiled.max_length = Some(count()?),
+                "pattern" => {
+                    let pattern = value.as_str().ok_or("`pattern` must be a string")?;
+                    let re = Regex::new(pattern).map_err(|e| format!("`pattern`: {}", e))?;
+                    compiled.pattern = Some(re);
+                }
+                "properties" => match value {
+                    Value::Object(properties) => {
+                        for (name, schema) in properties {
+                            compiled.properties.push((name.clone(), Schema::new(schema)?));
+                        }
+                    }
+                    _ => return Err("`properties` must be an object".to_string()),
+                },
+                "required" => {
+                    compiled.required = match value {
+                        Value::Array(names) => names
+                            .iter()
+                            .map(|name| name.as_str().map(str::to_string))
+                            .collect::<Option<_>>()
+                            .ok_or("`required` must be an array of strings")?,
+                        _ => return Err("`required` must be an array of strings".to_string()),
+                    }
+                }
+                "additionalProperties" => {
+                    compiled.additional_properties = Some(Box::new(Schema::new(value)?))
+                }
+                "items" => compiled.items = Some(Box::new(Schema::new(value)?)),
+                "minItems" => compiled.min_items = Some(count()?),
+                "maxItems" => compiled.max_items = Some(count()?),
+                "allOf" => compiled.all_of = schemas()?,
+                "anyOf" => compiled.any_of = schemas()?,
+                "oneOf" => compiled.one_of = schemas()?,
+                "not" => compiled.not = Some(Box::new(Schema::new(value)?)),
+                // would accept anything if it was skipped
+                "$ref" => return Err("`$ref` isn't supported".to_string()),
+                // annotations, and the keywords which aren't checked
+                _ => {}
+            }
+        }
+        Ok(compiled)
+    }
+
+    // the violations of `msg`, sent to the handler `handler`
+    pub(crate) fn check(&self, handler: &str, msg: &LuaMessage) -> Result<(), ActixLuaError> {
+        let mut violations = vec![];
+        self.validate(&Value::from(msg.clone()), "", &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ActixLuaError::InvalidMessage {
+                handler: handler.to_string(),
+                violations,
+            })
+        }
+    }
+
+    fn is_valid(&self, value: &Value) -> bool {
+        let mut violations = vec![];
+        self.validate(value, "", &mut violations);
+        violations.is_empty()
+    }
+
+    fn validate(&self, value: &Value, path: &str, violations: &mut Vec<SchemaViolation>) {
+        let mut violation = |message: String| {
+            violations.push(SchemaViolation {
+                path: path.to_string(),
+                message,
+            })
+        };
+        if self.never {
+            return violation("is not allowed".to_string());
+        }
+        if let Some(ref types) = self.types {
+            if !types.iter().any(|t| is_type(value, t)) {
+                return violation(format!("must be of type {}", types.join(" or ")));
+            }
+        }
+        if let Some(ref values) = self.values {
+            if !values.contains(value) {
+                let values: Vec<_> = values.iter().map(Value::to_string).collect();
+                violation(format!("must be one of {}", values.join(", ")));
+            }
+        }
+
+        if let Some(n) = value.as_f64() {
+            if let Some(min) = self.minimum.filter(|&min| n < min) {
+                violation(format!("must be at least {}", min));
+            }
+            if let Some(max) = self.maximum.filter(|&max| n > max) {
+                violation(format!("must be at most {}", max));
+            }
+            if let Some(min) = self.exclusive_minimum.filter(|&min| n <= min) {
+                violation(format!("must be greater than {}", min));
+            }
+            if let Some(max) = self.exclusive_maximum.filter(|&max| n >= max) {
+                violation(format!("must be less than {}", max));
+            }
+        }
+
+        if let Some(s) = value.as_str() {
+            let len = s.chars().count();
+            if let Some(min) = self.min_length.filter(|&min| len < min) {
+                violation(format!("must be at least {} characters long", min));
+            }
+            if let Some(max) = self.max_length.filter(|&max| len > max) {
+                violation(format!("must be at most {} characters long", max));
+            }
+            if let Some(re) = self.pattern.as_ref().filter(|re| !re.is_match(s)) {
+                violation(format!("must match `{}`", re.as_str()));
+            }
+        }
+
+        if let Some(items) = as_array(value) {
+            if let Some(min) = self.min_items.filter(|&min| items.len() < min) {
+                violation(format!("must have at least {} items", min));
+            }
+            if let Some(max) = self.max_items.filter(|&max| items.len() > max) {
+                violation(format!("must have at most {} items", max));
+            }
+            if let Some(ref schema) = self.items {
+                for (i, item) in items.iter().enumerate() {
+                    schema.validate(item, &format!("{}/{}", path, i), violations);
+                }
+            }
+        }
+
+        if let Value::Object(fields) = value {
+            for name in &self.required {
+                if !fields.contains_key(name) {
+                    violations.push(SchemaViolation {
+                        path: SchemaViolation::pointer(path, name),
+                        message: "is required".to_string(),
+                    });
+                }
+            }
+            for (name, field) in fields {
+                let path = SchemaViolation::pointer(path, name);
+                match self.properties.iter().find(|(property, _)| property == name) {
+                    Some((_, schema)) => schema.validate(field, &path, violations),
+                    None => {
+                        if let Some(ref schema) = self.additional_properties {
+                            schema.validate(field, &path, violations);
+                        }
+                    }
+                }
+            }
+        }
+
+        for schema in &self.all_of {
+            schema.validate(value, path, violations);
+        }
+        let mut violation = |message: &str| {
+            violations.push(SchemaViolation {
+                path: path.to_string(),
+                message: message.to_string(),
+            })
+        };
+        if !self.any_of.is_empty() && !self.any_of.iter().any(|s| s.is_valid(value)) {
+            violation("must match at least one schema of `anyOf`");
+        }
+        if !self.one_of.is_empty() && self.one_of.iter().filter(|s| s.is_valid(value)).count() != 1
+        {
+            violation("must match exactly one schema of `oneOf`");
+        }
+        if self.not.as_ref().is_some_and(|s| s.is_valid(value)) {
+            violation("must not match the schema of `not`");
+        }
+    }
+}
+
+fn is_type(value: &Value, t: &str) -> bool {
+    match (t, value) {
+        ("null", Value::Null) | ("boolean", Value::Bool(_)) | ("string", Value::String(_)) => true,
+        ("integer", Value::Number(n)) => n.as_f64().is_some_and(|n| n.fract() == 0.0),
+        ("number", Value::Number(_)) | ("object", Value::Object(_)) => true,
+        ("array", value) => as_array(value).is_some(),
+        _ => false,
+    }
+}
+
+// `value` as an array, with the empty table as an empty array
+fn as_array(value: &Value) -> Option<&[Value]> {
+    match value {
+        Value::Array(items) => Some(items),
+        Value::Object(fields) if fields.is_empty() => Some(&[]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::prelude::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+
+    #[derive(Serialize)]
+    struct Order {
+        sku: String,
+        quantity: i64,
+    }
+
+    impl Message for Order {
+        type Result = Result<i64, ActixLuaError>;
+    }
+
+    impl_lua_handler!(Order => "order", serde);
+
+    fn violations(schema: &Value, value: Value) -> Vec<String> {
+        let schema = Schema::new(schema).unwrap();
+        match schema.check("handle", &LuaMessage::from(value)) {
+            Ok(()) => vec![],
+            Err(ActixLuaError::InvalidMessage { violations, .. }) => {
+                violations.iter().map(|v| v.to_string()).collect()
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn schema_violations() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "sku": { "type": "string", "pattern": "^[A-Z]+-[0-9]+$" },
+                "quantity": { "type": "integer", "minimum": 1 },
+                "tags": { "type": "array", "items": { "enum": ["gift", "express"] } },
+                "note": { "type": ["string", "null"], "maxLength": 3 },
+            },
+            "required": ["sku", "quantity"],
+            "additionalProperties": false,
+        });
+        let valid = json!({ "sku": "AB-1", "quantity": 2, "tags": [], "note": "hi" });
+        assert!(violations(&schema, valid).is_empty());
+        let invalid = json!({
+            "sku": "ab",
+            "quantity": 1.5,
+            "tags": ["gift", "slow"],
+            "note": "later",
+            "coupon": "X",
+        });
+        let mut found = violations(&schema, invalid);
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                "/coupon: is not allowed",
+                "/note: must be at most 3 characters long",
+                "/quantity: must be of type integer",
+                "/sku: must match `^[A-Z]+-[0-9]+$`",
+                "/tags/1: must be one of \"gift\", \"express\"",
+            ]
+        );
+        assert_eq!(violations(&schema, json!({})), ["/sku: is required", "/quantity: is required"]);
+        assert_eq!(violations(&schema, json!(3)), ["must be of type object"]);
+
+        let one_of = json!({ "oneOf": [{ "type": "integer" }, { "minimum": 0 }] });
+        assert!(violations(&one_of, json!(-1)).is_empty());
+        assert_eq!(violations(&one_of, json!(1)), ["must match exactly one schema of `oneOf`"]);
+        assert_eq!(
+            Schema::new(&json!({ "$ref": "#/definitions/order" })).unwrap_err(),
+            "`$ref` isn't supported"
+        );
+    }
+
+    #[test]
+    fn schema_handler() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua("if ctx.topic == 'order' then return ctx.msg.quantity end")
+            .with_schema(
+                "order",
+                json!({
+                    "properties": { "quantity": { "type": "integer", "minimum": 1 } }
+                }),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let valid = Order {
+            sku: "AB-1".to_string(),
+            quantity: 2,
+        };
+        let invalid = Order {
+            sku: "AB-1".to_string(),
+            quantity: 0,
+        };
+        let l = addr
+            .send(valid)
+            .join(addr.send(invalid))
+            .join(addr.send(LuaMessage::from(0)));
+        Arbiter::spawn(l.map(|((valid, invalid), untyped)| {
+            assert_eq!(valid, Ok(2));
+            let violation = SchemaViolation {
+                path: "/quantity".to_string(),
+                message: "must be at least 1".to_string(),
+            };
+            assert_eq!(
+                invalid,
+                Err(ActixLuaError::InvalidMessage {
+                    handler: "order".to_string(),
+                    violations: vec![violation],
+                })
+            );
+            assert_eq!(untyped, LuaMessage::Nil);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+
+        let bad = LuaActorBuilder::new().with_schema("order", json!({ "type": "float" }));
+        match bad.build() {
+            Err(ActixLuaError::InvalidSchema { handler, message }) => {
+                assert_eq!(handler, "order");
+                assert_eq!(message, "unknown type `float`");
+            }
+            _ => panic!("should return error"),
+        }
+    }
+}
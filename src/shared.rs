@@ -0,0 +1,327 @@
+use rlua::Result as LuaResult;
+use rlua::{
+    AnyUserData, Error as LuaError, Function, Lua, MetaMethod, Table, ToLua, UserData,
+    UserDataMethods, Value,
+};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use error::ActixLuaError;
+use message::LuaMessage;
+
+/// A read-only view into a `LuaMessage` shared between many Lua VMs.
+///
+/// The data itself lives on the Rust side behind an `Arc`. Lua only sees a userdata which
+/// converts values on access, so the dataset is materialized once no matter how many actors
+/// expose it. Nested tables are returned as views as well, `pairs` works like on a table and `#`
+/// counts the entries.
+/// A view returned or sent by a script is converted to a copy of its data.
+#[derive(Clone)]
+pub struct SharedTable {
+    root: Arc<LuaMessage>,
+    path: Vec<String>,
+}
+
+impl SharedTable {
+    pub fn new(root: Arc<LuaMessage>) -> Self {
+        SharedTable { root, path: vec![] }
+    }
+
+    // set `name` to a view of `root`, and let `pairs` iterate over the views of the VM
+    pub(crate) fn install(vm: &Lua, name: &str, root: Arc<LuaMessage>) -> LuaResult<()> {
+        let view = vm.create_userdata(SharedTable::new(root))?;
+        // rlua can't declare `__pairs`, so it is added to the metatable of the views
+        let getmetatable: Function = vm.named_registry_value("getmetatable")?;
+        let metatable: Table = getmetatable.call(view.clone())?;
+        if !metatable.contains_key("__pairs")? {
+            let pairs = vm.create_function(|lua, view: AnyUserData| {
+                let next = view.borrow::<SharedTable>()?.iterator(lua)?;
+                Ok((next, view))
+            })?;
+            metatable.set("__pairs", pairs)?;
+        }
+        vm.globals().set(name, view)
+    }
+
+    // the data the view shows, copied out of the shared table
+    pub(crate) fn to_message(&self) -> LuaMessage {
+        self.current().cloned().unwrap_or(LuaMessage::Nil)
+    }
+
+    fn current(&self) -> Option<&LuaMessage> {
+        let mut value = &*self.root;
+        for key in &self.path {
+            value = match value {
+                LuaMessage::Table(t) => t.get(key)?,
+                _ => return None,
+            }
+        }
+        Some(value)
+    }
+
+    fn index<'lua>(&self, lua: &'lua Lua, key: Value<'lua>) -> LuaResult<Value<'lua>> {
+        let key = lua.coerce_string(key)?.to_str()?.to_string();
+        self.get(lua, key)
+    }
+
+    fn get<'lua>(&self, lua: &'lua Lua, key: String) -> LuaResult<Value<'lua>> {
+        match self.current() {
+            Some(LuaMessage::Table(t)) => match t.get(&key) {
+                Some(LuaMessage::Table(_)) => {
+                    let mut path = self.path.clone();
+                    path.push(key);
+                    SharedTable {
+                        root: self.root.clone(),
+                        path,
+                    }.to_lua(lua)
+                }
+                Some(v) => v.clone().to_lua(lua),
+                None => Ok(Value::Nil),
+            },
+            _ => Ok(Value::Nil),
+        }
+    }
+
+    // the `next` function of `pairs`, over the keys the table has when `pairs` is called
+    fn iterator<'lua>(&self, lua: &'lua Lua) -> LuaResult<Function<'lua>> {
+        let keys: Vec<String> = match self.current() {
+            Some(LuaMessage::Table(t)) => t.keys().cloned().collect(),
+            _ => vec![],
+        };
+        let mut keys = keys.into_iter();
+        let view = self.clone();
+        lua.create_function_mut(move |lua, _: (Value, Value)| match keys.next() {
+            Some(key) => {
+                let value = view.get(lua, key.clone())?;
+                Ok((key.to_lua(lua)?, value))
+            }
+            None => Ok((Value::Nil, Value::Nil)),
+        })
+    }
+
+    // the number of entries, which is the length of tables converted from Lua sequences
+    fn len(&self) -> usize {
+        match self.current() {
+            Some(LuaMessage::Table(t)) => t.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl UserData for SharedTable {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_meta_method(MetaMethod::Index, |lua, this, key: Value| this.index(lua, key));
+        methods.add_meta_method(MetaMethod::NewIndex, |_, _, _: (Value, Value)| {
+            Err::<(), _>(LuaError::RuntimeError(
+                "attempt to modify read-only shared data".to_string(),
+            ))
+        });
+        methods.add_meta_method(MetaMethod::Len, |_, this, ()| Ok(this.len()));
+    }
+}
+
+/// A mutable table shared between actors, guarded by a mutex.
+///
+/// Actors built with the same `LuaSharedState` see it as the `shared` global, with an API
+/// modeled after OpenResty's shared dictionaries:
+///
+/// ```lua
+/// shared:set("mode", "maintenance")
+/// local mode = shared:get("mode")
+/// local hits = shared:incr("hits", 1)
+/// shared:delete("mode")
+/// ```
+///
+/// Values are copied in and out of the table, every call takes the lock once.
+/// Use `incr` for counters since a `get` followed by a `set` is not atomic.
+#[derive(Clone, Default)]
+pub struct LuaSharedState {
+    inner: Arc<Mutex<HashMap<String, LuaMessage>>>,
+}
+
+impl LuaSharedState {
+    pub fn new() -> Self {
+        LuaSharedState::default()
+    }
+
+    /// Get a copy of the value stored at `key`.
+    pub fn get(&self, key: &str) -> Result<LuaMessage, ActixLuaError> {
+        let inner = self.lock()?;
+        Ok(inner.get(key).cloned().unwrap_or(LuaMessage::Nil))
+    }
+
+    /// Store `value` at `key`. Storing `Nil` removes the key.
+    pub fn set(&self, key: &str, value: LuaMessage) -> Result<(), ActixLuaError> {
+        let mut inner = self.lock()?;
+        if value == LuaMessage::Nil {
+            inner.remove(key);
+        } else {
+            inner.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+
+    /// Atomically add `delta` to the integer at `key` and return the new value.
+    /// Missing keys are treated as 0, and a sum which doesn't fit in an `i64` is an error.
+    pub fn incr(&self, key: &str, delta: i64) -> Result<i64, LuaError> {
+        let mut inner = self.lock()?;
+        let current = match inner.get(key) {
+            None => 0,
+            Some(LuaMessage::Integer(x)) => *x,
+            Some(_) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "shared value `{}` is not an integer",
+                    key
+                )))
+            }
+        };
+        let value = current.checked_add(delta).ok_or_else(|| {
+            LuaError::RuntimeError(format!("shared value `{}` overflows an integer", key))
+        })?;
+        inner.insert(key.to_string(), LuaMessage::Integer(value));
+        Ok(value)
+    }
+
+    // a panic while the lock was held may have left the table half updated, it's not used
+    // anymore
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<String, LuaMessage>>, ActixLuaError> {
+        self.inner.lock().map_err(|_| ActixLuaError::PoisonedState)
+    }
+}
+
+impl UserData for LuaSharedState {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("get", |_, this, key: String| Ok(this.get(&key)?));
+        methods.add_method("set", |_, this, (key, value): (String, LuaMessage)| {
+            Ok(this.set(&key, value)?)
+        });
+        methods.add_method("incr", |_, this, (key, delta): (String, Option<i64>)| {
+            this.incr(&key, delta.unwrap_or(1))
+        });
+        methods.add_method("delete", |_, this, key: String| {
+            Ok(this.set(&key, LuaMessage::Nil)?)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm;
+    use std::collections::HashMap;
+
+    fn dataset_prices() -> LuaMessage {
+        let mut prices = HashMap::new();
+        prices.insert("apple".to_string(), LuaMessage::from(3));
+        prices.insert("1".to_string(), LuaMessage::from("first"));
+        prices.insert("2".to_string(), LuaMessage::from("second"));
+        LuaMessage::from(prices)
+    }
+
+    fn dataset() -> Arc<LuaMessage> {
+        let mut t = HashMap::new();
+        t.insert("prices".to_string(), dataset_prices());
+        t.insert("currency".to_string(), LuaMessage::from("TWD"));
+        Arc::new(LuaMessage::from(t))
+    }
+
+    #[test]
+    fn shared_table_read() {
+        let lua = vm::new_vm().unwrap();
+        SharedTable::install(&lua, "data", dataset()).unwrap();
+
+        let v: String = lua.eval("return data.currency", None).unwrap();
+        assert_eq!(v, "TWD");
+        let v: i64 = lua.eval("return data.prices.apple", None).unwrap();
+        assert_eq!(v, 3);
+        let v: String = lua.eval("return data.prices[2]", None).unwrap();
+        assert_eq!(v, "second");
+        let v: usize = lua.eval("return #data.prices", None).unwrap();
+        assert_eq!(v, 3);
+        let v: bool = lua.eval("return data.missing == nil", None).unwrap();
+        assert!(v);
+    }
+
+    #[test]
+    fn shared_table_pairs() {
+        let lua = vm::new_vm().unwrap();
+        SharedTable::install(&lua, "data", dataset()).unwrap();
+
+        let v: String = lua
+            .eval(
+                r#"
+                local keys = {}
+                for k, v in pairs(data.prices) do
+                    keys[#keys + 1] = k .. "=" .. tostring(v)
+                end
+                table.sort(keys)
+                local nested = 0
+                for k, v in pairs(data) do
+                    if k == "prices" then nested = #v end
+                end
+                return table.concat(keys, ",") .. " " .. nested
+                "#,
+                None,
+            )
+            .unwrap();
+        assert_eq!(v, "1=first,2=second,apple=3 3");
+
+        // a returned view is a copy of its data
+        let prices = lua.eval::<LuaMessage>("return data.prices", None).unwrap();
+        assert_eq!(prices, dataset_prices());
+    }
+
+    #[test]
+    fn shared_table_read_only() {
+        let lua = vm::new_vm().unwrap();
+        SharedTable::install(&lua, "data", dataset()).unwrap();
+
+        assert!(lua.exec::<()>("data.currency = 'USD'", None).is_err());
+        assert!(lua.exec::<()>("data.prices.apple = 1", None).is_err());
+    }
+
+    #[test]
+    fn shared_state() {
+        let state = LuaSharedState::new();
+        let lua = Lua::new();
+        let lua2 = Lua::new();
+        lua.globals().set("shared", state.clone()).unwrap();
+        lua2.globals().set("shared", state.clone()).unwrap();
+
+        lua.exec::<()>(r#"shared:set("mode", "on")"#, None).unwrap();
+        let v: String = lua2.eval(r#"return shared:get("mode")"#, None).unwrap();
+        assert_eq!(v, "on");
+
+        lua.exec::<()>(r#"shared:incr("hits", 2)"#, None).unwrap();
+        let v: i64 = lua2.eval(r#"return shared:incr("hits")"#, None).unwrap();
+        assert_eq!(v, 3);
+        assert_eq!(state.get("hits"), Ok(LuaMessage::from(3)));
+
+        lua2.exec::<()>(r#"shared:delete("mode")"#, None).unwrap();
+        assert_eq!(state.get("mode"), Ok(LuaMessage::Nil));
+        assert!(lua.exec::<()>(r#"shared:set("x", "a"); shared:incr("x")"#, None).is_err());
+    }
+
+    #[test]
+    fn shared_state_overflow_and_poison() {
+        let state = LuaSharedState::new();
+        let lua = Lua::new();
+        lua.globals().set("shared", state.clone()).unwrap();
+
+        lua.exec::<()>(r#"shared:set("n", math.maxinteger)"#, None).unwrap();
+        let err = lua.exec::<()>(r#"shared:incr("n")"#, None).unwrap_err();
+        assert!(format!("{:?}", err).contains("overflows"));
+        assert_eq!(state.get("n"), Ok(LuaMessage::from(i64::MAX)));
+
+        let poisoned = state.clone();
+        let _ = ::std::thread::spawn(move || {
+            let _guard = poisoned.inner.lock().unwrap();
+            panic!("panicked with the lock held");
+        }).join();
+        assert_eq!(state.get("n"), Err(ActixLuaError::PoisonedState));
+        assert_eq!(state.set("n", LuaMessage::Nil), Err(ActixLuaError::PoisonedState));
+        let err = lua.exec::<()>(r#"shared:incr("n")"#, None).unwrap_err();
+        assert!(format!("{:?}", err).contains("PoisonedState"));
+    }
+}
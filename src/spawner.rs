@@ -0,0 +1,387 @@
+use actix::prelude::*;
+use rlua::{Lua, Result as LuaResult, Table};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actor::LuaActor;
+use builder::LuaActorBuilder;
+use bundle::script_hash;
+use error::{ActixLuaError, ErrorPolicy};
+use message::LuaMessage;
+use metrics::LuaActorMetrics;
+
+/// A service spawning supervised `LuaActor`s from scripts on disk, for code which doesn't
+/// build actors itself.
+///
+/// Send `SpawnActor` to `LuaActorSpawner::from_registry()`. The hooks of the actor are read
+/// from `<script_dir>/started.lua`, `handle.lua`, `handle_batch.lua` and `stopped.lua`, where
+/// they exist. A spawned actor which stops is started again with a fresh VM.
+///
+/// `ListActors` lists the spawned actors, which scripts get with `ctx.actors()` as well.
+///
+/// ```rust,ignore
+/// let addr = LuaActorSpawner::from_registry().send(SpawnActor {
+///     script_dir: "scripts/worker".to_string(),
+///     name: "worker-1".to_string(),
+///     overrides: HashMap::new(),
+/// });
+/// ```
+#[derive(Default)]
+pub struct LuaActorSpawner {
+    actors: Directory,
+}
+
+// the actors spawned by a `LuaActorSpawner`, shared with them for `ctx.actors()`
+#[derive(Clone, Default)]
+struct Directory(Arc<Mutex<HashMap<String, Spawned>>>);
+
+struct Spawned {
+    addr: Addr<LuaActor>,
+    script_hashes: HashMap<String, String>,
+    spawned_at: Instant,
+    metrics: LuaActorMetrics,
+}
+
+impl Directory {
+    fn list(&self) -> Vec<ActorInfo> {
+        let mut actors = self.0.lock().unwrap();
+        // forget actors which stopped for good
+        actors.retain(|_, spawned| spawned.addr.connected());
+        let mut list: Vec<_> = actors
+            .iter()
+            .map(|(name, spawned)| ActorInfo {
+                name: name.clone(),
+                script_hashes: spawned.script_hashes.clone(),
+                uptime: spawned.spawned_at.elapsed(),
+                requests: spawned.metrics.requests(),
+                notifications: spawned.metrics.notifications(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    fn lookup(&self, name: &str) -> Option<Addr<LuaActor>> {
+        let actors = self.0.lock().unwrap();
+        actors
+            .get(name)
+            .map(|spawned| spawned.addr.clone())
+            .filter(Addr::connected)
+    }
+
+    // the `__actors` function behind `ctx.actors()`
+    fn install(&self, vm: &Lua) -> LuaResult<()> {
+        let directory = self.clone();
+        let actors = vm.create_function(move |vm, ()| -> LuaResult<Table> {
+            vm.create_sequence_from(directory.list().into_iter().map(LuaMessage::from))
+        })?;
+        vm.globals().set("__actors", actors)
+    }
+}
+
+/// An actor spawned by `LuaActorSpawner`, see `ListActors`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActorInfo {
+    pub name: String,
+    /// The hashes of the hook scripts by hook, which change when a script does.
+    pub script_hashes: HashMap<String, String>,
+    /// The time since the actor was spawned.
+    pub uptime: Duration,
+    /// See `LuaActorMetrics::requests`.
+    pub requests: u64,
+    /// See `LuaActorMetrics::notifications`.
+    pub notifications: u64,
+}
+
+// the table scripts get from `ctx.actors()`, the uptime is in seconds
+impl From<ActorInfo> for LuaMessage {
+    fn from(info: ActorInfo) -> Self {
+        let mut t = HashMap::new();
+        t.insert("name".to_string(), LuaMessage::from(info.name));
+        let scripts = info
+            .script_hashes
+            .into_iter()
+            .map(|(hook, hash)| (hook, LuaMessage::from(hash)))
+            .collect::<HashMap<_, _>>();
+        t.insert("scripts".to_string(), LuaMessage::from(scripts));
+        t.insert(
+            "uptime".to_string(),
+            LuaMessage::from(info.uptime.as_secs_f64()),
+        );
+        t.insert("requests".to_string(), LuaMessage::from(info.requests as i64));
+        t.insert(
+            "notifications".to_string(),
+            LuaMessage::from(info.notifications as i64),
+        );
+        LuaMessage::from(t)
+    }
+}
+
+impl Actor for LuaActorSpawner {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LuaActorSpawner {}
+
+impl SystemService for LuaActorSpawner {}
+
+/// Spawn an actor from the scripts in `script_dir` and register it as `name`.
+///
+/// `overrides` configures the builder of the actor:
+///
+/// * `max_message_size`, `max_hops`, `batch_size`: integers
+/// * `accept_tell`: a boolean
+/// * `error_policy`: `"ignore"`, `"restart"` or `"stop"`
+/// * `args`: an array, see `LuaActorBuilder::with_args`
+///
+/// Fails with `ActixLuaError::SpawnError` for other overrides or if an actor runs as `name`
+/// already.
+pub struct SpawnActor {
+    pub script_dir: String,
+    pub name: String,
+    pub overrides: HashMap<String, LuaMessage>,
+}
+
+impl Message for SpawnActor {
+    type Result = Result<Addr<LuaActor>, ActixLuaError>;
+}
+
+/// Look up an actor spawned as `name`.
+pub struct LookupActor(pub String);
+
+impl Message for LookupActor {
+    type Result = Option<Addr<LuaActor>>;
+}
+
+/// List the running actors spawned by `LuaActorSpawner`, by name.
+pub struct ListActors;
+
+impl Message for ListActors {
+    type Result = Vec<ActorInfo>;
+}
+
+const OVERRIDES: &[&str] = &[
+    "max_message_size",
+    "max_hops",
+    "batch_size",
+    "accept_tell",
+    "error_policy",
+    "args",
+];
+
+impl LuaActorSpawner {
+    // the builder of the actor, and the hashes of its scripts
+    fn builder(
+        spawn: SpawnActor,
+    ) -> Result<(LuaActorBuilder, HashMap<String, String>), ActixLuaError> {
+        let error = |message: String| ActixLuaError::SpawnError {
+            name: spawn.name.clone(),
+            message,
+        };
+        let dir = Path::new(&spawn.script_dir);
+        let mut hashes = HashMap::new();
+        let mut script = |hook: &str| -> Result<Option<String>, ActixLuaError> {
+            let path = dir.join(format!("{}.lua", hook));
+            if !path.is_file() {
+                return Ok(None);
+            }
+            let script = fs::read_to_string(&path).map_err(|_| ActixLuaError::ScriptNotFound {
+                path: path.to_string_lossy().into_owned(),
+            })?;
+            hashes.insert(hook.to_string(), script_hash(&script));
+            Ok(Some(script))
+        };
+
+        let mut builder = LuaActorBuilder::new();
+        if let Some(script) = script("started")? {
+            builder = builder.on_started_with_lua(script);
+        }
+        if let Some(script) = script("handle")? {
+            builder = builder.on_handle_with_lua(script);
+        }
+        if let Some(script) = script("handle_batch")? {
+            builder = builder.on_handle_batch_with_lua(script);
+        }
+        if let Some(script) = script("stopped")? {
+            builder = builder.on_stopped_with_lua(script);
+        }
+        if hashes.is_empty() {
+            return Err(ActixLuaError::ScriptNotFound {
+                path: dir.join("handle.lua").to_string_lossy().into_owned(),
+            });
+        }
+
+        for (key, value) in spawn.overrides.iter() {
+            let invalid = || error(format!("invalid override `{}`: {:?}", key, value));
+            builder = match (key.as_str(), value) {
+                ("max_message_size", &LuaMessage::Integer(n)) if n >= 0 => {
+                    builder.with_max_message_size(n as usize)
+                }
+                ("max_hops", &LuaMessage::Integer(n)) if n >= 0 => {
+                    builder.with_max_hops(n as usize)
+                }
+                ("batch_size", &LuaMessage::Integer(n)) if n >= 0 => {
+                    builder.with_batch_size(n as usize)
+                }
+                ("accept_tell", &LuaMessage::Boolean(accept)) => builder.accept_tell(accept),
+                ("error_policy", LuaMessage::String(policy)) => {
+                    builder.with_error_policy(match policy.as_str() {
+                        "ignore" => ErrorPolicy::Ignore,
+                        "restart" => ErrorPolicy::Restart,
+                        "stop" => ErrorPolicy::Stop,
+                        _ => return Err(invalid()),
+                    })
+                }
+                ("args", LuaMessage::Table(args)) => {
+                    let args: Option<Vec<_>> = (1..=args.len())
+                        .map(|i| args.get(&i.to_string()).cloned())
+                        .collect();
+                    builder.with_args(args.ok_or_else(invalid)?)
+                }
+                (key, _) if OVERRIDES.contains(&key) => return Err(invalid()),
+                _ => return Err(error(format!("unknown override `{}`", key))),
+            };
+        }
+        Ok((builder, hashes))
+    }
+}
+
+impl Handler<SpawnActor> for LuaActorSpawner {
+    type Result = Result<Addr<LuaActor>, ActixLuaError>;
+
+    fn handle(&mut self, spawn: SpawnActor, _: &mut Context<Self>) -> Self::Result {
+        if self.actors.lookup(&spawn.name).is_some() {
+            return Err(ActixLuaError::SpawnError {
+                name: spawn.name,
+                message: "the name is taken".to_string(),
+            });
+        }
+        let name = spawn.name.clone();
+        let (builder, script_hashes) = LuaActorSpawner::builder(spawn)?;
+        let metrics = LuaActorMetrics::new();
+        let directory = self.actors.clone();
+        let mut actor = builder
+            .with_metrics(metrics.clone())
+            .with_vm(move |vm| directory.install(vm))
+            .build()?;
+        actor.supervised = true;
+        let addr = Supervisor::start(move |_| actor);
+        let spawned = Spawned {
+            addr: addr.clone(),
+            script_hashes,
+            spawned_at: Instant::now(),
+            metrics,
+        };
+        self.actors.0.lock().unwrap().insert(name, spawned);
+        Ok(addr)
+    }
+}
+
+impl Handler<LookupActor> for LuaActorSpawner {
+    type Result = Option<Addr<LuaActor>>;
+
+    fn handle(&mut self, LookupActor(name): LookupActor, _: &mut Context<Self>) -> Self::Result {
+        self.actors.lookup(&name)
+    }
+}
+
+impl Handler<ListActors> for LuaActorSpawner {
+    type Result = MessageResult<ListActors>;
+
+    fn handle(&mut self, _: ListActors, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.actors.list())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    fn spawn_actor(name: &str, overrides: HashMap<String, LuaMessage>) -> SpawnActor {
+        SpawnActor {
+            script_dir: "src/lua/test/spawn".to_string(),
+            name: name.to_string(),
+            overrides,
+        }
+    }
+
+    #[test]
+    fn spawner_spawn_actor() {
+        let system = System::new("test");
+
+        let mut overrides = HashMap::new();
+        let mut args = HashMap::new();
+        args.insert("1".to_string(), LuaMessage::from("worker"));
+        overrides.insert("args".to_string(), LuaMessage::from(args));
+        let mut unknown = HashMap::new();
+        unknown.insert("color".to_string(), LuaMessage::from("red"));
+
+        let spawner = LuaActorSpawner::from_registry();
+        let l = spawner
+            .send(spawn_actor("worker", overrides))
+            .join3(
+                spawner.send(spawn_actor("other", unknown)),
+                spawner.send(LookupActor("worker".to_string())),
+            )
+            .and_then(|(addr, unknown, found)| {
+                match unknown {
+                    Err(ActixLuaError::SpawnError { .. }) => {}
+                    Err(e) => panic!("unexpected error {}", e),
+                    Ok(_) => panic!("spawned with an unknown override"),
+                }
+                let addr = addr.unwrap();
+                assert!(found.unwrap() == addr);
+                let msg = || LuaMessage::from("work");
+                let sent = addr.send(msg()).join(addr.send(msg()));
+                sent.join(addr.send(LuaMessage::from("die")))
+                    // restarted with a fresh VM
+                    .and_then(move |sent| addr.send(msg()).map(|restarted| (sent, restarted)))
+            });
+        Arbiter::spawn(l.map(|(((first, second), _), restarted)| {
+            assert_eq!(first, LuaMessage::from("worker1"));
+            assert_eq!(second, LuaMessage::from("worker2"));
+            assert_eq!(restarted, LuaMessage::from("worker1"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn spawner_list_actors() {
+        let system = System::new("test");
+
+        let mut args = HashMap::new();
+        args.insert("1".to_string(), LuaMessage::from("lister"));
+        let mut overrides = HashMap::new();
+        overrides.insert("args".to_string(), LuaMessage::from(args));
+
+        let spawner = LuaActorSpawner::from_registry();
+        let l = spawner
+            .send(spawn_actor("lister", overrides))
+            .and_then(|addr| {
+                let addr = addr.unwrap();
+                addr.send(LuaMessage::from("work"))
+                    .and_then(move |_| addr.send(LuaMessage::from("actors")))
+            })
+            .and_then(move |names| spawner.send(ListActors).map(|actors| (names, actors)));
+        Arbiter::spawn(l.map(|(names, actors)| {
+            // the request being handled is counted already
+            assert_eq!(names, LuaMessage::from("lister:2"));
+            assert_eq!(actors.len(), 1);
+            assert_eq!(actors[0].name, "lister");
+            assert_eq!(actors[0].requests, 2);
+            let hooks = &actors[0].script_hashes;
+            assert_eq!(hooks.len(), 2);
+            assert_eq!(hooks["started"], script_hash("ctx.state.n = 0\n"));
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,172 @@
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Sink, Stream};
+use rlua::{Error as LuaError, FromLua, Function, Lua, Value};
+
+use vm::catch_panic;
+use error::{ActixLuaError, AskFuture};
+use message::{KeyCache, LuaMessage};
+
+struct Request {
+    msg: LuaMessage,
+    tx: oneshot::Sender<LuaMessage>,
+}
+
+/// The hooks of a [`LuaActorBuilder`] run by a plain task, for applications on tokio which
+/// don't run an actix system. Requires the `task` feature.
+///
+/// Create it with [`LuaActorBuilder::build_task`] and spawn it on a tokio runtime. The task
+/// runs the `started` hook when it is first polled, then the `handle` hook with every message
+/// sent with its [`LuaTaskHandle`]s, one at a time. Once every handle is dropped, it runs the
+/// `stopped` hook and completes.
+///
+/// The scripts get `ctx.msg` and `ctx.state`, but not the parts of `ctx` which need an actor
+/// such as `ctx.send` or `ctx.reply_later`. A script which yields replies
+/// `LuaMessage::ThreadYield`. The settings of the VM apply, the settings of the actor such as
+/// batching, tenants and error policies don't.
+///
+/// The task doesn't need actix, which can be left out with `default-features = false`.
+///
+/// ```rust,ignore
+/// let (task, handle) = LuaActorBuilder::new()
+///     .on_handle_with_lua("return ctx.msg + 1")
+///     .build_task(16)?;
+/// tokio::spawn(task);
+/// let two = handle.send(LuaMessage::from(1));
+/// ```
+///
+/// [`LuaActorBuilder`]: struct.LuaActorBuilder.html
+/// [`LuaActorBuilder::build_task`]: struct.LuaActorBuilder.html#method.build_task
+/// [`LuaTaskHandle`]: struct.LuaTaskHandle.html
+pub struct LuaTask {
+    vm: Lua,
+    keys: KeyCache,
+    rx: mpsc::Receiver<Request>,
+    started: bool,
+}
+
+/// Sends messages to a [`LuaTask`].
+///
+/// [`LuaTask`]: struct.LuaTask.html
+#[derive(Clone)]
+pub struct LuaTaskHandle {
+    tx: mpsc::Sender<Request>,
+}
+
+impl LuaTask {
+    // a task whose mailbox has room for `capacity` messages, and one more for each handle
+    pub(crate) fn new(vm: Lua, capacity: usize) -> (LuaTask, LuaTaskHandle) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let task = LuaTask {
+            vm,
+            keys: KeyCache::default(),
+            rx,
+            started: false,
+        };
+        (task, LuaTaskHandle { tx })
+    }
+
+    fn run(&mut self, hook: &str, msg: LuaMessage) -> Result<LuaMessage, ActixLuaError> {
+        let (vm, keys) = (&self.vm, &mut self.keys);
+        catch_panic(|| {
+            let run: Function = vm.globals().get("__run")?;
+            let msg = keys.convert(msg, vm)?;
+            // the prelude returns the traceback of a failed script as a second value
+            let (res, err) = run.call::<_, (Value, Option<String>)>((hook, msg))?;
+            if let Some(traceback) = err {
+                return Err(LuaError::RuntimeError(traceback).into());
+            }
+            Ok(LuaMessage::from_lua(res, vm)?)
+        })
+    }
+}
+
+impl Future for LuaTask {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if !self.started {
+            self.started = true;
+            if let Err(e) = self.run("started", LuaMessage::Nil) {
+                error!("lua task hook `started` failed: {}", e);
+            }
+        }
+        loop {
+            match self.rx.poll() {
+                Ok(Async::Ready(Some(Request { msg, tx }))) => {
+                    let res = self
+                        .run("handle", msg)
+                        .unwrap_or_else(LuaMessage::Error);
+                    // the sender may have stopped waiting
+                    let _ = tx.send(res);
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) | Err(()) => {
+                    if let Err(e) = self.run("stopped", LuaMessage::from("stop")) {
+                        error!("lua task hook `stopped` failed: {}", e);
+                    }
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+impl LuaTaskHandle {
+    /// Send `msg` to the task, once there is room in its mailbox.
+    ///
+    /// Like `Ask::ask`, a `LuaMessage::Error` reply is returned as the error. The future fails
+    /// with `ActixLuaError::ActorStopped` if the task was dropped.
+    pub fn send(&self, msg: LuaMessage) -> AskFuture<LuaMessage> {
+        let (tx, rx) = oneshot::channel();
+        let sent = self.tx.clone().send(Request { msg, tx });
+        Box::new(
+            sent.map_err(|_| ActixLuaError::ActorStopped)
+                .and_then(|_| rx.map_err(|_| ActixLuaError::ActorStopped))
+                .and_then(|res| match res {
+                    LuaMessage::Error(e) => Err(e),
+                    res => Ok(res),
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    use builder::LuaActorBuilder;
+
+    #[test]
+    fn lua_task() {
+        let (task, handle) = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.state.n = 0")
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "fail" then error("boom") end
+                ctx.state.n = ctx.state.n + ctx.msg
+                return ctx.state.n
+                "#,
+            ).build_task(2)
+            .unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+        rt.spawn(task);
+        let sums = future::join_all(vec![
+            handle.send(LuaMessage::from(1)),
+            handle.send(LuaMessage::from(2)),
+            handle.send(LuaMessage::from(3)),
+        ]);
+        let expected: Vec<LuaMessage> = vec![1.into(), 3.into(), 6.into()];
+        assert_eq!(rt.block_on(sums).unwrap(), expected);
+        match rt.block_on(handle.send(LuaMessage::from("fail"))) {
+            Err(ActixLuaError::RuntimeError { traceback }) => assert!(traceback.contains("boom")),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // the task completes once every handle is dropped
+        drop(handle);
+        rt.run().unwrap();
+    }
+}
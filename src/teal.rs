@@ -0,0 +1,103 @@
+use rlua::{Function, Lua, Table};
+
+use error::ActixLuaError;
+
+// Type-checks Teal hooks and compiles them to Lua with the compiler given to
+// `LuaActorBuilder::with_teal`, with the `teal` feature.
+//
+// The compiler runs in a VM of its own. Teal keeps the Lua code on the lines of the Teal code,
+// so the lines of runtime errors are the lines of the Teal script.
+pub(crate) struct Teal {
+    vm: Lua,
+}
+
+impl Teal {
+    // load `compiler`, the source of `tl.lua`
+    pub(crate) fn new(compiler: &str) -> Result<Teal, ActixLuaError> {
+        let vm = Lua::new();
+        {
+            let tl: Table = vm
+                .load(compiler, Some("tl"))
+                .and_then(|f| f.call(()))
+                .map_err(|e| ActixLuaError::compile("tl", &e))?;
+            let gen: Function = tl.get("gen")?;
+            vm.set_named_registry_value("gen", gen)?;
+        }
+        Ok(Teal { vm })
+    }
+
+    // the Lua code of `source`, or every syntax and type error in it
+    pub(crate) fn compile(&self, hook: &str, source: &str) -> Result<String, Vec<ActixLuaError>> {
+        let gen: Function = self
+            .vm
+            .named_registry_value("gen")
+            .map_err(|e| vec![e.into()])?;
+        let (lua, result) = gen
+            .call::<_, (Option<String>, Table)>(source)
+            .map_err(|e| vec![ActixLuaError::compile(hook, &e)])?;
+        let errors = diagnostics(hook, &result).map_err(|e| vec![e.into()])?;
+        match lua {
+            Some(lua) if errors.is_empty() => Ok(lua),
+            _ if !errors.is_empty() => Err(errors),
+            _ => Err(vec![ActixLuaError::CompileError {
+                hook: hook.to_string(),
+                line: None,
+                message: "Teal generated no code".to_string(),
+            }]),
+        }
+    }
+}
+
+// the syntax errors, followed by the type errors
+fn diagnostics(hook: &str, result: &Table) -> Result<Vec<ActixLuaError>, rlua::Error> {
+    let mut errors = vec![];
+    for kind in &["syntax_errors", "type_errors"] {
+        let list = match result.get::<_, Option<Table>>(*kind)? {
+            Some(list) => list,
+            None => continue,
+        };
+        for error in list.sequence_values::<Table>() {
+            let error = error?;
+            let column: Option<usize> = error.get("x")?;
+            let message: String = error.get("msg")?;
+            errors.push(ActixLuaError::CompileError {
+                hook: hook.to_string(),
+                line: error.get("y")?,
+                message: match column {
+                    Some(column) => format!("column {}: {}", column, message),
+                    None => message,
+                },
+            });
+        }
+    }
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn teal_type_errors() {
+        let teal = Teal::new(include_str!("lua/test/tl.lua")).unwrap();
+        assert_eq!(
+            teal.compile("handle", "local n: integer = 1\nreturn n"),
+            Ok("local n = 1\nreturn n".to_string())
+        );
+
+        let source = "local n: integer = \"one\"\nlocal s: string = 2\nreturn n";
+        let errors = teal.compile("handle", source).unwrap_err();
+        let lines: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                ActixLuaError::CompileError { line, .. } => *line,
+                e => panic!("unexpected error {:?}", e),
+            })
+            .collect();
+        assert_eq!(lines, [Some(1), Some(2)]);
+        assert_eq!(
+            errors[0].to_string(),
+            "handle:1: column 20: in local declaration: n: got string, expected integer"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use rlua::Lua;
+
+use std::collections::VecDeque;
+
+use actor::Replies;
+use builder::TenantFn;
+use message::KeyCache;
+
+// a VM of a `LuaActor` together with the state tied to it
+pub(crate) struct TenantVm {
+    pub(crate) vm: Lua,
+    pub(crate) keys: KeyCache,
+    pub(crate) replies: Replies,
+}
+
+// the VMs of an actor built with `LuaActorBuilder::with_tenants`, by tenant
+pub(crate) struct Tenants {
+    pub(crate) tenant_fn: Box<TenantFn>,
+    pub(crate) max_vms: usize,
+    // the tenant of the VM in use, `None` until the first message
+    pub(crate) current: Option<String>,
+    // the other VMs, least recently used first
+    idle: VecDeque<(String, TenantVm)>,
+    // the next thread id unused by any of the VMs, so a late reply never resumes a thread of
+    // another VM of the tenant
+    pub(crate) next_thread_id: i64,
+}
+
+impl Tenants {
+    pub(crate) fn new(tenant_fn: Box<TenantFn>, max_vms: usize) -> Self {
+        Tenants {
+            tenant_fn,
+            max_vms: max_vms.max(1),
+            current: None,
+            idle: VecDeque::new(),
+            next_thread_id: 0,
+        }
+    }
+
+    // take the idle VM of `tenant`
+    pub(crate) fn take(&mut self, tenant: &str) -> Option<TenantVm> {
+        let i = self.idle.iter().position(|(t, _)| t == tenant)?;
+        self.idle.remove(i).map(|(_, vm)| vm)
+    }
+
+    // keep the VM of `tenant` as the most recently used, and return the least recently used one
+    // beyond the limit
+    pub(crate) fn put(&mut self, tenant: String, vm: TenantVm) -> Option<(String, TenantVm)> {
+        self.idle.push_back((tenant, vm));
+        // one more VM is in use
+        if self.idle.len() >= self.max_vms {
+            self.idle.pop_front()
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = (String, TenantVm)> + '_ {
+        self.idle.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant_vm() -> TenantVm {
+        TenantVm {
+            vm: Lua::new(),
+            keys: KeyCache::default(),
+            replies: Replies::default(),
+        }
+    }
+
+    #[test]
+    fn tenants_lru() {
+        let mut tenants = Tenants::new(Box::new(|_| String::new()), 3);
+        assert!(tenants.put("a".to_string(), tenant_vm()).is_none());
+        assert!(tenants.put("b".to_string(), tenant_vm()).is_none());
+        assert!(tenants.take("a").is_some());
+        assert!(tenants.take("a").is_none());
+        assert!(tenants.put("c".to_string(), tenant_vm()).is_none());
+        // `b` was used least recently
+        let evicted = tenants.put("a".to_string(), tenant_vm()).map(|(t, _)| t);
+        assert_eq!(evicted, Some("b".to_string()));
+        let idle: Vec<_> = tenants.drain().map(|(t, _)| t).collect();
+        assert_eq!(idle, ["c", "a"]);
+    }
+}
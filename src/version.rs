@@ -0,0 +1,136 @@
+use vm::catch_panic;
+use builder::MigrateFn;
+use error::ActixLuaError;
+use message::LuaMessage;
+
+// The version of the messages a named handler expects, and the hook up-converting older ones,
+// see `LuaActorBuilder::with_message_version`.
+pub(crate) struct MessageVersion {
+    pub(crate) current: i64,
+    pub(crate) migrate: Box<MigrateFn>,
+}
+
+impl MessageVersion {
+    // `msg` at the current version, migrated one version at a time from the one in its
+    // `version` field. Messages without a `version` are left alone.
+    pub(crate) fn upgrade(
+        &self,
+        handler: &str,
+        msg: LuaMessage,
+    ) -> Result<LuaMessage, ActixLuaError> {
+        let mut version = match msg {
+            LuaMessage::Table(ref t) => match t.get("version") {
+                Some(&LuaMessage::Integer(version)) => version,
+                _ => return Ok(msg),
+            },
+            _ => return Ok(msg),
+        };
+        if version > self.current {
+            return Err(ActixLuaError::UnsupportedVersion {
+                handler: handler.to_string(),
+                version,
+                current: self.current,
+            });
+        }
+        let mut msg = msg;
+        while version < self.current {
+            msg = match catch_panic(|| (self.migrate)(version, msg))? {
+                LuaMessage::Table(mut t) => {
+                    t.insert("version".to_string(), LuaMessage::from(version + 1));
+                    LuaMessage::Table(t)
+                }
+                _ => {
+                    return Err(ActixLuaError::ConversionError {
+                        message: format!(
+                            "the migration of `{}` messages from version {} didn't return a table",
+                            handler, version
+                        ),
+                    })
+                }
+            };
+            version += 1;
+        }
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::prelude::*;
+    use builder::LuaActorBuilder;
+    use futures::Future;
+
+    use std::collections::HashMap;
+
+    struct Order(LuaMessage);
+
+    impl Message for Order {
+        type Result = Result<String, ActixLuaError>;
+    }
+
+    impl From<Order> for LuaMessage {
+        fn from(order: Order) -> Self {
+            order.0
+        }
+    }
+
+    impl_lua_handler!(Order => "order");
+
+    fn order(version: i64, field: &str) -> Order {
+        let mut t = HashMap::new();
+        t.insert("version".to_string(), LuaMessage::from(version));
+        t.insert(field.to_string(), LuaMessage::from("ada"));
+        Order(LuaMessage::from(t))
+    }
+
+    #[test]
+    fn migrate_versions() {
+        let system = System::new("test");
+
+        // version 2 renamed `name` to `customer`, version 3 made it a table
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua("return ctx.msg.version .. ':' .. ctx.msg.customer.name")
+            .with_message_version("order", 3, |version, msg| {
+                let mut t = match msg {
+                    LuaMessage::Table(t) => t,
+                    msg => return Ok(msg),
+                };
+                match version {
+                    1 => {
+                        let name = t.remove("name").unwrap_or(LuaMessage::Nil);
+                        t.insert("customer".to_string(), name);
+                    }
+                    _ => {
+                        let mut customer = HashMap::new();
+                        customer.insert("name".to_string(), t.remove("customer").unwrap());
+                        t.insert("customer".to_string(), LuaMessage::from(customer));
+                    }
+                }
+                Ok(LuaMessage::from(t))
+            })
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(order(1, "name"))
+            .join(addr.send(order(2, "customer")))
+            .join(addr.send(order(4, "customer")));
+        Arbiter::spawn(l.map(|((v1, v2), v4)| {
+            assert_eq!(v1, Ok("3:ada".to_string()));
+            assert_eq!(v2, Ok("3:ada".to_string()));
+            assert_eq!(
+                v4,
+                Err(ActixLuaError::UnsupportedVersion {
+                    handler: "order".to_string(),
+                    version: 4,
+                    current: 3,
+                })
+            );
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
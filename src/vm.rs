@@ -0,0 +1,244 @@
+use rlua::{Error as LuaError, Function, Lua, Table, Value};
+
+use std::panic::{self, AssertUnwindSafe};
+
+use cancel;
+use error::ActixLuaError;
+#[cfg(feature = "actix")]
+use message;
+#[cfg(feature = "actix")]
+use profiler;
+
+// instructions run between two calls of the counting hook
+const INSTRUCTION_STEP: i64 = 100;
+
+// the debug library breaks the safety of rlua, so scripts don't get it.
+// only `debug.traceback` is kept for the prelude to report errors with, and `debug.sethook`
+// to count instructions with, with `debug.gethook` and `debug.getinfo` for the profiler,
+// `debug.getlocal` and `debug.getupvalue` for the debugger and `debug.getmetatable` to add
+// `__pairs` to the views of shared data.
+// `collectgarbage` is kept as well for the `Gc` messages, and `load` and `string.dump` for
+// templates to copy the hooks between VMs.
+pub(crate) fn new_vm() -> Result<Lua, LuaError> {
+    let vm = unsafe { Lua::new_with_debug() };
+    {
+        let globals = vm.globals();
+        let debug: Table = globals.get("debug")?;
+        let traceback: Function = debug.get("traceback")?;
+        vm.set_named_registry_value("traceback", traceback)?;
+        let sethook: Function = debug.get("sethook")?;
+        vm.set_named_registry_value("sethook", sethook)?;
+        let gethook: Function = debug.get("gethook")?;
+        vm.set_named_registry_value("gethook", gethook)?;
+        let getinfo: Function = debug.get("getinfo")?;
+        vm.set_named_registry_value("getinfo", getinfo)?;
+        let getmetatable: Function = debug.get("getmetatable")?;
+        vm.set_named_registry_value("getmetatable", getmetatable)?;
+        #[cfg(feature = "debugger")]
+        {
+            let getlocal: Function = debug.get("getlocal")?;
+            vm.set_named_registry_value("getlocal", getlocal)?;
+            let getupvalue: Function = debug.get("getupvalue")?;
+            vm.set_named_registry_value("getupvalue", getupvalue)?;
+        }
+        let collectgarbage: Function = globals.get("collectgarbage")?;
+        vm.set_named_registry_value("collectgarbage", collectgarbage)?;
+        let load: Function = globals.get("load")?;
+        vm.set_named_registry_value("load", load)?;
+        let dump: Function = globals.get::<_, Table>("string")?.get("dump")?;
+        vm.set_named_registry_value("dump", dump)?;
+        globals.set("debug", Value::Nil)?;
+        let package: Table = globals.get("package")?;
+        let loaded: Table = package.get("loaded")?;
+        loaded.set("debug", Value::Nil)?;
+    }
+    Ok(vm)
+}
+
+// count the instructions run by the VM, read and reset with `take_instructions`.
+// a Lua hook function is set per thread, so every coroutine gets it when it is created.
+pub(crate) fn count_instructions(vm: &Lua) -> Result<(), LuaError> {
+    let counter = vm.load(
+        r#"
+        local sethook, step = ...
+        local count = 0
+        local function hook()
+            count = count + step
+        end
+        sethook(hook, "", step)
+
+        local create = coroutine.create
+        coroutine.create = function (f)
+            local thread = create(f)
+            sethook(thread, hook, "", step)
+            return thread
+        end
+        coroutine.wrap = function (f)
+            local thread = coroutine.create(f)
+            return function (...)
+                local ret = table.pack(coroutine.resume(thread, ...))
+                if not ret[1] then
+                    error(ret[2], 2)
+                end
+                return table.unpack(ret, 2, ret.n)
+            end
+        end
+
+        return function ()
+            local n = count
+            count = 0
+            return n
+        end
+        "#,
+        Some("CountInstructions"),
+    )?;
+    let sethook: Function = vm.named_registry_value("sethook")?;
+    let take: Function = counter.call((sethook, INSTRUCTION_STEP))?;
+    vm.set_named_registry_value("take_instructions", take)
+}
+
+pub(crate) fn load_prelude(vm: &Lua) -> Result<(), LuaError> {
+    // only actors can be profiled, see `Profile`
+    #[cfg(feature = "actix")]
+    profiler::install(vm)?;
+    let prelude = include_str!("lua/prelude.lua");
+    let traceback: Function = vm.named_registry_value("traceback")?;
+    let sethook: Function = vm.named_registry_value("sethook")?;
+    let gethook: Function = vm.named_registry_value("gethook")?;
+    let (cancelled, check_cancelled) = cancel::functions(vm)?;
+    vm.load(prelude, Some("Prelude"))?
+        .call::<_, ()>((traceback, sethook, gethook, cancelled, check_cancelled))
+}
+
+// compile the hooks into a VM with the prelude loaded
+pub(crate) fn load_hooks(
+    vm: &Lua,
+    hooks: &[(&str, Option<&str>)],
+) -> Result<(), ActixLuaError> {
+    let scripts: Table = vm.globals().get("__scripts")?;
+    for &(hook, script) in hooks {
+        if let Some(script) = script {
+            let f = vm
+                .load(script, Some(hook))
+                .map_err(|e| ActixLuaError::compile(hook, &e))?;
+            scripts.set(hook, f)?;
+        }
+    }
+    Ok(())
+}
+
+// the bytecode of the hooks loaded by `load_hooks`, with their debug information
+#[cfg(feature = "actix")]
+pub(crate) fn dump_hooks(
+    vm: &Lua,
+    hooks: &[(&'static str, Option<&str>)],
+) -> Result<Vec<(&'static str, Vec<u8>)>, ActixLuaError> {
+    let scripts: Table = vm.globals().get("__scripts")?;
+    let mut chunks = vec![];
+    for &(hook, script) in hooks {
+        if script.is_some() {
+            chunks.push((hook, dump(vm, scripts.get(hook)?)?));
+        }
+    }
+    Ok(chunks)
+}
+
+// load the hooks dumped by `dump_hooks` into a VM with the prelude loaded, without
+// compiling them again
+#[cfg(feature = "actix")]
+pub(crate) fn load_dumped_hooks(
+    vm: &Lua,
+    chunks: &[(&'static str, Vec<u8>)],
+) -> Result<(), ActixLuaError> {
+    let scripts: Table = vm.globals().get("__scripts")?;
+    for &(hook, ref chunk) in chunks {
+        scripts.set(hook, load_dumped(vm, hook, chunk)?)?;
+    }
+    Ok(())
+}
+
+// compile the modules `require` loads, given by their names, chunk names and sources
+pub(crate) fn preload_modules(
+    vm: &Lua,
+    modules: &[(&str, &str, &str)],
+) -> Result<(), ActixLuaError> {
+    let preload: Table = vm.globals().get::<_, Table>("package")?.get("preload")?;
+    for &(name, chunk_name, source) in modules {
+        let f = vm
+            .load(source, Some(chunk_name))
+            .map_err(|e| ActixLuaError::compile(chunk_name, &e))?;
+        preload.set(name, f)?;
+    }
+    Ok(())
+}
+
+// the bytecode of the modules `names` loaded by `preload_modules`, by name
+#[cfg(feature = "actix")]
+pub(crate) fn dump_modules<'a, I>(
+    vm: &Lua,
+    names: I,
+) -> Result<Vec<(String, Vec<u8>)>, ActixLuaError>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let preload: Table = vm.globals().get::<_, Table>("package")?.get("preload")?;
+    names
+        .into_iter()
+        .map(|name| Ok((name.to_string(), dump(vm, preload.get(name)?)?)))
+        .collect()
+}
+
+// preload the modules dumped by `dump_modules`, without compiling them again
+#[cfg(feature = "actix")]
+pub(crate) fn load_dumped_modules(
+    vm: &Lua,
+    chunks: &[(String, Vec<u8>)],
+) -> Result<(), ActixLuaError> {
+    let preload: Table = vm.globals().get::<_, Table>("package")?.get("preload")?;
+    for (name, chunk) in chunks {
+        preload.set(name.as_str(), load_dumped(vm, name, chunk)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "actix")]
+fn dump(vm: &Lua, f: Function) -> Result<Vec<u8>, ActixLuaError> {
+    let dump: Function = vm.named_registry_value("dump")?;
+    let chunk: rlua::String = dump.call(f)?;
+    Ok(chunk.as_bytes().to_vec())
+}
+
+#[cfg(feature = "actix")]
+fn load_dumped<'lua>(
+    vm: &'lua Lua,
+    name: &str,
+    chunk: &[u8],
+) -> Result<Function<'lua>, ActixLuaError> {
+    let load: Function = vm.named_registry_value("load")?;
+    let chunk = message::create_bytes(vm, chunk)?;
+    let (f, e): (Option<Function>, Option<String>) = load.call((chunk, name, "b"))?;
+    f.ok_or_else(|| {
+        let e = LuaError::RuntimeError(e.unwrap_or_default());
+        ActixLuaError::compile(name, &e)
+    })
+}
+
+// run `f`, turning a panic into `ActixLuaError::Panic`
+pub(crate) fn catch_panic<T, F>(f: F) -> Result<T, ActixLuaError>
+where
+    F: FnOnce() -> Result<T, ActixLuaError>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Err(ActixLuaError::Panic { message })
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use actix::msgs::{Execute, StopArbiter};
+use actix::prelude::*;
+use futures::Future;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actor::LuaActor;
+use builder::LuaActorTemplate;
+use error::ActixLuaError;
+
+/// An actor which pings the `LuaActor`s it watches every `interval`, and replaces one which
+/// doesn't answer within `deadline` with a fresh actor from its template, e.g. when a script is
+/// stuck in a loop without instruction limits, or in a blocking Rust callback.
+///
+/// Send `Watch` to start an actor under the watchdog. Every watched actor runs in an arbiter of
+/// its own, so a stuck actor only blocks its own thread. The thread can't be interrupted: the
+/// stuck actor is abandoned, and its arbiter stops if the actor ever gets unstuck. An actor
+/// which stopped is replaced as well.
+///
+/// The replacement has a new address, which `LookupWatched` finds. The recipient given to
+/// `with_alerts` gets a `WatchdogAlert` for every replacement.
+///
+/// ```rust,ignore
+/// let watchdog = LuaWatchdog::new(Duration::from_secs(1), Duration::from_secs(5))
+///     .with_alerts(alerts.recipient())
+///     .start();
+/// let template = LuaActorBuilder::new().on_handle("worker.lua").compile()?;
+/// let addr = watchdog.send(Watch {
+///     name: "worker".to_string(),
+///     template,
+/// });
+/// ```
+pub struct LuaWatchdog {
+    interval: Duration,
+    deadline: Duration,
+    watched: HashMap<String, Watched>,
+    alerts: Option<Recipient<WatchdogAlert>>,
+}
+
+struct Watched {
+    template: LuaActorTemplate,
+    // `None` while it is started, or after it couldn't be
+    instance: Option<Instance>,
+    starting: bool,
+    // bumped by every replacement, so the late pings of a replaced actor are ignored
+    generation: u64,
+    // whether the replaced actor timed out, for the alert
+    timed_out: bool,
+}
+
+struct Instance {
+    addr: Addr<LuaActor>,
+    arbiter: Addr<Arbiter>,
+}
+
+impl LuaWatchdog {
+    pub fn new(interval: Duration, deadline: Duration) -> Self {
+        LuaWatchdog {
+            interval,
+            deadline,
+            watched: HashMap::new(),
+            alerts: None,
+        }
+    }
+
+    /// send a `WatchdogAlert` to `recipient` whenever an actor is replaced.
+    pub fn with_alerts(mut self, recipient: Recipient<WatchdogAlert>) -> Self {
+        self.alerts = Some(recipient);
+        self
+    }
+
+    fn check(&mut self, ctx: &mut Context<Self>) {
+        let mut lost = vec![];
+        for (name, watched) in &self.watched {
+            let instance = match watched.instance {
+                Some(ref instance) => instance,
+                // retry the actors which couldn't be started
+                None if !watched.starting => {
+                    lost.push(name.clone());
+                    continue;
+                }
+                None => continue,
+            };
+            let (name, generation) = (name.clone(), watched.generation);
+            let ping = instance.addr.send(Ping).timeout(self.deadline);
+            ctx.spawn(ping.into_actor(self).then(move |res, act, ctx| {
+                if let Err(e) = res {
+                    error!("lua watchdog replaces actor `{}`: {}", name, e);
+                    if let Some(watched) = act.watched.get_mut(&name) {
+                        if watched.generation == generation && !watched.starting {
+                            watched.timed_out = match e {
+                                MailboxError::Timeout => true,
+                                MailboxError::Closed => false,
+                            };
+                            act.replace(&name, ctx);
+                        }
+                    }
+                }
+                actix::fut::ok(())
+            }));
+        }
+        for name in lost {
+            self.replace(&name, ctx);
+        }
+    }
+
+    // abandon the actor watched as `name`, and start a new one from its template
+    fn replace(&mut self, name: &str, ctx: &mut Context<Self>) {
+        let watched = match self.watched.get_mut(name) {
+            Some(watched) => watched,
+            None => return,
+        };
+        if let Some(instance) = watched.instance.take() {
+            instance.arbiter.do_send(StopArbiter(0));
+        }
+        watched.starting = true;
+        let name = name.to_string();
+        let start = start(&name, &watched.template);
+        ctx.spawn(start.into_actor(self).then(move |res, act, _| {
+            let watched = match act.watched.get_mut(&name) {
+                Some(watched) => watched,
+                None => return actix::fut::ok(()),
+            };
+            watched.starting = false;
+            match res {
+                Ok(instance) => {
+                    watched.generation += 1;
+                    if let Some(ref alerts) = act.alerts {
+                        let _ = alerts.do_send(WatchdogAlert {
+                            name: name.clone(),
+                            addr: instance.addr.clone(),
+                            timed_out: watched.timed_out,
+                        });
+                    }
+                    watched.instance = Some(instance);
+                }
+                Err(e) => error!("lua watchdog cannot restart actor `{}`: {}", name, e),
+            }
+            actix::fut::ok(())
+        }));
+    }
+}
+
+// start an actor from `template` in an arbiter of its own
+fn start(
+    name: &str,
+    template: &LuaActorTemplate,
+) -> Box<dyn Future<Item = Instance, Error = ActixLuaError>> {
+    let arbiter = Arbiter::new(format!("lua-watchdog-{}", name));
+    let template = template.clone();
+    let name = name.to_string();
+    let build = Execute::new(move || template.build().map(Actor::start));
+    Box::new(arbiter.send(build).then(move |res| match res {
+        Ok(Ok(addr)) => Ok(Instance { addr, arbiter }),
+        Ok(Err(e)) => {
+            arbiter.do_send(StopArbiter(0));
+            Err(e)
+        }
+        Err(e) => Err(ActixLuaError::SpawnError {
+            name,
+            message: e.to_string(),
+        }),
+    }))
+}
+
+impl Actor for LuaWatchdog {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(self.interval, |act, ctx| act.check(ctx));
+    }
+}
+
+// answered by a `LuaActor` without running its scripts
+struct Ping;
+
+impl Message for Ping {
+    type Result = ();
+}
+
+impl Handler<Ping> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Ping, _: &mut Context<Self>) {}
+}
+
+/// Start an actor from `template` under a `LuaWatchdog`, as `name`. The reply is its address.
+pub struct Watch {
+    pub name: String,
+    pub template: LuaActorTemplate,
+}
+
+impl Message for Watch {
+    type Result = Result<Addr<LuaActor>, ActixLuaError>;
+}
+
+/// Find the running actor watched as `name` by a `LuaWatchdog`.
+pub struct LookupWatched(pub String);
+
+impl Message for LookupWatched {
+    type Result = Option<Addr<LuaActor>>;
+}
+
+/// Sent by a `LuaWatchdog` when it replaced the actor watched as `name`.
+pub struct WatchdogAlert {
+    pub name: String,
+    /// The address of the new actor.
+    pub addr: Addr<LuaActor>,
+    /// Whether the replaced actor didn't answer in time, rather than having stopped.
+    pub timed_out: bool,
+}
+
+impl Message for WatchdogAlert {
+    type Result = ();
+}
+
+impl Handler<Watch> for LuaWatchdog {
+    type Result = ResponseActFuture<Self, Addr<LuaActor>, ActixLuaError>;
+
+    fn handle(&mut self, watch: Watch, _: &mut Context<Self>) -> Self::Result {
+        if self.watched.contains_key(&watch.name) {
+            return Box::new(actix::fut::err(ActixLuaError::SpawnError {
+                name: watch.name,
+                message: "the name is taken".to_string(),
+            }));
+        }
+        let name = watch.name;
+        let start = start(&name, &watch.template);
+        self.watched.insert(
+            name.clone(),
+            Watched {
+                template: watch.template,
+                instance: None,
+                starting: true,
+                generation: 0,
+                timed_out: false,
+            },
+        );
+        Box::new(start.into_actor(self).then(move |res, act, _| {
+            let res = res.map(|instance| {
+                let addr = instance.addr.clone();
+                if let Some(watched) = act.watched.get_mut(&name) {
+                    watched.instance = Some(instance);
+                    watched.starting = false;
+                }
+                addr
+            });
+            if res.is_err() {
+                act.watched.remove(&name);
+            }
+            actix::fut::result(res)
+        }))
+    }
+}
+
+impl Handler<LookupWatched> for LuaWatchdog {
+    type Result = Option<Addr<LuaActor>>;
+
+    fn handle(&mut self, lookup: LookupWatched, _: &mut Context<Self>) -> Self::Result {
+        let watched = self.watched.get(&lookup.0)?;
+        watched.instance.as_ref().map(|instance| instance.addr.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::LuaActorBuilder;
+    use futures_timer::Delay;
+    use message::LuaMessage;
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    struct Alerts(Arc<Mutex<Vec<(String, bool)>>>);
+
+    impl Actor for Alerts {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WatchdogAlert> for Alerts {
+        type Result = ();
+
+        fn handle(&mut self, alert: WatchdogAlert, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push((alert.name, alert.timed_out));
+        }
+    }
+
+    #[test]
+    fn watchdog_replaces_stuck_actor() {
+        let system = System::new("test");
+
+        let alerts = Arc::new(Mutex::new(vec![]));
+        let watchdog = LuaWatchdog::new(Duration::from_millis(50), Duration::from_millis(100))
+            .with_alerts(Alerts(alerts.clone()).start().recipient())
+            .start();
+        // blocks in native code
+        let template = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"if ctx.msg == "hang" then sleep(500) end return ctx.msg"#)
+            .with_vm(|vm| {
+                let sleep = vm.create_function(|_, millis: u64| {
+                    thread::sleep(Duration::from_millis(millis));
+                    Ok(())
+                })?;
+                vm.globals().set("sleep", sleep)
+            })
+            .compile()
+            .unwrap();
+
+        let lookup = watchdog.clone();
+        let l = watchdog
+            .send(Watch {
+                name: "worker".to_string(),
+                template: template.clone(),
+            })
+            .join(watchdog.send(Watch {
+                name: "worker".to_string(),
+                template,
+            }))
+            .and_then(|(first, taken)| {
+                match taken {
+                    Err(ActixLuaError::SpawnError { message, .. }) => {
+                        assert_eq!(message, "the name is taken")
+                    }
+                    _ => panic!("should return error"),
+                }
+                let first = first.unwrap();
+                first.do_send(LuaMessage::from("hang"));
+                Delay::new(Duration::from_millis(300))
+                    .map_err(|_| MailboxError::Closed)
+                    .and_then(move |()| lookup.send(LookupWatched("worker".to_string())))
+                    .map(move |second| (first, second.unwrap()))
+            })
+            .and_then(|(first, second)| {
+                assert!(first != second);
+                second.send(LuaMessage::from(1))
+            });
+        Arbiter::spawn(l.map(move |res| {
+            assert_eq!(res, LuaMessage::from(1));
+            assert_eq!(*alerts.lock().unwrap(), [("worker".to_string(), true)]);
+            System::current().stop();
+        }).map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+}
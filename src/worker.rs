@@ -0,0 +1,133 @@
+use futures::sync::oneshot;
+use futures::Future;
+use rlua::{Function, Lua, RegistryKey};
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use vm::catch_panic;
+use error::{ActixLuaError, AskFuture};
+use message::LuaMessage;
+
+// the compiled scripts kept by a worker, the cache is cleared beyond it
+const SCRIPT_CACHE_SIZE: usize = 64;
+
+struct Job {
+    script: Arc<str>,
+    msg: LuaMessage,
+    tx: oneshot::Sender<Result<LuaMessage, ActixLuaError>>,
+}
+
+/// Threads running Lua scripts on messages, for batch or ETL style work which doesn't need
+/// the identity or the state of an actor.
+///
+/// Each thread has a VM of its own with the standard libraries. A job runs `script` as a chunk
+/// with the message as its argument, `local msg = ...`, and resolves to what the script
+/// returns. Jobs are queued and taken by the first idle thread, so the pool is sized apart from
+/// the arbiters. Clones share the threads, which stop once every clone is dropped and the
+/// queued jobs are done.
+///
+/// ```rust,ignore
+/// let pool = LuaWorkerPool::new(4);
+/// let rows = lines.map(|line| pool.submit("return parse(...)", line.into()));
+/// ```
+#[derive(Clone)]
+pub struct LuaWorkerPool {
+    jobs: Sender<Job>,
+}
+
+impl LuaWorkerPool {
+    /// Start a pool of `threads` threads, at least one.
+    pub fn new(threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads.max(1) {
+            let queue = queue.clone();
+            thread::spawn(move || work(&queue));
+        }
+        LuaWorkerPool { jobs }
+    }
+
+    /// Run `script` on `msg` on one of the threads.
+    ///
+    /// The future fails with `ActixLuaError::CompileError` if the script doesn't compile, and
+    /// with the error it raised if it fails.
+    pub fn submit<S: Into<Arc<str>>>(&self, script: S, msg: LuaMessage) -> AskFuture<LuaMessage> {
+        let (tx, rx) = oneshot::channel();
+        let job = Job {
+            script: script.into(),
+            msg,
+            tx,
+        };
+        // the threads only stop once the pool is dropped
+        let _ = self.jobs.send(job);
+        // a worker which is gone drops the job
+        Box::new(rx.then(|res| res.unwrap_or(Err(ActixLuaError::ActorStopped))))
+    }
+}
+
+fn work(queue: &Mutex<Receiver<Job>>) {
+    let vm = Lua::new();
+    let mut scripts: HashMap<Arc<str>, RegistryKey> = HashMap::new();
+    loop {
+        // the lock is held while waiting, so the other threads wait for the lock instead
+        let Job { script, msg, tx } = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let res = catch_panic(|| run(&vm, &mut scripts, script, msg));
+        let _ = tx.send(res);
+    }
+}
+
+fn run(
+    vm: &Lua,
+    scripts: &mut HashMap<Arc<str>, RegistryKey>,
+    script: Arc<str>,
+    msg: LuaMessage,
+) -> Result<LuaMessage, ActixLuaError> {
+    let function: Function = match scripts.get(&script) {
+        Some(key) => vm.registry_value(key)?,
+        None => {
+            let function = vm
+                .load(&script, Some("job"))
+                .map_err(|e| ActixLuaError::compile("job", &e))?;
+            if scripts.len() >= SCRIPT_CACHE_SIZE {
+                scripts.clear();
+                vm.expire_registry_values();
+            }
+            scripts.insert(script, vm.create_registry_value(function.clone())?);
+            function
+        }
+    };
+    Ok(function.call(msg)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn worker_pool() {
+        let pool = LuaWorkerPool::new(2);
+        let square = "local n = ... return n * n";
+        let jobs: Vec<_> = (1..=10)
+            .map(|n| pool.submit(square, LuaMessage::from(n)))
+            .collect();
+        let squares = future::join_all(jobs).wait().unwrap();
+        let expected: Vec<_> = (1..=10).map(|n| LuaMessage::from(n * n)).collect();
+        assert_eq!(squares, expected);
+
+        match pool.submit("return (", LuaMessage::Nil).wait() {
+            Err(ActixLuaError::CompileError { hook, .. }) => assert_eq!(hook, "job"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match pool.submit("error('boom')", LuaMessage::Nil).wait() {
+            Err(ActixLuaError::RuntimeError { traceback }) => assert!(traceback.contains("boom")),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}